@@ -0,0 +1,36 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tera_core::quantization::{quantized_cosine, Quantized};
+use tera_core::similarity::{cosine_similarity, cosine_similarity_scalar};
+
+// Brute force search over a knowledge base of 10k chunks embedded in 384 dimensions
+fn vectors(count: usize) -> Vec<Vec<f32>> {
+    let mut seed = 42u64;
+    (0..count)
+        .map(|_| {
+            (0..384)
+                .map(|_| {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (seed >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn similarity(c: &mut Criterion) {
+    let index = vectors(10_000);
+    let query = &vectors(1)[0];
+    c.bench_function("cosine_similarity", |b| {
+        b.iter(|| index.iter().map(|v| cosine_similarity(black_box(query), v)).sum::<f32>())
+    });
+    c.bench_function("cosine_similarity_scalar", |b| {
+        b.iter(|| index.iter().map(|v| cosine_similarity_scalar(black_box(query), v)).sum::<f32>())
+    });
+    let quantized = index.iter().map(|v| Quantized::new(v)).collect::<Vec<_>>();
+    c.bench_function("quantized_cosine", |b| {
+        b.iter(|| quantized.iter().map(|v| quantized_cosine(black_box(query), v)).sum::<f32>())
+    });
+}
+
+criterion_group!(benches, similarity);
+criterion_main!(benches);
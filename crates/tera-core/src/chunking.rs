@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+
+/// Longest line `split_chunks` keeps whole
+pub const MAX_CHUNK_LEN: usize = 1000;
+
+/// Writing systems, which end sentences differently
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Script {
+    /// Latin, Cyrillic, Greek and other scripts ending sentences with `.`, `!` or `?` and a space
+    Latin,
+    /// Chinese, Japanese and Korean, ending sentences with `。`, `！` or `？` and no space
+    Cjk,
+    /// Hindi and other languages ending sentences with `।`
+    Devanagari,
+    /// Arabic and Persian, asking questions with `؟`
+    Arabic,
+}
+
+impl Script {
+    /// Script most letters of the text are written in
+    pub fn detect(text: &str) -> Script {
+        let (mut latin, mut cjk, mut devanagari, mut arabic) = (0, 0, 0, 0);
+        for c in text.chars().filter(|c| c.is_alphabetic()) {
+            match c as u32 {
+                0x3040..=0x30ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xac00..=0xd7af | 0xf900..=0xfaff => cjk += 1,
+                0x0900..=0x097f => devanagari += 1,
+                0x0600..=0x06ff | 0x0750..=0x077f => arabic += 1,
+                _ => latin += 1,
+            }
+        }
+        [
+            (Script::Cjk, cjk),
+            (Script::Devanagari, devanagari),
+            (Script::Arabic, arabic),
+        ]
+        .into_iter()
+        .filter(|(_, count)| *count > latin)
+        .max_by_key(|(_, count)| *count)
+        .map(|(script, _)| script)
+        .unwrap_or(Script::Latin)
+    }
+
+    fn ends_sentence(self, c: char, next: Option<char>) -> bool {
+        let spaced = |terminators: &[char]| terminators.contains(&c) && next.map(char::is_whitespace).unwrap_or(true);
+        match self {
+            // a period in 3.14 or example.com doesn't end a sentence
+            Script::Latin => spaced(&['.', '!', '?']),
+            // sentences follow each other without spaces
+            Script::Cjk => ['。', '！', '？', '．'].contains(&c) || spaced(&['.', '!', '?']),
+            Script::Devanagari => ['।', '॥'].contains(&c) || spaced(&['.', '!', '?']),
+            Script::Arabic => spaced(&['.', '!', '?', '؟']),
+        }
+    }
+}
+
+/// Splits a text in sentences, keeping their punctuation
+pub fn split_sentences(text: &str, script: Script) -> Vec<&str> {
+    let mut sentences = vec![];
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|(_, next)| *next);
+        if script.ends_sentence(c, next) {
+            let end = i + c.len_utf8();
+            sentences.push(text[start..end].trim());
+            start = end;
+        }
+    }
+    sentences.push(text[start..].trim());
+    sentences.retain(|s| !s.is_empty());
+    sentences
+}
+
+/// Splits a text in lines, lines longer than 1000 characters get split in sentences as well.
+/// Windows line endings are handled too.
+pub fn split_chunks(text: &str) -> Vec<&str> {
+    split_chunks_at(text, MAX_CHUNK_LEN)
+}
+
+/// Like `split_chunks`, with lines longer than `max_len` characters split in sentences
+pub fn split_chunks_at(text: &str, max_len: usize) -> Vec<&str> {
+    split_chunks_in(text, max_len, Script::detect(text))
+}
+
+/// Like `split_chunks_at`, splitting sentences the way the given script ends them
+pub fn split_chunks_in(text: &str, max_len: usize, script: Script) -> Vec<&str> {
+    split_chunks_by(text, max_len, script, &str::len)
+}
+
+/// Splits a text in lines no longer than `max_len` as measured by `len`, e.g. in tokens.
+/// Longer lines get split in sentences, and sentences still too long between words.
+pub fn split_chunks_by<'a>(text: &'a str, max_len: usize, script: Script, len: &impl Fn(&str) -> usize) -> Vec<&'a str> {
+    let mut chunks = vec![];
+    for line in text.lines().filter(|l| !l.is_empty()) {
+        if len(line) <= max_len {
+            chunks.push(line);
+            continue;
+        }
+        for sentence in split_sentences(line, script) {
+            if len(sentence) <= max_len {
+                chunks.push(sentence);
+            } else {
+                chunks.extend(split_to_fit(sentence, max_len, script, len));
+            }
+        }
+    }
+
+    chunks
+}
+
+// Fills pieces with as many words as fit, a single word longer than `max_len` is kept whole
+fn split_to_fit<'a>(sentence: &'a str, max_len: usize, script: Script, len: &impl Fn(&str) -> usize) -> Vec<&'a str> {
+    // any character ends a word in scripts written without spaces
+    let ends = sentence.char_indices().map(|(i, c)| i + c.len_utf8()).filter(|&end| {
+        script == Script::Cjk || end == sentence.len() || sentence[end..].starts_with(char::is_whitespace)
+    });
+    let mut pieces = vec![];
+    let (mut start, mut fitting) = (0, None);
+    for end in ends {
+        if len(sentence[start..end].trim()) <= max_len {
+            fitting = Some(end);
+            continue;
+        }
+        if let Some(cut) = fitting.take() {
+            pieces.push(sentence[start..cut].trim());
+            start = cut;
+        }
+        if len(sentence[start..end].trim()) <= max_len {
+            fitting = Some(end);
+        } else {
+            pieces.push(sentence[start..end].trim());
+            start = end;
+        }
+    }
+    pieces.push(sentence[start..].trim());
+    pieces.retain(|p| !p.is_empty());
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_text_has_no_chunks() {
+        assert!(split_chunks("").is_empty());
+        assert!(split_chunks("\n\r\n\n").is_empty());
+    }
+
+    #[test]
+    fn keeps_short_lines_whole() {
+        assert_eq!(split_chunks("First line.\r\nSecond line.\n"), ["First line.", "Second line."]);
+    }
+
+    #[test]
+    fn splits_long_lines_in_sentences_then_words() {
+        let line = "Pi is 3.14 or so. This sentence is far too long to fit in one chunk.";
+        let chunks = split_chunks_by(line, 20, Script::Latin, &str::len);
+        assert_eq!(chunks[0], "Pi is 3.14 or so.");
+        assert!(chunks[1..].iter().all(|chunk| chunk.len() <= 20));
+        assert_eq!(chunks[1..].join(" "), "This sentence is far too long to fit in one chunk.");
+    }
+
+    #[test]
+    fn keeps_words_longer_than_a_chunk() {
+        let word = "a".repeat(30);
+        assert_eq!(split_chunks_by(&format!("{} b", word), 10, Script::Latin, &str::len), [word.as_str(), "b"]);
+    }
+
+    #[test]
+    fn cuts_multibyte_text_between_characters() {
+        let text = "日本語の文章です。句読点で区切られています。".repeat(20);
+        let chars = |chunk: &str| chunk.chars().count();
+        let chunks = split_chunks_by(&text, 15, Script::detect(&text), &chars);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chars(chunk) <= 15));
+        assert_eq!(chunks.concat(), text);
+
+        let accented = "é".repeat(50);
+        let chunks = split_chunks_by(&accented, 20, Script::Cjk, &chars);
+        assert_eq!(chunks.iter().map(|chunk| chars(chunk)).collect::<Vec<_>>(), [20, 20, 10]);
+    }
+
+    #[test]
+    fn detects_scripts() {
+        assert_eq!(Script::detect("Bonjour tout le monde"), Script::Latin);
+        assert_eq!(Script::detect("今日は晴れです"), Script::Cjk);
+        assert_eq!(Script::detect("नमस्ते दुनिया"), Script::Devanagari);
+        assert_eq!(Script::detect("مرحبا بالعالم"), Script::Arabic);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::similarity::cosine_similarity;
+
+/// Vectors grouped around centroids by [`kmeans`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clusters {
+    /// Unit length, so they compare to vectors like them
+    pub centroids: Vec<Vec<f32>>,
+    /// The cluster of each vector, in the order of the vectors
+    pub assignments: Vec<usize>,
+}
+
+/// Groups vectors by direction with spherical k-means, cosine similarity deciding which centroid is
+/// closest. The first centroids are spread out from the first vector, so the result only depends on
+/// the order of the vectors. Fewer than `k` clusters come out when there are fewer distinct vectors.
+pub fn kmeans(vectors: &[Vec<f32>], k: usize, iterations: usize) -> Clusters {
+    let mut centroids = initial_centroids(vectors, k.max(1));
+    let mut assignments = vec![0; vectors.len()];
+    for _ in 0..iterations {
+        let assigned = vectors.iter().map(|v| closest(&centroids, v)).collect::<Vec<_>>();
+        let settled = assigned == assignments;
+        assignments = assigned;
+        if settled {
+            break;
+        }
+        for (index, centroid) in centroids.iter_mut().enumerate() {
+            let members = vectors.iter().zip(&assignments).filter(|(_, a)| **a == index);
+            // a cluster left without members keeps its centroid, it may win some back
+            if let Some(mean) = mean(members.map(|(v, _)| v.as_slice())) {
+                *centroid = mean;
+            }
+        }
+    }
+    Clusters {
+        centroids,
+        assignments,
+    }
+}
+
+// The sum of the vectors scaled to unit length, which points in their mean direction
+fn mean<'a>(mut vectors: impl Iterator<Item = &'a [f32]>) -> Option<Vec<f32>> {
+    let mut sum = vectors.next()?.to_vec();
+    for vector in vectors {
+        for (total, x) in sum.iter_mut().zip(vector) {
+            *total += x;
+        }
+    }
+    Some(normalized(sum))
+}
+
+fn normalized(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+    vector
+}
+
+fn closest(centroids: &[Vec<f32>], vector: &[f32]) -> usize {
+    centroids
+        .iter()
+        .map(|c| cosine_similarity(c, vector))
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(index, _)| index)
+        .unwrap_or(0)
+}
+
+// Farthest point first: each next centroid is the vector least similar to the centroids so far
+fn initial_centroids(vectors: &[Vec<f32>], k: usize) -> Vec<Vec<f32>> {
+    let Some(first) = vectors.first() else {
+        return vec![];
+    };
+    let mut centroids = vec![normalized(first.clone())];
+    let mut nearest = vectors.iter().map(|v| cosine_similarity(first, v)).collect::<Vec<_>>();
+    while centroids.len() < k {
+        let least_similar = nearest.iter().copied().enumerate().min_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((index, similarity)) = least_similar else {
+            break;
+        };
+        // what is left are duplicates of the centroids
+        if similarity >= 1.0 - f32::EPSILON {
+            break;
+        }
+        let centroid = normalized(vectors[index].clone());
+        for (best, vector) in nearest.iter_mut().zip(vectors) {
+            *best = best.max(cosine_similarity(&centroid, vector));
+        }
+        centroids.push(centroid);
+    }
+    centroids
+}
@@ -0,0 +1,82 @@
+use crate::similarity::cosine_similarity;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A chunk with its embedding
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub id: String,
+    pub content_id: String,
+    pub content: String,
+    pub metadata: Value,
+    pub vector: Vec<f32>,
+}
+
+/// An index exported with `tera export-index`. Queries have to be embedded with the same
+/// embedder, which stays on the host.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportedIndex {
+    /// The embedding backend the vectors come from
+    pub embedder: Value,
+    pub dimensions: usize,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hit {
+    pub score: f32,
+    pub id: String,
+    pub content_id: String,
+    pub content: String,
+    pub metadata: Value,
+}
+
+/// Where the entries of an index are kept
+pub trait VectorStore {
+    fn insert(&mut self, entry: Entry);
+    fn entries(&self) -> Box<dyn Iterator<Item = &Entry> + '_>;
+}
+
+/// Keeps every entry in memory, fine for the size of a personal knowledge base
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    entries: Vec<Entry>,
+}
+
+impl VectorStore for MemoryStore {
+    fn insert(&mut self, entry: Entry) {
+        self.entries.push(entry);
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = &Entry> + '_> {
+        Box::new(self.entries.iter())
+    }
+}
+
+impl From<ExportedIndex> for MemoryStore {
+    fn from(index: ExportedIndex) -> Self {
+        Self {
+            entries: index.entries,
+        }
+    }
+}
+
+/// The `limit` entries most similar to the query, best first
+pub fn search<S: VectorStore + ?Sized>(store: &S, query: &[f32], limit: usize) -> Vec<Hit> {
+    let mut scored = store
+        .entries()
+        .map(|entry| (cosine_similarity(query, &entry.vector), entry))
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(score, entry)| Hit {
+            score,
+            id: entry.id.clone(),
+            content_id: entry.content_id.clone(),
+            content: entry.content.clone(),
+            metadata: entry.metadata.clone(),
+        })
+        .collect()
+}
@@ -0,0 +1,12 @@
+//! Chunking, vector search, clustering and projection shared by Tera and its browser frontend.
+//!
+//! Nothing in here touches the file system or the network so it compiles to `wasm32-unknown-unknown`,
+//! storage is plugged in through [`index::VectorStore`].
+pub mod chunking;
+pub mod clustering;
+pub mod index;
+pub mod projection;
+pub mod quantization;
+pub mod similarity;
+#[cfg(feature = "wasm")]
+pub mod wasm;
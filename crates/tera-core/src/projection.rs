@@ -0,0 +1,58 @@
+// Power iterations per component, plenty for the two leading ones to settle
+const ITERATIONS: usize = 100;
+
+/// Projects vectors on their first two principal components, e.g. to draw a knowledge base on a plane.
+/// Vectors close in embedding space stay close; the axes have no meaning of their own.
+pub fn project_2d(vectors: &[Vec<f32>]) -> Vec<[f32; 2]> {
+    let Some(dimensions) = vectors.iter().map(|v| v.len()).min() else {
+        return vec![];
+    };
+    let mut mean = vec![0.0; dimensions];
+    for vector in vectors {
+        for (total, x) in mean.iter_mut().zip(vector) {
+            *total += x / vectors.len() as f32;
+        }
+    }
+    let centered = vectors
+        .iter()
+        .map(|v| v.iter().zip(&mean).map(|(x, m)| x - m).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let first = principal_component(&centered, &[]);
+    let second = principal_component(&centered, &[&first]);
+    centered.iter().map(|v| [dot(v, &first), dot(v, &second)]).collect()
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+// The direction of most variance orthogonal to the components found before, by power iteration
+// over the covariance without building it: v <- Xᵀ X v
+fn principal_component(centered: &[Vec<f32>], found: &[&[f32]]) -> Vec<f32> {
+    let dimensions = centered[0].len();
+    // deterministic start, not orthogonal to the data in practice
+    let mut component = (0..dimensions).map(|i| 1.0 / (i + 1) as f32).collect::<Vec<_>>();
+    for _ in 0..ITERATIONS {
+        let mut next = vec![0.0; dimensions];
+        for vector in centered {
+            let along = dot(vector, &component);
+            for (n, x) in next.iter_mut().zip(vector) {
+                *n += along * x;
+            }
+        }
+        for previous in found {
+            let overlap = dot(&next, previous);
+            for (n, p) in next.iter_mut().zip(previous.iter()) {
+                *n -= overlap * p;
+            }
+        }
+        let norm = dot(&next, &next).sqrt();
+        // no variance left, e.g. a single vector
+        if norm == 0.0 {
+            return vec![0.0; dimensions];
+        }
+        component = next.into_iter().map(|x| x / norm).collect();
+    }
+    component
+}
@@ -0,0 +1,105 @@
+use crate::similarity::{cosine, dot_and_norms};
+
+// Codes converted to floats at once, on the stack, for the SIMD kernels
+const BLOCK: usize = 64;
+
+/// A vector stored with one byte per dimension, about a quarter of its `f32` size
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantized {
+    /// Value of the code 1, codes span the largest magnitude of the vector
+    pub scale: f32,
+    pub codes: Vec<i8>,
+}
+
+impl Quantized {
+    /// Quantizes a vector symmetrically to int8
+    pub fn new(vector: &[f32]) -> Quantized {
+        let max = vector.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        let scale = if max == 0.0 { 1.0 } else { max / 127.0 };
+        Quantized {
+            scale,
+            codes: vector.iter().map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8).collect(),
+        }
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.codes.iter().map(|c| *c as f32 * self.scale).collect()
+    }
+
+    /// The scale in little endian followed by the codes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.scale.to_le_bytes().to_vec();
+        bytes.extend(self.codes.iter().map(|c| *c as u8));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Quantized> {
+        let scale = f32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+        Some(Quantized {
+            scale,
+            codes: bytes[4..].iter().map(|b| *b as i8).collect(),
+        })
+    }
+}
+
+/// Cosine similarity of a query to a quantized vector, computed on the codes as the scale cancels out
+pub fn quantized_cosine(query: &[f32], vector: &Quantized) -> f32 {
+    let n = query.len().min(vector.codes.len());
+    let mut block = [0.0f32; BLOCK];
+    let (mut dot, mut norm_query, mut norm_codes) = (0.0, 0.0, 0.0);
+    for (query, codes) in query[..n].chunks(BLOCK).zip(vector.codes[..n].chunks(BLOCK)) {
+        for (x, code) in block.iter_mut().zip(codes) {
+            *x = *code as f32;
+        }
+        let (block_dot, block_query, block_codes) = dot_and_norms(query, &block[..codes.len()]);
+        dot += block_dot;
+        norm_query += block_query;
+        norm_codes += block_codes;
+    }
+    norm_query += query[n..].iter().map(|x| x * x).sum::<f32>();
+    norm_codes += vector.codes[n..].iter().map(|c| (*c as f32).powi(2)).sum::<f32>();
+    cosine(dot, norm_query, norm_codes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity::cosine_similarity_scalar;
+
+    #[test]
+    fn dequantizes_within_half_a_step() {
+        let vector = (0..385).map(|i| (i as f32 * 0.37).sin() * 3.0).collect::<Vec<_>>();
+        let quantized = Quantized::new(&vector);
+        assert_eq!(quantized.codes.iter().map(|c| c.unsigned_abs()).max(), Some(127));
+        for (x, y) in vector.iter().zip(quantized.dequantize()) {
+            assert!((x - y).abs() <= quantized.scale / 2.0 + 1e-6, "{} != {}", x, y);
+        }
+    }
+
+    #[test]
+    fn quantizes_zero_vectors() {
+        let quantized = Quantized::new(&[0.0; 8]);
+        assert_eq!(quantized.dequantize(), [0.0; 8]);
+        assert_eq!(quantized_cosine(&[1.0; 8], &quantized), 0.0);
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let quantized = Quantized::new(&[0.5, -1.0, 0.25, 0.0]);
+        let bytes = quantized.to_bytes();
+        assert_eq!(bytes.len(), 4 + 4);
+        assert_eq!(Quantized::from_bytes(&bytes), Some(quantized));
+        assert_eq!(Quantized::from_bytes(&bytes[..3]), None);
+    }
+
+    #[test]
+    fn quantized_cosine_matches_the_codes_as_floats() {
+        let query = (0..385).map(|i| (i as f32 * 0.37).sin()).collect::<Vec<_>>();
+        let vector = Quantized::new(&(0..385).map(|i| (i as f32 * 0.11).cos()).collect::<Vec<_>>());
+        let codes = vector.codes.iter().map(|c| *c as f32).collect::<Vec<_>>();
+        let expected = cosine_similarity_scalar(&query, &codes);
+        assert!((quantized_cosine(&query, &vector) - expected).abs() < 1e-5);
+        let shorter = cosine_similarity_scalar(&query[..100], &codes);
+        assert!((quantized_cosine(&query[..100], &vector) - shorter).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,163 @@
+/// Cosine similarity of two vectors, with AVX2 or NEON kernels when the CPU has them
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let (dot, mut norm_a, mut norm_b) = dot_and_norms(&a[..n], &b[..n]);
+    // like zip, the dot product stops at the shorter vector while the norms cover both
+    norm_a += a[n..].iter().map(|x| x * x).sum::<f32>();
+    norm_b += b[n..].iter().map(|x| x * x).sum::<f32>();
+    cosine(dot, norm_a, norm_b)
+}
+
+/// The portable implementation, which the SIMD kernels are benchmarked against
+pub fn cosine_similarity_scalar(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>();
+    cosine(dot, norm_a, norm_b)
+}
+
+pub(crate) fn cosine(dot: f32, norm_a: f32, norm_b: f32) -> f32 {
+    let (norm_a, norm_b) = (norm_a.sqrt(), norm_b.sqrt());
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+// Dot product and squared norms in a single pass over vectors of the same length
+pub(crate) fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+    #[cfg(target_arch = "x86_64")]
+    if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+        // SAFETY: the CPU supports the features the kernel is compiled for
+        return unsafe { avx2::dot_and_norms(a, b) };
+    }
+    #[cfg(target_arch = "aarch64")]
+    // SAFETY: NEON is part of every aarch64 CPU
+    return unsafe { neon::dot_and_norms(a, b) };
+    #[allow(unreachable_code)]
+    scalar_dot_and_norms(a, b, 0)
+}
+
+// From `start` on, for the tails the kernels leave
+fn scalar_dot_and_norms(a: &[f32], b: &[f32], start: usize) -> (f32, f32, f32) {
+    a[start..]
+        .iter()
+        .zip(&b[start..])
+        .fold((0.0, 0.0, 0.0), |(dot, norm_a, norm_b), (x, y)| {
+            (dot + x * y, norm_a + x * x, norm_b + y * y)
+        })
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        let (mut dot, mut norm_a, mut norm_b) = (_mm256_setzero_ps(), _mm256_setzero_ps(), _mm256_setzero_ps());
+        let mut i = 0;
+        while i + 8 <= a.len() {
+            let x = _mm256_loadu_ps(a.as_ptr().add(i));
+            let y = _mm256_loadu_ps(b.as_ptr().add(i));
+            dot = _mm256_fmadd_ps(x, y, dot);
+            norm_a = _mm256_fmadd_ps(x, x, norm_a);
+            norm_b = _mm256_fmadd_ps(y, y, norm_b);
+            i += 8;
+        }
+        let (tail_dot, tail_a, tail_b) = super::scalar_dot_and_norms(a, b, i);
+        (sum(dot) + tail_dot, sum(norm_a) + tail_a, sum(norm_b) + tail_b)
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn sum(v: __m256) -> f32 {
+        let mut lanes = [0.0f32; 8];
+        _mm256_storeu_ps(lanes.as_mut_ptr(), v);
+        lanes.iter().sum()
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    pub unsafe fn dot_and_norms(a: &[f32], b: &[f32]) -> (f32, f32, f32) {
+        let (mut dot, mut norm_a, mut norm_b) = (vdupq_n_f32(0.0), vdupq_n_f32(0.0), vdupq_n_f32(0.0));
+        let mut i = 0;
+        while i + 4 <= a.len() {
+            let x = vld1q_f32(a.as_ptr().add(i));
+            let y = vld1q_f32(b.as_ptr().add(i));
+            dot = vfmaq_f32(dot, x, y);
+            norm_a = vfmaq_f32(norm_a, x, x);
+            norm_b = vfmaq_f32(norm_b, y, y);
+            i += 4;
+        }
+        let (tail_dot, tail_a, tail_b) = super::scalar_dot_and_norms(a, b, i);
+        (vaddvq_f32(dot) + tail_dot, vaddvq_f32(norm_a) + tail_a, vaddvq_f32(norm_b) + tail_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lengths around the 8 and 4 lanes of the kernels, so their tails get covered too
+    const LENGTHS: [usize; 9] = [0, 1, 3, 4, 7, 8, 9, 17, 385];
+
+    fn vector(length: usize, seed: u64) -> Vec<f32> {
+        let mut seed = seed;
+        (0..length)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (seed >> 40) as f32 / (1u64 << 24) as f32 - 0.5
+            })
+            .collect()
+    }
+
+    fn assert_close(kernel: (f32, f32, f32), scalar: (f32, f32, f32)) {
+        for (k, s) in [(kernel.0, scalar.0), (kernel.1, scalar.1), (kernel.2, scalar.2)] {
+            assert!((k - s).abs() <= 1e-4 * s.abs().max(1.0), "{} != {}", k, s);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn avx2_matches_scalar() {
+        if !is_x86_feature_detected!("avx2") || !is_x86_feature_detected!("fma") {
+            return;
+        }
+        for length in LENGTHS {
+            let (a, b) = (vector(length, 1), vector(length, 2));
+            let kernel = unsafe { avx2::dot_and_norms(&a, &b) };
+            assert_close(kernel, scalar_dot_and_norms(&a, &b, 0));
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn neon_matches_scalar() {
+        for length in LENGTHS {
+            let (a, b) = (vector(length, 1), vector(length, 2));
+            let kernel = unsafe { neon::dot_and_norms(&a, &b) };
+            assert_close(kernel, scalar_dot_and_norms(&a, &b, 0));
+        }
+    }
+
+    #[test]
+    fn scores_parallel_opposite_and_zero_vectors() {
+        let a = vector(17, 5);
+        let opposite = a.iter().map(|x| -x).collect::<Vec<_>>();
+        assert!((cosine_similarity(&a, &a) - 1.0).abs() < 1e-5);
+        assert!((cosine_similarity(&a, &opposite) + 1.0).abs() < 1e-5);
+        assert_eq!(cosine_similarity(&a, &[0.0; 17]), 0.0);
+        assert_eq!(cosine_similarity(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn matches_scalar_cosine() {
+        for length in LENGTHS {
+            let (a, b) = (vector(length, 3), vector(length + length % 2, 4));
+            let (simd, scalar) = (cosine_similarity(&a, &b), cosine_similarity_scalar(&a, &b));
+            assert!((simd - scalar).abs() < 1e-5, "{} != {} for {} dimensions", simd, scalar, length);
+        }
+    }
+}
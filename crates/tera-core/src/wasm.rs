@@ -0,0 +1,34 @@
+use crate::index::{search, ExportedIndex, MemoryStore};
+use wasm_bindgen::prelude::*;
+
+/// An exported index loaded in the browser
+#[wasm_bindgen]
+pub struct Index {
+    store: MemoryStore,
+    dimensions: usize,
+}
+
+#[wasm_bindgen]
+impl Index {
+    /// Loads the JSON written by `tera export-index`
+    #[wasm_bindgen(constructor)]
+    pub fn new(json: &str) -> Result<Index, JsError> {
+        let index: ExportedIndex = serde_json::from_str(json)?;
+        Ok(Index {
+            dimensions: index.dimensions,
+            store: index.into(),
+        })
+    }
+
+    /// Searches with a query embedded by the host, returns the hits as JSON
+    pub fn search(&self, query: &[f32], limit: usize) -> Result<String, JsError> {
+        if query.len() != self.dimensions {
+            return Err(JsError::new(&format!(
+                "Expected a query vector of {} dimensions, got {}",
+                self.dimensions,
+                query.len()
+            )));
+        }
+        Ok(serde_json::to_string(&search(&self.store, query, limit))?)
+    }
+}
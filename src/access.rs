@@ -0,0 +1,70 @@
+use crate::config::{live, ApiKey};
+use crate::database::VectorIndex;
+use std::future::Future;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+tokio::task_local! {
+    // The key of the client being served
+    static GRANTED: Arc<ApiKey>;
+}
+
+/// The key of an `Authorization: Bearer <key>` header, if it is one of the `api_keys` or the `token`
+/// the server takes besides them. A server without either lets every client do anything.
+pub fn authenticate(authorization: Option<&str>, token: Option<&str>) -> Option<ApiKey> {
+    let config = live();
+    if config.api_keys.is_empty() && token.is_none() {
+        return Some(ApiKey::admin());
+    }
+    let sent = authorization?.strip_prefix("Bearer ")?.trim();
+    if token.is_some_and(|token| same_key(sent, token)) {
+        return Some(ApiKey::admin());
+    }
+    config.api_keys.iter().find(|k| same_key(sent, &k.key)).cloned()
+}
+
+// In constant time, so how long a comparison takes tells nothing of the keys
+fn same_key(sent: &str, key: &str) -> bool {
+    sent.as_bytes().ct_eq(key.as_bytes()).into()
+}
+
+/// Runs `f` for the client holding the key, what it reads and memorizes being limited to the
+/// collections of the key
+pub async fn scope<F: Future>(key: ApiKey, f: F) -> F::Output {
+    GRANTED.scope(Arc::new(key), f).await
+}
+
+/// The key of the client being served, to serve it from another task with `scope`
+pub fn granted() -> Option<ApiKey> {
+    GRANTED.try_with(|key| (**key).clone()).ok()
+}
+
+/// Whether the client being served may read the chunk, any chunk can be read outside of a `scope`
+pub fn readable(chunk: &VectorIndex) -> bool {
+    let source = chunk.metadata["source"].as_str().unwrap_or_default();
+    let connector = chunk.metadata["connector"].as_str().unwrap_or_default();
+    writable(source, connector)
+}
+
+/// Whether the client being served may memorize content from the source with the connector
+pub fn writable(source: &str, connector: &str) -> bool {
+    GRANTED.try_with(|key| key.covers(source, connector)).unwrap_or(true)
+}
+
+/// Whether the client being served reads every collection, e.g. to see the titles of all documents
+pub fn unrestricted() -> bool {
+    GRANTED.try_with(|key| key.collections.is_empty()).unwrap_or(true)
+}
+
+/// The name of the key of the client being served, `None` outside of a `scope` or for a server
+/// without keys
+pub fn client() -> Option<String> {
+    GRANTED
+        .try_with(|key| match (&key.name, key.key.is_empty()) {
+            (Some(name), _) => Some(name.clone()),
+            (None, true) => None,
+            (None, false) => Some("unnamed key".to_string()),
+        })
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,215 @@
+use crate::chat::Session;
+use crate::config::{EmbeddingBackend, DATA_DIR, EMBEDDINGS};
+use crate::database::{self, Clauses, SearchPage, VectorIndex, DB};
+use crate::embeddings::{embed, embed_texts};
+use crate::inference::{
+    answer_or_clarify, answer_over_text, answer_timeline, answer_with_context, compare_documents,
+    count_tokens, retrieve_context, Answer, AnswerLength, AnswerOptions, Limits, Model, Reply,
+};
+use crate::ingest::ingest_text;
+use crate::notify::{self, Notifier};
+use crate::postprocess::PostProcessor;
+use crate::tasks::{self, Task};
+use crate::tts;
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Tera for other Rust apps, create it with [`Tera::builder`]
+#[derive(Debug, Clone)]
+pub struct Tera {
+    options: AnswerOptions,
+}
+
+/// Settings of a [`Tera`]. The data directory and the embedder are shared by the whole process,
+/// so they can only be chosen once, before anything was memorized or asked.
+#[derive(Debug, Clone, Default)]
+pub struct TeraBuilder {
+    options: AnswerOptions,
+    data_dir: Option<PathBuf>,
+    embedder: Option<EmbeddingBackend>,
+    notifiers: Vec<Arc<dyn Notifier>>,
+}
+
+impl TeraBuilder {
+    pub fn model(mut self, model: Model) -> Self {
+        self.options.model = model;
+        self
+    }
+
+    /// LoRA adapter merged into the model
+    pub fn lora(mut self, adapter: impl Into<PathBuf>) -> Self {
+        self.options.lora = Some(adapter.into());
+        self
+    }
+
+    pub fn length(mut self, length: AnswerLength) -> Self {
+        self.options.length = length;
+        self
+    }
+
+    pub fn follow_ups(mut self, follow_ups: bool) -> Self {
+        self.options.follow_ups = follow_ups;
+        self
+    }
+
+    /// Caps on generating each answer, reaching one ends it at its last complete sentence
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.options.limits = limits;
+        self
+    }
+
+    /// Adds a step rewriting answers, run after the configured `post_processing`
+    pub fn post_processor(mut self, step: impl PostProcessor + 'static) -> Self {
+        self.options.post_processors.push(Arc::new(step));
+        self
+    }
+
+    /// Also delivers reminders and saved search matches through this notifier
+    pub fn notifier(mut self, notifier: impl Notifier + 'static) -> Self {
+        self.notifiers.push(Arc::new(notifier));
+        self
+    }
+
+    /// Where the config and the database live, `tera` in the local config directory by default
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// Overrides the embedder of the config file
+    pub fn embedder(mut self, embedder: EmbeddingBackend) -> Self {
+        self.embedder = Some(embedder);
+        self
+    }
+
+    /// Opens the database
+    pub async fn build(self) -> Result<Tera> {
+        if let Some(dir) = self.data_dir {
+            std::fs::create_dir_all(&dir)?;
+            if DATA_DIR.get_or_init(|| dir.clone()) != &dir {
+                anyhow::bail!(
+                    "Tera is already using the data directory {}",
+                    DATA_DIR.get().unwrap().display()
+                );
+            }
+        }
+        if let Some(embedder) = self.embedder {
+            if EMBEDDINGS.get_or_init(|| embedder.clone()) != &embedder {
+                anyhow::bail!("Tera is already using another embedder");
+            }
+        }
+        for notifier in self.notifiers {
+            notify::register(notifier);
+        }
+        DB.get().await;
+
+        Ok(Tera {
+            options: self.options,
+        })
+    }
+}
+
+impl Tera {
+    pub fn builder() -> TeraBuilder {
+        TeraBuilder::default()
+    }
+
+    /// Answers a question from the memorized content
+    pub async fn ask(&self, question: &str) -> Result<Answer> {
+        let context = retrieve_context(question, &self.options).await?;
+        answer_with_context(question, context, None, &self.options).await
+    }
+
+    /// Answers a question, or asks which topic is meant when it matches unrelated ones about as well
+    pub async fn ask_or_clarify(&self, question: &str) -> Result<Reply> {
+        let context = retrieve_context(question, &self.options).await?;
+        answer_or_clarify(question, context, None, &self.options).await
+    }
+
+    /// Answers a question about a document, e.g. a pasted email, which is never memorized
+    pub async fn ask_about(&self, question: &str, document: &str) -> Result<Answer> {
+        answer_over_text(question, document, &self.options).await
+    }
+
+    /// Answers a question comparing documents named by their source, retrieving from each separately
+    pub async fn compare(&self, question: &str, documents: &[String]) -> Result<Answer> {
+        compare_documents(question, documents, &self.options).await
+    }
+
+    /// Answers how something evolved with a timeline of the matching content, oldest first
+    pub async fn timeline(&self, question: &str) -> Result<Answer> {
+        answer_timeline(question, &self.options).await
+    }
+
+    /// How many tokens the model of this Tera reads in a text, e.g. to decide how much context to pass
+    pub fn count_tokens(&self, text: &str) -> Result<usize> {
+        count_tokens(text, self.options.model)
+    }
+
+    /// A conversation remembering its previous turns, which text can be attached to for its duration
+    pub fn session(&self) -> Session {
+        Session::new(self.options.clone())
+    }
+
+    /// Has the model find the action items of memorized content, stored as tasks
+    pub async fn extract_tasks(&self, id: &str) -> Result<Vec<Task>> {
+        tasks::extract_tasks(id).await
+    }
+
+    /// Reads an answer out with the `tts` voice of the config, returning WAV audio
+    pub async fn speak(&self, answer: &Answer) -> Result<Vec<u8>> {
+        let text = answer.text.clone();
+        tokio::task::spawn_blocking(move || tts::speak(&text)).await?
+    }
+
+    /// The vectors of the texts with the embedder of the knowledge base, for similarity tasks of the app
+    pub async fn embed(&self, texts: &[impl AsRef<str>]) -> Result<Vec<Vec<f32>>> {
+        embed_texts(texts).await
+    }
+
+    /// Memorizes a piece of text and returns the id of the new content
+    pub async fn ingest(&self, title: &str, text: &str) -> Result<String> {
+        ingest_text(title, text, "api").await
+    }
+
+    /// Moves content to the trash, it can be restored for `trash_days`
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        database::delete_content(id).await
+    }
+
+    /// Puts deleted content back
+    pub async fn restore(&self, id: &str) -> Result<()> {
+        database::restore(id).await?;
+        Ok(())
+    }
+
+    /// Permanently deletes the content in the trash and returns how many were deleted
+    pub async fn empty_trash(&self) -> Result<usize> {
+        database::empty_trash().await
+    }
+
+    /// The memorized chunks most relevant to the query, which can have operators like `"exact phrase"` or `source:notes`
+    pub async fn search(&self, query: &str) -> Result<Vec<VectorIndex>> {
+        self.search_with(query, &Clauses::default()).await
+    }
+
+    /// The chunks most relevant to the query which satisfy its operators and the clauses
+    pub async fn search_with(&self, query: &str, clauses: &Clauses) -> Result<Vec<VectorIndex>> {
+        let (query, mut parsed) = Clauses::parse(query)?;
+        parsed.extend(clauses.clone());
+        database::search_with(embed(&query).await?, &parsed, 4).await
+    }
+
+    /// The first `page_size` chunks most relevant to the query, more with [`Tera::next_page`]
+    pub async fn search_page(&self, query: &str, clauses: &Clauses, page_size: usize) -> Result<SearchPage> {
+        let (query, mut parsed) = Clauses::parse(query)?;
+        parsed.extend(clauses.clone());
+        database::search_page(embed(&query).await?, &parsed, page_size).await
+    }
+
+    /// The results following the cursor of a previous page, in the order they were first ranked
+    pub async fn next_page(&self, cursor: &str, page_size: usize) -> Result<SearchPage> {
+        database::next_page(cursor, page_size).await
+    }
+}
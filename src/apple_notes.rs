@@ -0,0 +1,223 @@
+use crate::database::{commit_content, insert_content, insert_vector_index};
+use crate::provenance::Provenance;
+use crate::queue::{self, Priority};
+use crate::redaction::loggable;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use regex::Regex;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::json;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tempfile::tempdir;
+use tracing::debug;
+
+// Core Data timestamps count seconds from 2001-01-01
+const CORE_DATA_EPOCH: i64 = 978_307_200;
+
+#[derive(Debug, Clone)]
+pub struct Note {
+    pub title: String,
+    pub folder: String,
+    pub text: String,
+    pub modified_at: Option<DateTime<Utc>>,
+}
+
+/// Reads notes from `NoteStore.sqlite` or from a folder of exported notes
+pub fn read_notes(path: &Path) -> Result<Vec<Note>> {
+    if path.is_dir() {
+        read_export(path, path)
+    } else {
+        read_store(path)
+    }
+}
+
+fn read_store(path: &Path) -> Result<Vec<Note>> {
+    // Notes keeps the database open, query a copy along with its write-ahead log
+    let temp_dir = tempdir()?;
+    let copy = temp_dir.path().join("NoteStore.sqlite");
+    std::fs::copy(path, &copy).context("Unable to copy the Notes database")?;
+    for suffix in ["-wal", "-shm"] {
+        let sidecar = PathBuf::from(format!("{}{}", path.display(), suffix));
+        if sidecar.exists() {
+            std::fs::copy(&sidecar, temp_dir.path().join(format!("NoteStore.sqlite{}", suffix)))?;
+        }
+    }
+
+    let conn = Connection::open_with_flags(&copy, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut statement = conn.prepare(
+        "SELECT note.ZTITLE1, folder.ZTITLE2, note.ZMODIFICATIONDATE1, data.ZDATA
+        FROM ZICCLOUDSYNCINGOBJECT AS note
+        JOIN ZICNOTEDATA AS data ON data.ZNOTE = note.Z_PK
+        LEFT JOIN ZICCLOUDSYNCINGOBJECT AS folder ON folder.Z_PK = note.ZFOLDER
+        WHERE note.ZTITLE1 IS NOT NULL AND (note.ZMARKEDFORDELETION IS NULL OR note.ZMARKEDFORDELETION = 0)",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, Option<String>>(0)?,
+            row.get::<_, Option<String>>(1)?,
+            row.get::<_, Option<f64>>(2)?,
+            row.get::<_, Option<Vec<u8>>>(3)?,
+        ))
+    })?;
+
+    let mut notes = vec![];
+    for row in rows {
+        let (title, folder, modified_at, data) = row?;
+        let Some(data) = data else {
+            continue;
+        };
+        let text = match decode_note_body(&data) {
+            Ok(text) => text,
+            Err(e) => {
+                debug!(error = ?e, title = ?title.as_deref().map(loggable), "Unable to decode note, skipping");
+                continue;
+            }
+        };
+        notes.push(Note {
+            title: title.unwrap_or_default(),
+            folder: folder.unwrap_or_default(),
+            text,
+            modified_at: modified_at
+                .and_then(|t| DateTime::from_timestamp(t as i64 + CORE_DATA_EPOCH, 0)),
+        });
+    }
+    Ok(notes)
+}
+
+// Note bodies are gzipped protobufs, the text sits in Document(2) > Note(3) > text(2)
+fn decode_note_body(data: &[u8]) -> Result<String> {
+    let mut decoded = vec![];
+    GzDecoder::new(data).read_to_end(&mut decoded)?;
+    let document = protobuf_field(&decoded, 2).context("No document in note")?;
+    let note = protobuf_field(document, 3).context("No note in document")?;
+    let text = protobuf_field(note, 2).context("No text in note")?;
+    Ok(String::from_utf8_lossy(text).replace('\u{fffc}', "").to_string())
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// First length delimited field with the given number
+fn protobuf_field(data: &[u8], number: u64) -> Option<&[u8]> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = read_varint(data, &mut pos)?;
+        match key & 0x7 {
+            0 => {
+                read_varint(data, &mut pos)?;
+            }
+            1 => pos += 8,
+            2 => {
+                let len = read_varint(data, &mut pos)? as usize;
+                let value = data.get(pos..pos + len)?;
+                if key >> 3 == number {
+                    return Some(value);
+                }
+                pos += len;
+            }
+            5 => pos += 4,
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn read_export(root: &Path, dir: &Path) -> Result<Vec<Note>> {
+    let tags = Regex::new(r"<[^>]*>").unwrap();
+    let mut notes = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            notes.extend(read_export(root, &path)?);
+            continue;
+        }
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        if !["txt", "md", "html", "htm"].contains(&extension) {
+            continue;
+        }
+        let text = crate::platform::read_text_file(&path)?;
+        let text = if extension.starts_with("htm") {
+            tags.replace_all(&text.replace("<br>", "\n").replace("</div>", "\n"), "")
+                .to_string()
+        } else {
+            text
+        };
+        let folder = path
+            .parent()
+            .and_then(|p| p.strip_prefix(root).ok())
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+        notes.push(Note {
+            title: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            folder,
+            text,
+            modified_at: std::fs::metadata(&path)?.modified().ok().map(DateTime::<Utc>::from),
+        });
+    }
+    Ok(notes)
+}
+
+pub async fn ingest_apple_notes(path: PathBuf) -> Result<()> {
+    println!("Processing Apple Notes from {}", path.display());
+    let notes = read_notes(&path)?;
+    println!("Extracted {} notes", notes.len());
+    let provenance = Provenance::file(&path).step("note_extraction");
+
+    for (i, note) in notes.iter().enumerate() {
+        print!("Memorizing notes {}/{}\r", i + 1, notes.len());
+        // waits for the documents saved meanwhile by hand, and paces the notes to the workers
+        let _permit = queue::admit(Priority::Bulk).await;
+        let content = insert_content(&note.title, &note.text, "apple_notes")
+            .await
+            .context("Unable to insert content")?;
+
+        let mut paragraphs = note.text.split('\n').collect::<Vec<&str>>();
+        paragraphs.retain(|p| !p.trim().is_empty());
+        for (j, paragraph) in paragraphs.iter().enumerate() {
+            let res = insert_vector_index(
+                content.id.clone(),
+                j as u16,
+                paragraph,
+                json!({
+                    "connector": "apple_notes",
+                    "title": note.title,
+                    "folder": note.folder,
+                    "date": note.modified_at,
+                    "upload_time": Utc::now(),
+                    "source": "Apple Notes",
+                    "provenance": provenance,
+                }),
+            )
+            .await;
+
+            match res {
+                Ok(_) => {}
+                Err(e) => {
+                    if e.to_string().contains("Content chunk is empty") {
+                        continue;
+                    }
+                    println!("Unable to insert vector index: {}", e);
+                }
+            }
+        }
+        commit_content(&content.id).await?;
+    }
+    println!("Memorized {} notes", notes.len());
+
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use crate::config::{data_dir, CONFIG};
+use crate::database::VectorIndex;
+use crate::{access, crypto};
+use anyhow::{Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use surrealdb::sql::Thing;
+
+lazy_static! {
+    // Entries are appended by concurrent requests, and rotated in between
+    static ref APPEND: Mutex<()> = Mutex::new(());
+}
+
+/// Something Tera was asked or memorized
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// The name of the API key of the client, or the user of the system running Tera
+    pub user: String,
+    /// `query`, `search` or `ingestion`
+    pub event: String,
+    /// HMAC-SHA256 of the question or search with the key in `audit.key`, so repeated questions can be
+    /// matched without keeping them, nor guessing them from a list of likely questions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_hash: Option<String>,
+    /// The content surfaced, or the content memorized
+    #[serde(default)]
+    pub sources: Vec<AuditSource>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditSource {
+    pub id: String,
+    pub content_id: String,
+    /// Sealed like the knowledge base when it is encrypted
+    pub title: String,
+}
+
+/// The current audit log, older ones are numbered `audit.1.jsonl`, `audit.2.jsonl`... oldest last
+pub fn audit_path() -> PathBuf {
+    data_dir().join("audit.jsonl")
+}
+
+fn rotated_path(index: usize) -> PathBuf {
+    data_dir().join(format!("audit.{}.jsonl", index))
+}
+
+fn key_path() -> PathBuf {
+    data_dir().join("audit.key")
+}
+
+fn user() -> String {
+    access::client()
+        .or_else(|| std::env::var("USER").ok())
+        .or_else(|| std::env::var("USERNAME").ok())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// The key the questions are hashed with, created on first use. Without it the log can't tell which
+// questions were asked.
+fn key() -> Result<Vec<u8>> {
+    let path = key_path();
+    if let Ok(key) = std::fs::read_to_string(&path) {
+        return BASE64.decode(key.trim()).context("Invalid audit key");
+    }
+    let mut key = vec![0u8; 32];
+    getrandom::getrandom(&mut key).context("Unable to generate the audit key")?;
+    std::fs::create_dir_all(data_dir())?;
+    std::fs::write(&path, BASE64.encode(&key))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(key)
+}
+
+// Starts a new log once the current one would grow past `max_bytes`, dropping the oldest beyond `keep`
+fn rotate(incoming: usize) -> Result<()> {
+    let Some(audit) = &CONFIG.audit else {
+        return Ok(());
+    };
+    let path = audit_path();
+    let size = std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+    if size == 0 || size + incoming as u64 <= audit.max_bytes {
+        return Ok(());
+    }
+    let _ = std::fs::remove_file(rotated_path(audit.keep));
+    for index in (1..audit.keep).rev() {
+        let from = rotated_path(index);
+        if from.exists() {
+            std::fs::rename(&from, rotated_path(index + 1))?;
+        }
+    }
+    if audit.keep == 0 {
+        std::fs::remove_file(&path)?;
+    } else {
+        std::fs::rename(&path, rotated_path(1))?;
+    }
+    Ok(())
+}
+
+fn append(entry: &AuditEntry) -> Result<()> {
+    let line = format!("{}\n", serde_json::to_string(entry)?);
+    let _lock = APPEND.lock().unwrap_or_else(|e| e.into_inner());
+    rotate(line.len())?;
+    let mut file = OpenOptions::new().create(true).append(true).open(audit_path())?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+fn entry(event: &str, query: Option<&str>, mut sources: Vec<AuditSource>) -> Result<AuditEntry> {
+    for source in sources.iter_mut() {
+        source.title = crypto::seal(&source.title)?;
+    }
+    Ok(AuditEntry {
+        at: Utc::now(),
+        user: user(),
+        event: event.to_string(),
+        query_hash: query.map(query_hash).transpose()?,
+        sources,
+    })
+}
+
+// Failing to audit is reported and doesn't fail what is being audited
+fn record(event: &str, query: Option<&str>, sources: Vec<AuditSource>) {
+    if CONFIG.audit.is_none() {
+        return;
+    }
+    if let Err(e) = entry(event, query, sources).and_then(|entry| append(&entry)) {
+        eprintln!("Unable to write the audit log: {:#}", e);
+    }
+}
+
+/// Records a question or a search, `query` or `search`, with the chunks it surfaced
+pub fn record_query(event: &str, query: &str, chunks: &[VectorIndex]) {
+    let sources = chunks
+        .iter()
+        .map(|chunk| AuditSource {
+            id: chunk.id.id.to_raw(),
+            content_id: chunk.content_id.id.to_raw(),
+            title: chunk.metadata["source"].as_str().unwrap_or_default().to_string(),
+        })
+        .collect();
+    record(event, Some(query), sources);
+}
+
+/// Records memorized content
+pub fn record_ingestion(content_id: &Thing, title: &str) {
+    let source = AuditSource {
+        id: content_id.id.to_raw(),
+        content_id: content_id.id.to_raw(),
+        title: title.to_string(),
+    };
+    record("ingestion", None, vec![source]);
+}
+
+/// The entries of the audit log and of the rotated ones, oldest first, with their titles opened
+pub fn read_entries() -> Result<Vec<AuditEntry>> {
+    let keep = CONFIG.audit.as_ref().map(|a| a.keep).unwrap_or(0);
+    let mut entries = vec![];
+    let paths = (1..=keep).rev().map(rotated_path).chain(std::iter::once(audit_path()));
+    for path in paths.filter(|p| p.exists()) {
+        for line in std::fs::read_to_string(&path)?.lines().filter(|l| !l.trim().is_empty()) {
+            let mut entry = serde_json::from_str::<AuditEntry>(line)?;
+            for source in entry.sources.iter_mut() {
+                source.title = crypto::open(&source.title)?;
+            }
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// The hash of a question as found in `query_hash`, to look up when something was asked
+pub fn query_hash(query: &str) -> Result<String> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key()?).map_err(|_| anyhow::anyhow!("Invalid audit key"))?;
+    mac.update(query.as_bytes());
+    Ok(format!("{:x}", mac.finalize().into_bytes()))
+}
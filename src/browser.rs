@@ -0,0 +1,116 @@
+use crate::config::CONFIG;
+use anyhow::{Context, Result};
+use chromiumoxide::browser::{Browser, BrowserConfig};
+use chromiumoxide::cdp::browser_protocol::fetch::{
+    ContinueRequestParams, EnableParams, EventRequestPaused, HeaderEntry, RequestPattern,
+};
+use chromiumoxide::cdp::browser_protocol::network::{CookieParam, SetCookiesParams};
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use reqwest::Url;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+// Pages rendering their content client side usually fetch it right after loading
+const RENDER_WAIT: Duration = Duration::from_secs(2);
+
+lazy_static! {
+    // Launched on the first page to render, shared by the pages of a crawl
+    static ref BROWSER: Mutex<Option<Browser>> = Mutex::new(None);
+}
+
+async fn launch() -> Result<Browser> {
+    debug!("Launching headless browser");
+    let config = BrowserConfig::builder().build().map_err(anyhow::Error::msg)?;
+    let (browser, mut handler) = Browser::launch(config)
+        .await
+        .context("Unable to launch Chrome or Chromium, is it installed?")?;
+    tokio::spawn(async move {
+        while let Some(event) = handler.next().await {
+            if event.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(browser)
+}
+
+/// HTML of a page once its scripts ran, in a headless Chrome or Chromium
+pub async fn render(url: &Url) -> Result<String> {
+    let mut browser = BROWSER.lock().await;
+    if browser.is_none() {
+        *browser = Some(launch().await?);
+    }
+    let browser = browser.as_ref().context("No browser")?;
+
+    let page = browser.new_page("about:blank").await?;
+    let host = url.host_str().unwrap_or_default().to_string();
+    let site = CONFIG.site(&host);
+    // cookies stay with the site, the scripts, fonts and trackers it loads from other hosts don't get them
+    if !site.cookies.is_empty() {
+        let cookies = site
+            .cookies
+            .iter()
+            .map(|(name, value)| {
+                CookieParam::builder()
+                    .name(name)
+                    .value(value)
+                    .url(format!("{}://{}/", url.scheme(), host))
+                    .build()
+                    .map_err(anyhow::Error::msg)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        page.execute(SetCookiesParams::new(cookies)).await?;
+    }
+    // and headers are only added to the requests made to the site
+    if !site.headers.is_empty() {
+        let pattern = RequestPattern::builder().url_pattern(format!("*://{}/*", host)).build();
+        page.execute(EnableParams::builder().pattern(pattern).build()).await?;
+        let mut paused = page.event_listener::<EventRequestPaused>().await?;
+        let intercepting = page.clone();
+        tokio::spawn(async move {
+            while let Some(event) = paused.next().await {
+                let to_site = Url::parse(&event.request.url).is_ok_and(|u| u.host_str() == Some(host.as_str()));
+                let mut headers = event
+                    .request
+                    .headers
+                    .inner()
+                    .as_object()
+                    .map(|sent| {
+                        sent.iter()
+                            .map(|(name, value)| HeaderEntry::new(name, value.as_str().unwrap_or_default()))
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+                if to_site {
+                    headers.extend(site.headers.iter().map(|(name, value)| HeaderEntry::new(name, value)));
+                }
+                let request = ContinueRequestParams::builder()
+                    .request_id(event.request_id.clone())
+                    .headers(headers)
+                    .build();
+                if let Ok(request) = request {
+                    if let Err(e) = intercepting.execute(request).await {
+                        debug!(error = ?e, "Unable to continue request");
+                    }
+                }
+            }
+        });
+    }
+
+    page.goto(url.as_str()).await?.wait_for_navigation().await?;
+    tokio::time::sleep(RENDER_WAIT).await;
+    let html = page.content().await?;
+    page.close().await?;
+    Ok(html)
+}
+
+/// Closes the browser if a page was rendered
+pub async fn close() -> Result<()> {
+    if let Some(mut browser) = BROWSER.lock().await.take() {
+        browser.close().await?;
+        browser.wait().await?;
+    }
+    Ok(())
+}
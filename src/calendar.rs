@@ -0,0 +1,161 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, Weekday};
+use regex::Regex;
+
+/// Format of event dates in chunk metadata, sorts lexicographically
+pub const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Debug, Clone, Default)]
+pub struct Event {
+    pub summary: String,
+    pub description: String,
+    pub location: String,
+    pub start: Option<NaiveDateTime>,
+    pub end: Option<NaiveDateTime>,
+}
+
+impl Event {
+    /// The text which gets embedded for the event
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Event: {}", self.summary);
+        if let Some(start) = self.start {
+            text += &format!(" on {}", start.format("%A, %B %e, %Y at %H:%M"));
+        }
+        if let Some(end) = self.end {
+            text += &format!(" until {}", end.format("%A, %B %e, %Y at %H:%M"));
+        }
+        if !self.location.is_empty() {
+            text += &format!(" at {}", self.location);
+        }
+        if !self.description.is_empty() {
+            text += &format!(". {}", self.description);
+        }
+        text
+    }
+}
+
+/// Extracts the VEVENTs of an iCalendar file
+pub fn parse_ics(ics: &str) -> Vec<Event> {
+    // long lines are folded with a leading space or tab
+    let unfolded = ics
+        .replace("\r\n", "\n")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+
+    let mut events = vec![];
+    let mut current: Option<Event> = None;
+    for line in unfolded.lines() {
+        match line {
+            "BEGIN:VEVENT" => current = Some(Event::default()),
+            "END:VEVENT" => events.extend(current.take()),
+            _ => {
+                let Some(event) = current.as_mut() else {
+                    continue;
+                };
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                // drop parameters such as DTSTART;TZID=Europe/Paris
+                let name = key.split(';').next().unwrap_or_default();
+                match name {
+                    "SUMMARY" => event.summary = unescape(value),
+                    "DESCRIPTION" => event.description = unescape(value),
+                    "LOCATION" => event.location = unescape(value),
+                    "DTSTART" => event.start = parse_ics_date(value),
+                    "DTEND" => event.end = parse_ics_date(value),
+                    _ => {}
+                }
+            }
+        }
+    }
+    events
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("\\n", " ")
+        .replace("\\N", " ")
+        .replace("\\,", ",")
+        .replace("\\;", ";")
+        .replace("\\\\", "\\")
+}
+
+// 20240115T090000Z, 20240115T090000 or 20240115 for all day events
+fn parse_ics_date(value: &str) -> Option<NaiveDateTime> {
+    if let Some(utc) = value.strip_suffix('Z') {
+        let date = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S").ok()?;
+        return Some(date.and_utc().with_timezone(&chrono::Local).naive_local());
+    }
+    NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y%m%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })
+}
+
+/// Resolves relative dates in a question ("tomorrow", "next tuesday", "this week", "2024-03-01")
+/// to the time range it refers to.
+pub fn resolve_date_range(
+    query: &str,
+    now: NaiveDateTime,
+) -> Option<(NaiveDateTime, NaiveDateTime)> {
+    let query = query.to_lowercase();
+    let today = now.date();
+    let range = |start: NaiveDate, days: i64| {
+        Some((
+            start.and_hms_opt(0, 0, 0)?,
+            (start + Duration::days(days)).and_hms_opt(0, 0, 0)?,
+        ))
+    };
+    let day = |date: NaiveDate| range(date, 1);
+    let week = |monday: NaiveDate| range(monday, 7);
+
+    let iso = Regex::new(r"\b(\d{4}-\d{2}-\d{2})\b").unwrap();
+    if let Some(date) = iso.captures(&query) {
+        return day(NaiveDate::parse_from_str(&date[1], "%Y-%m-%d").ok()?);
+    }
+    if query.contains("day after tomorrow") {
+        return day(today + Duration::days(2));
+    }
+    if query.contains("tomorrow") {
+        return day(today + Duration::days(1));
+    }
+    if query.contains("yesterday") {
+        return day(today - Duration::days(1));
+    }
+    if query.contains("today") || query.contains("tonight") {
+        return day(today);
+    }
+
+    let monday = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    if query.contains("next week") {
+        return week(monday + Duration::days(7));
+    }
+    if query.contains("last week") {
+        return week(monday - Duration::days(7));
+    }
+    if query.contains("this week") {
+        return week(monday);
+    }
+
+    let weekday = Regex::new(
+        r"\b(next|last|this|on)?\s*(monday|tuesday|wednesday|thursday|friday|saturday|sunday)\b",
+    )
+    .unwrap();
+    if let Some(captures) = weekday.captures(&query) {
+        let target: Weekday = captures[2].parse().ok()?;
+        let ahead = (target.num_days_from_monday() as i64
+            - today.weekday().num_days_from_monday() as i64)
+            .rem_euclid(7);
+        let date = match captures.get(1).map(|m| m.as_str()) {
+            Some("last") => today - Duration::days(if ahead == 0 { 7 } else { 7 - ahead }),
+            // "next tuesday" on a tuesday means in a week
+            Some("next") if ahead == 0 => today + Duration::days(7),
+            _ => today + Duration::days(ahead),
+        };
+        return day(date);
+    }
+
+    None
+}
@@ -0,0 +1,242 @@
+use crate::config::{live, with_profile};
+use crate::database::VectorIndex;
+use crate::tts;
+use crate::inference::{
+    answer_with_context, count_tokens, embed_text, generate_with, rank_chunks, retrieve_context, Answer,
+    AnswerOptions,
+};
+use crate::redaction::loggable;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use tracing::debug;
+
+// Tokens, keeps the history well below the model's context
+const HISTORY_BUDGET: usize = 750;
+// Turns which are always kept verbatim
+const RECENT_TURNS: usize = 2;
+// Chunks of the attachments given to the model along with the knowledge base, best matches first
+const ATTACHED_CHUNKS: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Turn {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Text attached to a session, e.g. an email just received, searched with the knowledge base but never stored
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub title: String,
+    /// Embedded in memory, with ids of the `attachment` table which has no records
+    pub chunks: Vec<VectorIndex>,
+}
+
+/// A conversation with Tera, older turns get summarized once the history outgrows its budget
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub options: AnswerOptions,
+    /// Profile of the config the questions are answered with
+    pub profile: Option<String>,
+    pub summary: Option<String>,
+    pub turns: Vec<Turn>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl Session {
+    pub fn new(options: AnswerOptions) -> Self {
+        Self {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Attaches a text to the rest of the conversation, it is left out of the knowledge base
+    pub async fn attach(&mut self, title: &str, text: &str) -> Result<&Attachment> {
+        let chunks = embed_text(title, text).await?;
+        debug!(title = %loggable(title), chunks = chunks.len(), "Attached to session");
+        self.attachments.push(Attachment {
+            title: title.to_string(),
+            chunks,
+        });
+        Ok(&self.attachments[self.attachments.len() - 1])
+    }
+
+    /// Forgets the attachments
+    pub fn detach(&mut self) {
+        self.attachments.clear();
+    }
+
+    pub async fn ask(&mut self, question: &str) -> Result<Answer> {
+        self.ask_with(question, vec![]).await
+    }
+
+    /// Like `ask`, the given references coming first, e.g. notes about the device a voice question comes from
+    pub async fn ask_with(&mut self, question: &str, references: Vec<VectorIndex>) -> Result<Answer> {
+        let profile = self.profile.clone();
+        with_profile(profile.as_deref(), self.answer(question, references)).await?
+    }
+
+    async fn answer(&mut self, question: &str, mut references: Vec<VectorIndex>) -> Result<Answer> {
+        // the previous question helps retrieval for follow ups like "and when was that?"
+        let retrieval_query = match self.turns.last() {
+            Some(turn) => format!("{} {}", turn.question, question),
+            None => question.to_string(),
+        };
+        let attached = self.attachments.iter().flat_map(|a| a.chunks.iter().cloned()).collect::<Vec<_>>();
+        references.extend(rank_chunks(&retrieval_query, attached, ATTACHED_CHUNKS).await?);
+        references.extend(retrieve_context(&retrieval_query, &self.options).await?);
+        let mut seen = HashSet::new();
+        references.retain(|r| seen.insert(r.id.to_string()));
+        let history = self.history();
+        let history = (!history.is_empty()).then_some(history);
+        let answer =
+            answer_with_context(question, references, history.as_deref(), &self.options).await?;
+
+        self.turns.push(Turn {
+            question: question.to_string(),
+            answer: answer.text.clone(),
+        });
+        self.compact().await?;
+
+        Ok(answer)
+    }
+
+    pub fn history(&self) -> String {
+        let mut history = String::new();
+        if let Some(summary) = &self.summary {
+            history += &format!("summary: {}\n", summary);
+        }
+        history += &format_turns(&self.turns);
+        history
+    }
+
+    // Folds all but the recent turns into the summary when the history is over budget
+    async fn compact(&mut self) -> Result<()> {
+        if self.turns.len() <= RECENT_TURNS || count_tokens(&self.history(), self.options.model)? <= HISTORY_BUDGET {
+            return Ok(());
+        }
+        let older = self.turns.drain(..self.turns.len() - RECENT_TURNS).collect::<Vec<_>>();
+        debug!(turns = older.len(), "Summarizing conversation");
+
+        let system = "You summarize conversations. Write a short summary of the conversation keeping every name, date and fact mentioned.";
+        let mut user = String::new();
+        if let Some(summary) = &self.summary {
+            user += &format!("earlier summary: \"{}\"\n", summary);
+        }
+        user += &format!("conversation: \"{}\"\n", format_turns(&older));
+        self.summary = Some(generate_with(system, &user, &self.options, 200, true).await?);
+
+        Ok(())
+    }
+}
+
+fn format_turns(turns: &[Turn]) -> String {
+    turns
+        .iter()
+        .map(|t| format!("user: {}\ntera: {}\n", t.question, t.answer))
+        .collect()
+}
+
+#[cfg(feature = "whisper")]
+async fn listen() -> Result<String> {
+    crate::microphone::listen().await
+}
+
+#[cfg(not(feature = "whisper"))]
+async fn listen() -> Result<String> {
+    anyhow::bail!("Voice questions need Tera built with the whisper feature")
+}
+
+/// Interactive chat on stdin until `exit` or end of input, answers are read out with `speak`.
+/// With `voice`, pressing Enter on an empty line records a question on the microphone.
+/// `/attach <path>` adds a file to the conversation without memorizing it, `/detach` forgets the attached files.
+/// `/profile <name>` answers the next questions with a profile of the config, `/profile` without one.
+pub async fn run_repl(
+    options: AnswerOptions,
+    profile: Option<String>,
+    speak: bool,
+    voice: bool,
+) -> Result<()> {
+    if let Some(name) = &profile {
+        live().with_profile(name)?;
+    }
+    let mut session = Session::new(options);
+    session.profile = profile;
+    println!("Chatting with Tera, type \"exit\" to leave or \"/attach <path>\" to discuss a file without memorizing it.");
+    if voice {
+        println!("Press Enter on an empty line to ask out loud.");
+    }
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let mut input = String::new();
+        if std::io::stdin().read_line(&mut input)? == 0 {
+            break;
+        }
+        if voice && input.trim().is_empty() {
+            input = match listen().await {
+                Ok(heard) => heard,
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    continue;
+                }
+            };
+            println!("> {}", input);
+        }
+        let question = input.trim();
+        if question.is_empty() {
+            continue;
+        }
+        if question == "exit" || question == "quit" {
+            break;
+        }
+        if let Some(path) = question.strip_prefix("/attach ") {
+            let path = Path::new(path.trim());
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) => {
+                    println!("Unable to read {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let title = path.file_name().unwrap_or_default().to_string_lossy();
+            let attachment = session.attach(&title, &text).await?;
+            println!("Attached {} ({} chunks) to this conversation", attachment.title, attachment.chunks.len());
+            continue;
+        }
+        if question == "/detach" {
+            session.detach();
+            println!("Forgot the attached files");
+            continue;
+        }
+        if let Some(name) = question.strip_prefix("/profile").filter(|n| n.is_empty() || n.starts_with(' ')) {
+            let name = name.trim();
+            if name.is_empty() {
+                session.profile = None;
+                println!("Answering without a profile");
+            } else if live().profiles.contains_key(name) {
+                session.profile = Some(name.to_string());
+                println!("Answering with the {} profile", name);
+            } else {
+                println!("Unknown profile {}", name);
+            }
+            continue;
+        }
+        let answer = session.ask(question).await?;
+        println!("{}", answer.text);
+        for follow_up in answer.follow_ups {
+            println!("  - {}", follow_up);
+        }
+        if speak {
+            let text = answer.text.clone();
+            // the conversation goes on when the voice fails
+            let spoken = tokio::task::spawn_blocking(move || tts::play(&tts::speak(&text)?)).await?;
+            if let Err(e) = spoken {
+                eprintln!("{:#}", e);
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,5 +1,10 @@
-use crate::ingest::IngestType;
+use tera::inference::{AnswerLength, Model};
+use tera::ingest::IngestType;
+use tera::map::MapFormat;
+use tera::output::OutputFormat;
+use chrono::NaiveDate;
 use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 /// A fictional versioning CLI
@@ -18,18 +23,194 @@ pub enum Commands {
     Ask {
         /// The question to ask
         query: String,
+        /// The model used to answer
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+        /// LoRA adapter (PEFT directory or safetensors file) to apply over the model
+        #[arg(long, value_name = "PATH")]
+        lora: Option<PathBuf>,
+        /// How detailed the answer should be
+        #[arg(short, long, value_enum, default_value_t = AnswerLength::Normal)]
+        length: AnswerLength,
+        /// Suggest follow-up questions
+        #[arg(long)]
+        follow_ups: bool,
+        /// Ask back which topic is meant when the question matches unrelated ones about as well
+        #[arg(long)]
+        clarify: bool,
+        /// Stop the answer after this many tokens, at its last complete sentence
+        #[arg(long)]
+        max_tokens: Option<usize>,
+        /// Stop the answer after this many seconds, at its last complete sentence
+        #[arg(long)]
+        max_seconds: Option<u64>,
+        /// Answer by querying this registered SQLite database
+        #[arg(short, long)]
+        database: Option<String>,
+        /// Answer about this file, `-` for stdin, without memorizing it or searching the memorized content
+        #[arg(long, value_name = "PATH", conflicts_with = "database")]
+        about: Option<PathBuf>,
+        /// Compare the documents from these sources, e.g. `--compare "offer A*" --compare "offer B*"`
+        #[arg(long, value_name = "SOURCE", conflicts_with_all = ["database", "about", "clarify"])]
+        compare: Vec<String>,
+        /// Answer with a timeline of the matching content, for questions like "how did my view on X change?"
+        #[arg(long, conflicts_with_all = ["database", "about", "clarify", "compare"])]
+        timeline: bool,
+        /// Generate a new answer even if a nearly identical question was answered recently
+        #[arg(long)]
+        fresh: bool,
+        /// Answer with the prompt, collections and retrieval of this profile of the config
+        #[arg(long)]
+        profile: Option<String>,
+        /// Print the answer and its sources as JSON, same as `--format json`
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+        /// How to print the answer
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Plain)]
+        format: OutputFormat,
+    },
+    /// Answer a question as a single line of JSON, for launchers such as Raycast and Alfred
+    Quick {
+        /// The question to ask
+        question: String,
+        /// The model used to answer
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+        /// How detailed the answer should be
+        #[arg(short, long, value_enum, default_value_t = AnswerLength::Short)]
+        length: AnswerLength,
+        /// Also put the answer on the clipboard
+        #[arg(long)]
+        copy: bool,
+    },
+    /// Search memorized content without generating an answer
+    #[command(arg_required_else_help = true)]
+    Search {
+        /// What to search for, not needed with `--cursor`
+        #[arg(required_unless_present = "cursor", default_value = "")]
+        query: String,
+        /// How to print the results
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Plain)]
+        format: OutputFormat,
+        /// Results per page, the best 4 are printed without it
+        #[arg(long)]
+        page_size: Option<usize>,
+        /// Print the page after this cursor of a previous page
+        #[arg(long)]
+        cursor: Option<String>,
+        /// Only results containing this keyword, can be repeated
+        #[arg(long)]
+        must: Vec<String>,
+        /// Rank results containing this keyword higher, can be repeated
+        #[arg(long)]
+        should: Vec<String>,
+        /// Leave out results containing this keyword, can be repeated
+        #[arg(long)]
+        must_not: Vec<String>,
+    },
+    /// Have a conversation with Tera
+    Chat {
+        /// The model used to answer
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+        /// LoRA adapter (PEFT directory or safetensors file) to apply over the model
+        #[arg(long, value_name = "PATH")]
+        lora: Option<PathBuf>,
+        /// How detailed the answers should be
+        #[arg(short, long, value_enum, default_value_t = AnswerLength::Normal)]
+        length: AnswerLength,
+        /// Read answers out with the `tts` voice of the config
+        #[arg(long)]
+        speak: bool,
+        /// Ask out loud: Enter on an empty line records the microphone until Enter again, transcribed with Whisper
+        #[arg(long)]
+        voice: bool,
+        /// Answer with the prompt, collections and retrieval of this profile of the config
+        #[arg(long)]
+        profile: Option<String>,
     },
     /// Let Tera learn from your content
     Upload {
         #[arg(value_name = "Type")]
         content_type: IngestType,
         path: PathBuf,
+        /// Field mapping from the config to use for JSON uploads
+        #[arg(long)]
+        mapping: Option<String>,
+        /// Print the chunks which would be stored, without embedding or storing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Memorize every supported file in a directory tree, the type is guessed from the extension
+    UploadDir {
+        path: PathBuf,
+        /// How many files are memorized at the same time
+        #[arg(short, long, default_value = "4")]
+        concurrency: usize,
+        /// Only memorize files of this type
+        #[arg(long, value_enum)]
+        only: Option<IngestType>,
+        /// Field mapping from the config to use for JSON files
+        #[arg(long)]
+        mapping: Option<String>,
+        /// Print the chunks which would be stored, without embedding or storing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Memorize a web page and the pages of the same site it links to
+    #[cfg(feature = "crawl")]
+    Crawl {
+        url: String,
+        /// The URL is a sitemap, memorize the pages it lists which changed since the last crawl
+        #[arg(long)]
+        sitemap: bool,
+        /// How many links away from the page to go, 0 only memorizes the page
+        #[arg(short, long, default_value = "2")]
+        depth: usize,
+        /// Stop after memorizing this many pages
+        #[arg(long, default_value = "100")]
+        max_pages: usize,
+        /// Milliseconds to wait between two requests
+        #[arg(long, default_value = "1000")]
+        delay: u64,
+        /// Print the chunks which would be stored, without embedding or storing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Memorize what is piped to stdin, e.g. `cat notes.txt | tera ingest`
+    Ingest {
+        /// Title of the content
+        #[arg(short, long)]
+        title: Option<String>,
+        /// Print the chunks which would be stored, without embedding or storing anything
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Tell Tera something to remember
     Remember {
         /// The content to remember
         content: String,
     },
+    /// Write today's journal entry, answering the prompts of the config unless given or piped to stdin
+    Journal {
+        /// The entry
+        entry: Option<String>,
+        /// Day of the entry, today by default
+        #[arg(long, value_name = "YYYY-MM-DD")]
+        date: Option<NaiveDate>,
+        /// Ask a question about the journal instead, e.g. "what was I worried about in March?"
+        #[arg(long, value_name = "QUESTION", conflicts_with_all = ["entry", "date"])]
+        ask: Option<String>,
+        /// The model used to answer
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+    },
+    /// Memorize the clipboard, or what is piped to stdin
+    Clip {
+        /// Keep memorizing everything copied until stopped
+        #[arg(short, long)]
+        watch: bool,
+    },
     /// Forget something Tera remembers
     Forget {
         /// The content to forget
@@ -39,6 +220,182 @@ pub enum Commands {
         #[arg(short, long, group = "forget", default_value = "false")]
         all: bool,
     },
+    /// Content left out of answers and searches without deleting it, e.g. while sharing the screen
+    Exclusions {
+        #[command(subcommand)]
+        command: ExclusionsCommands,
+    },
+    /// Deleted content, which can be restored for `trash_days`
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommands,
+    },
+    /// Copies of the knowledge base to roll back to, e.g. after a bad bulk upload
+    Snapshots {
+        #[command(subcommand)]
+        command: SnapshotsCommands,
+    },
+    /// Rate how useful the sources of an answer were, improving future answers
+    Feedback {
+        /// The id printed with the answer
+        answer_id: String,
+        /// Rate a single source, all sources of the answer otherwise
+        #[arg(short, long)]
+        chunk_id: Option<String>,
+        /// The sources were not useful
+        #[arg(long)]
+        not_useful: bool,
+    },
+    /// Review what Tera was asked and memorized, with `audit` on in the config
+    Audit {
+        /// How many of the last entries to print
+        #[arg(short, long, default_value = "20")]
+        limit: usize,
+        /// Only the times this question or search was asked
+        #[arg(short, long)]
+        query: Option<String>,
+        /// Print the entries as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+    /// How much Tera is used: questions, latency, most retrieved documents and growth of the knowledge base
+    Stats {
+        /// Over this many days
+        #[arg(short, long, default_value = "30")]
+        days: i64,
+        /// Print the stats as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// What the knowledge base is about, documents grouped by topic
+    Topics {
+        #[command(subcommand)]
+        command: TopicsCommands,
+    },
+    /// Check the model, the index, disk space and the scheduler, fails when Tera can't answer
+    Health {
+        /// The model questions would be answered with
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+        /// Print the statuses as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Re-embed all memorized content after changing the embedding model
+    MigrateEmbeddings,
+    /// Rewrite stored vectors after changing `quantization`
+    Quantize,
+    /// Rebuild the memory mapped vector file searched with `mmap_vectors`
+    Reindex,
+    /// Forget chunks past the retention of their source and empty old trash, see `retention` in the config
+    Cleanup,
+    /// Encrypt what was memorized before encryption was enabled in the config
+    Encrypt,
+    /// Write every chunk and its vector to a JSON file, for searching in the browser with tera-core
+    #[command(arg_required_else_help = true)]
+    ExportIndex {
+        /// Where to write the index
+        path: PathBuf,
+    },
+    /// Write every chunk placed on a plane by meaning, to look at the knowledge base in other tools
+    #[command(arg_required_else_help = true)]
+    ExportMap {
+        /// Where to write the map
+        path: PathBuf,
+        /// Defaults to the extension of the path: `.csv`, `.html` or JSON otherwise
+        #[arg(short, long, value_enum)]
+        format: Option<MapFormat>,
+    },
+    /// Tasks and appointments found in memorized content, with `extract_reminders` in the config
+    Reminders {
+        #[command(subcommand)]
+        command: RemindersCommands,
+    },
+    /// Action items of memorized documents, with their owner and due date
+    Tasks {
+        #[command(subcommand)]
+        command: TasksCommands,
+    },
+    /// Saved searches you can re-run or subscribe to
+    Searches {
+        #[command(subcommand)]
+        command: SearchesCommands,
+    },
+    /// SQLite databases Tera can query to answer questions
+    #[cfg(feature = "tables")]
+    Databases {
+        #[command(subcommand)]
+        command: DatabasesCommands,
+    },
+    /// List the enabled connectors with their last run
+    Connectors,
+    /// Merge the knowledge bases of your devices through the `sync` remote of the config
+    Sync,
+    /// Commands run on a schedule by `tera scheduler`
+    #[cfg(feature = "scheduler")]
+    Jobs {
+        #[command(subcommand)]
+        command: JobsCommands,
+    },
+    /// Run scheduled jobs until stopped
+    #[cfg(feature = "scheduler")]
+    Scheduler,
+    /// List the models Tera can answer with
+    Models,
+    /// Count the tokens of a text for a model, the text being piped to stdin when not given
+    Tokens {
+        /// The text to count
+        text: Option<String>,
+        /// The model whose tokenizer reads the text
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+    },
+    /// Serve Tera over gRPC for other apps, see proto/tera.proto
+    #[cfg(feature = "server")]
+    Grpc {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:50051")]
+        addr: SocketAddr,
+        /// Listen on this unix socket instead, only you can connect to it
+        #[arg(long, value_name = "PATH", conflicts_with = "addr")]
+        socket: Option<PathBuf>,
+        /// Serve this snapshot, or the latest one in this directory, read-only instead of the knowledge base
+        #[arg(long, value_name = "PATH")]
+        replica: Option<PathBuf>,
+        /// The model answering requests which don't ask for one, until switched with `SwitchModel`
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+    },
+    /// Keep the models and the database loaded, running the commands of other tera invocations
+    #[cfg(unix)]
+    Daemon,
+    /// Serve Tera as a conversation agent for Home Assistant, see the README
+    HomeAssistant {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8124")]
+        addr: SocketAddr,
+        /// The model used to answer
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+        /// How detailed the answers should be, short ones suit voice assistants best
+        #[arg(short, long, value_enum, default_value_t = AnswerLength::Short)]
+        length: AnswerLength,
+    },
+    /// Put Tera in the tray or the menu bar, with a hotkey popping up a quick-ask window
+    #[cfg(feature = "tray")]
+    Tray {
+        /// Global hotkey asking a question
+        #[arg(long, default_value = "ctrl+shift+space")]
+        hotkey: String,
+        /// The model used to answer
+        #[arg(short, long, value_enum, default_value_t = Model::Phi2)]
+        model: Model,
+        /// How detailed the answers should be
+        #[arg(short, long, value_enum, default_value_t = AnswerLength::Short)]
+        length: AnswerLength,
+    },
+    /// Serve Tera as an MCP server over stdio, for Claude Desktop and other MCP clients
+    Mcp,
     /// List all content Tera remembers sorted by added date
     List {
         /// How many items you want to skip from the beginning
@@ -49,3 +406,214 @@ pub enum Commands {
         limit: u16,
     }
 }
+
+impl Commands {
+    /// Whether the command runs in the daemon when one is listening. Only short commands which end
+    /// on their own are, as commands run there one at a time: servers, the scheduler, chats, watching
+    /// the clipboard and uploads, crawls or maintenance run in their own process.
+    #[cfg(unix)]
+    pub fn forwarded(&self) -> bool {
+        if self.replaces_database() {
+            return false;
+        }
+        match self {
+            Commands::Clip { watch } => !watch,
+            Commands::Ask { .. }
+            | Commands::Quick { .. }
+            | Commands::Search { .. }
+            | Commands::Ingest { .. }
+            | Commands::Remember { .. }
+            | Commands::Journal { .. }
+            | Commands::Forget { .. }
+            | Commands::Exclusions { .. }
+            | Commands::Trash { .. }
+            | Commands::Snapshots { .. }
+            | Commands::Feedback { .. }
+            | Commands::Audit { .. }
+            | Commands::Stats { .. }
+            | Commands::Topics { .. }
+            | Commands::Health { .. }
+            | Commands::Reminders { .. }
+            | Commands::Tasks { .. }
+            | Commands::Searches { .. }
+            | Commands::Connectors
+            | Commands::MigrateEmbeddings
+            | Commands::Models
+            | Commands::Tokens { .. }
+            | Commands::List { .. } => true,
+            #[cfg(feature = "tables")]
+            Commands::Databases { .. } => true,
+            #[cfg(feature = "scheduler")]
+            Commands::Jobs { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the command deletes or replaces the database, which mustn't happen while a process,
+    /// e.g. the daemon, has it open
+    pub fn replaces_database(&self) -> bool {
+        matches!(
+            self,
+            Commands::Forget { all: true, .. }
+                | Commands::Snapshots {
+                    command: SnapshotsCommands::Restore { .. }
+                }
+        )
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SearchesCommands {
+    /// Save a search, replacing any search with the same name
+    Save {
+        name: String,
+        query: String,
+        /// Get notified when new content matches
+        #[arg(short, long)]
+        subscribe: bool,
+    },
+    /// List saved searches and how many new matches they have
+    List,
+    /// Run a saved search
+    Run {
+        name: String,
+        /// How to print the results
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Plain)]
+        format: OutputFormat,
+    },
+    /// Delete a saved search
+    Delete { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RemindersCommands {
+    /// List reminders not done yet, soonest first
+    List,
+    /// Find the reminders of content memorized before `extract_reminders` was enabled
+    Extract { content_id: String },
+    /// Mark a reminder as done
+    Done { reminder_id: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TasksCommands {
+    /// List tasks left to do, by due date
+    List {
+        /// Also list tasks done
+        #[arg(short, long)]
+        all: bool,
+    },
+    /// Find the action items of a memorized document, replacing its tasks not done yet
+    Extract { content_id: String },
+    /// Mark a task done
+    Done { task_id: String },
+    /// Mark a task not done
+    Undone { task_id: String },
+}
+
+#[cfg(feature = "tables")]
+#[derive(Debug, Subcommand)]
+pub enum DatabasesCommands {
+    /// Register a SQLite database, it is only ever opened read-only
+    Add { name: String, path: PathBuf },
+    /// List registered databases
+    List,
+    /// Forget a registered database, the file itself is left untouched
+    Remove { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ExclusionsCommands {
+    /// List the excluded documents and sources
+    List {
+        /// Print the exclusions as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Leave a document, or every source matching a pattern, out of answers and searches
+    Add {
+        #[arg(required_unless_present = "source")]
+        content_id: Option<String>,
+        /// Source or connector, `*` is a wildcard, e.g. `--source "WhatsApp Chat*"`
+        #[arg(long, conflicts_with = "content_id")]
+        source: Option<String>,
+    },
+    /// Let an excluded document or source back into answers and searches
+    Remove {
+        #[arg(required_unless_present = "source")]
+        content_id: Option<String>,
+        #[arg(long, conflicts_with = "content_id")]
+        source: Option<String>,
+    },
+    /// Let everything back into answers and searches
+    Clear,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TrashCommands {
+    /// List deleted content
+    List,
+    /// Put deleted content back
+    Restore { content_id: String },
+    /// Permanently delete everything in the trash
+    Empty,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SnapshotsCommands {
+    /// Take a snapshot of everything Tera remembers
+    Create,
+    /// List snapshots
+    List,
+    /// Replace everything Tera remembers with a snapshot
+    Restore { snapshot_id: String },
+    /// Delete a snapshot
+    Delete { snapshot_id: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TopicsCommands {
+    /// Group the documents by topic, replacing the previous topics
+    Build {
+        /// How many topics, about the square root of half the number of documents by default
+        #[arg(short, long)]
+        clusters: Option<usize>,
+    },
+    /// List the topics, largest first
+    List {
+        /// Print the topics as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the documents of a topic
+    Show {
+        topic_id: String,
+        /// Print the topic as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[cfg(feature = "scheduler")]
+#[derive(Debug, Subcommand)]
+pub enum JobsCommands {
+    /// Schedule a tera command, e.g. `tera jobs add git --schedule "0 7 * * *" -- upload git ~/code`
+    Add {
+        name: String,
+        /// Cron expression: minute hour day month weekday
+        #[arg(short, long)]
+        schedule: String,
+        /// Delay runs by up to this many seconds
+        #[arg(short, long, default_value = "0")]
+        jitter: u64,
+        /// The tera command to run
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// List scheduled jobs with their last and next run
+    List,
+    /// Run a job now
+    Run { name: String },
+    /// Remove a job from the schedule
+    Cancel { name: String },
+}
@@ -0,0 +1,135 @@
+use crate::platform::{decode_text, read_stdin};
+use crate::provenance::Provenance;
+use crate::queue::{self, Priority};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde_json::json;
+use std::io::{IsTerminal, Write};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+// How often the clipboard is checked in watch mode
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// The clipboard tools of each platform, tried in order
+fn paste_commands() -> Vec<(&'static str, Vec<&'static str>)> {
+    if cfg!(target_os = "macos") {
+        vec![("pbpaste", vec![])]
+    } else if cfg!(target_os = "windows") {
+        // pipes use the OEM code page unless told otherwise
+        vec![(
+            "powershell",
+            vec![
+                "-NoProfile",
+                "-Command",
+                "[Console]::OutputEncoding = [Text.Encoding]::UTF8; Get-Clipboard -Raw",
+            ],
+        )]
+    } else {
+        vec![
+            ("wl-paste", vec!["--no-newline"]),
+            ("xclip", vec!["-selection", "clipboard", "-o"]),
+            ("xsel", vec!["--clipboard", "--output"]),
+        ]
+    }
+}
+
+fn copy_commands() -> Vec<(&'static str, Vec<&'static str>)> {
+    if cfg!(target_os = "macos") {
+        vec![("pbcopy", vec![])]
+    } else if cfg!(target_os = "windows") {
+        // clip.exe mangles anything outside of the OEM code page
+        vec![(
+            "powershell",
+            vec![
+                "-NoProfile",
+                "-Command",
+                "[Console]::InputEncoding = [Text.Encoding]::UTF8; Set-Clipboard -Value ([Console]::In.ReadToEnd())",
+            ],
+        )]
+    } else {
+        vec![
+            ("wl-copy", vec![]),
+            ("xclip", vec!["-selection", "clipboard"]),
+            ("xsel", vec!["--clipboard", "--input"]),
+        ]
+    }
+}
+
+pub fn read_clipboard() -> Result<String> {
+    for (program, args) in paste_commands() {
+        if let Ok(output) = Command::new(program).args(&args).output() {
+            if output.status.success() {
+                return Ok(decode_text(&output.stdout));
+            }
+        }
+    }
+    anyhow::bail!("Unable to read the clipboard, install wl-clipboard, xclip or xsel")
+}
+
+pub fn write_clipboard(text: &str) -> Result<()> {
+    for (program, args) in copy_commands() {
+        let Ok(mut child) = Command::new(program).args(&args).stdin(Stdio::piped()).spawn() else {
+            continue;
+        };
+        child
+            .stdin
+            .take()
+            .context("Unable to write to the clipboard")?
+            .write_all(text.as_bytes())?;
+        if child.wait()?.success() {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("Unable to write the clipboard, install wl-clipboard, xclip or xsel")
+}
+
+async fn save_snippet(snippet: &str, origin: &str) -> Result<()> {
+    let content = queue::memorize(
+        Priority::Interactive,
+        &format!("Clipboard snippet on {}", Utc::now().format("%Y-%m-%d %H:%M")),
+        snippet.to_string(),
+        json!({
+            "connector": "clipboard",
+            "source": "clipboard",
+            "captured_from": origin,
+            "time": Utc::now(),
+            "provenance": Provenance::new(origin),
+        }),
+    )
+    .await?;
+    println!("Memorized {}", content.title);
+    Ok(())
+}
+
+/// Saves piped stdin or the current clipboard, or every new clipboard content when watching
+pub async fn capture(watch: bool) -> Result<()> {
+    if !std::io::stdin().is_terminal() {
+        let snippet = read_stdin()?;
+        if snippet.trim().is_empty() {
+            anyhow::bail!("Nothing to memorize on stdin");
+        }
+        return save_snippet(&snippet, "stdin").await;
+    }
+
+    if !watch {
+        let snippet = read_clipboard()?;
+        if snippet.trim().is_empty() {
+            anyhow::bail!("The clipboard is empty");
+        }
+        return save_snippet(&snippet, "clipboard").await;
+    }
+
+    println!("Watching the clipboard, press Ctrl+C to stop.");
+    // only what gets copied from now on is saved
+    let mut last = read_clipboard()?;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let snippet = read_clipboard()?;
+        if snippet == last || snippet.trim().is_empty() {
+            continue;
+        }
+        save_snippet(&snippet, "clipboard").await?;
+        last = snippet;
+    }
+}
@@ -0,0 +1,982 @@
+use crate::redaction::Pii;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Once, OnceLock, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::debug;
+
+lazy_static! {
+    /// The config as the process started with, see [`live`] for settings which change without a restart
+    pub static ref CONFIG: Config = load_config().expect("Unable to load config");
+    pub static ref CONFIG_MANAGER: ConfigManager = ConfigManager::new(CONFIG.clone());
+}
+
+// Settings read anew by each question or job, they are applied as soon as the config file changes.
+// The others are read once, by the model, the database or a server, and need a restart.
+const LIVE_SETTINGS: &[&str] = &[
+    "examples",
+    "max_examples",
+    "source_weights",
+    "retention",
+    "trash_days",
+    "neighbours",
+    "retrieval",
+    "post_processing",
+    "prompt_injection",
+    "min_confidence",
+    "repeated_questions",
+    "journal",
+    "notifications",
+    "webhooks",
+    "log_content",
+    "system_prompt",
+    "collections",
+    "profiles",
+    "api_keys",
+];
+// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+static WATCH: Once = Once::new();
+
+tokio::task_local! {
+    // The name of the profile of the question and the live config with it applied, while answering it
+    static PROFILED: (String, Arc<Config>);
+}
+
+// Settings of an embedding app, they have to be set before the config or the database are first used
+pub(crate) static DATA_DIR: OnceLock<PathBuf> = OnceLock::new();
+pub(crate) static EMBEDDINGS: OnceLock<EmbeddingBackend> = OnceLock::new();
+
+/// User configuration, read from `tera/config.json` in the local config directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Where answers get generated
+    pub inference: InferenceBackend,
+    /// Where chunks and questions get embedded
+    pub embeddings: EmbeddingBackend,
+    /// Question/answer pairs showing the model how to answer
+    pub examples: Vec<FewShotExample>,
+    /// How many of the examples most similar to the question get injected in the prompt
+    pub max_examples: usize,
+    /// Multipliers applied to the similarity of chunks from matching sources, `*` is a wildcard
+    pub source_weights: HashMap<String, f32>,
+    /// Named field mappings for JSON uploads
+    pub json_mappings: HashMap<String, JsonMapping>,
+    /// How memorized text and answers are encrypted at rest
+    pub encryption: Encryption,
+    /// Personal information masked before chunks are stored, by connector name, `*` for all others
+    pub redaction: HashMap<String, Vec<Pii>>,
+    /// Days after which chunks from matching sources are forgotten by `tera cleanup`, `*` is a wildcard
+    pub retention: HashMap<String, u32>,
+    /// Days deleted content stays in the trash before `tera cleanup` deletes it for good
+    pub trash_days: u32,
+    /// Where knowledge bases of several devices are merged by `tera sync`
+    pub sync: Option<SyncRemote>,
+    /// Where memorized text is kept
+    pub storage: Storage,
+    /// Settings of each connector, by name
+    pub connectors: HashMap<String, ConnectorConfig>,
+    /// Headers and cookies sent to matching hosts by the web connector, `*` is a wildcard
+    pub sites: HashMap<String, SiteConfig>,
+    /// How many documents get memorized at once, interactive saves going before bulk uploads and crawls
+    pub ingest_queue: IngestQueue,
+    /// What happens to text longer than the local embedding model reads
+    pub long_chunks: LongChunks,
+    /// With reduced embedding `dimensions`, keep the whole vectors aside and re-rank the best matches with them
+    pub rescore: bool,
+    /// How stored vectors are encoded, `tera quantize` rewrites them after a change
+    pub quantization: Quantization,
+    /// Search a memory mapped copy of the vectors rather than loading them from the database
+    pub mmap_vectors: bool,
+    /// Chunks of the same document given to the model around each retrieved chunk
+    pub neighbours: Neighbours,
+    /// How results are picked among the chunks most similar to a query
+    pub retrieval: Retrieval,
+    /// Steps cleaning up generated answers, in order
+    pub post_processing: Vec<PostProcessing>,
+    /// What happens to references holding instructions for the model, e.g. "ignore previous instructions"
+    pub prompt_injection: InjectionHandling,
+    /// Answers less confident than this, from 0 to 1, are replaced by "I'm not sure"
+    pub min_confidence: f32,
+    /// When `tera ask` offers the answer to a nearly identical question asked before
+    pub repeated_questions: RepeatedQuestions,
+    /// Settings of `tera journal`
+    pub journal: Journal,
+    /// Ask the model for tasks and appointments with a date in memorized content, see `tera reminders`
+    pub extract_reminders: bool,
+    /// Where reminders and matches of subscribed searches are sent, besides stderr
+    pub notifications: Vec<NotificationChannel>,
+    /// URLs events are posted to, e.g. for home automation or n8n flows
+    pub webhooks: Vec<Webhook>,
+    /// Settings of `tera home-assistant`
+    pub home_assistant: HomeAssistant,
+    /// Keys clients of `tera home-assistant` and `tera grpc` send, each with what it may do
+    pub api_keys: Vec<ApiKey>,
+    /// Voice reading answers out, see `tera chat --speak`
+    pub tts: Option<Tts>,
+    /// Log of the questions, searches and ingestions in `audit.jsonl`, off without it
+    pub audit: Option<Audit>,
+    /// Where spans of the pipeline are exported over OTLP, with the `otel` feature
+    pub telemetry: Option<Telemetry>,
+    /// How questions, prompts and memorized text appear in debug logs and spans
+    pub log_content: LogContent,
+    /// Added to the instructions of the model for every answer
+    pub system_prompt: Option<String>,
+    /// Sources or connectors answers come from, `*` is a wildcard, all of them when empty
+    pub collections: Vec<String>,
+    /// Personas with their own prompt, collections and retrieval, by name, picked with `--profile`
+    pub profiles: HashMap<String, Profile>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            inference: InferenceBackend::default(),
+            embeddings: EmbeddingBackend::default(),
+            examples: vec![],
+            max_examples: 2,
+            source_weights: HashMap::new(),
+            json_mappings: HashMap::new(),
+            encryption: Encryption::default(),
+            redaction: HashMap::new(),
+            retention: HashMap::new(),
+            trash_days: 30,
+            sync: None,
+            storage: Storage::default(),
+            connectors: HashMap::new(),
+            sites: HashMap::new(),
+            ingest_queue: IngestQueue::default(),
+            long_chunks: LongChunks::default(),
+            rescore: false,
+            quantization: Quantization::default(),
+            mmap_vectors: false,
+            neighbours: Neighbours::default(),
+            retrieval: Retrieval::default(),
+            post_processing: vec![
+                PostProcessing::StripArtifacts,
+                PostProcessing::FixMarkdown,
+                PostProcessing::NormalizeWhitespace,
+            ],
+            prompt_injection: InjectionHandling::default(),
+            min_confidence: 0.0,
+            repeated_questions: RepeatedQuestions::default(),
+            journal: Journal::default(),
+            extract_reminders: false,
+            notifications: vec![],
+            webhooks: vec![],
+            home_assistant: HomeAssistant::default(),
+            api_keys: vec![],
+            tts: None,
+            audit: None,
+            telemetry: None,
+            log_content: LogContent::default(),
+            system_prompt: None,
+            collections: vec![],
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+/// Settings of a connector, e.g. `pdf` or `git`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ConnectorConfig {
+    pub enabled: bool,
+    /// Lines longer than this many characters are split in sentences, the default being
+    /// the sequence length of the local embedding model in tokens
+    pub chunk_size: Option<usize>,
+    /// Cron expression to upload `paths` on, run by `tera scheduler`
+    pub schedule: Option<String>,
+    pub paths: Vec<PathBuf>,
+    /// File names to upload, `*` is a wildcard, all files when empty
+    pub include: Vec<String>,
+    /// File names to leave out, `*` is a wildcard
+    pub exclude: Vec<String>,
+}
+
+impl Default for ConnectorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            chunk_size: None,
+            schedule: None,
+            paths: vec![],
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+}
+
+impl ConnectorConfig {
+    /// Whether the include and exclude filters let a file through
+    pub fn accepts(&self, path: &Path) -> bool {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        let included = self.include.is_empty() || self.include.iter().any(|p| wildcard_match(p, name));
+        included && !self.exclude.iter().any(|p| wildcard_match(p, name))
+    }
+}
+
+/// How references holding instructions for the model get into the prompt
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectionHandling {
+    /// As they are
+    Off,
+    /// Marked untrusted, the model being told not to follow them
+    Flag,
+    /// With the sentences holding instructions removed
+    #[default]
+    Neutralize,
+    /// Left out
+    Drop,
+}
+
+/// A step of the clean up of generated answers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcessing {
+    /// Removes special tokens and prompt labels the model wrote out
+    StripArtifacts,
+    /// Closes code blocks and emphasis left open
+    FixMarkdown,
+    /// Adds `[1]` style markers after sentences taken from a source
+    Citations,
+    /// Trims lines and collapses repeated spaces and blank lines
+    NormalizeWhitespace,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Retrieval {
+    /// The best chunks, wherever they come from
+    #[default]
+    Chunks,
+    /// The best documents, then their best chunks, so a single document can't crowd out the others
+    Documents {
+        #[serde(default)]
+        score: DocumentScore,
+        /// Chunks kept from each document
+        #[serde(default = "default_per_document")]
+        per_document: usize,
+    },
+}
+
+fn default_per_document() -> usize {
+    2
+}
+
+/// How the scores of its chunks rank a document
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentScore {
+    /// Its best chunk
+    #[default]
+    Max,
+    /// Every chunk, the n-th best weighing 1/n, so documents matching in several places rank higher
+    WeightedSum,
+}
+
+/// How many chunks around a retrieved chunk complete its sentences and arguments
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Neighbours {
+    /// Chunks added before
+    pub before: u16,
+    /// Chunks added after
+    pub after: u16,
+    /// Tokens the neighbours of all retrieved chunks can take together, as counted by the default model
+    pub max_tokens: usize,
+}
+
+impl Default for Neighbours {
+    fn default() -> Self {
+        Self {
+            before: 1,
+            after: 1,
+            max_tokens: 1500,
+        }
+    }
+}
+
+/// Settings answering as a persona, e.g. `work` or `personal`, in place of the ones of the config
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Profile {
+    /// Replaces `system_prompt`, e.g. "You assist me at work, answer formally."
+    pub system_prompt: Option<String>,
+    /// Replaces `collections`, e.g. `["git", "*/work/*"]`
+    pub collections: Vec<String>,
+    /// Replaces `retrieval`
+    pub retrieval: Option<Retrieval>,
+    /// Replaces `neighbours`
+    pub neighbours: Option<Neighbours>,
+    /// Added to `source_weights`, replacing the weights of the same sources
+    pub source_weights: HashMap<String, f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RepeatedQuestions {
+    /// How long answers are offered again, 0 to always generate a new one
+    pub days: u32,
+    /// How similar, from 0 to 1, a past question has to be to the new one
+    pub similarity: f32,
+}
+
+impl Default for RepeatedQuestions {
+    fn default() -> Self {
+        Self {
+            days: 7,
+            similarity: 0.95,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IngestQueue {
+    /// Documents memorized at the same time
+    pub workers: usize,
+    /// Documents waiting in memory, the text of the next ones waits on disk
+    pub capacity: usize,
+}
+
+impl Default for IngestQueue {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            capacity: 16,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Journal {
+    /// Asked in turn when writing an entry, the answers make the entry
+    pub prompts: Vec<String>,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Self {
+            prompts: vec![
+                "How are you feeling today?".to_string(),
+                "What happened today?".to_string(),
+                "What is on your mind?".to_string(),
+            ],
+        }
+    }
+}
+
+/// A way of delivering notifications
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationChannel {
+    /// notify-send on Linux, Notification Center on macOS
+    Desktop,
+    /// Posts the notification as JSON
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    /// Messages of a Telegram bot
+    Telegram { token: String, chat_id: String },
+    /// A Slack incoming webhook
+    Slack { webhook_url: String },
+}
+
+/// An outbound webhook
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    /// Events posted, all of them when empty
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    /// Signs payloads with HMAC-SHA256, in the `X-Tera-Signature` header
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    AnswerCompleted,
+    ContentIngested,
+    JobFailed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HomeAssistant {
+    /// Bearer token Home Assistant has to send, anyone reaching the address can ask otherwise unless
+    /// `api_keys` are set. It may do anything an admin key does.
+    pub token: Option<String>,
+}
+
+/// What the holder of an API key may do, each role allowing what the ones before it do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Ask and search
+    ReadOnly,
+    /// Memorize content as well
+    Ingest,
+    /// Everything, e.g. switching the model or reading the usage statistics
+    Admin,
+}
+
+/// A key a client sends as a bearer token
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    /// Who holds the key, for the logs
+    #[serde(default)]
+    pub name: Option<String>,
+    pub role: Role,
+    /// Sources or connectors the key reads and memorizes to, `*` is a wildcard, all of them when empty
+    #[serde(default)]
+    pub collections: Vec<String>,
+}
+
+impl ApiKey {
+    /// A key which may do anything, e.g. for clients of a server without `api_keys`
+    pub fn admin() -> ApiKey {
+        ApiKey {
+            key: String::new(),
+            name: None,
+            role: Role::Admin,
+            collections: vec![],
+        }
+    }
+
+    /// Whether the key reads and memorizes chunks from the source, of the connector
+    pub fn covers(&self, source: &str, connector: &str) -> bool {
+        self.collections.is_empty()
+            || self.collections.iter().any(|p| wildcard_match(p, source) || wildcard_match(p, connector))
+    }
+}
+
+/// A piper voice, https://github.com/rhasspy/piper
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tts {
+    /// The piper binary, found on the PATH by default
+    #[serde(default = "default_tts_program")]
+    pub program: String,
+    /// The `.onnx` model of the voice, with its `.onnx.json` next to it
+    pub voice: PathBuf,
+    /// Speaker of voices with several
+    #[serde(default)]
+    pub speaker: Option<u32>,
+}
+
+fn default_tts_program() -> String {
+    "piper".to_string()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Audit {
+    /// Size the log grows to before a new one is started
+    pub max_bytes: u64,
+    /// How many of the previous logs are kept
+    pub keep: usize,
+}
+
+impl Default for Audit {
+    fn default() -> Self {
+        Self {
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Telemetry {
+    /// OTLP over HTTP, `http://localhost:4318/v1/traces` or `OTEL_EXPORTER_OTLP_ENDPOINT` when not set
+    pub endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "tera".to_string(),
+        }
+    }
+}
+
+/// How content is written in logs, sizes and timings are always kept
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogContent {
+    /// As it is
+    #[default]
+    Full,
+    /// The first few characters
+    Truncated,
+    /// A SHA-256 prefix, so the same text can be followed across lines without being readable
+    Hashed,
+}
+
+/// Encoding of stored vectors
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Quantization {
+    /// 32 bit floats
+    #[default]
+    None,
+    /// One byte per dimension and a scale, a quarter of the size for a sliver of accuracy
+    Int8,
+}
+
+/// How text longer than the 512 tokens of the local embedding model gets embedded
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LongChunks {
+    /// Only the beginning is embedded
+    Truncate,
+    /// Chunks are stored as several chunks which fit, long questions are embedded like `mean_pool`
+    #[default]
+    Split,
+    /// The average of the embeddings of consecutive windows
+    MeanPool,
+}
+
+/// What the web connector sends to a site, e.g. the session cookie of an account
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SiteConfig {
+    pub headers: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FewShotExample {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Which fields of JSON records are content, metadata and timestamp.
+/// Fields are dotted paths such as `author.name`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct JsonMapping {
+    /// Path to the array of records when the file is an object, e.g. `data.messages`
+    pub records: Option<String>,
+    /// Fields joined to form the text of a record
+    pub content: Vec<String>,
+    /// Fields kept as metadata
+    pub metadata: Vec<String>,
+    /// Field holding the date of a record
+    pub timestamp: Option<String>,
+}
+
+impl Default for JsonMapping {
+    fn default() -> Self {
+        Self {
+            records: None,
+            content: ["title", "text", "content", "message", "body"]
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            metadata: ["author", "sender", "url", "tags"]
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            timestamp: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InferenceBackend {
+    /// Run the model selected on the command line in process
+    #[default]
+    Local,
+    /// Delegate generation to an OpenAI compatible server (llama.cpp, ollama, vLLM, ...)
+    Remote {
+        /// e.g. `http://localhost:8080/v1`
+        base_url: String,
+        api_key: Option<String>,
+        /// Model name sent with each request
+        #[serde(default = "default_remote_model")]
+        model: String,
+    },
+    /// Generate with a model managed by Ollama
+    Ollama {
+        #[serde(default = "default_ollama_url")]
+        base_url: String,
+        model: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Storage {
+    /// Everything in the local database
+    #[default]
+    Local,
+    /// Long texts in an S3 compatible object storage, with the recently used ones cached locally
+    S3 {
+        /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://localhost:9000` for MinIO
+        endpoint: String,
+        bucket: String,
+        #[serde(default = "default_region")]
+        region: String,
+        access_key: String,
+        secret_key: String,
+        /// Size of the local cache in megabytes
+        #[serde(default = "default_cache_mb")]
+        cache_mb: u64,
+    },
+}
+
+/// Storage shared by the devices, which each write their changes to files of their own
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SyncRemote {
+    /// A folder kept in sync by other means, e.g. Syncthing, Dropbox or a network drive
+    Folder { path: PathBuf },
+    /// A WebDAV collection, e.g. in Nextcloud
+    WebDav {
+        /// e.g. `https://cloud.example.com/remote.php/dav/files/me/tera`
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// A bucket of an S3 compatible object storage
+    S3 {
+        /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://localhost:9000` for MinIO
+        endpoint: String,
+        bucket: String,
+        #[serde(default = "default_region")]
+        region: String,
+        access_key: String,
+        secret_key: String,
+        /// Folder of the bucket the changes go to, e.g. `tera/`
+        #[serde(default)]
+        prefix: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encryption {
+    #[default]
+    None,
+    /// Key derived from a passphrase, read from `TERA_PASSPHRASE` or asked for
+    Passphrase,
+    /// Random key kept in the OS keychain
+    Keychain,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EmbeddingBackend {
+    /// BAAI/bge-small-en-v1.5 in process
+    #[default]
+    Local,
+    /// Embed with a model managed by Ollama, e.g. `nomic-embed-text`
+    Ollama {
+        #[serde(default = "default_ollama_url")]
+        base_url: String,
+        model: String,
+        /// Leading dimensions of the vectors to keep, for Matryoshka models such as `nomic-embed-text`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dimensions: Option<usize>,
+    },
+}
+
+impl EmbeddingBackend {
+    /// Dimensions stored vectors get cut to, None when they are kept whole
+    pub fn dimensions(&self) -> Option<usize> {
+        match self {
+            EmbeddingBackend::Local => None,
+            EmbeddingBackend::Ollama { dimensions, .. } => *dimensions,
+        }
+    }
+}
+
+fn default_ollama_url() -> String {
+    crate::ollama::DEFAULT_URL.to_string()
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_cache_mb() -> u64 {
+    512
+}
+
+fn default_remote_model() -> String {
+    "default".to_string()
+}
+
+impl Config {
+    /// The trust weight of a source, the most specific matching pattern wins
+    pub fn source_weight(&self, source: &str) -> f32 {
+        self.source_weights
+            .iter()
+            .filter(|(pattern, _)| wildcard_match(pattern, source))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, weight)| *weight)
+            .unwrap_or(1.0)
+    }
+
+    /// Whether chunks from the source, of the connector, are answered from, see `collections`
+    pub fn in_collections(&self, source: &str, connector: &str) -> bool {
+        self.collections.is_empty()
+            || self.collections.iter().any(|p| wildcard_match(p, source) || wildcard_match(p, connector))
+    }
+
+    /// This config with the settings of the named profile in place of its own
+    pub fn with_profile(&self, name: &str) -> Result<Config> {
+        let profile = self.profiles.get(name).with_context(|| {
+            let mut names = self.profiles.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            format!("Unknown profile {}, the config has: {}", name, names.join(", "))
+        })?;
+        let mut config = self.clone();
+        if profile.system_prompt.is_some() {
+            config.system_prompt = profile.system_prompt.clone();
+        }
+        if !profile.collections.is_empty() {
+            config.collections = profile.collections.clone();
+        }
+        if let Some(retrieval) = profile.retrieval {
+            config.retrieval = retrieval;
+        }
+        if let Some(neighbours) = &profile.neighbours {
+            config.neighbours = neighbours.clone();
+        }
+        config.source_weights.extend(profile.source_weights.clone());
+        Ok(config)
+    }
+
+    /// Settings of a connector, the defaults when it isn't configured
+    pub fn connector(&self, name: &str) -> ConnectorConfig {
+        self.connectors.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Headers and cookies of every pattern matching a host, the most specific pattern wins
+    pub fn site(&self, host: &str) -> SiteConfig {
+        let mut sites = self
+            .sites
+            .iter()
+            .filter(|(pattern, _)| wildcard_match(pattern, host))
+            .collect::<Vec<_>>();
+        sites.sort_by_key(|(pattern, _)| pattern.len());
+        let mut merged = SiteConfig::default();
+        for (_, site) in sites {
+            merged.headers.extend(site.headers.clone());
+            merged.cookies.extend(site.cookies.clone());
+        }
+        merged
+    }
+
+    /// How many days chunks of a source are kept, the most specific matching pattern wins
+    pub fn retention_days(&self, source: &str) -> Option<u32> {
+        self.retention
+            .iter()
+            .filter(|(pattern, _)| wildcard_match(pattern, source))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, days)| *days)
+    }
+}
+
+// Case insensitive match where `*` matches any run of characters
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let text = text.to_lowercase();
+    let parts = pattern.split('*').collect::<Vec<&str>>();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !text.starts_with(first) || !text[first.len()..].ends_with(last) {
+        return false;
+    }
+    let mut rest = &text[first.len()..text.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Where Tera keeps its config and data, `tera` in the local config directory unless overridden
+pub fn data_dir() -> PathBuf {
+    match DATA_DIR.get() {
+        Some(dir) => dir.clone(),
+        None => dirs::config_local_dir()
+            .expect("Unable to get local config directory")
+            .join("tera"),
+    }
+}
+
+/// SQLite database holding the imported tables, kept in snapshots whether or not the `tables` feature is built
+pub fn tables_path() -> PathBuf {
+    data_dir().join("tables.sqlite")
+}
+
+pub fn config_path() -> PathBuf {
+    data_dir().join("config.json")
+}
+
+fn load_config() -> Result<Config> {
+    let path = config_path();
+    if !path.exists() {
+        debug!(path = ?path, "No config file, using defaults");
+        return Ok(with_overrides(Config::default()));
+    }
+    let config = std::fs::read_to_string(&path)?;
+    let config: Config = serde_json::from_str(&config)
+        .with_context(|| format!("Unable to parse config file {}", path.display()))?;
+    // not the config itself, it holds API keys and passwords
+    debug!(path = ?path, "Loaded config");
+    crate::connectors::validate(&config)
+        .with_context(|| format!("Invalid config file {}", path.display()))?;
+    Ok(with_overrides(config))
+}
+
+fn with_overrides(mut config: Config) -> Config {
+    if let Some(embeddings) = EMBEDDINGS.get() {
+        config.embeddings = embeddings.clone();
+    }
+    config
+}
+
+/// What a reload of the config file changed, by setting name
+#[derive(Debug, Clone, Default)]
+pub struct Reload {
+    /// Applied to what starts from now on
+    pub applied: Vec<String>,
+    /// Left as they were until a restart, e.g. `inference` or `embeddings`
+    pub needs_restart: Vec<String>,
+}
+
+/// Holds the current config, updated as the config file changes
+pub struct ConfigManager {
+    current: RwLock<Arc<Config>>,
+    changes: watch::Sender<Arc<Config>>,
+}
+
+impl ConfigManager {
+    fn new(config: Config) -> ConfigManager {
+        let config = Arc::new(config);
+        ConfigManager {
+            current: RwLock::new(config.clone()),
+            changes: watch::channel(config).0,
+        }
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Receives the config after each change applied
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.changes.subscribe()
+    }
+
+    /// Reads the config file again, applying the live settings which changed. The others are reported
+    /// and keep their value, the model and the database were set up with it.
+    pub fn reload(&self) -> Result<Reload> {
+        let loaded = serde_json::to_value(load_config()?)?;
+        let mut merged = serde_json::to_value(&*self.current())?;
+        let (Some(loaded), Some(fields)) = (loaded.as_object(), merged.as_object_mut()) else {
+            anyhow::bail!("The config isn't an object");
+        };
+        let mut reload = Reload::default();
+        for (name, value) in loaded {
+            if fields.get(name) == Some(value) {
+                continue;
+            }
+            if LIVE_SETTINGS.contains(&name.as_str()) {
+                fields.insert(name.clone(), value.clone());
+                reload.applied.push(name.clone());
+            } else {
+                reload.needs_restart.push(name.clone());
+            }
+        }
+        if !reload.applied.is_empty() {
+            let config = Arc::new(serde_json::from_value::<Config>(merged)?);
+            *self.current.write().unwrap_or_else(|e| e.into_inner()) = config.clone();
+            self.changes.send_replace(config);
+        }
+        Ok(reload)
+    }
+
+    /// Reloads the config file whenever it changes, for servers and the daemon
+    pub fn watch(&'static self) {
+        WATCH.call_once(|| {
+            tokio::spawn(async move {
+                let mut modified = modified_at();
+                loop {
+                    tokio::time::sleep(WATCH_INTERVAL).await;
+                    let now = modified_at();
+                    if now == modified {
+                        continue;
+                    }
+                    modified = now;
+                    match self.reload() {
+                        Ok(reload) => report(&reload),
+                        Err(e) => eprintln!("Config not reloaded, keeping the current one: {:#}", e),
+                    }
+                }
+            });
+        });
+    }
+}
+
+fn modified_at() -> Option<SystemTime> {
+    std::fs::metadata(config_path()).and_then(|m| m.modified()).ok()
+}
+
+fn report(reload: &Reload) {
+    if !reload.applied.is_empty() {
+        eprintln!("Config reloaded: {}", reload.applied.join(", "));
+    }
+    if !reload.needs_restart.is_empty() {
+        eprintln!("Restart Tera to apply: {}", reload.needs_restart.join(", "));
+    }
+}
+
+/// The config with the latest live settings, e.g. `min_confidence` or `retrieval`, and those of the
+/// profile of the question being answered, see `with_profile`
+pub fn live() -> Arc<Config> {
+    PROFILED
+        .try_with(|(_, config)| config.clone())
+        .unwrap_or_else(|_| CONFIG_MANAGER.current())
+}
+
+/// The profile of the question being answered, see `with_profile`
+pub fn profile() -> Option<String> {
+    PROFILED.try_with(|(name, _)| name.clone()).ok()
+}
+
+/// Runs `f` with the settings of the profile returned by `live`, those of the config without one
+pub async fn with_profile<F: Future>(name: Option<&str>, f: F) -> Result<F::Output> {
+    let Some(name) = name else {
+        return Ok(f.await);
+    };
+    let config = CONFIG_MANAGER.current().with_profile(name)?;
+    debug!(profile = name, "Answering with profile");
+    Ok(PROFILED.scope((name.to_string(), Arc::new(config)), f).await)
+}
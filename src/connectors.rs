@@ -0,0 +1,175 @@
+use crate::config::{Config, ConnectorConfig, CONFIG};
+use crate::database::DB;
+use crate::ingest::IngestType;
+#[cfg(feature = "scheduler")]
+use crate::scheduler::{add_job, parse_schedule};
+use anyhow::{Error, Result};
+#[cfg(feature = "scheduler")]
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "scheduler")]
+use std::path::Path;
+use surrealdb::sql::Datetime;
+#[cfg(feature = "scheduler")]
+use tracing::debug;
+
+/// A source of content, configured in the `connectors` section of the config
+#[derive(Debug, Clone)]
+pub struct Connector {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Upload type of connectors reading files, which can be run on a schedule
+    pub ingest_type: Option<IngestType>,
+}
+
+fn connector(name: &'static str, description: &'static str, ingest_type: Option<IngestType>) -> Connector {
+    Connector {
+        name,
+        description,
+        ingest_type,
+    }
+}
+
+/// Every connector Tera has, the names are the `connector` of the chunks they store
+pub fn connectors() -> Vec<Connector> {
+    let mut connectors = vec![
+        connector("whatsapp", "WhatsApp chat exports", Some(IngestType::Whatsapp)),
+        connector("text", "Text and markdown files", Some(IngestType::Text)),
+    ];
+    #[cfg(feature = "pdf")]
+    connectors.push(connector("pdf", "PDF documents", Some(IngestType::PDF)));
+    #[cfg(feature = "whisper")]
+    connectors.push(connector("audio", "Transcribed WAV recordings", Some(IngestType::Audio)));
+    connectors.extend([
+        connector("calendar", "iCalendar files", Some(IngestType::Calendar)),
+        connector("contacts", "vCard address books", Some(IngestType::Contacts)),
+    ]);
+    #[cfg(feature = "tables")]
+    connectors.push(connector("table", "CSV and Parquet tables", Some(IngestType::Table)));
+    connectors.push(connector("json", "JSON and JSONL exports", Some(IngestType::Json)));
+    #[cfg(feature = "apple-notes")]
+    connectors.push(connector("apple_notes", "Apple Notes", Some(IngestType::AppleNotes)));
+    #[cfg(feature = "pdf")]
+    connectors.push(connector("zotero", "Zotero libraries", Some(IngestType::Zotero)));
+    connectors.push(connector("git", "Commit history of git repositories", Some(IngestType::Git)));
+    #[cfg(feature = "crawl")]
+    connectors.push(connector("web", "Pages of crawled websites", None));
+    #[cfg(feature = "tables")]
+    connectors.push(connector("sqlite", "Registered SQLite databases", None));
+    connectors.extend([
+        connector("clipboard", "Clipboard captures", None),
+        connector("remember", "`tera remember`", None),
+        connector("journal", "`tera journal` entries", None),
+        connector("stdin", "`tera ingest`", None),
+        connector("grpc", "The gRPC Ingest call", None),
+        connector("mcp", "The MCP save_note tool", None),
+        connector("home_assistant", "Device notes sent by Home Assistant", None),
+        connector("api", "The Rust library", None),
+    ]);
+    connectors
+}
+
+/// Checks the `connectors` section of the config
+pub fn validate(config: &Config) -> Result<()> {
+    let known = connectors();
+    for (name, settings) in &config.connectors {
+        let Some(connector) = known.iter().find(|c| c.name == name.as_str()) else {
+            let names = known.iter().map(|c| c.name).collect::<Vec<_>>();
+            anyhow::bail!("Unknown connector {}, expected one of {}", name, names.join(", "));
+        };
+        if settings.chunk_size == Some(0) {
+            anyhow::bail!("The chunk_size of connector {} has to be positive", name);
+        }
+        if let Some(schedule) = &settings.schedule {
+            if !cfg!(feature = "scheduler") {
+                anyhow::bail!(
+                    "Connector {} runs on {}, which needs Tera built with the `scheduler` feature",
+                    name,
+                    schedule
+                );
+            }
+            #[cfg(feature = "scheduler")]
+            parse_schedule(schedule)?;
+            if connector.ingest_type.is_none() {
+                anyhow::bail!("Connector {} can't run on a schedule", name);
+            }
+            if settings.paths.is_empty() {
+                anyhow::bail!("Connector {} has a schedule but no paths", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of the last run of a connector
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectorRun {
+    pub last_run: Datetime,
+    /// `ok` or the error of the last run
+    pub last_status: String,
+    pub runs: u64,
+}
+
+/// Records how a run of a connector went
+pub async fn record_run(name: &str, result: &Result<()>) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let previous: Option<ConnectorRun> = db.select(("connector_run", name)).await?;
+    let status = match result {
+        Ok(()) => "ok".to_string(),
+        Err(e) => e.to_string(),
+    };
+    let _: Option<ConnectorRun> = db
+        .update(("connector_run", name))
+        .content(ConnectorRun {
+            last_run: Datetime::default(),
+            last_status: status,
+            runs: previous.map(|p| p.runs).unwrap_or(0) + 1,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Enabled connectors with their settings and last run
+pub async fn list_connectors() -> Result<Vec<(Connector, ConnectorConfig, Option<ConnectorRun>)>, Error> {
+    let db = DB.get().await.clone();
+    let mut listed = vec![];
+    for connector in connectors() {
+        let settings = CONFIG.connector(connector.name);
+        if !settings.enabled {
+            continue;
+        }
+        let run: Option<ConnectorRun> = db.select(("connector_run", connector.name)).await?;
+        listed.push((connector, settings, run));
+    }
+    Ok(listed)
+}
+
+/// Schedules an upload job for every path of the connectors which have a schedule
+#[cfg(feature = "scheduler")]
+pub async fn schedule_connectors() -> Result<(), Error> {
+    for connector in connectors() {
+        let settings = CONFIG.connector(connector.name);
+        let (Some(schedule), Some(ingest_type), true) = (&settings.schedule, connector.ingest_type, settings.enabled) else {
+            continue;
+        };
+        let upload_type = ingest_type
+            .to_possible_value()
+            .map(|v| v.get_name().to_string())
+            .unwrap_or_default();
+        for (i, path) in settings.paths.iter().enumerate() {
+            let name = match settings.paths.len() {
+                1 => connector.name.to_string(),
+                _ => format!("{}-{}", connector.name, i + 1),
+            };
+            debug!(job = name, "Scheduling connector");
+            let path = path.display().to_string();
+            // a folder of files of the connector's type, git and notes connectors take folders themselves
+            let command = match Path::new(&path).is_dir() && ingest_type.reads_single_file() {
+                true => vec!["upload-dir".to_string(), "--only".to_string(), upload_type.clone(), path],
+                false => vec!["upload".to_string(), upload_type.clone(), path],
+            };
+            add_job(&name, schedule, 0, command).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,370 @@
+use crate::database::{is_dry_run, VectorIndex, DB};
+use crate::redaction::loggable;
+use crate::storage;
+use anyhow::{Context, Error, Result};
+use lazy_static::lazy_static;
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use surrealdb::sql::{Datetime, Thing};
+use tracing::debug;
+
+// Chunks read at once while linking the chunks already stored
+const PAGE_SIZE: usize = 500;
+
+lazy_static! {
+    // The names chunks get matched against as they are stored, built again once the people change
+    static ref MATCHER: Mutex<Option<Arc<Matcher>>> = Mutex::new(None);
+}
+
+/// A person from an address book
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Person {
+    #[serde(with = "crate::crypto::sealed")]
+    pub name: String,
+    /// The vCard UID, the same contact exported again keeps it even once renamed
+    #[serde(default)]
+    pub uid: String,
+    #[serde(with = "crate::crypto::sealed_list")]
+    pub emails: Vec<String>,
+    #[serde(with = "crate::crypto::sealed_list")]
+    pub phones: Vec<String>,
+    #[serde(with = "crate::crypto::sealed")]
+    pub organization: String,
+    #[serde(with = "crate::crypto::sealed")]
+    pub title: String,
+    #[serde(with = "crate::crypto::sealed")]
+    pub birthday: String,
+    #[serde(with = "crate::crypto::sealed")]
+    pub note: String,
+}
+
+impl Person {
+    /// Stable record key derived from the UID, or from the name without one, re-importing a contact
+    /// updates it
+    pub fn key(&self) -> String {
+        match self.uid.is_empty() {
+            true => self.name_key(),
+            false => format!("uid_{}", sanitized(&self.uid)),
+        }
+    }
+
+    // The key of contacts imported before their UID was read
+    fn name_key(&self) -> String {
+        sanitized(&self.name)
+    }
+
+    /// The text which gets embedded for the contact
+    pub fn to_text(&self) -> String {
+        let mut text = format!("Contact: {}", self.name);
+        if !self.emails.is_empty() {
+            text += &format!(", email {}", self.emails.join(", "));
+        }
+        if !self.phones.is_empty() {
+            text += &format!(", phone {}", self.phones.join(", "));
+        }
+        if !self.title.is_empty() {
+            text += &format!(", {}", self.title);
+        }
+        if !self.organization.is_empty() {
+            text += &format!(" at {}", self.organization);
+        }
+        if !self.birthday.is_empty() {
+            text += &format!(", born {}", self.birthday);
+        }
+        if !self.note.is_empty() {
+            text += &format!(". {}", self.note);
+        }
+        text
+    }
+
+    // The full name in any case, or the first name written as one when it is distinctive enough, so
+    // that Mark matches but not "mark the date"
+    fn patterns(&self) -> Vec<String> {
+        let mut patterns = vec![format!(r"(?i)\b{}\b", regex::escape(&self.name))];
+        if let Some(first) = self.name.split_whitespace().next() {
+            let capitalized = first.chars().next().is_some_and(char::is_uppercase);
+            if capitalized && first.chars().count() >= 3 && first != self.name {
+                patterns.push(format!(r"\b{}\b", regex::escape(first)));
+            }
+        }
+        patterns
+    }
+}
+
+fn sanitized(key: &str) -> String {
+    key.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+// The names of many people matched at once, reading each text a single time
+struct Matcher {
+    people: Vec<Thing>,
+    // the person of each pattern of the set
+    owners: Vec<usize>,
+    names: RegexSet,
+}
+
+impl Matcher {
+    fn new(people: &[(Thing, Person)]) -> Result<Matcher> {
+        let (owners, patterns): (Vec<usize>, Vec<String>) = people
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (_, person))| person.patterns().into_iter().map(move |pattern| (i, pattern)))
+            .unzip();
+        Ok(Matcher {
+            people: people.iter().map(|(id, _)| id.clone()).collect(),
+            owners,
+            names: RegexSet::new(patterns).context("Unable to match the names of contacts")?,
+        })
+    }
+
+    /// The people any of the texts mention
+    fn mentioned(&self, texts: &[&str]) -> Vec<Thing> {
+        let mut found = texts
+            .iter()
+            .flat_map(|text| self.names.matches(text).into_iter())
+            .map(|pattern| self.owners[pattern])
+            .collect::<Vec<_>>();
+        found.sort_unstable();
+        found.dedup();
+        found.into_iter().map(|i| self.people[i].clone()).collect()
+    }
+}
+
+// Matches every person stored, built on first use
+async fn matcher() -> Result<Arc<Matcher>, Error> {
+    if let Some(matcher) = MATCHER.lock().unwrap_or_else(|e| e.into_inner()).clone() {
+        return Ok(matcher);
+    }
+    let matcher = Arc::new(Matcher::new(&all_people().await?)?);
+    *MATCHER.lock().unwrap_or_else(|e| e.into_inner()) = Some(matcher.clone());
+    Ok(matcher)
+}
+
+/// Has the names matched again once people were added or the knowledge base was replaced
+pub(crate) fn people_changed() {
+    *MATCHER.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Mention {
+    person: Thing,
+    chunk: Thing,
+    created_at: Datetime,
+}
+
+#[derive(Deserialize, Debug)]
+struct MentionedChunk {
+    chunk: VectorIndex,
+}
+
+/// Extracts the contacts of a vCard file
+pub fn parse_vcf(vcf: &str) -> Vec<Person> {
+    let unfolded = vcf
+        .replace("\r\n", "\n")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+
+    let mut people = vec![];
+    let mut current: Option<Person> = None;
+    for line in unfolded.lines() {
+        match line {
+            "BEGIN:VCARD" => current = Some(Person::default()),
+            "END:VCARD" => {
+                if let Some(person) = current.take() {
+                    if !person.name.is_empty() {
+                        people.push(person);
+                    }
+                }
+            }
+            _ => {
+                let Some(person) = current.as_mut() else {
+                    continue;
+                };
+                let Some((key, value)) = line.split_once(':') else {
+                    continue;
+                };
+                // drop groups and parameters, e.g. item1.EMAIL;type=INTERNET
+                let name = key.split(';').next().unwrap_or_default();
+                let name = name.rsplit('.').next().unwrap_or_default().to_uppercase();
+                let value = value.replace("\\,", ",").replace("\\n", " ");
+                match name.as_str() {
+                    "FN" => person.name = value.trim().to_string(),
+                    "UID" => person.uid = value.trim().to_string(),
+                    // structured name is only used when there is no formatted one
+                    "N" if person.name.is_empty() => {
+                        let parts = value.split(';').collect::<Vec<&str>>();
+                        let given = parts.get(1).copied().unwrap_or_default();
+                        let family = parts.first().copied().unwrap_or_default();
+                        person.name = format!("{} {}", given, family).trim().to_string();
+                    }
+                    "EMAIL" => person.emails.push(value),
+                    "TEL" => person.phones.push(value),
+                    "ORG" => person.organization = value.replace(';', " ").trim().to_string(),
+                    "TITLE" => person.title = value,
+                    "BDAY" => person.birthday = value,
+                    "NOTE" => person.note = value,
+                    _ => {}
+                }
+            }
+        }
+    }
+    people
+}
+
+pub async fn upsert_person(person: &Person) -> Result<Thing, Error> {
+    if is_dry_run() {
+        return Ok(Thing::from(("person", person.key().as_str())));
+    }
+    let db = DB.get().await.clone();
+    if person.key() != person.name_key() {
+        // the same contact imported before its UID was read
+        db.query("DELETE mention WHERE person = $old; DELETE $old")
+            .bind(("old", Thing::from(("person", person.name_key().as_str()))))
+            .await?
+            .check()
+            .context("Unable to replace contact")?;
+    }
+    let _: Option<Person> = db
+        .update(("person", person.key()))
+        .content(person.clone())
+        .await?;
+    people_changed();
+    Ok(Thing::from(("person", person.key().as_str())))
+}
+
+/// The contacts which are new or changed since they were last imported, e.g. from a newer export of
+/// the same address book
+pub async fn changed(people: Vec<Person>) -> Result<Vec<Person>, Error> {
+    if is_dry_run() {
+        return Ok(people);
+    }
+    let db = DB.get().await.clone();
+    let mut changed = vec![];
+    for person in people {
+        let stored: Option<Person> = db.select(("person", person.key())).await?;
+        let mut result = db
+            .query("SELECT VALUE id FROM vector_index WHERE metadata.type = 'contact' AND metadata.person = $key LIMIT 1")
+            .bind(("key", Thing::from(("person", person.key().as_str())).to_string()))
+            .await?;
+        let cards: Vec<Thing> = result.take(0)?;
+        if stored.as_ref() != Some(&person) || cards.is_empty() {
+            changed.push(person);
+        }
+    }
+    Ok(changed)
+}
+
+/// Every person stored, with their record
+pub(crate) async fn all_people() -> Result<Vec<(Thing, Person)>, Error> {
+    #[derive(Deserialize)]
+    struct Row {
+        id: Thing,
+        #[serde(flatten)]
+        person: Person,
+    }
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM person").await?;
+    let rows: Vec<Row> = result.take(0)?;
+    Ok(rows.into_iter().map(|r| (r.id, r.person)).collect())
+}
+
+async fn add_mention(person: Thing, chunk: Thing) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    db.query("CREATE mention CONTENT $mention")
+        .bind((
+            "mention",
+            Mention {
+                person,
+                chunk,
+                created_at: Datetime::default(),
+            },
+        ))
+        .await?
+        .check()
+        .context("Unable to link mention")?;
+    Ok(())
+}
+
+/// Links a newly stored chunk to the people it mentions
+pub async fn link_chunk(chunk: &VectorIndex) -> Result<(), Error> {
+    let sender = chunk.metadata["sender"].as_str().unwrap_or_default();
+    for id in matcher().await?.mentioned(&[&chunk.content_chunk, sender]) {
+        add_mention(id, chunk.id.clone()).await?;
+    }
+    Ok(())
+}
+
+/// Links the chunks already stored which mention any of the people, reading the chunks once for all
+/// of them
+pub async fn link_existing_chunks(people: &[(Thing, Person)]) -> Result<usize, Error> {
+    #[derive(Deserialize)]
+    struct Row {
+        id: Thing,
+        // as stored, possibly sealed or offloaded
+        content_chunk: String,
+        sender: Option<String>,
+    }
+    if is_dry_run() || people.is_empty() {
+        return Ok(0);
+    }
+    let db = DB.get().await.clone();
+    db.query("DELETE mention WHERE person INSIDE $people")
+        .bind(("people", people.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>()))
+        .await?
+        .check()?;
+    let matcher = Matcher::new(people)?;
+    let mut linked = 0;
+    let mut start = 0;
+    loop {
+        let mut result = db
+            .query(
+                "SELECT id, content_chunk, metadata.sender AS sender FROM vector_index
+                WHERE metadata.type != 'contact' START $start LIMIT $limit",
+            )
+            .bind(("start", start))
+            .bind(("limit", PAGE_SIZE))
+            .await?;
+        let rows: Vec<Row> = result.take(0)?;
+        let texts = storage::load_all(rows.iter().map(|row| row.content_chunk.clone()).collect()).await?;
+        for (row, text) in rows.iter().zip(texts) {
+            for id in matcher.mentioned(&[&text, row.sender.as_deref().unwrap_or_default()]) {
+                add_mention(id, row.id.clone()).await?;
+                linked += 1;
+            }
+        }
+        if rows.len() < PAGE_SIZE {
+            break;
+        }
+        start += PAGE_SIZE;
+    }
+    debug!(people = people.len(), linked = linked, "Linked existing chunks");
+    Ok(linked)
+}
+
+/// The contact card and latest mentions of every person named in the query
+pub async fn people_context(query: &str) -> Result<Vec<VectorIndex>, Error> {
+    let db = DB.get().await.clone();
+    let mut context = vec![];
+    for id in matcher().await?.mentioned(&[query]) {
+        debug!(person = %loggable(&id.to_string()), "Query mentions a contact");
+        // the latest card of a contact imported again from another file
+        let mut result = db
+            .query(
+                "SELECT * FROM vector_index WHERE metadata.type = 'contact' AND metadata.person = $key
+                ORDER BY created_at DESC LIMIT 1",
+            )
+            .query("SELECT chunk.* AS chunk, created_at FROM mention WHERE person = $person ORDER BY created_at DESC LIMIT 3")
+            .bind(("key", id.to_string()))
+            .bind(("person", id))
+            .await?;
+        let card: Vec<VectorIndex> = result.take(0)?;
+        let mentions: Vec<MentionedChunk> = result.take(1)?;
+        context.extend(card);
+        context.extend(mentions.into_iter().map(|m| m.chunk));
+    }
+    Ok(context)
+}
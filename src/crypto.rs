@@ -0,0 +1,200 @@
+use crate::config::{Encryption, CONFIG};
+use anyhow::Result;
+#[cfg(feature = "encryption")]
+use {
+    crate::config::data_dir,
+    anyhow::Context,
+    argon2::Argon2,
+    base64::engine::general_purpose::STANDARD as BASE64,
+    base64::Engine,
+    chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng},
+    chacha20poly1305::{Key, XChaCha20Poly1305, XNonce},
+    serde::{Deserialize, Serialize},
+    std::sync::OnceLock,
+    tracing::debug,
+};
+
+// Marks sealed values, anything else was stored before encryption was enabled
+const PREFIX: &str = "tera:enc1:";
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 24;
+// Sealed with the key so a wrong passphrase is noticed before anything gets written
+#[cfg(feature = "encryption")]
+const CHECK: &str = "tera";
+
+#[cfg(feature = "encryption")]
+static CIPHER: OnceLock<Option<XChaCha20Poly1305>> = OnceLock::new();
+
+/// Salt and check value of a passphrase protected knowledge base, in `encryption.json`
+#[cfg(feature = "encryption")]
+#[derive(Serialize, Deserialize, Debug)]
+struct KeyFile {
+    salt: String,
+    check: String,
+}
+
+/// Loads the key of the configured encryption, asking for the passphrase if needed
+#[cfg(feature = "encryption")]
+pub fn unlock() -> Result<()> {
+    if CIPHER.get().is_some() {
+        return Ok(());
+    }
+    let cipher = match CONFIG.encryption {
+        Encryption::None => None,
+        Encryption::Passphrase => Some(passphrase_cipher()?),
+        Encryption::Keychain => Some(keychain_cipher()?),
+    };
+    let _ = CIPHER.set(cipher);
+    Ok(())
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn unlock() -> Result<()> {
+    if CONFIG.encryption != Encryption::None {
+        anyhow::bail!("Tera was built without the `encryption` feature, set `encryption` to `none` in the config");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+fn cipher() -> Option<&'static XChaCha20Poly1305> {
+    CIPHER.get().and_then(|c| c.as_ref())
+}
+
+#[cfg(feature = "encryption")]
+fn passphrase() -> Result<String> {
+    if let Ok(passphrase) = std::env::var("TERA_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    rpassword::prompt_password("Passphrase: ").context("Unable to read the passphrase")
+}
+
+#[cfg(feature = "encryption")]
+fn passphrase_cipher() -> Result<XChaCha20Poly1305> {
+    let path = data_dir().join("encryption.json");
+    let existing: Option<KeyFile> = match std::fs::read_to_string(&path) {
+        Ok(file) => Some(serde_json::from_str(&file)?),
+        Err(_) => None,
+    };
+    let salt = match &existing {
+        Some(key_file) => BASE64.decode(&key_file.salt)?,
+        None => XChaCha20Poly1305::generate_nonce(&mut OsRng).to_vec(),
+    };
+
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase()?.as_bytes(), &salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("Unable to derive the key: {}", e))?;
+    let cipher = XChaCha20Poly1305::new(&key);
+
+    match existing {
+        Some(key_file) => {
+            if open_with(&cipher, &key_file.check).ok().as_deref() != Some(CHECK) {
+                anyhow::bail!("Wrong passphrase");
+            }
+        }
+        None => {
+            debug!(path = ?path, "Creating encryption key file");
+            std::fs::create_dir_all(data_dir())?;
+            let key_file = KeyFile {
+                salt: BASE64.encode(&salt),
+                check: seal_with(&cipher, CHECK)?,
+            };
+            std::fs::write(&path, serde_json::to_string(&key_file)?)?;
+        }
+    }
+    Ok(cipher)
+}
+
+// A random key kept in the OS keychain, created on first use
+#[cfg(feature = "encryption")]
+fn keychain_cipher() -> Result<XChaCha20Poly1305> {
+    let entry = keyring::Entry::new("tera", "knowledge-base")?;
+    let key = match entry.get_password() {
+        Ok(key) => BASE64.decode(key)?,
+        Err(keyring::Error::NoEntry) => {
+            let key = XChaCha20Poly1305::generate_key(&mut OsRng);
+            entry.set_password(&BASE64.encode(key))?;
+            key.to_vec()
+        }
+        Err(e) => return Err(e).context("Unable to read the key from the keychain"),
+    };
+    XChaCha20Poly1305::new_from_slice(&key).map_err(|_| anyhow::anyhow!("Invalid key in the keychain"))
+}
+
+#[cfg(feature = "encryption")]
+fn seal_with(cipher: &XChaCha20Poly1305, plain: &str) -> Result<String> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = cipher
+        .encrypt(&nonce, plain.as_bytes())
+        .map_err(|_| anyhow::anyhow!("Unable to encrypt"))?;
+    Ok(format!("{}{}", PREFIX, BASE64.encode([nonce.as_slice(), sealed.as_slice()].concat())))
+}
+
+#[cfg(feature = "encryption")]
+fn open_with(cipher: &XChaCha20Poly1305, sealed: &str) -> Result<String> {
+    let bytes = BASE64.decode(sealed.trim_start_matches(PREFIX))?;
+    if bytes.len() < NONCE_LEN {
+        anyhow::bail!("Encrypted value is truncated");
+    }
+    let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+    let plain = cipher
+        .decrypt(XNonce::from_slice(nonce), sealed)
+        .map_err(|_| anyhow::anyhow!("Unable to decrypt, the key does not match"))?;
+    Ok(String::from_utf8(plain)?)
+}
+
+/// Encrypts a value when encryption is enabled
+pub fn seal(plain: &str) -> Result<String> {
+    #[cfg(feature = "encryption")]
+    if let Some(cipher) = cipher() {
+        return seal_with(cipher, plain);
+    }
+    Ok(plain.to_string())
+}
+
+/// Decrypts a sealed value, plain values are returned as is
+pub fn open(value: &str) -> Result<String> {
+    if !value.starts_with(PREFIX) {
+        return Ok(value.to_string());
+    }
+    #[cfg(feature = "encryption")]
+    if let Some(cipher) = cipher() {
+        return open_with(cipher, value);
+    }
+    anyhow::bail!("The knowledge base is encrypted, set `encryption` in the config")
+}
+
+/// Serde helpers for text fields which are encrypted at rest, `#[serde(with = "crate::crypto::sealed")]`
+pub mod sealed {
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::seal(value).map_err(ser::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        super::open(&value).map_err(de::Error::custom)
+    }
+}
+
+/// Like `sealed`, for lists of texts each encrypted on its own
+pub mod sealed_list {
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+        let sealed = values
+            .iter()
+            .map(String::as_str)
+            .map(super::seal)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(ser::Error::custom)?;
+        sealed.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+        let values = Vec::<String>::deserialize(deserializer)?;
+        values.iter().map(|value| super::open(value).map_err(de::Error::custom)).collect()
+    }
+}
@@ -0,0 +1,290 @@
+use crate::config::{data_dir, CONFIG_MANAGER};
+use crate::platform::bind_private_socket;
+use crate::redaction::loggable;
+use crate::{shutdown, systemd};
+use anyhow::{Context, Result};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::future::Future;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::debug;
+
+// The output of a command comes in frames of a kind byte, the length of the bytes as a big endian
+// u32 and the bytes. The last frame holds the exit code.
+const STDOUT: u8 = 1;
+const STDERR: u8 = 2;
+const EXIT: u8 = 0;
+
+// Time a client gets to send its request, a stuck one would hold up the daemon
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+static SERVING: AtomicBool = AtomicBool::new(false);
+
+/// A command for the daemon, as given on the command line
+#[derive(Serialize, Deserialize, Debug)]
+struct Request {
+    /// Arguments after `tera`
+    args: Vec<String>,
+    /// Where the command was run, so relative paths work
+    cwd: PathBuf,
+}
+
+/// Where the daemon listens
+pub fn socket_path() -> PathBuf {
+    data_dir().join("tera.sock")
+}
+
+// The request line, read a byte at a time so the input of the command following it stays in the socket
+fn read_request(stream: &mut UnixStream) -> Result<Request> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut line = vec![];
+    let mut byte = [0];
+    loop {
+        stream.read_exact(&mut byte).context("No request from the client")?;
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+    }
+    // the command reads its input at its own pace
+    stream.set_read_timeout(None)?;
+    Ok(serde_json::from_slice(&line)?)
+}
+
+fn write_frame(stream: &mut impl Write, kind: u8, bytes: &[u8]) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(bytes.len() + 5);
+    frame.push(kind);
+    frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    frame.extend_from_slice(bytes);
+    stream.write_all(&frame)
+}
+
+// The kind and the bytes of the next frame, `None` once the daemon closed the connection
+fn read_frame(stream: &mut impl Read) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0; 5];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let mut bytes = vec![0; u32::from_be_bytes(header[1..].try_into()?) as usize];
+    stream.read_exact(&mut bytes)?;
+    Ok(Some((header[0], bytes)))
+}
+
+fn check(result: i32) -> Result<i32> {
+    if result < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(result)
+}
+
+// Sends what is written to a pipe to the client in frames of the given kind, until the pipe is closed
+fn forward_pipe(read: RawFd, kind: u8, stream: Arc<Mutex<UnixStream>>) -> JoinHandle<()> {
+    let mut pipe = unsafe { File::from_raw_fd(read) };
+    std::thread::spawn(move || {
+        let mut buffer = [0; 8192];
+        loop {
+            let read = match pipe.read(&mut buffer) {
+                Ok(0) => return,
+                Ok(read) => read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            // the pipe is drained even once the client is gone, so the command doesn't block writing
+            let mut stream = stream.lock().unwrap_or_else(|e| e.into_inner());
+            let _ = write_frame(&mut *stream, kind, &buffer[..read]);
+        }
+    })
+}
+
+// Reads stdin from the client and sends stdout and stderr to it through pipes while a command runs
+struct Redirect {
+    saved: [RawFd; 3],
+    forwarders: Vec<JoinHandle<()>>,
+}
+
+impl Redirect {
+    fn to(stream: &UnixStream) -> Result<Redirect> {
+        std::io::stdout().flush()?;
+        std::io::stderr().flush()?;
+        // dropped when a step fails, which puts back what got redirected already
+        let mut redirect = Redirect {
+            saved: [-1; 3],
+            forwarders: vec![],
+        };
+        for fd in 0..3 {
+            redirect.saved[fd as usize] = check(unsafe { libc::dup(fd) })?;
+        }
+        check(unsafe { libc::dup2(stream.as_raw_fd(), 0) })?;
+
+        let shared = Arc::new(Mutex::new(stream.try_clone()?));
+        for (fd, kind) in [(1, STDOUT), (2, STDERR)] {
+            let mut pipe = [0; 2];
+            check(unsafe { libc::pipe(pipe.as_mut_ptr()) })?;
+            let attached = check(unsafe { libc::fcntl(pipe[0], libc::F_SETFD, libc::FD_CLOEXEC) })
+                .and_then(|_| check(unsafe { libc::dup2(pipe[1], fd) }));
+            unsafe { libc::close(pipe[1]) };
+            if let Err(e) = attached {
+                unsafe { libc::close(pipe[0]) };
+                return Err(e);
+            }
+            redirect.forwarders.push(forward_pipe(pipe[0], kind, shared.clone()));
+        }
+        Ok(redirect)
+    }
+}
+
+impl Drop for Redirect {
+    fn drop(&mut self) {
+        let _ = std::io::stdout().flush();
+        let _ = std::io::stderr().flush();
+        for (fd, saved) in self.saved.iter().enumerate().filter(|(_, saved)| **saved >= 0) {
+            unsafe {
+                libc::dup2(*saved, fd as RawFd);
+                libc::close(*saved);
+            }
+        }
+        // the pipes got closed above, all the output is sent once the forwarders end
+        for forwarder in self.forwarders.drain(..) {
+            let _ = forwarder.join();
+        }
+    }
+}
+
+/// Runs the commands of clients with `execute` until stopped, the models and the database staying loaded
+/// between them. Commands run one at a time, with the input and output of their client.
+/// On SIGTERM or Ctrl+C the command under way gets the shutdown deadline to finish before the daemon stops.
+pub async fn serve<F, Fut>(execute: F) -> Result<()>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let path = socket_path();
+    // a socket activated by systemd stays with it, to start the daemon again on the next connection
+    let (listener, created) = match systemd::activated_socket() {
+        Some(listener) => (listener, false),
+        None => (bind_private_socket(&path)?, true),
+    };
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    shutdown::listen();
+    CONFIG_MANAGER.watch();
+    let home = std::env::current_dir()?;
+    SERVING.store(true, Ordering::SeqCst);
+    println!("Tera daemon listening on {}, press Ctrl+C to stop.", path.display());
+    systemd::notify("READY=1")?;
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => accepted?.0,
+            _ = shutdown::wait() => break,
+        };
+        // blocking, as stdin, stdout and stderr of the command
+        let mut stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        let request = match tokio::task::block_in_place(|| read_request(&mut stream)) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!(error = ?e, "Invalid daemon request");
+                continue;
+            }
+        };
+        debug!(args = %loggable(&request.args.join(" ")), "Daemon command");
+
+        let code = {
+            // e.g. out of file descriptors, the daemon keeps serving the next clients
+            let _redirect = match Redirect::to(&stream) {
+                Ok(redirect) => redirect,
+                Err(e) => {
+                    eprintln!("Unable to run a command of a client: {:#}", e);
+                    let _ = write_frame(&mut stream, EXIT, &[1]);
+                    continue;
+                }
+            };
+            let result = match std::env::set_current_dir(&request.cwd) {
+                // printing panics once the client is gone, e.g. after Ctrl+C, which mustn't stop the daemon
+                Ok(()) => AssertUnwindSafe(execute(request.args)).catch_unwind().await,
+                Err(e) => Ok(Err(e.into())),
+            };
+            match result {
+                Ok(Ok(())) => 0,
+                Ok(Err(e)) => {
+                    eprintln!("Error: {:?}", e);
+                    1
+                }
+                Err(_) => 101,
+            }
+        };
+        std::env::set_current_dir(&home)?;
+        let _ = write_frame(&mut stream, EXIT, &[code]);
+    }
+
+    systemd::notify("STOPPING=1")?;
+    if created {
+        let _ = std::fs::remove_file(&path);
+    }
+    shutdown::finish();
+    println!("Tera daemon stopped");
+    Ok(())
+}
+
+/// Whether this process is the daemon, which outlives the commands it runs
+pub fn is_serving() -> bool {
+    SERVING.load(Ordering::SeqCst)
+}
+
+/// Whether a daemon is listening, and so holds the database open
+pub fn is_listening() -> bool {
+    UnixStream::connect(socket_path()).is_ok()
+}
+
+/// Runs a command in the daemon when one is listening, with the input and output of this process.
+/// Returns its exit code, `None` without a daemon.
+pub fn forward(args: Vec<String>) -> Result<Option<i32>> {
+    let mut stream = match UnixStream::connect(socket_path()) {
+        Ok(stream) => stream,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => return Ok(None),
+        Err(e) => return Err(e).context("Unable to reach the daemon"),
+    };
+    let request = Request {
+        args,
+        cwd: std::env::current_dir()?,
+    };
+    stream.write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())?;
+
+    let mut input = stream.try_clone()?;
+    std::thread::spawn(move || {
+        let _ = std::io::copy(&mut std::io::stdin().lock(), &mut input);
+        let _ = input.shutdown(std::net::Shutdown::Write);
+    });
+
+    loop {
+        let Some((kind, bytes)) = read_frame(&mut stream)? else {
+            anyhow::bail!("The daemon stopped before the command finished");
+        };
+        match kind {
+            STDOUT => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&bytes)?;
+                stdout.flush()?;
+            }
+            STDERR => {
+                let mut stderr = std::io::stderr().lock();
+                stderr.write_all(&bytes)?;
+                stderr.flush()?;
+            }
+            EXIT => return Ok(Some(*bytes.first().context("Missing exit code")? as i32)),
+            kind => anyhow::bail!("Unknown frame {} from the daemon", kind),
+        }
+    }
+}
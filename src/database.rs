@@ -1,30 +1,130 @@
-use crate::embeddings::get_embeddings;
+use crate::{access, audit, storage};
+use crate::config::{
+    live, profile, wildcard_match, DocumentScore, EmbeddingBackend, LongChunks, Quantization, Retrieval,
+    WebhookEvent, CONFIG,
+};
+use crate::contacts::link_chunk;
+use crate::exclusion::excluded;
+use crate::embeddings::{cosine_similarity, count_tokens, embed_with, reduce, MAX_CHUNK_TOKENS};
+use crate::feedback::feedback_boosts;
+use crate::injection;
+use crate::quantization::StoredVector;
+use crate::provenance;
+use crate::redaction::redact;
+use crate::saved_search::notify_subscribers;
+use crate::stats;
+use crate::sync::{record, Op};
+use crate::vector_file::{self, index_chunk};
+use crate::webhooks;
 use anyhow::{Context, Error, Result};
 use async_once::AsyncOnce;
 use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use surrealdb::engine::local::{Db, RocksDb};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{OnceLock, RwLock};
+use surrealdb::engine::local::{Db, Mem, RocksDb};
 use surrealdb::sql::{thing, Datetime, Thing, Uuid};
 use surrealdb::Surreal;
-use tracing::debug;
+use tera_core::chunking::{split_chunks_by, split_chunks_in, Script, MAX_CHUNK_LEN};
+use tera_core::index::{Entry, ExportedIndex};
+use tracing::{debug, instrument};
 
 lazy_static! {
-    pub static ref DB: AsyncOnce<Surreal<Db>> = AsyncOnce::new(async {
-        let db = connect_db().await.expect("Unable to connect to database");
-        db
-    });
+    pub static ref DB: Database = Database {
+        opened: AsyncOnce::new(async {
+            let db = connect_db().await.expect("Unable to connect to database");
+            db
+        }),
+        reloaded: RwLock::new(None),
+    };
+}
+
+/// The database, of which a replica swaps in a whole new copy when a newer snapshot arrives
+pub struct Database {
+    opened: AsyncOnce<Surreal<Db>>,
+    reloaded: RwLock<Option<Surreal<Db>>>,
+}
+
+impl Database {
+    pub async fn get(&'static self) -> Surreal<Db> {
+        let opened = self.opened.get().await;
+        let reloaded = self.reloaded.read().unwrap_or_else(|e| e.into_inner());
+        reloaded.as_ref().unwrap_or(opened).clone()
+    }
+
+    // Queries get the new copy from now on, those under way finish on the previous one
+    pub(crate) fn swap(&self, db: Surreal<Db>) {
+        *self.reloaded.write().unwrap_or_else(|e| e.into_inner()) = Some(db);
+        crate::contacts::people_changed();
+    }
+}
+
+// Snapshot served read-only, the database lives in memory instead of the data directory
+static REPLICA: OnceLock<PathBuf> = OnceLock::new();
+
+/// Serves a snapshot read-only instead of the knowledge base, has to be called before the database is first used
+pub fn open_replica(path: &Path) -> Result<(), Error> {
+    let snapshot = crate::snapshot::replica_snapshot(path)?;
+    if REPLICA.set(snapshot).is_err() {
+        anyhow::bail!("A replica is already open");
+    }
+    Ok(())
+}
+
+pub fn is_replica() -> bool {
+    REPLICA.get().is_some()
+}
+
+tokio::task_local! {
+    // The contents stored by the upload under way
+    static STORED: RefCell<Vec<Thing>>;
+    // Set by `--dry-run` for its upload, documents are parsed and chunked but nothing is embedded or stored
+    static DRY_RUN: bool;
+}
+
+/// Runs an upload, returning the ids of the contents it stored along with its outcome
+pub async fn storing<T>(upload: impl Future<Output = T>) -> (T, Vec<Thing>) {
+    STORED
+        .scope(RefCell::new(vec![]), async {
+            let outcome = upload.await;
+            (outcome, STORED.with(|stored| stored.take()))
+        })
+        .await
+}
+
+/// Runs an upload which, with `dry_run`, prints the chunks it would store instead of embedding and
+/// storing them, without opening the knowledge base
+pub async fn with_dry_run<T>(dry_run: bool, upload: impl Future<Output = T>) -> T {
+    DRY_RUN.scope(dry_run, upload).await
+}
+
+pub fn is_dry_run() -> bool {
+    DRY_RUN.try_with(|dry_run| *dry_run).unwrap_or(false)
+}
+
+pub(crate) fn ensure_writable() -> Result<(), Error> {
+    if is_replica() {
+        anyhow::bail!("This is a read-only replica, memorize content on the main machine");
+    }
+    Ok(())
 }
 
 async fn connect_db() -> Result<Surreal<Db>, Box<dyn std::error::Error>> {
-    // get directory of current binary
-    let path = dirs::config_local_dir()
-        .expect("Unable to get local config directory")
-        .join("tera").join("database");
+    crate::crypto::unlock()?;
+    let path = crate::config::data_dir().join("database");
 
     debug!(path = ?path, "Connecting to database");
 
-    let db = Surreal::new::<RocksDb>(path).await?;
+    let db = match REPLICA.get() {
+        Some(_) => Surreal::new::<Mem>(()).await?,
+        None => Surreal::new::<RocksDb>(path).await?,
+    };
 
     db.use_ns("rag").use_db("content").await?;
 
@@ -49,8 +149,8 @@ async fn connect_db() -> Result<Surreal<Db>, Box<dyn std::error::Error>> {
             DEFINE FIELD content_id ON TABLE vector_index TYPE record<content>;
             DEFINE FIELD content_chunk ON TABLE vector_index TYPE string;
             DEFINE FIELD chunk_number ON TABLE vector_index TYPE int;
-            DEFINE FIELD vector ON TABLE vector_index TYPE array<float>;
-            DEFINE FIELD vector.* ON TABLE vector_index TYPE float;
+            DEFINE FIELD vector ON TABLE vector_index TYPE array<float> | string;
+            DEFINE FIELD next_vector ON TABLE vector_index TYPE option<array<float>>;
             DEFINE FIELD metadata ON TABLE vector_index FLEXIBLE TYPE object;
             DEFINE FIELD created_at ON TABLE vector_index TYPE datetime DEFAULT time::now();
             DEFINE INDEX vectorIdIndex ON TABLE vector_index COLUMNS id UNIQUE;
@@ -58,16 +158,146 @@ async fn connect_db() -> Result<Surreal<Db>, Box<dyn std::error::Error>> {
     )
     .await?;
 
+    db.query(
+        "
+            DEFINE TABLE answer SCHEMAFULL;
+
+            DEFINE FIELD id ON TABLE answer TYPE record;
+            DEFINE FIELD query ON TABLE answer TYPE string;
+            DEFINE FIELD text ON TABLE answer TYPE string;
+            DEFINE FIELD sources ON TABLE answer TYPE array<record<vector_index>>;
+            DEFINE FIELD query_vector ON TABLE answer TYPE option<array<float> | string>;
+            DEFINE FIELD confidence ON TABLE answer TYPE option<float>;
+            DEFINE FIELD profile ON TABLE answer TYPE option<string>;
+            DEFINE FIELD settings ON TABLE answer TYPE option<string>;
+            DEFINE FIELD created_at ON TABLE answer TYPE datetime DEFAULT time::now();
+        ",
+    )
+    .await?;
+
+    db.query(
+        "
+            DEFINE TABLE feedback SCHEMAFULL;
+
+            DEFINE FIELD answer_id ON TABLE feedback TYPE record<answer>;
+            DEFINE FIELD chunk_id ON TABLE feedback TYPE record<vector_index>;
+            DEFINE FIELD useful ON TABLE feedback TYPE bool;
+            DEFINE FIELD created_at ON TABLE feedback TYPE datetime DEFAULT time::now();
+            DEFINE INDEX feedbackIndex ON TABLE feedback COLUMNS answer_id, chunk_id UNIQUE;
+        ",
+    )
+    .await?;
+
+    db.query(
+        "
+            DEFINE TABLE saved_search SCHEMAFULL;
+
+            DEFINE FIELD name ON TABLE saved_search TYPE string;
+            DEFINE FIELD query ON TABLE saved_search TYPE string;
+            DEFINE FIELD subscribed ON TABLE saved_search TYPE bool;
+            DEFINE FIELD unseen ON TABLE saved_search TYPE array<record<vector_index>>;
+            DEFINE FIELD created_at ON TABLE saved_search TYPE datetime DEFAULT time::now();
+        ",
+    )
+    .await?;
+
+    db.query(
+        "
+            DEFINE TABLE person SCHEMALESS;
+
+            DEFINE TABLE mention SCHEMAFULL;
+
+            DEFINE FIELD person ON TABLE mention TYPE record<person>;
+            DEFINE FIELD chunk ON TABLE mention TYPE record<vector_index>;
+            DEFINE FIELD created_at ON TABLE mention TYPE datetime DEFAULT time::now();
+            DEFINE INDEX mentionPersonIndex ON TABLE mention COLUMNS person;
+        ",
+    )
+    .await?;
+
+    db.query("DEFINE TABLE index_meta SCHEMALESS;").await?;
+
+    db.query("DEFINE TABLE trash SCHEMALESS;").await?;
+
+    db.query("DEFINE TABLE ingest_journal SCHEMALESS;").await?;
+
+    db.query("DEFINE TABLE connector_run SCHEMALESS;").await?;
+
+    db.query("DEFINE TABLE uploaded_file SCHEMALESS;").await?;
+
+    db.query("DEFINE TABLE web_page SCHEMALESS;").await?;
+
+    db.query("DEFINE TABLE full_vector SCHEMALESS;").await?;
+
+    db.query("DEFINE TABLE search_cursor SCHEMALESS;").await?;
+
+    db.query("DEFINE TABLE excluded_content SCHEMALESS; DEFINE TABLE excluded_source SCHEMALESS;").await?;
+
+    db.query(
+        "
+            DEFINE TABLE oplog SCHEMALESS;
+            DEFINE INDEX oplogPushedIndex ON TABLE oplog COLUMNS device, pushed;
+            DEFINE TABLE sync_clock SCHEMALESS;
+            DEFINE TABLE sync_state SCHEMALESS;
+        ",
+    )
+    .await?;
+
+    db.query(
+        "
+            DEFINE TABLE job SCHEMAFULL;
+
+            DEFINE FIELD name ON TABLE job TYPE string;
+            DEFINE FIELD schedule ON TABLE job TYPE string;
+            DEFINE FIELD command ON TABLE job TYPE array<string>;
+            DEFINE FIELD jitter ON TABLE job TYPE int;
+            DEFINE FIELD next_run ON TABLE job TYPE datetime;
+            DEFINE FIELD last_run ON TABLE job TYPE option<datetime>;
+            DEFINE FIELD last_status ON TABLE job TYPE option<string>;
+            DEFINE FIELD created_at ON TABLE job TYPE datetime DEFAULT time::now();
+        ",
+    )
+    .await?;
+
+    if let Some(snapshot) = REPLICA.get() {
+        debug!(path = ?snapshot, "Loading replica");
+        crate::snapshot::replace_database(&db, snapshot).await?;
+    } else {
+        let rolled_back = recover_interrupted(&db).await?;
+        if !rolled_back.is_empty() {
+            eprintln!(
+                "Rolled back {} documents which were being memorized when Tera stopped, upload them again: {}",
+                rolled_back.len(),
+                rolled_back.join(", ")
+            );
+        }
+    }
+
+    if let Some(upgrade) = crate::migrations::migrate(&db).await? {
+        eprintln!(
+            "Upgraded the knowledge base from schema {} to {}: {}",
+            upgrade.from,
+            crate::migrations::SCHEMA_VERSION,
+            upgrade.steps.join(", ")
+        );
+        if let Some(snapshot) = upgrade.snapshot {
+            eprintln!("The knowledge base from before is kept as snapshot {}", snapshot);
+        }
+    }
+
     Ok(db)
 }
 
 
 pub async fn forget_all_content() -> Result<(), Error> {
-    let path = dirs::config_local_dir()
-        .expect("Unable to get local config directory")
-        .join("tera");
+    // only the database, the config file lives next to it
+    let path = crate::config::data_dir().join("database");
     debug!(path = ?path, "Droping database");
     std::fs::remove_dir_all(path)?;
+    let tables = crate::config::tables_path();
+    if tables.exists() {
+        std::fs::remove_file(tables)?;
+    }
 
     Ok(())
 }
@@ -76,12 +306,13 @@ pub async fn forget_all_content() -> Result<(), Error> {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Content {
     pub id: Thing,
+    #[serde(with = "crate::crypto::sealed")]
     pub title: String,
+    #[serde(with = "crate::storage::blob")]
     pub text: String,
     pub created_at: Datetime,
 }
 impl Content {
-    #[allow(dead_code)]
     pub async fn get_vector_indexes(&self) -> Result<Vec<VectorIndex>, Error> {
         let db = DB.get().await.clone();
         let mut result = db
@@ -94,18 +325,63 @@ impl Content {
     }
 }
 
+// Candidates from the reduced vectors which get re-ranked with the whole ones
+const RESCORE_CANDIDATES: usize = 64;
+// Results a paged search keeps
+const MAX_SEARCH_RESULTS: usize = 200;
+// How long the pages of a search can be read
+const CURSOR_TTL_HOURS: i64 = 1;
+// Chunks scored when collections, access or exclusions filter the results, which can rule most of them out
+const CLAUSE_CANDIDATES: usize = 1000;
+// Chunks whose text is checked at once against keyword and metadata clauses
+const PREFILTER_PAGE: usize = 1000;
+// Added to the score of a result for each should keyword it contains
+const SHOULD_BOOST: f32 = 0.05;
+
+/// Whole vector of a chunk whose stored vector is reduced, keyed like the chunk
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct FullVector {
+    content_id: Thing,
+    vector: Vec<f32>,
+    /// Staged by `migrate_embeddings`
+    next_vector: Option<Vec<f32>>,
+}
+
+fn full_vector_id(chunk: &Thing) -> Thing {
+    Thing {
+        tb: "full_vector".to_string(),
+        id: chunk.id.clone(),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkVector {
+    id: Thing,
+    vector: StoredVector,
+}
+
+#[derive(Deserialize, Debug)]
+struct KeyedVector {
+    key: String,
+    vector: Vec<f32>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VectorIndex {
     pub id: Thing,
     pub content_id: Thing,
+    #[serde(with = "crate::storage::blob")]
     pub content_chunk: String,
     pub chunk_number: u16,
     pub metadata: serde_json::Value,
+    #[serde(with = "crate::quantization::vector")]
     pub vector: Vec<f32>,
     pub created_at: Datetime,
+    /// Similarity to the query, only set on search results
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
 }
 impl VectorIndex {
-    #[allow(dead_code)]
     pub async fn get_content(&self) -> Result<Content, Error> {
         let db = DB.get().await.clone();
 
@@ -139,11 +415,36 @@ impl VectorIndex {
     }
 }
 
-pub async fn insert_content(title: &str, text: &str) -> Result<Content, Error> {
+/// Stores content, with the personal information configured for the connector masked
+pub async fn insert_content(title: &str, text: &str, connector: &str) -> Result<Content, Error> {
+    store_content(title, &redact(connector, text)?).await
+}
+
+async fn store_content(title: &str, text: &str) -> Result<Content, Error> {
+    if is_dry_run() {
+        println!("Would memorize {} ({} characters)", title, text.chars().count());
+        return Ok(Content {
+            id: thing("content:dry_run")?,
+            title: title.to_string(),
+            text: text.to_string(),
+            created_at: Datetime::default(),
+        });
+    }
+    ensure_writable()?;
     let db = DB.get().await.clone();
     let id = Uuid::new_v4().0.to_string().replace("-", "");
     let id = thing(format!("content:{}", id).as_str())?;
 
+    // journaled first, so a crash before `commit_content` rolls the content back on the next start
+    let _: Option<JournalEntry> = db
+        .create(("ingest_journal", id.id.to_raw()))
+        .content(JournalEntry {
+            content_id: id.clone(),
+            title: title.to_string(),
+            started_at: Datetime::default(),
+        })
+        .await?;
+
     let content: Content = db
         .create(("content", id.clone()))
         .content(Content {
@@ -154,31 +455,169 @@ pub async fn insert_content(title: &str, text: &str) -> Result<Content, Error> {
         })
         .await?
         .context("Unable to insert content")?;
+    record(Op::InsertContent { content: content.clone() }).await?;
+    let _ = STORED.try_with(|stored| stored.borrow_mut().push(id));
     Ok(content)
 }
 
+/// Content being memorized, kept until all of its chunks are stored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct JournalEntry {
+    content_id: Thing,
+    #[serde(with = "crate::crypto::sealed")]
+    title: String,
+    started_at: Datetime,
+}
+
+/// Marks content as fully memorized once all of its chunks are stored, and notifies saved searches
+pub async fn commit_content(id: &Thing) -> Result<(), Error> {
+    if is_dry_run() {
+        return Ok(());
+    }
+    let db = DB.get().await.clone();
+    let _: Option<JournalEntry> = db.delete(("ingest_journal", id.id.to_raw())).await?;
+    // the content is memorized already, failing to find its reminders doesn't undo that
+    if CONFIG.extract_reminders {
+        if let Err(e) = crate::reminders::extract_from(id).await {
+            debug!(error = ?e, content = ?id, "Unable to extract reminders");
+        }
+    }
+    let audited = CONFIG.audit.is_some();
+    if audited || webhooks::subscribed(WebhookEvent::ContentIngested) {
+        let content: Option<Content> = db.select(id.clone()).await?;
+        let title = content.map(|c| c.title).unwrap_or_default();
+        if audited {
+            audit::record_ingestion(id, &title);
+        }
+        webhooks::emit(WebhookEvent::ContentIngested, json!({"content_id": id.id.to_raw(), "title": title})).await;
+    }
+    stats::record_ingestion(id).await;
+    // nor failing to notify saved searches
+    if let Err(e) = notify_subscribers(id.clone()).await {
+        eprintln!("Unable to notify saved searches of {}: {:?}", id, e);
+    }
+    Ok(())
+}
+
+// Rolls back content whose ingestion was interrupted, returning their titles
+async fn recover_interrupted(db: &Surreal<Db>) -> Result<Vec<String>, Error> {
+    let mut result = db.query("SELECT * FROM ingest_journal").await?;
+    let interrupted: Vec<JournalEntry> = result.take(0)?;
+    let mut titles = vec![];
+    for entry in interrupted {
+        debug!(content = ?entry.content_id, "Rolling back interrupted ingestion");
+        remove_content_in(db, &entry.content_id).await?;
+        crate::sync::record_in(db, Op::Delete { content_id: entry.content_id.clone() }).await?;
+        let _: Option<JournalEntry> = db.delete(("ingest_journal", entry.content_id.id.to_raw())).await?;
+        titles.push(entry.title);
+    }
+    Ok(titles)
+}
+
+/// Embeds and stores a chunk, with the personal information configured for the connector in its metadata masked
 pub async fn insert_vector_index(
     content_id: Thing,
     chunk_number: u16,
     content_chunk: &str,
-    metadata: serde_json::Value,
+    mut metadata: serde_json::Value,
+) -> Result<VectorIndex, Error> {
+    let connector = metadata["connector"].as_str().unwrap_or_default();
+    let redacted = redact(connector, content_chunk)?;
+    if redacted != content_chunk {
+        provenance::add_step(&mut metadata, "redaction");
+    }
+    store_vector_index(content_id, chunk_number, &redacted, metadata).await
+}
+
+// The text of a chunk as stored, in whatever script it is written. The local model only embeds its
+// ascii characters, multilingual models behind Ollama read all of it.
+fn stored_text(content_chunk: &str) -> Result<&str, Error> {
+    let content_chunk = content_chunk.trim();
+    if content_chunk.is_empty() {
+        return Err(anyhow::anyhow!("Content chunk is empty"));
+    }
+    Ok(content_chunk)
+}
+
+async fn store_vector_index(
+    content_id: Thing,
+    chunk_number: u16,
+    content_chunk: &str,
+    mut metadata: serde_json::Value,
 ) -> Result<VectorIndex, Error> {
-    let db = DB.get().await.clone();
     let id = Uuid::new_v4().0.to_string().replace("-", "");
     let id = thing(format!("vector_index:{}", id).as_str())?;
+    provenance::complete(&mut metadata);
 
-    let content_chunk = content_chunk
-        .chars()
-        .filter(|c| c.is_ascii())
-        .collect::<String>();
+    let content_chunk = stored_text(content_chunk)?;
 
-    let content_chunk = content_chunk.trim();
+    // chunks the local model can't read whole are stored as parts with the same number
+    if CONFIG.long_chunks == LongChunks::Split
+        && index_embedder().await? == EmbeddingBackend::Local
+        && count_tokens(content_chunk)? > MAX_CHUNK_TOKENS
+    {
+        let parts = split_chunks_by(content_chunk, MAX_CHUNK_TOKENS, Script::detect(content_chunk), &|part: &str| {
+            count_tokens(part).unwrap_or(part.len())
+        });
+        if parts.len() > 1 {
+            let mut stored = vec![];
+            for (i, part) in parts.into_iter().enumerate() {
+                let mut metadata = metadata.clone();
+                if let Some(fields) = metadata.as_object_mut() {
+                    fields.insert("part".to_string(), i.into());
+                }
+                provenance::add_step(&mut metadata, "split");
+                stored.push(Box::pin(store_vector_index(content_id.clone(), chunk_number, part, metadata)).await?);
+            }
+            return stored.into_iter().next().context("No part stored");
+        }
+    }
 
-    if content_chunk.is_empty() {
-        return Err(anyhow::anyhow!("Content chunk is empty"));
+    // flagged when memorized, the prompt deals with it as configured by the time it gets retrieved
+    if injection::detect(content_chunk) {
+        debug!(chunk = chunk_number, "Chunk holds instructions for the model");
+        if let Some(fields) = metadata.as_object_mut() {
+            fields.insert("injection".to_string(), Value::Bool(true));
+        }
+    }
+
+    if is_dry_run() {
+        println!(
+            "--- chunk {}, {} characters, {} tokens\nmetadata: {}\n{}",
+            chunk_number,
+            content_chunk.chars().count(),
+            count_tokens(content_chunk)?,
+            metadata,
+            content_chunk
+        );
+        return Ok(VectorIndex {
+            id,
+            content_id,
+            content_chunk: content_chunk.to_string(),
+            chunk_number,
+            metadata,
+            vector: vec![],
+            created_at: Datetime::default(),
+            score: None,
+        });
     }
+    ensure_writable()?;
+    let db = DB.get().await.clone();
 
-    let vector = get_embeddings(&content_chunk)?.reshape((384,))?.to_vec1()?;
+    let backend = index_embedder().await?;
+    let full_vector = embed_with(&backend, content_chunk).await?;
+    let vector = reduce(&backend, &full_vector);
+    // the whole vector only gets read back for the few best matches of a search
+    if CONFIG.rescore && vector.len() < full_vector.len() {
+        let _: Option<FullVector> = db
+            .create(full_vector_id(&id))
+            .content(FullVector {
+                content_id: content_id.clone(),
+                vector: full_vector,
+                next_vector: None,
+            })
+            .await?;
+    }
 
     let vector_index: VectorIndex = db
         .create(("vector_index", id.clone()))
@@ -190,56 +629,760 @@ pub async fn insert_vector_index(
             content_chunk: content_chunk.to_string(),
             vector,
             created_at: Datetime::default(),
+            score: None,
         })
         .await?
         .context("Unable to insert vector index")?;
+    index_chunk(&vector_index)?;
+
+    if vector_index.metadata["type"] != "contact" {
+        link_chunk(&vector_index).await?;
+    }
+    record(Op::InsertChunk { chunk: vector_index.clone() }).await?;
 
     Ok(vector_index)
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredAnswer {
+    pub id: Thing,
+    #[serde(with = "crate::crypto::sealed")]
+    pub query: String,
+    #[serde(with = "crate::crypto::sealed")]
+    pub text: String,
+    pub sources: Vec<Thing>,
+    /// Embedding of the query, empty for answers stored before repeated questions were detected
+    #[serde(default, with = "crate::quantization::vector")]
+    pub query_vector: Vec<f32>,
+    #[serde(default)]
+    pub confidence: Option<f32>,
+    /// Profile the question was answered with
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Model, adapter and length of the answer, empty for answers stored before they were recorded
+    #[serde(default)]
+    pub settings: Option<String>,
+    pub created_at: Datetime,
+}
+
+// What is compared to find a similar answer, without the sealed question and answer
+#[derive(Deserialize, Debug, Clone)]
+struct AnswerKey {
+    id: Thing,
+    #[serde(default, with = "crate::quantization::vector")]
+    query_vector: Vec<f32>,
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(default)]
+    settings: Option<String>,
+    created_at: Datetime,
+}
+
+pub async fn insert_answer(
+    query: &str,
+    query_vector: Vec<f32>,
+    settings: &str,
+    text: &str,
+    confidence: f32,
+    sources: Vec<Thing>,
+) -> Result<StoredAnswer, Error> {
+    let db = DB.get().await.clone();
+    let id = Uuid::new_v4().0.to_string().replace("-", "");
+    let id = thing(format!("answer:{}", id).as_str())?;
+
+    let answer: StoredAnswer = db
+        .create(("answer", id.clone()))
+        .content(StoredAnswer {
+            id: id.clone(),
+            query: query.to_string(),
+            text: text.to_string(),
+            sources,
+            query_vector,
+            confidence: Some(confidence),
+            profile: profile(),
+            settings: Some(settings.to_string()),
+            created_at: Datetime::default(),
+        })
+        .await?
+        .context("Unable to insert answer")?;
+    Ok(answer)
+}
+
+/// The stored answer to the question most similar to the query since `since` with the same profile and
+/// `settings`, if it is at least `min_similarity` similar and its sources can all still be read. Compared
+/// here as query vectors may be quantized, only the best answer gets decrypted.
+pub async fn similar_answer(
+    query_vector: &[f32],
+    settings: &str,
+    since: Datetime,
+    min_similarity: f32,
+) -> Result<Option<(StoredAnswer, Vec<VectorIndex>)>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT id, query_vector, profile, settings, created_at FROM answer WHERE created_at > $since")
+        .bind(("since", since))
+        .await?;
+    let answers: Vec<AnswerKey> = result.take(0)?;
+    let current = profile();
+    let best = answers
+        .into_iter()
+        .filter(|a| !a.query_vector.is_empty() && a.profile == current && a.settings.as_deref() == Some(settings))
+        .map(|a| (cosine_similarity(query_vector, &a.query_vector), a))
+        .filter(|(similarity, _)| *similarity >= min_similarity)
+        // the most recent of equally similar ones
+        .max_by(|a, b| a.0.total_cmp(&b.0).then(a.1.created_at.cmp(&b.1.created_at)));
+    let Some((_, best)) = best else {
+        return Ok(None);
+    };
+    let Some(answer) = db.select::<Option<StoredAnswer>>(best.id).await? else {
+        return Ok(None);
+    };
+
+    // an answer from content which was forgotten, excluded or can't be read here is given again
+    let excluded = excluded().await?;
+    let mut sources = vec![];
+    for id in &answer.sources {
+        match db.select::<Option<VectorIndex>>(id.clone()).await {
+            Ok(Some(chunk)) if access::readable(&chunk) && !excluded.excludes(&chunk) => sources.push(chunk),
+            Ok(_) => return Ok(None),
+            Err(e) => {
+                debug!(error = ?e, source = %id, "Unable to read a source of a past answer");
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some((answer, sources)))
+}
+
 pub async fn smart_insert_content(
     title: &str,
     text: &str,
-    metadata: Value,
+    mut metadata: Value,
 ) -> Result<Content, Error> {
-    let content = insert_content(title, text).await?;
-
-    let mut chunks = text.split("\n").collect::<Vec<&str>>();
-    chunks.retain(|c| !c.is_empty());
-
-    // Iterate over the chunks if the chunk is larger than 1000 characters recursively split it and add it to the chunks
-    for (i, chunk) in chunks.clone().iter().enumerate() {
-        if chunk.len() > 1000 {
-            let mut split_chunks = chunk.split(".").collect::<Vec<&str>>();
-            split_chunks.retain(|c| !c.is_empty());
-            for split_chunk in split_chunks {
-                chunks.insert(i, split_chunk);
-            }
-        }
+    // redacted once up front, the NER pass is too slow to run on the content and again on each chunk
+    let redacted = redact(metadata["connector"].as_str().unwrap_or_default(), text)?;
+    if redacted != text {
+        provenance::add_step(&mut metadata, "redaction");
+    }
+    let text = redacted;
+    let content = store_content(title, &text).await?;
+
+    let chunk_size = CONFIG
+        .connector(metadata["connector"].as_str().unwrap_or_default())
+        .chunk_size;
+    // long lines get split where sentences of the document's language end
+    if let (Some(language), Some(fields)) = (language_of(&text), metadata.as_object_mut()) {
+        fields.entry("language").or_insert_with(|| Value::String(language.to_string()));
     }
+    let script = Script::detect(&text);
+    // the local model's tokenizer is at hand, chunks get filled up to its sequence length
+    let chunks = match (index_embedder().await?, chunk_size) {
+        (EmbeddingBackend::Local, None) => split_chunks_by(&text, MAX_CHUNK_TOKENS, script, &|chunk: &str| {
+            count_tokens(chunk).unwrap_or(chunk.len())
+        }),
+        (_, chunk_size) => split_chunks_in(&text, chunk_size.unwrap_or(MAX_CHUNK_LEN), script),
+    };
+    provenance::add_step(&mut metadata, "chunking");
 
     for (i, chunk) in chunks.clone().into_iter().enumerate() {
-        print!("Memorizing chunk {}/{}\r", i + 1, chunks.len());
-        let res = insert_vector_index(content.id.clone(), i as u16, chunk, metadata.clone()).await;
+        // progress goes to stderr so stdout stays usable in pipelines
+        eprint!("Memorizing chunk {}/{}\r", i + 1, chunks.len());
+        let res = store_vector_index(content.id.clone(), i as u16, chunk, metadata.clone()).await;
         match res {
             Ok(_) => {}
             Err(e) => {
                 if e.to_string().contains("Content chunk is empty") {
                     continue;
                 }
-                println!("Unable to insert vector index: {}", e);
+                eprintln!("Unable to insert vector index: {}", e);
             }
         }
     }
 
+    commit_content(&content.id).await?;
+
     Ok(content)
 }
 
+/// ISO 639-3 code of the language of a text
+#[cfg(feature = "languages")]
+pub(crate) fn language_of(text: &str) -> Option<&'static str> {
+    whatlang::detect_lang(text).map(|language| language.code())
+}
+
+// Texts are split the same in every language without the `languages` feature
+#[cfg(not(feature = "languages"))]
+pub(crate) fn language_of(_: &str) -> Option<&'static str> {
+    None
+}
+
 pub async fn get_releted_chunks(query: Vec<f32>) -> Result<Vec<VectorIndex>, Error> {
+    search_with(query, &Clauses::default(), 4).await
+}
+
+/// Keyword and metadata constraints combined with the similarity to the query, which ranks the results
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Clauses {
+    /// Keywords every result contains
+    #[serde(default)]
+    pub must: Vec<String>,
+    /// Keywords ranking the results containing them higher
+    #[serde(default)]
+    pub should: Vec<String>,
+    /// Keywords no result contains
+    #[serde(default)]
+    pub must_not: Vec<String>,
+    /// Metadata values the results have, `*` matching any run of characters
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Only results memorized before
+    #[serde(default)]
+    pub before: Option<Datetime>,
+    /// Only results memorized on or after
+    #[serde(default)]
+    pub after: Option<Datetime>,
+}
+
+impl Clauses {
+    /// Reads the operators out of a query and returns the text left to search for with the clauses.
+    /// A `"quoted phrase"` must appear in the results, `before:` and `after:` take a `YYYY-MM-DD` date,
+    /// and other `field:value` operators match the metadata, e.g. `source:notes` or `source:"*.md"`. Fields
+    /// start with a letter, so `10:30` stays a word.
+    pub fn parse(query: &str) -> Result<(String, Clauses), Error> {
+        let mut clauses = Clauses::default();
+        let mut words = vec![];
+        let mut rest = query.trim();
+        while !rest.is_empty() {
+            let (token, remaining) = next_token(rest);
+            rest = remaining.trim_start();
+            if let Some(phrase) = token.strip_prefix('"') {
+                let phrase = phrase.trim_end_matches('"').trim();
+                if !phrase.is_empty() {
+                    clauses.must.push(phrase.to_string());
+                    words.push(phrase);
+                }
+                continue;
+            }
+            let field = token.split_once(':').filter(|(field, value)| {
+                // not the scheme of a url, nor a time or a ratio like 10:30
+                field.starts_with(|c: char| c.is_ascii_alphabetic())
+                    && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                    && !value.is_empty()
+                    && !value.starts_with("//")
+            });
+            let Some((field, value)) = field else {
+                words.push(token);
+                continue;
+            };
+            let value = value.trim_matches('"');
+            match field {
+                "before" => clauses.before = Some(parse_day(value)?),
+                "after" => clauses.after = Some(parse_day(value)?),
+                _ => {
+                    clauses.metadata.insert(field.to_string(), value.to_string());
+                }
+            }
+        }
+        Ok((words.join(" "), clauses))
+    }
+
+    /// Adds the clauses given separately from the query, e.g. as flags
+    pub fn extend(&mut self, other: Clauses) {
+        self.must.extend(other.must);
+        self.should.extend(other.should);
+        self.must_not.extend(other.must_not);
+        self.metadata.extend(other.metadata);
+        self.before = other.before.or(self.before.take());
+        self.after = other.after.or(self.after.take());
+    }
+
+    fn filters(&self) -> bool {
+        !self.must.is_empty()
+            || !self.must_not.is_empty()
+            || !self.metadata.is_empty()
+            || self.before.is_some()
+            || self.after.is_some()
+    }
+
+    // Keywords match regardless of case
+    fn matches(&self, text: &str, metadata: &Value, created_at: &Datetime) -> bool {
+        let text = text.to_lowercase();
+        self.must.iter().all(|k| text.contains(&k.to_lowercase()))
+            && !self.must_not.iter().any(|k| text.contains(&k.to_lowercase()))
+            && self.metadata.iter().all(|(field, pattern)| match &metadata[field] {
+                Value::String(value) => wildcard_match(pattern, value),
+                Value::Null => false,
+                value => wildcard_match(pattern, &value.to_string()),
+            })
+            && self.before.as_ref().map(|before| created_at < before).unwrap_or(true)
+            && self.after.as_ref().map(|after| created_at >= after).unwrap_or(true)
+    }
+
+    // The clauses the database checks itself, as a condition and the strings it binds, the dates being
+    // `$before` and `$after`. Keywords are only looked for in text stored as is, sealed or offloaded text
+    // being checked by `matches` once loaded, and metadata patterns by their literal parts.
+    fn condition(&self) -> (String, Vec<(String, String)>) {
+        fn bind(strings: &mut Vec<(String, String)>, value: &str) -> String {
+            let name = format!("clause{}", strings.len());
+            strings.push((name.clone(), value.to_lowercase()));
+            name
+        }
+        let mut conditions = vec![];
+        let mut strings = vec![];
+        // sealed and offloaded values start with `tera:`
+        let stored = "string::starts_with(content_chunk, 'tera:')";
+        for keyword in &self.must {
+            let name = bind(&mut strings, keyword);
+            conditions.push(format!(
+                "({} OR string::contains(string::lowercase(content_chunk), ${}))",
+                stored, name
+            ));
+        }
+        for keyword in &self.must_not {
+            let name = bind(&mut strings, keyword);
+            conditions.push(format!(
+                "({} OR !string::contains(string::lowercase(content_chunk), ${}))",
+                stored, name
+            ));
+        }
+        // fields only go in the query as plain names, others are left to `matches`
+        let fields = self.metadata.iter().filter(|(field, _)| {
+            field.starts_with(|c: char| c.is_ascii_alphabetic())
+                && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        });
+        for (field, pattern) in fields {
+            conditions.push(format!("metadata.{} != NONE", field));
+            for part in pattern.split('*').filter(|p| !p.is_empty()) {
+                let name = bind(&mut strings, part);
+                conditions.push(format!("string::contains(string::lowercase(<string> metadata.{}), ${})", field, name));
+            }
+        }
+        if self.before.is_some() {
+            conditions.push("created_at < $before".to_string());
+        }
+        if self.after.is_some() {
+            conditions.push("created_at >= $after".to_string());
+        }
+        match conditions.is_empty() {
+            true => ("true".to_string(), strings),
+            false => (conditions.join(" AND "), strings),
+        }
+    }
+
+    fn boost(&self, text: &str) -> f32 {
+        let text = text.to_lowercase();
+        let matched = self.should.iter().filter(|k| text.contains(&k.to_lowercase())).count();
+        matched as f32 * SHOULD_BOOST
+    }
+}
+
+// A word, a quoted phrase or a field operator with a quoted value, and what follows it
+fn next_token(text: &str) -> (&str, &str) {
+    let mut quoted = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => return (&text[..i], &text[i..]),
+            _ => {}
+        }
+    }
+    (text, "")
+}
+
+// Midnight UTC of a `YYYY-MM-DD` date
+fn parse_day(value: &str) -> Result<Datetime, Error> {
+    let day = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .with_context(|| format!("Invalid date {}, expected YYYY-MM-DD", value))?;
+    let midnight = day.and_hms_opt(0, 0, 0).context("Invalid date")?;
+    Ok(Datetime::from(chrono::TimeZone::from_utc_datetime(&chrono::Utc, &midnight)))
+}
+
+// What the clauses are checked on, the text as stored, possibly sealed or offloaded
+#[derive(Deserialize, Debug)]
+struct ClauseFields {
+    id: Thing,
+    content_chunk: String,
+    metadata: Value,
+    created_at: Datetime,
+}
+
+// The chunks satisfying the clauses. The database narrows them down a page at a time, the text of the
+// chunks left being loaded to check keywords in sealed or offloaded text.
+#[instrument(name = "prefiltering", skip_all)]
+async fn prefilter(db: &Surreal<Db>, clauses: &Clauses) -> Result<Vec<Thing>, Error> {
+    let (condition, strings) = clauses.condition();
+    let sql = format!(
+        "SELECT id, content_chunk, metadata, created_at FROM vector_index WHERE {} START $start LIMIT $limit",
+        condition
+    );
+    let keywords = !clauses.must.is_empty() || !clauses.must_not.is_empty();
+    let mut matching = vec![];
+    let mut start = 0;
+    loop {
+        let mut query = db
+            .query(&sql)
+            .bind(("start", start))
+            .bind(("limit", PREFILTER_PAGE))
+            .bind(("before", clauses.before.clone()))
+            .bind(("after", clauses.after.clone()));
+        for binding in &strings {
+            query = query.bind(binding.clone());
+        }
+        let chunks: Vec<ClauseFields> = query.await?.take(0)?;
+        let texts = match keywords {
+            true => storage::load_all(chunks.iter().map(|c| c.content_chunk.clone()).collect()).await?,
+            false => vec![String::new(); chunks.len()],
+        };
+        let read = chunks.len();
+        for (chunk, text) in chunks.into_iter().zip(texts) {
+            if clauses.matches(&text, &chunk.metadata, &chunk.created_at) {
+                matching.push(chunk.id);
+            }
+        }
+        if read < PREFILTER_PAGE {
+            debug!(matching = matching.len(), "Prefiltered chunks");
+            return Ok(matching);
+        }
+        start += PREFILTER_PAGE;
+    }
+}
+
+/// The `limit` chunks most relevant to the query which satisfy the clauses. Keyword, metadata and
+/// date clauses pick the chunks scored, collections, access and exclusions are checked on the best
+/// candidates by similarity.
+#[instrument(name = "search", skip_all, fields(limit = limit))]
+pub async fn search_with(query: Vec<f32>, clauses: &Clauses, limit: usize) -> Result<Vec<VectorIndex>, Error> {
+    let config = live();
+    let excluded = excluded().await?;
+    let filtered = !config.collections.is_empty() || !access::unrestricted() || !excluded.is_empty();
+    let only = match clauses.filters() {
+        true => Some(prefilter(&DB.get().await.clone(), clauses).await?),
+        false => None,
+    };
+    // over fetch so source weights and feedback can reorder the candidates
+    let candidates = if filtered {
+        CLAUSE_CANDIDATES.max(limit * 4)
+    } else {
+        limit * 4
+    };
+    let mut chunks = rank_chunks(query, candidates, only).await?;
+    chunks.retain(|c| {
+        let source = c.metadata["source"].as_str().unwrap_or_default();
+        let connector = c.metadata["connector"].as_str().unwrap_or_default();
+        config.in_collections(source, connector)
+            && access::readable(c)
+            && !excluded.excludes(c)
+    });
+    if !clauses.should.is_empty() {
+        for chunk in chunks.iter_mut() {
+            chunk.score = chunk.score.map(|s| s + clauses.boost(&chunk.content_chunk));
+        }
+        chunks.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+    }
+    if let Retrieval::Documents { score, per_document } = config.retrieval {
+        chunks = by_document(chunks, score, per_document);
+    }
+    chunks.truncate(limit);
+    Ok(chunks)
+}
+
+// Groups chunks sorted best first by document, best documents first, keeping the `per_document` best of each
+fn by_document(chunks: Vec<VectorIndex>, score: DocumentScore, per_document: usize) -> Vec<VectorIndex> {
+    let mut documents: Vec<(f32, Vec<VectorIndex>)> = vec![];
+    for chunk in chunks {
+        match documents.iter_mut().find(|(_, d)| d[0].content_id == chunk.content_id) {
+            Some((_, document)) => document.push(chunk),
+            None => documents.push((0.0, vec![chunk])),
+        }
+    }
+    for (document_score, document) in documents.iter_mut() {
+        let scores = document.iter().map(|c| c.score.unwrap_or(0.0));
+        *document_score = match score {
+            DocumentScore::Max => scores.fold(f32::MIN, f32::max),
+            DocumentScore::WeightedSum => scores.enumerate().map(|(i, s)| s / (i + 1) as f32).sum(),
+        };
+        document.truncate(per_document.max(1));
+    }
+    documents.sort_by(|a, b| b.0.total_cmp(&a.0));
+    documents.into_iter().flat_map(|(_, document)| document).collect()
+}
+
+// The best `candidates` chunks by similarity, among `only` when given, reordered by source weights and feedback
+#[instrument(name = "ranking", skip_all, fields(candidates = candidates))]
+async fn rank_chunks(
+    query: Vec<f32>,
+    candidates: usize,
+    only: Option<Vec<Thing>>,
+) -> Result<Vec<VectorIndex>, Error> {
+    let db = DB.get().await.clone();
+    let reduced = reduce(&index_embedder().await?, &query);
+    let rescore = CONFIG.rescore && reduced.len() < query.len();
+    let limit = if rescore { RESCORE_CANDIDATES.max(candidates) } else { candidates };
+    // the vector file has every chunk, the few picked by clauses are read from the database
+    let mut vector_indexes = if CONFIG.mmap_vectors && only.is_none() {
+        fetch_scored(&db, vector_file::search(&reduced, limit).await?).await?
+    } else {
+        scan_chunks(&db, &reduced, limit, only).await?
+    };
+
+    if rescore {
+        vector_indexes = rescore_chunks(&db, &query, vector_indexes, candidates).await?;
+    }
+    rerank(vector_indexes).await
+}
+
+// Scores the candidates again with their whole vectors, keeping the `candidates` best
+#[instrument(name = "rescoring", skip_all, fields(candidates = vector_indexes.len()))]
+async fn rescore_chunks(
+    db: &Surreal<Db>,
+    query: &[f32],
+    mut vector_indexes: Vec<VectorIndex>,
+    candidates: usize,
+) -> Result<Vec<VectorIndex>, Error> {
+    let ids = vector_indexes.iter().map(|v| full_vector_id(&v.id)).collect::<Vec<_>>();
+    let mut result = db
+        .query("SELECT meta::id(id) AS key, vector FROM full_vector WHERE id INSIDE $ids")
+        .bind(("ids", ids))
+        .await?;
+    let full_vectors: Vec<KeyedVector> = result.take(0)?;
+    let full_vectors = full_vectors
+        .into_iter()
+        .map(|v| (v.key, v.vector))
+        .collect::<HashMap<_, _>>();
+    // chunks without a whole vector, e.g. restored from the trash, keep their reduced score
+    for vector_index in vector_indexes.iter_mut() {
+        if let Some(full_vector) = full_vectors.get(&vector_index.id.id.to_raw()) {
+            vector_index.score = Some(cosine_similarity(query, full_vector));
+        }
+    }
+    vector_indexes.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+    vector_indexes.truncate(candidates);
+    Ok(vector_indexes)
+}
+
+// Reorders the candidates by the weight of their source and the feedback on them
+#[instrument(name = "reranking", skip_all, fields(candidates = vector_indexes.len()))]
+async fn rerank(mut vector_indexes: Vec<VectorIndex>) -> Result<Vec<VectorIndex>, Error> {
+    let ids = vector_indexes.iter().map(|v| v.id.clone()).collect::<Vec<_>>();
+    let boosts = feedback_boosts(ids).await?;
+    let config = live();
+    for vector_index in vector_indexes.iter_mut() {
+        let source = vector_index.metadata["source"].as_str().unwrap_or_default();
+        let weight = config.source_weight(source);
+        let boost = boosts.get(&vector_index.id.to_string()).copied().unwrap_or(0.0);
+        vector_index.score = vector_index.score.map(|s| s * weight + boost);
+    }
+    vector_indexes.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+    Ok(vector_indexes)
+}
+
+/// A page of search results
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchPage {
+    pub chunks: Vec<VectorIndex>,
+    /// Pass to `next_page` for the following results, None on the last page
+    pub next_cursor: Option<String>,
+    /// How many results the search has over all its pages
+    pub total: usize,
+}
+
+/// Results of a paged search, so later pages are read in the same order without scoring again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct SearchCursor {
+    ids: Vec<Thing>,
+    scores: Vec<f32>,
+    created_at: Datetime,
+}
+
+/// Searches and returns the first `page_size` results, the next ones are read with the cursor.
+/// Chunks memorized after the search don't show up in its pages.
+pub async fn search_page(query: Vec<f32>, clauses: &Clauses, page_size: usize) -> Result<SearchPage, Error> {
+    let db = DB.get().await.clone();
+    db.query("DELETE search_cursor WHERE created_at < $cutoff")
+        .bind(("cutoff", Datetime::from(chrono::Utc::now() - chrono::Duration::hours(CURSOR_TTL_HOURS))))
+        .await?
+        .check()?;
+
+    let chunks = search_with(query, clauses, MAX_SEARCH_RESULTS).await?;
+    let key = Uuid::new_v4().0.to_string().replace("-", "");
+    let _: Option<SearchCursor> = db
+        .create(("search_cursor", key.as_str()))
+        .content(SearchCursor {
+            ids: chunks.iter().map(|c| c.id.clone()).collect(),
+            scores: chunks.iter().map(|c| c.score.unwrap_or(0.0)).collect(),
+            created_at: Datetime::default(),
+        })
+        .await?;
+    let total = chunks.len();
+    let next_cursor = (total > page_size).then(|| format!("{}:{}", key, page_size));
+    Ok(SearchPage {
+        chunks: chunks.into_iter().take(page_size).collect(),
+        next_cursor,
+        total,
+    })
+}
+
+/// The page of a search after the given cursor
+pub async fn next_page(cursor: &str, page_size: usize) -> Result<SearchPage, Error> {
     let db = DB.get().await.clone();
+    let (key, offset) = cursor
+        .split_once(':')
+        .and_then(|(key, offset)| Some((key, offset.parse::<usize>().ok()?)))
+        .context("Invalid cursor")?;
+    let search: SearchCursor = db
+        .select(("search_cursor", key))
+        .await?
+        .context("The search expired, search again")?;
+
+    let offset = offset.min(search.ids.len());
+    let end = (offset + page_size).min(search.ids.len());
+    let ids = search.ids[offset..end].to_vec();
+    let mut result = db
+        .query("SELECT * FROM vector_index WHERE id INSIDE $ids")
+        .bind(("ids", ids.clone()))
+        .await?;
+    let found: Vec<VectorIndex> = result.take(0)?;
+    // in the order of the search, chunks deleted or excluded since are left out, as are those of other API keys
+    let excluded = excluded().await?;
+    let mut chunks = vec![];
+    for (id, score) in ids.iter().zip(&search.scores[offset..end]) {
+        if let Some(chunk) = found.iter().find(|c| &c.id == id && access::readable(c) && !excluded.excludes(c)) {
+            chunks.push(VectorIndex {
+                score: Some(*score),
+                ..chunk.clone()
+            });
+        }
+    }
+    Ok(SearchPage {
+        chunks,
+        next_cursor: (end < search.ids.len()).then(|| format!("{}:{}", key, end)),
+        total: search.ids.len(),
+    })
+}
+
+// Scores every chunk with the SIMD kernels, quantized ones included as the database only compares
+// arrays of floats
+async fn scan_chunks(
+    db: &Surreal<Db>,
+    query: &[f32],
+    limit: usize,
+    only: Option<Vec<Thing>>,
+) -> Result<Vec<VectorIndex>, Error> {
+    let mut result = match only {
+        Some(ids) => {
+            db.query("SELECT id, vector FROM vector_index WHERE id INSIDE $ids")
+                .bind(("ids", ids))
+                .await?
+        }
+        None => db.query("SELECT id, vector FROM vector_index").await?,
+    };
+    let vectors: Vec<ChunkVector> = result.take(0)?;
+    let mut scored = vectors
+        .into_iter()
+        .map(|v| (v.vector.similarity(query), v.id))
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.truncate(limit);
+    fetch_scored(db, scored.into_iter().map(|(score, id)| (id, score)).collect()).await
+}
+
+// Chunks scored outside of the database, best first. Chunks deleted since they were scored are left out.
+async fn fetch_scored(db: &Surreal<Db>, scored: Vec<(Thing, f32)>) -> Result<Vec<VectorIndex>, Error> {
+    let ids = scored.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>();
     let mut result = db
-        .query("SELECT *, vector::similarity::cosine(vector, $query) AS score FROM vector_index ORDER BY score DESC LIMIT 4")
-        .bind(("query", query))
+        .query("SELECT * FROM vector_index WHERE id INSIDE $ids")
+        .bind(("ids", ids))
+        .await?;
+    let mut vector_indexes: Vec<VectorIndex> = result.take(0)?;
+    let scores = scored
+        .into_iter()
+        .map(|(id, score)| (id.to_string(), score))
+        .collect::<HashMap<_, _>>();
+    for vector_index in vector_indexes.iter_mut() {
+        vector_index.score = scores.get(&vector_index.id.to_string()).copied();
+    }
+    vector_indexes.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+    Ok(vector_indexes)
+}
+
+/// Rewrites every chunk so its vector is encoded as set by `quantization`
+pub async fn requantize() -> Result<usize, Error> {
+    ensure_writable()?;
+    let db = DB.get().await.clone();
+    let chunks: Vec<VectorIndex> = db.select("vector_index").await?;
+    let mut rewritten = 0;
+    for chunk in chunks {
+        let _: Option<VectorIndex> = db.update(chunk.id.clone()).content(chunk).await?;
+        rewritten += 1;
+        print!("Rewrote {} chunks\r", rewritten);
+        let _ = std::io::stdout().flush();
+    }
+    Ok(rewritten)
+}
+
+/// Rewrites memorized text, answers and contacts, encrypting whatever was stored in plain text
+pub async fn encrypt_existing() -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let mut rewritten = 0;
+
+    let contents: Vec<Content> = db.select("content").await?;
+    for content in contents {
+        let _: Option<Content> = db.update(content.id.clone()).content(content).await?;
+        rewritten += 1;
+    }
+    let chunks: Vec<VectorIndex> = db.select("vector_index").await?;
+    for chunk in chunks {
+        let _: Option<VectorIndex> = db.update(chunk.id.clone()).content(chunk).await?;
+        rewritten += 1;
+        print!("Encrypted {} records\r", rewritten);
+        let _ = std::io::stdout().flush();
+    }
+    let answers: Vec<StoredAnswer> = db.select("answer").await?;
+    for answer in answers {
+        let _: Option<StoredAnswer> = db.update(answer.id.clone()).content(answer).await?;
+        rewritten += 1;
+    }
+    for (id, person) in crate::contacts::all_people().await? {
+        let _: Option<crate::contacts::Person> = db.update(id).content(person).await?;
+        rewritten += 1;
+    }
+
+    Ok(rewritten)
+}
+
+/// Every chunk with its vector, for searching outside of Tera
+pub async fn export_index() -> Result<ExportedIndex, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT * FROM vector_index ORDER BY created_at ASC")
+        .await?;
+    let vector_indexes: Vec<VectorIndex> = result.take(0)?;
+
+    let entries = vector_indexes
+        .into_iter()
+        .map(|v| Entry {
+            id: v.id.id.to_raw(),
+            content_id: v.content_id.id.to_raw(),
+            content: v.content_chunk,
+            metadata: v.metadata,
+            vector: v.vector,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(ExportedIndex {
+        embedder: serde_json::to_value(index_embedder().await?)?,
+        dimensions: entries.first().map(|e| e.vector.len()).unwrap_or_default(),
+        entries,
+    })
+}
+
+// Events overlapping the given range, dates are stored as sortable strings
+pub async fn get_events_between(start: &str, end: &str) -> Result<Vec<VectorIndex>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT * FROM vector_index WHERE metadata.type = 'event' AND metadata.start < $end AND (metadata.end ?? metadata.start) >= $start ORDER BY metadata.start ASC LIMIT 20")
+        .bind(("start", start))
+        .bind(("end", end))
         .await?;
     let vector_indexes: Vec<VectorIndex> = result.take(0)?;
 
@@ -260,18 +1403,524 @@ pub async fn get_all_content(start: u16, limit: u16) -> Result<Vec<Content>, Err
 }
 
 
-// Delete content by id
+/// Deleted content and its chunks, kept in the trash so the deletion can be undone
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Trashed {
+    pub content: Content,
+    pub chunks: Vec<VectorIndex>,
+    pub deleted_at: Datetime,
+}
+
+// Delete content by id, moving it to the trash
 pub async fn delete_content(id: &str) -> Result<(), Error> {
+    ensure_writable()?;
     let db = DB.get().await.clone();
+    let key = id;
     let id = thing(format!("content:{}", id).as_str())?;
 
+    let content: Content = db
+        .select(id.clone())
+        .await?
+        .context("Unknown content")?;
+    let chunks = content.get_vector_indexes().await?;
+    let _: Option<Trashed> = db
+        .update(("trash", key))
+        .content(Trashed {
+            content,
+            chunks,
+            deleted_at: Datetime::default(),
+        })
+        .await?;
+
+    remove_content(&id).await?;
+    record(Op::Delete { content_id: id }).await
+}
+
+/// Deletes content with its chunks and mentions, without going through the trash
+pub(crate) async fn remove_content(id: &Thing) -> Result<(), Error> {
+    remove_content_in(&DB.get().await.clone(), id).await
+}
+
+async fn remove_content_in(db: &Surreal<Db>, id: &Thing) -> Result<(), Error> {
+    let mut result = db
+        .query(
+            "SELECT VALUE text FROM content WHERE id = $id;
+            SELECT VALUE content_chunk FROM vector_index WHERE content_id = $id;
+            SELECT VALUE id FROM vector_index WHERE content_id = $id",
+        )
+        .bind(("id", id.clone()))
+        .await?;
+    let mut stored: Vec<String> = result.take(0)?;
+    stored.extend(result.take::<Vec<String>>(1)?);
+    let chunks: Vec<Thing> = result.take(2)?;
+
+    db.query("DELETE FROM mention WHERE chunk.content_id = $id")
+        .bind(("id", id.clone()))
+        .await?.check().context("Unable to delete mentions")?;
+
     db.query("DELETE FROM vector_index WHERE content_id = $id")
         .bind(("id", id.clone()))
         .await?.check().context("Unable to delete vector index")?;
+    vector_file::forget_chunks(&chunks)?;
+
+    db.query("DELETE FROM full_vector WHERE content_id = $id")
+        .bind(("id", id.clone()))
+        .await?.check().context("Unable to delete full vectors")?;
     
     db.query("DELETE FROM content WHERE id = $id")
-        .bind(("id", id))
+        .bind(("id", id.clone()))
+        .await?.check().context("Unable to delete content")?;
+
+    storage::delete_unreferenced(db, stored).await.context("Unable to delete the texts in the object storage")?;
+    Ok(())
+}
+
+/// Deleted content, most recently deleted first
+pub async fn list_trash() -> Result<Vec<Trashed>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM trash ORDER BY deleted_at DESC").await?;
+    let trashed: Vec<Trashed> = result.take(0)?;
+    Ok(trashed)
+}
+
+/// Puts deleted content and its chunks back in the index
+pub async fn restore(id: &str) -> Result<Content, Error> {
+    ensure_writable()?;
+    let db = DB.get().await.clone();
+    let trashed: Trashed = db
+        .select(("trash", id))
+        .await?
+        .context("Not in the trash")?;
+
+    let content: Content = db
+        .create(("content", trashed.content.id.id.clone()))
+        .content(trashed.content)
+        .await?
+        .context("Unable to restore content")?;
+    // logged as new inserts so they win over the deletion on other devices
+    record(Op::InsertContent { content: content.clone() }).await?;
+    for chunk in trashed.chunks {
+        let chunk: VectorIndex = db
+            .create(("vector_index", chunk.id.id.clone()))
+            .content(chunk)
+            .await?
+            .context("Unable to restore vector index")?;
+        index_chunk(&chunk)?;
+        if chunk.metadata["type"] != "contact" {
+            link_chunk(&chunk).await?;
+        }
+        record(Op::InsertChunk { chunk }).await?;
+    }
+
+    let deleted = db
+        .query("DELETE $id RETURN BEFORE")
+        .bind(("id", Thing::from(("trash", id))))
+        .await?;
+    forget_trashed(&db, deleted).await?;
+    Ok(content)
+}
+
+// Deletes the texts which only deleted records of the trash held from the object storage, returns how
+// many records were deleted. They are read as is, so their texts aren't downloaded to be deleted.
+async fn forget_trashed(db: &Surreal<Db>, mut deleted: surrealdb::Response) -> Result<usize, Error> {
+    let deleted: Vec<Value> = deleted.take(0)?;
+    let stored = deleted.iter().flat_map(storage::references).collect();
+    storage::delete_unreferenced(db, stored).await.context("Unable to delete the texts in the object storage")?;
+    Ok(deleted.len())
+}
+
+/// Permanently deletes everything in the trash
+pub async fn empty_trash() -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let deleted = db.query("DELETE FROM trash RETURN BEFORE").await?;
+    forget_trashed(&db, deleted).await
+}
+
+/// Permanently deletes what was moved to the trash more than `trash_days` ago
+pub async fn expire_trash() -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let deleted = db
+        .query("DELETE FROM trash WHERE deleted_at < $cutoff RETURN BEFORE")
+        .bind(("cutoff", Datetime::from(chrono::Utc::now() - chrono::Duration::days(live().trash_days as i64))))
+        .await?;
+    forget_trashed(&db, deleted).await
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ChunkAge {
+    id: Thing,
+    content_id: Thing,
+    source: Option<String>,
+    created_at: Datetime,
+}
+
+/// Forgets chunks older than the retention of their source, and the content left without chunks
+pub async fn expire_content() -> Result<usize, Error> {
+    let config = live();
+    if config.retention.is_empty() {
+        return Ok(0);
+    }
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT id, content_id, metadata.source AS source, created_at FROM vector_index")
+        .await?;
+    let chunks: Vec<ChunkAge> = result.take(0)?;
+
+    let now = chrono::Utc::now();
+    let expired = chunks
+        .into_iter()
+        .filter(|chunk| {
+            let source = chunk.source.as_deref().unwrap_or_default();
+            match config.retention_days(source) {
+                Some(days) => chunk.created_at.0 < now - chrono::Duration::days(days as i64),
+                None => false,
+            }
+        })
+        .collect::<Vec<_>>();
+    if expired.is_empty() {
+        return Ok(0);
+    }
+    debug!(chunks = expired.len(), "Expiring chunks");
+
+    let ids = expired.iter().map(|c| c.id.clone()).collect::<Vec<_>>();
+    let mut contents = expired.into_iter().map(|c| c.content_id).collect::<Vec<_>>();
+    contents.sort_by_key(|c| c.to_string());
+    contents.dedup();
+    let mut result = db
+        .query(
+            "SELECT VALUE content_chunk FROM vector_index WHERE id INSIDE $ids;
+            SELECT VALUE text FROM content WHERE id INSIDE $contents",
+        )
+        .bind(("ids", ids.clone()))
+        .bind(("contents", contents.clone()))
+        .await?;
+    let mut stored: Vec<String> = result.take(0)?;
+    stored.extend(result.take::<Vec<String>>(1)?);
+
+    db.query("DELETE FROM mention WHERE chunk INSIDE $ids")
+        .bind(("ids", ids.clone()))
+        .await?.check().context("Unable to delete mentions")?;
+
+    db.query("DELETE FROM vector_index WHERE id INSIDE $ids")
+        .bind(("ids", ids.clone()))
+        .await?.check().context("Unable to delete vector index")?;
+    vector_file::forget_chunks(&ids)?;
+
+    let full_vectors = ids.iter().map(full_vector_id).collect::<Vec<_>>();
+    db.query("DELETE FROM full_vector WHERE id INSIDE $ids")
+        .bind(("ids", full_vectors))
+        .await?.check().context("Unable to delete full vectors")?;
+
+    // content is only kept while some of its chunks are
+    db.query("DELETE FROM content WHERE id INSIDE $contents AND count((SELECT id FROM vector_index WHERE content_id = $parent.id)) = 0")
+        .bind(("contents", contents))
         .await?.check().context("Unable to delete content")?;
 
+    // the text of content which kept chunks is still held
+    storage::delete_unreferenced(&db, stored).await.context("Unable to delete the texts in the object storage")?;
+    Ok(ids.len())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct IndexMeta {
+    backend: EmbeddingBackend,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ChunkText {
+    id: Thing,
+    content_id: Thing,
+    #[serde(with = "crate::storage::blob")]
+    content_chunk: String,
+}
+
+/// The embedding backend the stored vectors were computed with
+pub async fn index_embedder() -> Result<EmbeddingBackend, Error> {
+    // a dry run doesn't open the knowledge base, it chunks for the embedder configured
+    if is_dry_run() {
+        return Ok(CONFIG.embeddings.clone());
+    }
+    let db = DB.get().await.clone();
+    let meta: Option<IndexMeta> = db.select(("index_meta", "embedder")).await?;
+    match meta {
+        Some(meta) => Ok(meta.backend),
+        None => {
+            // vectors stored before the embedder was recorded come from the built in model
+            let mut result = db.query("SELECT id FROM vector_index LIMIT 1").await?;
+            let existing: Vec<Value> = result.take(0)?;
+            let backend = if existing.is_empty() {
+                CONFIG.embeddings.clone()
+            } else {
+                EmbeddingBackend::Local
+            };
+            set_index_meta("embedder", &backend).await?;
+            Ok(backend)
+        }
+    }
+}
+
+async fn set_index_meta(key: &str, backend: &EmbeddingBackend) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let _: Option<IndexMeta> = db
+        .update(("index_meta", key))
+        .content(IndexMeta {
+            backend: backend.clone(),
+        })
+        .await?;
     Ok(())
 }
+
+// Switches searches to the staged vectors in one transaction, failing while a chunk memorized during
+// the migration still lacks one
+const CUTOVER: &str = "BEGIN TRANSACTION;
+    IF count((SELECT id FROM vector_index WHERE next_vector = NONE LIMIT 1)) > 0 {
+        THROW 'Chunks without a staged vector are left';
+    };
+    UPDATE vector_index SET vector = next_vector, next_vector = NONE WHERE next_vector != NONE;
+    UPDATE full_vector SET vector = next_vector, next_vector = NONE;
+    DELETE full_vector WHERE vector = NONE;
+    UPDATE index_meta:embedder CONTENT $meta;
+    DELETE index_meta:migration;
+    COMMIT TRANSACTION;";
+
+static MIGRATING: AtomicBool = AtomicBool::new(false);
+
+// Re-embed all chunks with the configured embedding backend.
+// New vectors are staged in `next_vector` so searches keep using the old ones until every
+// chunk is done, an interrupted migration resumes where it stopped.
+pub async fn migrate_embeddings() -> Result<usize, Error> {
+    migrate(true).await
+}
+
+/// Re-embeds all chunks in a task of its own, e.g. for the daemon to keep answering with the previous
+/// vectors meanwhile. Does nothing while a migration is under way.
+pub fn migrate_in_background() {
+    if MIGRATING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    tokio::spawn(async {
+        match migrate(false).await {
+            Ok(0) => {}
+            Ok(migrated) => eprintln!("Re-embedded {} chunks, searches use the new model", migrated),
+            Err(e) => eprintln!("Unable to re-embed memorized content: {:#}", e),
+        }
+        MIGRATING.store(false, Ordering::SeqCst);
+    });
+}
+
+/// Carries on in the background with a migration that got interrupted
+pub async fn resume_migration() -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let pending: Option<IndexMeta> = db.select(("index_meta", "migration")).await?;
+    if pending.map(|m| m.backend) == Some(CONFIG.embeddings.clone()) {
+        migrate_in_background();
+    }
+    Ok(())
+}
+
+async fn migrate(progress: bool) -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let target = CONFIG.embeddings.clone();
+    if index_embedder().await? == target {
+        return Ok(0);
+    }
+
+    let previous: Option<IndexMeta> = db.select(("index_meta", "migration")).await?;
+    if previous.map(|m| m.backend) != Some(target.clone()) {
+        debug!(target = ?target, "Starting a new embedding migration");
+        db.query("UPDATE vector_index SET next_vector = NONE")
+            .await?
+            .check()
+            .context("Unable to reset staged vectors")?;
+        set_index_meta("migration", &target).await?;
+    }
+
+    let mut migrated = 0;
+    loop {
+        stage_vectors(&db, &target, progress, &mut migrated).await?;
+        let cutover = db
+            .query(CUTOVER)
+            .bind(("meta", IndexMeta { backend: target.clone() }))
+            .await?
+            .check();
+        match cutover {
+            Ok(_) => break,
+            // chunks memorized since the last pass, still embedded with the previous model, get staged too
+            Err(e) if !unstaged(&db).await?.is_empty() => debug!(error = ?e, "Staging chunks memorized meanwhile"),
+            Err(e) => return Err(e).context("Unable to switch to the new vectors"),
+        }
+    }
+
+    // staged vectors are plain floats
+    if CONFIG.quantization != Quantization::None {
+        requantize().await?;
+    }
+    vector_file::refresh().await?;
+
+    Ok(migrated)
+}
+
+async fn unstaged(db: &Surreal<Db>) -> Result<Vec<ChunkText>, Error> {
+    let mut result = db
+        .query("SELECT id, content_id, content_chunk FROM vector_index WHERE next_vector = NONE LIMIT 100")
+        .await?;
+    Ok(result.take(0)?)
+}
+
+// Stages a vector of `target` for every chunk without one
+async fn stage_vectors(
+    db: &Surreal<Db>,
+    target: &EmbeddingBackend,
+    progress: bool,
+    migrated: &mut usize,
+) -> Result<(), Error> {
+    loop {
+        let chunks = unstaged(db).await?;
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        for chunk in chunks {
+            let full_vector = embed_with(target, &chunk.content_chunk).await?;
+            let vector = reduce(target, &full_vector);
+            if CONFIG.rescore && vector.len() < full_vector.len() {
+                db.query("UPDATE type::thing('full_vector', $key) SET content_id = $content_id, next_vector = $vector")
+                    .bind(("key", chunk.id.id.clone()))
+                    .bind(("content_id", chunk.content_id))
+                    .bind(("vector", full_vector))
+                    .await?
+                    .check()
+                    .context("Unable to stage full vector")?;
+            }
+            db.query("UPDATE $id SET next_vector = $vector")
+                .bind(("id", chunk.id))
+                .bind(("vector", vector))
+                .await?
+                .check()
+                .context("Unable to stage vector")?;
+            *migrated += 1;
+            if progress {
+                print!("Re-embedded {} chunks\r", migrated);
+                let _ = std::io::stdout().flush();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stores_cjk_chunks_whole() {
+        let chunk = "  東京は日本の首都です。人口は約千四百万人です。 ";
+        assert_eq!(stored_text(chunk).unwrap(), "東京は日本の首都です。人口は約千四百万人です。");
+        assert_eq!(stored_text("नमस्ते दुनिया।").unwrap(), "नमस्ते दुनिया।");
+        assert_eq!(stored_text("مرحبا بالعالم").unwrap(), "مرحبا بالعالم");
+    }
+
+    #[test]
+    fn rejects_blank_chunks() {
+        assert!(stored_text(" \n\t").is_err());
+    }
+
+    // The knowledge base of the tests, in a directory of its own
+    async fn test_db() -> Surreal<Db> {
+        let dir = std::env::temp_dir().join(format!("tera-test-{}", std::process::id()));
+        crate::config::DATA_DIR.get_or_init(|| dir);
+        DB.get().await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restores_forgotten_content() {
+        let db = test_db().await;
+        db.query(
+            "CREATE content:boiler SET title = 'Boiler', text = 'The boiler code is 4711', created_at = time::now();
+            CREATE vector_index:boiler SET content_id = content:boiler, content_chunk = 'The boiler code is 4711',
+                chunk_number = 0, vector = [1.0, 0.0, 0.0], metadata = { source: 'boiler.txt', connector: 'txt' },
+                created_at = time::now();",
+        )
+        .await
+        .unwrap()
+        .check()
+        .unwrap();
+        let found = || async {
+            let chunks = search_with(vec![1.0, 0.0, 0.0], &Clauses::default(), 4).await.unwrap();
+            chunks.into_iter().find(|c| c.id == Thing::from(("vector_index", "boiler")))
+        };
+        assert!(found().await.is_some());
+
+        delete_content("boiler").await.unwrap();
+        assert!(found().await.is_none());
+
+        let content = restore("boiler").await.unwrap();
+        assert_eq!(content.id, Thing::from(("content", "boiler")));
+        let chunk = found().await.unwrap();
+        assert_eq!(chunk.content_id, content.id);
+        assert_eq!(chunk.get_content().await.unwrap().text, "The boiler code is 4711");
+        assert!(list_trash().await.unwrap().iter().all(|t| t.content.id != content.id));
+    }
+
+    #[tokio::test]
+    async fn dry_runs_end_with_their_upload() {
+        assert!(with_dry_run(true, async { is_dry_run() }).await);
+        assert!(!with_dry_run(false, async { is_dry_run() }).await);
+        // a daemon keeps storing what later commands upload
+        assert!(!is_dry_run());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn prefilters_chunks_by_keyword() {
+        let db = test_db().await;
+        db.query(
+            "CREATE vector_index:unpaid SET content_id = content:bills, chunk_number = 0,
+                content_chunk = 'The INVOICE of March is unpaid', vector = [0.0, 1.0, 0.0],
+                metadata = { source: 'bills.txt' }, created_at = time::now();
+            CREATE vector_index:draft SET content_id = content:bills, chunk_number = 1,
+                content_chunk = 'Draft invoice for April', vector = [1.0, 0.0, 0.0],
+                metadata = { source: 'bills.txt' }, created_at = time::now();",
+        )
+        .await
+        .unwrap()
+        .check()
+        .unwrap();
+        let clauses = Clauses {
+            must: vec!["invoice".to_string()],
+            must_not: vec!["draft".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(prefilter(&db, &clauses).await.unwrap(), vec![Thing::from(("vector_index", "unpaid"))]);
+        // the least similar chunk, found as the only one with the keywords
+        let chunks = search_with(vec![1.0, 0.0, 0.0], &clauses, 1).await.unwrap();
+        assert_eq!(chunks[0].id, Thing::from(("vector_index", "unpaid")));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn prefilters_chunks_by_metadata_and_date() {
+        let db = test_db().await;
+        db.query(
+            "CREATE vector_index:plan SET content_id = content:plans, chunk_number = 0,
+                content_chunk = 'Plan of the garden', vector = [0.0, 1.0, 0.0],
+                metadata = { source: 'Garden.md', page: 2 }, created_at = <datetime> '2024-05-02T10:00:00Z';
+            CREATE vector_index:old_plan SET content_id = content:plans, chunk_number = 1,
+                content_chunk = 'Older plan of the garden', vector = [0.0, 1.0, 0.0],
+                metadata = { source: 'Garden.md', page: 2 }, created_at = <datetime> '2023-05-02T10:00:00Z';
+            CREATE vector_index:photo SET content_id = content:plans, chunk_number = 2,
+                content_chunk = 'Photo of the garden', vector = [0.0, 1.0, 0.0],
+                metadata = { source: 'garden.jpg', page: 2 }, created_at = <datetime> '2024-05-02T10:00:00Z';",
+        )
+        .await
+        .unwrap()
+        .check()
+        .unwrap();
+        let (_, clauses) = Clauses::parse("source:\"*.MD\" page:2 after:2024-01-01 before:2025-01-01").unwrap();
+        assert_eq!(prefilter(&db, &clauses).await.unwrap(), vec![Thing::from(("vector_index", "plan"))]);
+    }
+
+    #[test]
+    fn keeps_times_as_words() {
+        let (text, clauses) = Clauses::parse("meeting at 10:30 source:notes").unwrap();
+        assert_eq!(text, "meeting at 10:30");
+        assert_eq!(clauses.metadata.len(), 1);
+        assert_eq!(clauses.metadata["source"], "notes");
+    }
+}
@@ -1,3 +1,6 @@
+use crate::config::{EmbeddingBackend, LongChunks, CONFIG};
+use crate::database::index_embedder;
+use crate::ollama;
 use anyhow::{Context, Error as E, Result};
 use candle_core::{Device, Tensor};
 use candle_nn::VarBuilder;
@@ -5,9 +8,33 @@ use candle_transformers::models::bert::{BertModel, Config, DTYPE};
 use hf_hub::{api::sync::Api, Repo};
 use lazy_static::lazy_static;
 use tokenizers::{PaddingParams, Tokenizer};
+use tracing::{debug, instrument};
+
+pub use tera_core::similarity::cosine_similarity;
 
 lazy_static! {
     pub static ref AI: (BertModel, Tokenizer) = load_model().expect("Unable to load model");
+    // loaded on its own so counting tokens doesn't load the model
+    static ref TOKENIZER: Tokenizer = load_tokenizer().expect("Unable to load tokenizer");
+}
+
+fn load_tokenizer() -> Result<Tokenizer> {
+    let api = Api::new()?.repo(Repo::model("BAAI/bge-small-en-v1.5".to_string()));
+    Tokenizer::from_file(api.get("tokenizer.json")?).map_err(E::msg)
+}
+
+/// Longest text the local embedding model reads, in tokens, leaving room for `[CLS]` and `[SEP]`
+pub const MAX_CHUNK_TOKENS: usize = 510;
+
+/// How many tokens the local embedding model sees in a text
+pub fn count_tokens(text: &str) -> Result<usize> {
+    let encoding = TOKENIZER.encode(model_input(text), false).map_err(E::msg)?;
+    Ok(encoding.len())
+}
+
+// The model is English only and reads ascii characters, what it ignores doesn't count against its sequence length
+fn model_input(sentence: &str) -> String {
+    sentence.chars().filter(|c| c.is_ascii()).collect()
 }
 
 pub fn load_model() -> Result<(BertModel, Tokenizer)> {
@@ -37,43 +64,86 @@ pub fn load_model() -> Result<(BertModel, Tokenizer)> {
     Ok((model, tokenizer))
 }
 
+/// Embeds a sentence with the embedding backend the index was built with
+#[instrument(name = "embedding", skip_all)]
+pub async fn embed(sentence: &str) -> Result<Vec<f32>> {
+    embed_with(&index_embedder().await?, sentence).await
+}
+
+/// Embeds a sentence with the given backend, the vector is whole even when stored ones are reduced
+pub async fn embed_with(backend: &EmbeddingBackend, sentence: &str) -> Result<Vec<f32>> {
+    match backend {
+        EmbeddingBackend::Local => Ok(get_embeddings(sentence)?.reshape((384,))?.to_vec1()?),
+        EmbeddingBackend::Ollama { base_url, model, .. } => {
+            ollama::embed(base_url, model, sentence).await
+        }
+    }
+}
+
+/// Embeds texts with the embedding backend the index was built with, a whole vector for each, so other
+/// apps can compare texts of their own, e.g. with [`cosine_similarity`]
+#[instrument(name = "embedding", skip_all, fields(texts = texts.len()))]
+pub async fn embed_texts(texts: &[impl AsRef<str>]) -> Result<Vec<Vec<f32>>> {
+    let backend = index_embedder().await?;
+    let mut vectors = Vec::with_capacity(texts.len());
+    for text in texts {
+        vectors.push(embed_with(&backend, text.as_ref()).await?);
+    }
+    Ok(vectors)
+}
+
+/// Keeps the leading `dimensions` of the backend, renormalized. Matryoshka models pack most of
+/// the meaning in the first dimensions, so 256 of 768 lose little while taking a third of the memory.
+pub fn reduce(backend: &EmbeddingBackend, vector: &[f32]) -> Vec<f32> {
+    let Some(dimensions) = backend.dimensions().filter(|d| *d < vector.len()) else {
+        return vector.to_vec();
+    };
+    let reduced = &vector[..dimensions];
+    let norm = reduced.iter().map(|x| x * x).sum::<f32>().sqrt();
+    reduced.iter().map(|x| x / norm.max(f32::EPSILON)).collect()
+}
+
 pub fn get_embeddings(sentence: &str) -> Result<Tensor> {
     let (model, tokenizer) = &*AI;
 
-    // drop any non-ascii characters
-    let sentence = sentence
-        .chars()
-        .filter(|c| c.is_ascii())
-        .collect::<String>();
-
+    let sentence = model_input(sentence);
     let tokens = tokenizer
-        .encode_batch(vec![sentence], true)
+        .encode(sentence, false)
         .map_err(E::msg)
         .context("Unable to encode sentence")?;
+    let ids = tokens.get_ids();
+    let windows = match (ids.len() > MAX_CHUNK_TOKENS, CONFIG.long_chunks) {
+        (false, _) => vec![ids],
+        (true, LongChunks::Truncate) => {
+            debug!(tokens = ids.len(), "Truncating text longer than the embedding model reads");
+            vec![&ids[..MAX_CHUNK_TOKENS]]
+        }
+        (true, _) => ids.chunks(MAX_CHUNK_TOKENS).collect(),
+    };
+    let cls = tokenizer.token_to_id("[CLS]").context("No [CLS] token")?;
+    let sep = tokenizer.token_to_id("[SEP]").context("No [SEP] token")?;
+
+    let mut pooled = vec![];
+    for window in windows {
+        let ids = [&[cls][..], window, &[sep][..]].concat();
+        let token_ids = Tensor::new(ids.as_slice(), &Device::Cpu)?
+            .unsqueeze(0)
+            .context("Unable to get token ids")?;
+        let token_type_ids = token_ids
+            .zeros_like()
+            .context("Unable to get token type ids")?;
 
-    let token_ids = tokens
-        .iter()
-        .map(|tokens| {
-            let tokens = tokens.get_ids().to_vec();
-            Ok(Tensor::new(tokens.as_slice(), &Device::Cpu)?)
-        })
-        .collect::<Result<Vec<_>>>()
-        .context("Unable to get token ids")?;
-
-    let token_ids = Tensor::stack(&token_ids, 0).context("Unable to stack token ids")?;
-    let token_type_ids = token_ids
-        .zeros_like()
-        .context("Unable to get token type ids")?;
-
-    let embeddings = model
-        .forward(&token_ids, &token_type_ids)
-        .context("Unable to get embeddings")?;
-
-    let (_n_sentence, n_tokens, _hidden_size) = embeddings
-        .dims3()
-        .context("Unable to get embeddings dimensions")?;
-    let embeddings =
-        (embeddings.sum(1)? / (n_tokens as f64)).context("Unable to get embeddings sum")?;
+        let embeddings = model
+            .forward(&token_ids, &token_type_ids)
+            .context("Unable to get embeddings")?;
+
+        let (_n_sentence, n_tokens, _hidden_size) = embeddings
+            .dims3()
+            .context("Unable to get embeddings dimensions")?;
+        pooled.push((embeddings.sum(1)? / (n_tokens as f64)).context("Unable to get embeddings sum")?);
+    }
+    // each window counts the same, then the average gets normalized like a single window
+    let embeddings = Tensor::cat(&pooled, 0)?.mean_keepdim(0)?;
     let embeddings = embeddings
         .broadcast_div(&embeddings.sqr()?.sum_keepdim(1)?.sqrt()?)
         .context("Unable to get embeddings broadcast div")?;
@@ -0,0 +1,157 @@
+use crate::config::wildcard_match;
+use crate::database::{ensure_writable, Content, VectorIndex, DB};
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use surrealdb::sql::{thing, Datetime, Thing};
+use tracing::debug;
+
+/// Memorized content left out of answers and searches until it is included again, e.g. while
+/// sharing the screen. Nothing is deleted.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Exclusion {
+    Document {
+        content_id: String,
+        title: String,
+        excluded_at: Datetime,
+    },
+    /// Every source or connector matching the pattern, `*` being a wildcard
+    Source { pattern: String, excluded_at: Datetime },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ExcludedContent {
+    content_id: Thing,
+    excluded_at: Datetime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ExcludedSource {
+    pattern: String,
+    excluded_at: Datetime,
+}
+
+/// What is left out of answers and searches, read once per search
+#[derive(Debug, Clone, Default)]
+pub struct Excluded {
+    documents: HashSet<Thing>,
+    sources: Vec<String>,
+}
+
+impl Excluded {
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty() && self.sources.is_empty()
+    }
+
+    /// Whether the chunk is excluded, by its document or its source
+    pub fn excludes(&self, chunk: &VectorIndex) -> bool {
+        let source = chunk.metadata["source"].as_str().unwrap_or_default();
+        let connector = chunk.metadata["connector"].as_str().unwrap_or_default();
+        self.documents.contains(&chunk.content_id)
+            || self.sources.iter().any(|p| wildcard_match(p, source) || wildcard_match(p, connector))
+    }
+}
+
+/// The documents and sources excluded right now
+pub async fn excluded() -> Result<Excluded, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM excluded_content; SELECT * FROM excluded_source").await?;
+    let documents: Vec<ExcludedContent> = result.take(0)?;
+    let sources: Vec<ExcludedSource> = result.take(1)?;
+    Ok(Excluded {
+        documents: documents.into_iter().map(|d| d.content_id).collect(),
+        sources: sources.into_iter().map(|s| s.pattern).collect(),
+    })
+}
+
+/// Leaves a document out of answers and searches, it stays memorized
+pub async fn exclude_document(id: &str) -> Result<Content, Error> {
+    ensure_writable()?;
+    let db = DB.get().await.clone();
+    let content_id = thing(format!("content:{}", id).as_str())?;
+    let content: Content = db.select(content_id.clone()).await?.context("Unknown content")?;
+    let _: Option<ExcludedContent> = db
+        .update(("excluded_content", id))
+        .content(ExcludedContent {
+            content_id,
+            excluded_at: Datetime::default(),
+        })
+        .await?;
+    debug!(content_id = id, "Excluded document");
+    Ok(content)
+}
+
+/// Leaves every source or connector matching the pattern out of answers and searches
+pub async fn exclude_source(pattern: &str) -> Result<(), Error> {
+    ensure_writable()?;
+    let db = DB.get().await.clone();
+    let _: Option<ExcludedSource> = db
+        .update(("excluded_source", pattern))
+        .content(ExcludedSource {
+            pattern: pattern.to_string(),
+            excluded_at: Datetime::default(),
+        })
+        .await?;
+    debug!(pattern = pattern, "Excluded source");
+    Ok(())
+}
+
+/// Lets an excluded document back into answers and searches
+pub async fn include_document(id: &str) -> Result<(), Error> {
+    ensure_writable()?;
+    let db = DB.get().await.clone();
+    let removed: Option<ExcludedContent> = db.delete(("excluded_content", id)).await?;
+    removed.with_context(|| format!("Content {} isn't excluded", id))?;
+    Ok(())
+}
+
+/// Lets the sources of an excluded pattern back into answers and searches
+pub async fn include_source(pattern: &str) -> Result<(), Error> {
+    ensure_writable()?;
+    let db = DB.get().await.clone();
+    let removed: Option<ExcludedSource> = db.delete(("excluded_source", pattern)).await?;
+    removed.with_context(|| format!("{} isn't excluded, see `tera exclusions list`", pattern))?;
+    Ok(())
+}
+
+/// Lets everything back into answers and searches, returning how many exclusions there were
+pub async fn include_all() -> Result<usize, Error> {
+    ensure_writable()?;
+    let db = DB.get().await.clone();
+    let documents: Vec<ExcludedContent> = db.delete("excluded_content").await?;
+    let sources: Vec<ExcludedSource> = db.delete("excluded_source").await?;
+    Ok(documents.len() + sources.len())
+}
+
+/// The exclusions, most recent first. Documents deleted since are left out.
+pub async fn list_exclusions() -> Result<Vec<Exclusion>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM excluded_content; SELECT * FROM excluded_source").await?;
+    let documents: Vec<ExcludedContent> = result.take(0)?;
+    let sources: Vec<ExcludedSource> = result.take(1)?;
+
+    let mut exclusions = vec![];
+    for document in documents {
+        let content: Option<Content> = db.select(document.content_id.clone()).await?;
+        if let Some(content) = content {
+            exclusions.push(Exclusion::Document {
+                content_id: document.content_id.id.to_raw(),
+                title: content.title,
+                excluded_at: document.excluded_at,
+            });
+        }
+    }
+    exclusions.extend(sources.into_iter().map(|s| Exclusion::Source {
+        pattern: s.pattern,
+        excluded_at: s.excluded_at,
+    }));
+    exclusions.sort_by(|a, b| excluded_at(b).cmp(excluded_at(a)));
+    Ok(exclusions)
+}
+
+fn excluded_at(exclusion: &Exclusion) -> &Datetime {
+    match exclusion {
+        Exclusion::Document { excluded_at, .. } | Exclusion::Source { excluded_at, .. } => excluded_at,
+    }
+}
@@ -0,0 +1,77 @@
+use crate::database::{StoredAnswer, DB};
+use anyhow::{Context, Error, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use surrealdb::sql::{thing, Thing};
+
+// Largest boost (or penalty) feedback can add to a chunk similarity
+const MAX_BOOST: f32 = 0.05;
+
+#[derive(Deserialize, Debug)]
+struct FeedbackCount {
+    chunk_id: Thing,
+    up: i64,
+    down: i64,
+}
+
+/// Records whether a chunk was useful for an answer, later retrievals favor useful chunks
+pub async fn record_feedback(answer_id: &str, chunk_id: &str, useful: bool) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let answer_id = thing(format!("answer:{}", answer_id).as_str())?;
+    let chunk_id = thing(format!("vector_index:{}", chunk_id).as_str())?;
+
+    let answer: StoredAnswer = db
+        .select(answer_id.clone())
+        .await?
+        .context("Unknown answer")?;
+    if !answer.sources.contains(&chunk_id) {
+        anyhow::bail!("Chunk {} is not a source of this answer", chunk_id);
+    }
+
+    // rating the same chunk of the same answer again replaces the previous rating
+    db.query("DELETE FROM feedback WHERE answer_id = $answer AND chunk_id = $chunk")
+        .bind(("answer", answer_id.clone()))
+        .bind(("chunk", chunk_id.clone()))
+        .await?
+        .check()
+        .context("Unable to replace feedback")?;
+    db.query("CREATE feedback SET answer_id = $answer, chunk_id = $chunk, useful = $useful")
+        .bind(("answer", answer_id))
+        .bind(("chunk", chunk_id))
+        .bind(("useful", useful))
+        .await?
+        .check()
+        .context("Unable to record feedback")?;
+
+    Ok(())
+}
+
+/// Records the same feedback for every source of an answer
+pub async fn record_answer_feedback(answer_id: &str, useful: bool) -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let id = thing(format!("answer:{}", answer_id).as_str())?;
+    let answer: StoredAnswer = db.select(id).await?.context("Unknown answer")?;
+    for source in answer.sources.iter() {
+        record_feedback(answer_id, &source.id.to_raw(), useful).await?;
+    }
+    Ok(answer.sources.len())
+}
+
+/// Similarity boost of each chunk (keyed by record id) from the accumulated feedback
+pub async fn feedback_boosts(chunk_ids: Vec<Thing>) -> Result<HashMap<String, f32>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT chunk_id, count(useful = true) AS up, count(useful = false) AS down FROM feedback WHERE chunk_id IN $ids GROUP BY chunk_id")
+        .bind(("ids", chunk_ids))
+        .await?;
+    let counts: Vec<FeedbackCount> = result.take(0)?;
+
+    Ok(counts
+        .into_iter()
+        .map(|c| {
+            // saturates so a handful of votes can't bury or crown a chunk
+            let net = (c.up - c.down) as f32;
+            (c.chunk_id.to_string(), MAX_BOOST * net / (net.abs() + 1.0))
+        })
+        .collect())
+}
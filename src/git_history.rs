@@ -0,0 +1,179 @@
+use crate::database::{commit_content, insert_content, insert_vector_index, is_dry_run, DB};
+use crate::provenance::Provenance;
+use crate::queue::{self, Priority};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde_json::json;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Lines of `--stat` output kept per commit
+const MAX_STAT_LINES: usize = 15;
+
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub hash: String,
+    pub author: String,
+    pub date: String,
+    pub subject: String,
+    pub body: String,
+    pub stat: String,
+}
+
+impl Commit {
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "Commit {} by {} on {}: {}",
+            &self.hash[..self.hash.len().min(10)],
+            self.author,
+            self.date,
+            self.subject
+        );
+        if !self.body.is_empty() {
+            text += &format!("\n{}", self.body);
+        }
+        if !self.stat.is_empty() {
+            text += &format!("\nChanged files:\n{}", self.stat);
+        }
+        text
+    }
+}
+
+/// Reads the commit log of a repository, newest first
+pub fn read_log(repository: &PathBuf) -> Result<Vec<Commit>> {
+    // records are separated by \x1e and fields by \x1f, the stat follows the last field
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repository)
+        // git for Windows may otherwise re-encode messages to the local code page
+        .arg("-c")
+        .arg("i18n.logOutputEncoding=UTF-8")
+        .arg("log")
+        .arg("--no-merges")
+        .arg("--date=iso-strict")
+        .arg("--stat=120")
+        .arg("--pretty=format:%x1e%H%x1f%an%x1f%ad%x1f%s%x1f%b%x1f")
+        .output()
+        .context("Unable to run git")?;
+    if !output.status.success() {
+        anyhow::bail!("git log failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    let log = String::from_utf8_lossy(&output.stdout);
+
+    let mut commits = vec![];
+    for record in log.split('\u{1e}').filter(|r| !r.trim().is_empty()) {
+        let fields = record.split('\u{1f}').collect::<Vec<&str>>();
+        if fields.len() < 6 {
+            continue;
+        }
+        let stat = fields[5]
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<&str>>();
+        // keep the summary line when the file list gets truncated
+        let stat = if stat.len() > MAX_STAT_LINES {
+            let mut kept = stat[..MAX_STAT_LINES - 1].to_vec();
+            kept.push(stat[stat.len() - 1]);
+            kept
+        } else {
+            stat
+        };
+        commits.push(Commit {
+            hash: fields[0].trim().to_string(),
+            author: fields[1].to_string(),
+            date: fields[2].to_string(),
+            subject: fields[3].to_string(),
+            body: fields[4].trim().to_string(),
+            stat: stat.join("\n"),
+        });
+    }
+    Ok(commits)
+}
+
+/// Memorizes the commits of a repository which were not memorized before
+pub async fn ingest_git_history(repository: PathBuf) -> Result<()> {
+    let repository = repository.canonicalize().context("Unable to find repository")?;
+    let name = repository
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    println!("Processing git history of {}", repository.display());
+
+    // a dry run shows every commit, it doesn't open the knowledge base
+    let known: HashSet<String> = match is_dry_run() {
+        true => HashSet::new(),
+        false => {
+            let db = DB.get().await.clone();
+            let mut result = db
+                .query(
+                    "SELECT VALUE metadata.hash FROM vector_index
+                    WHERE metadata.type = 'commit' AND metadata.repository = $repository",
+                )
+                .bind(("repository", repository.display().to_string()))
+                .await?;
+            result.take::<Vec<String>>(0)?.into_iter().collect()
+        }
+    };
+
+    let commits = read_log(&repository)?
+        .into_iter()
+        .filter(|c| !known.contains(&c.hash))
+        .collect::<Vec<Commit>>();
+    if commits.is_empty() {
+        println!("No new commits");
+        return Ok(());
+    }
+    println!("Found {} new commits", commits.len());
+
+    let title = format!("Git history of {}", name);
+    // waits for the documents saved meanwhile by hand, and paces a folder to the workers
+    let _permit = queue::admit(Priority::Bulk).await;
+    let content = insert_content(
+        &title,
+        &commits
+            .iter()
+            .map(|c| c.to_text())
+            .collect::<Vec<String>>()
+            .join("\n\n"),
+        "git",
+    )
+    .await
+    .context("Unable to insert content")?;
+
+    for (i, commit) in commits.iter().enumerate() {
+        print!("Memorizing commits {}/{}\r", i + 1, commits.len());
+        let res = insert_vector_index(
+            content.id.clone(),
+            i as u16,
+            &commit.to_text(),
+            json!({
+                "connector": "git",
+                "type": "commit",
+                "hash": commit.hash,
+                "author": commit.author,
+                "date": commit.date,
+                "repository": repository.display().to_string(),
+                "upload_time": Utc::now(),
+                "source": format!("git {}", name),
+                "provenance": Provenance::new(format!("git:{}#{}", repository.display(), commit.hash)),
+            }),
+        )
+        .await;
+
+        match res {
+            Ok(_) => {}
+            Err(e) => {
+                if e.to_string().contains("Content chunk is empty") {
+                    continue;
+                }
+                println!("Unable to insert vector index: {}", e);
+            }
+        }
+    }
+    println!("Memorized {}", title);
+    commit_content(&content.id).await?;
+
+    Ok(())
+}
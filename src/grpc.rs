@@ -0,0 +1,327 @@
+use crate::audit;
+use crate::config::{ApiKey, Role, CONFIG_MANAGER};
+use crate::database::{is_replica, next_page, search_page, search_with, Clauses, SearchPage, VectorIndex};
+use crate::embeddings::embed;
+use crate::inference::{
+    answer_or_clarify, answer_timeline, answer_with_context, answer_with_context_streaming, compare_documents,
+    retrieve_context, text_context, AnswerLength, AnswerOptions, Limits, Model, Reply,
+};
+use crate::ingest::ingest_text;
+#[cfg(unix)]
+use crate::platform::bind_private_socket;
+use crate::redaction::loggable;
+use crate::{access, shutdown, stats};
+use clap::ValueEnum;
+use proto::tera_server::{Tera, TeraServer};
+use proto::{
+    answer_event, AnswerEvent, AskRequest, Chunk, IngestRequest, IngestResponse, SearchRequest,
+    SearchResponse, SwitchModelRequest, SwitchModelResponse,
+};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::RwLock;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio_stream::wrappers::UnixListenerStream;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status};
+use tracing::debug;
+
+pub mod proto {
+    tonic::include_proto!("tera");
+}
+
+#[derive(Debug, Default)]
+pub struct TeraService {
+    /// Clients are the user, over a private unix socket, and need no API key
+    local: bool,
+}
+
+impl TeraService {
+    // The API key of the request, if it allows the role
+    fn authorize<T>(&self, request: &Request<T>, role: Role) -> Result<ApiKey, Status> {
+        if self.local {
+            return Ok(ApiKey::admin());
+        }
+        let authorization = request.metadata().get("authorization").and_then(|v| v.to_str().ok());
+        let key = access::authenticate(authorization, None).ok_or_else(|| Status::unauthenticated("Invalid API key"))?;
+        if key.role < role {
+            return Err(Status::permission_denied(format!("The key needs the {:?} role", role)));
+        }
+        debug!(key = ?key.name, role = ?key.role, "Authenticated");
+        Ok(key)
+    }
+}
+
+fn internal(e: impl std::fmt::Display) -> Status {
+    Status::internal(e.to_string())
+}
+
+fn chunk(source: VectorIndex) -> Chunk {
+    Chunk {
+        id: source.id.id.to_raw(),
+        content_id: source.content_id.id.to_raw(),
+        content: source.content_chunk,
+        metadata: source.metadata.to_string(),
+        score: source.score,
+    }
+}
+
+fn answer(answer: crate::inference::Answer) -> proto::Answer {
+    proto::Answer {
+        id: answer.id,
+        text: answer.text,
+        sources: answer.sources.into_iter().map(chunk).collect(),
+        follow_ups: answer.follow_ups,
+        confidence: answer.confidence,
+        clarification: None,
+    }
+}
+
+// Answers requests not asking for a model, switched by `SwitchModel`
+static ACTIVE_MODEL: RwLock<Model> = RwLock::new(Model::Phi2);
+
+fn model_name(model: Model) -> String {
+    model.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+}
+
+// Empty fields fall back to the same defaults as the cli
+fn answer_options(request: &AskRequest) -> Result<AnswerOptions, Status> {
+    let mut options = AnswerOptions {
+        follow_ups: request.follow_ups,
+        limits: Limits {
+            max_tokens: (request.max_tokens > 0).then_some(request.max_tokens as usize),
+            max_time: (request.max_seconds > 0).then(|| Duration::from_secs(request.max_seconds.into())),
+        },
+        ..Default::default()
+    };
+    options.model = if request.model.is_empty() {
+        *ACTIVE_MODEL.read().unwrap_or_else(|e| e.into_inner())
+    } else {
+        Model::from_str(&request.model, true).map_err(Status::invalid_argument)?
+    };
+    if !request.length.is_empty() {
+        options.length =
+            AnswerLength::from_str(&request.length, true).map_err(Status::invalid_argument)?;
+    }
+    Ok(options)
+}
+
+// The references of a request, from its document when it has one
+async fn context(request: &AskRequest, options: &AnswerOptions) -> anyhow::Result<Vec<VectorIndex>> {
+    if request.document.is_empty() {
+        retrieve_context(&request.query, options).await
+    } else {
+        text_context(&request.query, &request.document).await
+    }
+}
+
+#[tonic::async_trait]
+impl Tera for TeraService {
+    type StreamAnswerStream = Pin<Box<dyn Stream<Item = Result<AnswerEvent, Status>> + Send>>;
+
+    async fn ask(&self, request: Request<AskRequest>) -> Result<Response<proto::Answer>, Status> {
+        let key = self.authorize(&request, Role::ReadOnly)?;
+        let request = request.into_inner();
+        access::scope(key, async move {
+            debug!(query = %loggable(&request.query), "gRPC ask");
+            let options = answer_options(&request)?;
+            if request.timeline {
+                let result = answer_timeline(&request.query, &options).await.map_err(internal)?;
+                return Ok(Response::new(answer(result)));
+            }
+            if !request.compare.is_empty() {
+                let result = compare_documents(&request.query, &request.compare, &options)
+                    .await
+                    .map_err(internal)?;
+                return Ok(Response::new(answer(result)));
+            }
+            let context = context(&request, &options).await.map_err(internal)?;
+            if request.clarify {
+                let reply = answer_or_clarify(&request.query, context, None, &options)
+                    .await
+                    .map_err(internal)?;
+                return Ok(Response::new(match reply {
+                    Reply::Answer(result) => answer(result),
+                    Reply::Clarification(clarification) => proto::Answer {
+                        clarification: Some(proto::Clarification {
+                            question: clarification.question,
+                            topics: clarification.topics,
+                        }),
+                        ..Default::default()
+                    },
+                }));
+            }
+            let result = answer_with_context(&request.query, context, None, &options)
+                .await
+                .map_err(internal)?;
+            Ok::<_, Status>(Response::new(answer(result)))
+        })
+        .await
+    }
+
+    async fn stream_answer(
+        &self,
+        request: Request<AskRequest>,
+    ) -> Result<Response<Self::StreamAnswerStream>, Status> {
+        let key = self.authorize(&request, Role::ReadOnly)?;
+        let request = request.into_inner();
+        debug!(query = %loggable(&request.query), "gRPC stream answer");
+        let options = answer_options(&request)?;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(access::scope(key, async move {
+            let result = match context(&request, &options).await {
+                Ok(context) => {
+                    let on_token = |token: &str| {
+                        let _ = tx.send(Ok(AnswerEvent {
+                            event: Some(answer_event::Event::Token(token.to_string())),
+                        }));
+                    };
+                    answer_with_context_streaming(&request.query, context, None, &options, on_token)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            let event = match result {
+                Ok(result) => Ok(AnswerEvent {
+                    event: Some(answer_event::Event::Answer(answer(result))),
+                }),
+                Err(e) => Err(internal(e)),
+            };
+            // the client may be gone already
+            let _ = tx.send(event);
+        }));
+
+        Ok(Response::new(Box::pin(UnboundedReceiverStream::new(rx))))
+    }
+
+    async fn ingest(
+        &self,
+        request: Request<IngestRequest>,
+    ) -> Result<Response<IngestResponse>, Status> {
+        let key = self.authorize(&request, Role::Ingest)?;
+        if is_replica() {
+            return Err(Status::permission_denied("This server is a read-only replica"));
+        }
+        let request = request.into_inner();
+        if request.text.trim().is_empty() {
+            return Err(Status::invalid_argument("Nothing to memorize"));
+        }
+        let title = if request.title.is_empty() {
+            format!("gRPC input on {}", chrono::Utc::now().date_naive())
+        } else {
+            request.title
+        };
+        if !key.covers(&title, "grpc") {
+            return Err(Status::permission_denied("The key can't memorize to this collection"));
+        }
+        let id = ingest_text(&title, &request.text, "grpc").await.map_err(internal)?;
+        Ok(Response::new(IngestResponse { id }))
+    }
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<SearchResponse>, Status> {
+        let key = self.authorize(&request, Role::ReadOnly)?;
+        let request = request.into_inner();
+        access::scope(key, async move {
+            let page_size = request.page_size as usize;
+            let (query, mut clauses) =
+                Clauses::parse(&request.query).map_err(|e| Status::invalid_argument(e.to_string()))?;
+            clauses.extend(Clauses {
+                must: request.must,
+                should: request.should,
+                must_not: request.must_not,
+                ..Default::default()
+            });
+            let page = if !request.cursor.is_empty() {
+                next_page(&request.cursor, page_size.max(1)).await.map_err(internal)?
+            } else {
+                let query = embed(&query).await.map_err(internal)?;
+                if page_size == 0 {
+                    let chunks = search_with(query, &clauses, 4).await.map_err(internal)?;
+                    SearchPage {
+                        total: chunks.len(),
+                        chunks,
+                        next_cursor: None,
+                    }
+                } else {
+                    search_page(query, &clauses, page_size).await.map_err(internal)?
+                }
+            };
+            audit::record_query("search", &query, &page.chunks);
+            stats::record_search().await;
+            Ok::<_, Status>(Response::new(SearchResponse {
+                chunks: page.chunks.into_iter().map(chunk).collect(),
+                next_cursor: page.next_cursor.unwrap_or_default(),
+                total: page.total as u32,
+            }))
+        })
+        .await
+    }
+
+    async fn switch_model(
+        &self,
+        request: Request<SwitchModelRequest>,
+    ) -> Result<Response<SwitchModelResponse>, Status> {
+        self.authorize(&request, Role::Admin)?;
+        let model = Model::from_str(&request.into_inner().model, true).map_err(Status::invalid_argument)?;
+        debug!(model =? model, "Warming up model");
+        // the previous model keeps answering while the new one loads, requests under way finish with it
+        tokio::task::spawn_blocking(move || model.warm_up())
+            .await
+            .map_err(internal)?
+            .map_err(internal)?;
+        let previous = std::mem::replace(&mut *ACTIVE_MODEL.write().unwrap_or_else(|e| e.into_inner()), model);
+        println!("Answering with {} instead of {}", model_name(model), model_name(previous));
+        Ok(Response::new(SwitchModelResponse {
+            previous: model_name(previous),
+        }))
+    }
+}
+
+// Loads the model answering by default before listening, so the first request doesn't wait for it
+async fn load_model(model: Model) -> anyhow::Result<()> {
+    tokio::task::spawn_blocking(move || model.warm_up()).await??;
+    *ACTIVE_MODEL.write().unwrap_or_else(|e| e.into_inner()) = model;
+    Ok(())
+}
+
+/// Serves the Tera gRPC service until Ctrl+C or SIGTERM, answering with the model unless asked otherwise.
+/// Requests under way get the shutdown deadline to finish.
+pub async fn serve(addr: SocketAddr, model: Model) -> anyhow::Result<()> {
+    load_model(model).await?;
+    shutdown::listen();
+    CONFIG_MANAGER.watch();
+    println!("Serving Tera over gRPC on {}", addr);
+    tonic::transport::Server::builder()
+        .add_service(TeraServer::new(TeraService::default()))
+        .serve_with_shutdown(addr, shutdown::wait())
+        .await?;
+    shutdown::finish();
+    Ok(())
+}
+
+/// Serves over a unix socket instead of TCP, which only the current user can connect to
+#[cfg(unix)]
+pub async fn serve_socket(path: &Path, model: Model) -> anyhow::Result<()> {
+    load_model(model).await?;
+    let listener = bind_private_socket(path)?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    shutdown::listen();
+    CONFIG_MANAGER.watch();
+    println!("Serving Tera over gRPC on {}", path.display());
+    tonic::transport::Server::builder()
+        .add_service(TeraServer::new(TeraService { local: true }))
+        .serve_with_incoming_shutdown(UnixListenerStream::new(listener), shutdown::wait())
+        .await?;
+    let _ = std::fs::remove_file(path);
+    shutdown::finish();
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use crate::config::{InferenceBackend, CONFIG};
+use crate::database::DB;
+use crate::inference::Model;
+use crate::vector_file::vector_file_path;
+#[cfg(feature = "scheduler")]
+use crate::scheduler;
+use crate::shutdown;
+use anyhow::{Error, Result};
+use serde::Serialize;
+
+// Free space under which memorizing content may soon fail, and under which it does
+#[cfg(unix)]
+const LOW_DISK_SPACE: u64 = 1024 * 1024 * 1024;
+#[cfg(unix)]
+const NO_DISK_SPACE: u64 = 100 * 1024 * 1024;
+
+/// Best first, a component down makes Tera unable to answer
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    /// Working, with something to look at
+    Degraded,
+    Down,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Component {
+    /// `server`, `model`, `index`, `disk` or `scheduler`
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+/// The state of Tera and of what it depends on
+#[derive(Serialize, Debug, Clone)]
+pub struct Health {
+    /// The worst status of the components
+    pub status: Status,
+    pub components: Vec<Component>,
+}
+
+impl Health {
+    /// Whether questions can be answered, degraded components aside
+    pub fn ready(&self) -> bool {
+        self.status != Status::Down
+    }
+}
+
+fn component(name: &'static str, status: Status, detail: impl Into<String>) -> Component {
+    Component {
+        name,
+        status,
+        detail: detail.into(),
+    }
+}
+
+fn server() -> Component {
+    if shutdown::requested() {
+        return component("server", Status::Down, "Stopping");
+    }
+    component("server", Status::Ok, "Running")
+}
+
+fn model(model: Model) -> Component {
+    match &CONFIG.inference {
+        InferenceBackend::Local if model.is_loaded() => component("model", Status::Ok, format!("{:?} loaded", model)),
+        InferenceBackend::Local => component(
+            "model",
+            Status::Degraded,
+            format!("{:?} not loaded yet, the first question loads it", model),
+        ),
+        InferenceBackend::Remote { base_url, .. } => component("model", Status::Ok, format!("Served by {}", base_url)),
+        InferenceBackend::Ollama { base_url, model: name } => {
+            component("model", Status::Ok, format!("{} served by Ollama at {}", name, base_url))
+        }
+    }
+}
+
+async fn count_chunks() -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT count() AS total FROM vector_index GROUP ALL").await?;
+    let total: Option<usize> = result.take((0, "total"))?;
+    Ok(total.unwrap_or(0))
+}
+
+async fn index() -> Component {
+    let chunks = match count_chunks().await {
+        Ok(chunks) => chunks,
+        Err(e) => return component("index", Status::Down, format!("Unable to query the database: {}", e)),
+    };
+    let path = vector_file_path();
+    // a missing vector file is built by the next search
+    if CONFIG.mmap_vectors && path.exists() {
+        if let Err(e) = std::fs::File::open(&path) {
+            return component("index", Status::Down, format!("Unable to open {}: {}", path.display(), e));
+        }
+    }
+    component("index", Status::Ok, format!("{} chunks", chunks))
+}
+
+#[cfg(unix)]
+fn disk() -> Component {
+    let free = match crate::platform::free_space(&crate::config::data_dir()) {
+        Ok(free) => free,
+        Err(e) => return component("disk", Status::Degraded, format!("{:#}", e)),
+    };
+    let detail = format!("{} MB free", free / (1024 * 1024));
+    match free {
+        free if free < NO_DISK_SPACE => component("disk", Status::Down, detail),
+        free if free < LOW_DISK_SPACE => component("disk", Status::Degraded, detail),
+        _ => component("disk", Status::Ok, detail),
+    }
+}
+
+#[cfg(not(unix))]
+fn disk() -> Component {
+    component("disk", Status::Ok, "Free space isn't checked on this platform")
+}
+
+#[cfg(feature = "scheduler")]
+async fn count_jobs() -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT count() AS total FROM job GROUP ALL").await?;
+    let total: Option<usize> = result.take((0, "total"))?;
+    Ok(total.unwrap_or(0))
+}
+
+// Scheduled jobs don't run without `tera scheduler`, answers aren't affected
+#[cfg(feature = "scheduler")]
+async fn scheduler() -> Component {
+    if let Some(at) = scheduler::last_heartbeat() {
+        return component("scheduler", Status::Ok, format!("Running, last seen {}", at.to_rfc3339()));
+    }
+    match count_jobs().await {
+        Ok(0) => component("scheduler", Status::Ok, "Not running, no jobs scheduled"),
+        Ok(jobs) => component("scheduler", Status::Degraded, format!("Not running, {} jobs are waiting", jobs)),
+        Err(e) => component("scheduler", Status::Degraded, format!("Unable to list jobs: {}", e)),
+    }
+}
+
+/// Checks what answering with the model depends on, for `/readyz`
+pub async fn check(for_model: Model) -> Health {
+    #[cfg_attr(not(feature = "scheduler"), allow(unused_mut))]
+    let mut components = vec![server(), model(for_model), index().await, disk()];
+    #[cfg(feature = "scheduler")]
+    components.push(scheduler().await);
+    Health {
+        status: components.iter().map(|c| c.status).max().unwrap_or(Status::Ok),
+        components,
+    }
+}
@@ -0,0 +1,358 @@
+use crate::chat::Session;
+use crate::config::{ApiKey, Role, CONFIG, CONFIG_MANAGER};
+use crate::database::{search_with, Clauses, VectorIndex};
+use crate::embeddings::{embed, embed_texts};
+use crate::inference::AnswerOptions;
+use crate::provenance::Provenance;
+use crate::queue::{self, Priority};
+use crate::{access, health, shutdown, stats, topics};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinSet;
+use tracing::debug;
+
+// Largest request body accepted
+const MAX_BODY: usize = 1024 * 1024;
+// Longest request or header line accepted, and most headers
+const MAX_LINE: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+// Time a client gets to send the whole request, a slow one doesn't hold its handler for longer
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// Conversations are forgotten after this long without a question, Home Assistant gives up on them after 5 minutes
+const CONVERSATION_TTL: Duration = Duration::from_secs(10 * 60);
+// Notes of each entity the question is about given to the model
+const ENTITY_CHUNKS: usize = 2;
+// Days summarized by `/stats`
+const STATS_DAYS: i64 = 30;
+// Texts embedded by a request to `/embed`
+const MAX_EMBED_TEXTS: usize = 256;
+// Source of the notes about devices
+const NOTES_SOURCE: &str = "Home Assistant";
+
+// By API key and conversation id, so a key can't carry on the conversation of another one
+type Conversations = Arc<Mutex<HashMap<(String, String), (Instant, Session)>>>;
+
+/// A question of the conversation API of Home Assistant
+#[derive(Deserialize, Debug)]
+struct ConversationRequest {
+    text: String,
+    conversation_id: Option<String>,
+    language: Option<String>,
+    /// Entities the question is about, e.g. those of the area of the voice satellite, `*` being a wildcard
+    #[serde(default)]
+    entity_ids: Vec<String>,
+    /// Profile of the config answering, e.g. one per voice assistant, kept for the rest of the conversation
+    profile: Option<String>,
+}
+
+/// A note about a device, e.g. when its filter was last changed
+#[derive(Deserialize, Debug)]
+struct NoteRequest {
+    text: String,
+    entity_id: String,
+    title: Option<String>,
+    /// Friendly name of the entity, e.g. "Living room thermostat"
+    name: Option<String>,
+    area: Option<String>,
+}
+
+/// Texts to embed for another app
+#[derive(Deserialize, Debug)]
+struct EmbedRequest {
+    texts: Vec<String>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+// A line of the request, ending it without its line ending
+async fn read_line(reader: &mut BufReader<&mut TcpStream>) -> Result<String> {
+    let mut line = String::new();
+    (&mut *reader).take(MAX_LINE).read_line(&mut line).await?;
+    if line.len() as u64 == MAX_LINE && !line.ends_with('\n') {
+        anyhow::bail!("Request line longer than {} bytes", MAX_LINE);
+    }
+    Ok(line)
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let line = read_line(&mut reader).await?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().context("Empty request")?.to_string();
+    let path = parts.next().context("Request without a path")?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_line(&mut reader).await?;
+        if line.trim().is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            anyhow::bail!("Request with more than {} headers", MAX_HEADERS);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let length = headers
+        .get("content-length")
+        .map(|l| l.parse::<usize>())
+        .transpose()?
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        anyhow::bail!("Request body of {} bytes is too large", length);
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &Value) -> Result<()> {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+// The role a key needs for an endpoint
+fn required_role(method: &str, path: &str) -> Role {
+    match (method, path) {
+        ("POST", "/api/notes") => Role::Ingest,
+        ("GET", "/stats") => Role::Admin,
+        _ => Role::ReadOnly,
+    }
+}
+
+// The notes of the entities, best match of the question first
+async fn entity_notes(question: &str, entity_ids: &[String]) -> Result<Vec<VectorIndex>> {
+    if entity_ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let query = embed(question).await?;
+    let mut notes = vec![];
+    for entity_id in entity_ids {
+        let clauses = Clauses {
+            metadata: HashMap::from([("entity_id".to_string(), entity_id.clone())]),
+            ..Default::default()
+        };
+        notes.extend(search_with(query.clone(), &clauses, ENTITY_CHUNKS).await?);
+    }
+    Ok(notes)
+}
+
+// The answer in the shape of a Home Assistant `ConversationResult`
+async fn process(
+    request: ConversationRequest,
+    key: &ApiKey,
+    conversations: &Conversations,
+    options: &AnswerOptions,
+) -> Value {
+    let language = request.language.clone().unwrap_or_else(|| "en".to_string());
+    let conversation_id = request
+        .conversation_id
+        .clone()
+        .unwrap_or_else(|| surrealdb::sql::Uuid::new_v4().0.to_string().replace("-", ""));
+    let conversation = (key.key.clone(), conversation_id.clone());
+
+    let mut session = {
+        let mut conversations = conversations.lock().await;
+        conversations.retain(|_, (last, _)| last.elapsed() < CONVERSATION_TTL);
+        match conversations.remove(&conversation) {
+            Some((_, session)) => session,
+            None => Session::new(options.clone()),
+        }
+    };
+    if request.profile.is_some() {
+        session.profile = request.profile.clone();
+    }
+    let result = match entity_notes(&request.text, &request.entity_ids).await {
+        Ok(notes) => session.ask_with(&request.text, notes).await,
+        Err(e) => Err(e),
+    };
+    conversations
+        .lock()
+        .await
+        .insert(conversation, (Instant::now(), session));
+
+    let response = match result {
+        Ok(answer) => json!({
+            "response_type": "query_answer",
+            "language": language,
+            "speech": {"plain": {"speech": answer.text, "extra_data": null}},
+            "card": {},
+            "data": {"targets": [], "success": [], "failed": []},
+        }),
+        Err(e) => {
+            debug!(error = ?e, "Unable to answer");
+            json!({
+                "response_type": "error",
+                "language": language,
+                "speech": {"plain": {"speech": "Sorry, I couldn't answer that.", "extra_data": null}},
+                "card": {},
+                "data": {"code": "unknown"},
+            })
+        }
+    };
+    json!({"conversation_id": conversation_id, "response": response})
+}
+
+async fn save_note(note: NoteRequest) -> Result<Value> {
+    let title = note
+        .title
+        .or_else(|| note.name.clone())
+        .unwrap_or_else(|| format!("Note about {}", note.entity_id));
+    let content = queue::memorize(
+        Priority::Interactive,
+        &title,
+        note.text,
+        json!({
+            "connector": "home_assistant",
+            "source": NOTES_SOURCE,
+            "entity_id": note.entity_id,
+            "name": note.name,
+            "area": note.area,
+            "upload_time": chrono::Utc::now(),
+            "provenance": Provenance::new(format!("home_assistant:{}", note.entity_id)),
+        }),
+    )
+    .await?;
+    Ok(json!({"id": content.id.id.to_raw()}))
+}
+
+async fn handle(mut stream: TcpStream, conversations: Conversations, options: AnswerOptions) -> Result<()> {
+    let request = tokio::time::timeout(READ_TIMEOUT, read_request(&mut stream))
+        .await
+        .context("The client didn't send its request in time")??;
+    debug!(method = request.method, path = request.path, "Home Assistant request");
+    // probes of monitoring don't carry the token, and see no content
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/healthz") => return respond(&mut stream, "200 OK", &json!({"status": "ok"})).await,
+        ("GET", "/readyz") => {
+            let health = health::check(options.model).await;
+            let status = if health.ready() { "200 OK" } else { "503 Service Unavailable" };
+            return respond(&mut stream, status, &json!(health)).await;
+        }
+        _ => {}
+    }
+    let authorization = request.headers.get("authorization").map(String::as_str);
+    let Some(key) = access::authenticate(authorization, CONFIG.home_assistant.token.as_deref()) else {
+        return respond(&mut stream, "401 Unauthorized", &json!({"error": "Invalid token"})).await;
+    };
+    if key.role < required_role(&request.method, &request.path) {
+        let error = format!("The key can't {} {}", request.method, request.path);
+        return respond(&mut stream, "403 Forbidden", &json!({"error": error})).await;
+    }
+    debug!(key = ?key.name, role = ?key.role, "Authenticated");
+    access::scope(key.clone(), route(&mut stream, request, &key, conversations, options)).await
+}
+
+// Answers a request the key may make
+async fn route(
+    stream: &mut TcpStream,
+    request: Request,
+    key: &ApiKey,
+    conversations: Conversations,
+    options: AnswerOptions,
+) -> Result<()> {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/api/conversation/process") => match serde_json::from_slice::<ConversationRequest>(&request.body) {
+            Ok(question) => {
+                let response = process(question, key, &conversations, &options).await;
+                respond(stream, "200 OK", &response).await
+            }
+            Err(e) => respond(stream, "400 Bad Request", &json!({"error": e.to_string()})).await,
+        },
+        ("POST", "/api/notes") => match serde_json::from_slice::<NoteRequest>(&request.body) {
+            Ok(_) if !access::writable(NOTES_SOURCE, "home_assistant") => {
+                let error = "The key can't memorize notes of Home Assistant";
+                respond(stream, "403 Forbidden", &json!({"error": error})).await
+            }
+            Ok(note) => match save_note(note).await {
+                Ok(saved) => respond(stream, "200 OK", &saved).await,
+                Err(e) => respond(stream, "500 Internal Server Error", &json!({"error": e.to_string()})).await,
+            },
+            Err(e) => respond(stream, "400 Bad Request", &json!({"error": e.to_string()})).await,
+        },
+        ("POST", "/embed") => match serde_json::from_slice::<EmbedRequest>(&request.body) {
+            Ok(request) if request.texts.len() > MAX_EMBED_TEXTS => {
+                let error = format!("At most {} texts can be embedded at once", MAX_EMBED_TEXTS);
+                respond(stream, "400 Bad Request", &json!({"error": error})).await
+            }
+            Ok(request) => match embed_texts(&request.texts).await {
+                Ok(vectors) => {
+                    let dimensions = vectors.first().map(|v| v.len()).unwrap_or(0);
+                    respond(stream, "200 OK", &json!({"vectors": vectors, "dimensions": dimensions})).await
+                }
+                Err(e) => respond(stream, "500 Internal Server Error", &json!({"error": e.to_string()})).await,
+            },
+            Err(e) => respond(stream, "400 Bad Request", &json!({"error": e.to_string()})).await,
+        },
+        ("GET", "/topics") if !access::unrestricted() => {
+            let error = "Topics list documents of every collection, the key only reads some";
+            respond(stream, "403 Forbidden", &json!({"error": error})).await
+        }
+        ("GET", "/topics") => match topics::list_topics().await {
+            Ok(topics) => respond(stream, "200 OK", &json!(topics)).await,
+            Err(e) => respond(stream, "500 Internal Server Error", &json!({"error": e.to_string()})).await,
+        },
+        ("GET", "/stats") => match stats::stats(STATS_DAYS).await {
+            Ok(stats) => respond(stream, "200 OK", &json!(stats)).await,
+            Err(e) => respond(stream, "500 Internal Server Error", &json!({"error": e.to_string()})).await,
+        },
+        _ => respond(stream, "404 Not Found", &json!({"error": "Unknown endpoint"})).await,
+    }
+}
+
+/// Serves the conversation API of Home Assistant, so voice assistants in the home can ask Tera, an
+/// endpoint memorizing notes about devices, embeddings for other apps and health checks until Ctrl+C
+/// or SIGTERM
+pub async fn serve(addr: SocketAddr, options: AnswerOptions) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    shutdown::listen();
+    CONFIG_MANAGER.watch();
+    println!("Serving Home Assistant on http://{}, press Ctrl+C to stop.", addr);
+    let conversations = Conversations::default();
+    let mut requests = JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // reaps finished requests
+            Some(_) = requests.join_next(), if !requests.is_empty() => continue,
+            _ = shutdown::wait() => break,
+        };
+        let conversations = conversations.clone();
+        let options = options.clone();
+        requests.spawn(async move {
+            if let Err(e) = handle(stream, conversations, options).await {
+                debug!(error = ?e, peer = ?peer, "Home Assistant request failed");
+            }
+        });
+    }
+    // questions under way get answered, cut once the deadline passes
+    while requests.join_next().await.is_some() {}
+    shutdown::finish();
+    Ok(())
+}
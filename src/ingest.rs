@@ -1,21 +1,333 @@
-use crate::database::{insert_content, insert_vector_index, smart_insert_content};
+use crate::calendar::{parse_ics, DATE_FORMAT};
+use crate::access;
+use crate::config::{JsonMapping, CONFIG};
+use crate::connectors::record_run;
+use crate::contacts::{self, link_existing_chunks, parse_vcf, upsert_person};
+use crate::database::{
+    commit_content, insert_content, insert_vector_index, is_dry_run, remove_content, storing, with_dry_run, DB,
+};
+use crate::platform::{read_stdin, read_text_file};
+use crate::provenance::Provenance;
+use crate::queue::{self, Priority};
+use crate::redaction::redact;
+use crate::sync::{record, Op};
+#[cfg(feature = "whisper")]
 use crate::whisper::whisper_decode;
 use anyhow::Context;
-use chrono::{NaiveDateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use clap::ValueEnum;
 use regex::Regex;
-use serde_json::json;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use surrealdb::sql::{Datetime, Thing};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+tokio::task_local! {
+    // Set while the files of a folder get memorized side by side, their progress lines would mix
+    static SIDE_BY_SIDE: ();
+}
+
+// Progress through the items of a file, on a line of its own which the next one replaces
+fn progress(items: &str, done: usize, total: usize) {
+    if SIDE_BY_SIDE.try_with(|_| ()).is_ok() {
+        return;
+    }
+    print!("Memorizing {} {}/{}\r", items, done, total);
+    let _ = std::io::stdout().flush();
+}
 
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum IngestType {
     Whatsapp,
+    #[cfg(feature = "pdf")]
     PDF,
     Text,
+    #[cfg(feature = "whisper")]
     Audio,
+    /// iCalendar (.ics) file
+    Calendar,
+    /// vCard (.vcf) address book
+    Contacts,
+    /// CSV or Parquet table
+    #[cfg(feature = "tables")]
+    Table,
+    /// JSON or JSONL export
+    Json,
+    /// Apple Notes database (NoteStore.sqlite) or a folder of exported notes
+    #[cfg(feature = "apple-notes")]
+    AppleNotes,
+    /// Zotero data directory, usually ~/Zotero
+    #[cfg(feature = "pdf")]
+    Zotero,
+    /// Commit history of a git repository
+    Git,
+}
+
+impl IngestType {
+    /// Name of the connector, as in the `connectors` section of the config
+    pub fn connector(&self) -> &'static str {
+        match self {
+            IngestType::Whatsapp => "whatsapp",
+            #[cfg(feature = "pdf")]
+            IngestType::PDF => "pdf",
+            IngestType::Text => "text",
+            #[cfg(feature = "whisper")]
+            IngestType::Audio => "audio",
+            IngestType::Calendar => "calendar",
+            IngestType::Contacts => "contacts",
+            #[cfg(feature = "tables")]
+            IngestType::Table => "table",
+            IngestType::Json => "json",
+            #[cfg(feature = "apple-notes")]
+            IngestType::AppleNotes => "apple_notes",
+            #[cfg(feature = "pdf")]
+            IngestType::Zotero => "zotero",
+            IngestType::Git => "git",
+        }
+    }
+
+    /// Whether uploads of this type are a single file, rather than a folder
+    pub fn reads_single_file(&self) -> bool {
+        match self {
+            #[cfg(feature = "apple-notes")]
+            IngestType::AppleNotes => false,
+            IngestType::Git => false,
+            #[cfg(feature = "pdf")]
+            IngestType::Zotero => false,
+            _ => true,
+        }
+    }
+
+    /// The type of a file going by its name, None for files Tera can't read
+    pub fn of_file(path: &Path) -> Option<IngestType> {
+        let name = path.file_name()?.to_str()?;
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "txt" if name.starts_with("WhatsApp Chat") => Some(IngestType::Whatsapp),
+            "txt" | "md" | "markdown" | "org" | "rst" => Some(IngestType::Text),
+            #[cfg(feature = "pdf")]
+            "pdf" => Some(IngestType::PDF),
+            #[cfg(feature = "whisper")]
+            "wav" => Some(IngestType::Audio),
+            "ics" => Some(IngestType::Calendar),
+            "vcf" => Some(IngestType::Contacts),
+            #[cfg(feature = "tables")]
+            "csv" | "parquet" => Some(IngestType::Table),
+            "json" | "jsonl" => Some(IngestType::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Memorizes a file or directory with the connector of the given type
+pub async fn ingest_path(content_type: IngestType, path: PathBuf, mapping: &JsonMapping) -> anyhow::Result<()> {
+    let connector = content_type.connector();
+    if !CONFIG.connector(connector).enabled {
+        anyhow::bail!("The {} connector is disabled in the config", connector);
+    }
+    // each document of the upload waits in the ingestion queue for a worker
+    let result = match content_type.reads_single_file() && path.is_file() && !is_dry_run() {
+        true => upload_file(content_type, path, mapping).await,
+        false => run_connector(content_type, path, mapping).await,
+    };
+    if !is_dry_run() {
+        // the outcome of the upload is what matters to the caller
+        if let Err(e) = record_run(connector, &result).await {
+            eprintln!("Unable to record the run of connector {}: {}", connector, e);
+        }
+    }
+    result
+}
+
+/// A file memorized whole, so that uploading it again, e.g. on a schedule, skips it while it is
+/// unchanged and replaces what was memorized of it once it changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UploadedFile {
+    path: String,
+    modified: Option<Datetime>,
+    /// SHA-256 of the file
+    hash: String,
+    content_ids: Vec<Thing>,
+}
+
+// Whether some of the contents are still memorized, e.g. not forgotten since
+async fn still_memorized(content_ids: &[Thing]) -> anyhow::Result<bool> {
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT VALUE id FROM content WHERE id INSIDE $ids LIMIT 1")
+        .bind(("ids", content_ids.to_vec()))
+        .await?;
+    let found: Vec<Thing> = result.take(0)?;
+    Ok(!found.is_empty())
+}
+
+fn file_hash(path: &Path) -> anyhow::Result<String> {
+    Ok(format!("{:x}", Sha256::digest(std::fs::read(path)?)))
+}
+
+async fn upload_file(content_type: IngestType, path: PathBuf, mapping: &JsonMapping) -> anyhow::Result<()> {
+    let key = path.canonicalize()?.display().to_string();
+    let modified = std::fs::metadata(&path)?
+        .modified()
+        .ok()
+        .map(|m| Datetime::from(DateTime::<Utc>::from(m)));
+    let db = DB.get().await.clone();
+    let previous: Option<UploadedFile> = db.select(("uploaded_file", key.as_str())).await?;
+
+    // the modification time spares reading the file again, the hash catches a file touched but unchanged
+    let mut hash = None;
+    if let Some(previous) = &previous {
+        let unchanged = match modified.is_some() && previous.modified == modified {
+            true => true,
+            false => previous.hash == *hash.insert(file_hash(&path)?),
+        };
+        if unchanged && still_memorized(&previous.content_ids).await? {
+            println!("{} is unchanged since it was memorized", path.display());
+            return Ok(());
+        }
+    }
+    let hash = match hash {
+        Some(hash) => hash,
+        None => file_hash(&path)?,
+    };
+
+    let (result, content_ids) = storing(run_connector(content_type, path, mapping)).await;
+    result?;
+    for id in previous.into_iter().flat_map(|p| p.content_ids) {
+        if content_ids.contains(&id) {
+            continue;
+        }
+        remove_content(&id).await?;
+        record(Op::Delete { content_id: id }).await?;
+    }
+    let _: Option<UploadedFile> = db
+        .update(("uploaded_file", key.as_str()))
+        .content(UploadedFile {
+            path: key.clone(),
+            modified,
+            hash,
+            content_ids,
+        })
+        .await?;
+    Ok(())
+}
+
+async fn run_connector(content_type: IngestType, path: PathBuf, mapping: &JsonMapping) -> anyhow::Result<()> {
+    match content_type {
+        IngestType::Whatsapp => ingest_wa_chat_log(path).await,
+        IngestType::Text => ingest_via_txt_file(path).await,
+        #[cfg(feature = "pdf")]
+        IngestType::PDF => ingest_via_pdf_file(path).await,
+        #[cfg(feature = "whisper")]
+        IngestType::Audio => ingest_via_audio_file(path).await,
+        IngestType::Calendar => ingest_via_ics_file(path).await,
+        IngestType::Contacts => ingest_via_vcf_file(path).await,
+        #[cfg(feature = "tables")]
+        IngestType::Table => crate::tables::ingest_table(path).await,
+        IngestType::Json => ingest_via_json_file(path, mapping).await,
+        #[cfg(feature = "apple-notes")]
+        IngestType::AppleNotes => crate::apple_notes::ingest_apple_notes(path).await,
+        #[cfg(feature = "pdf")]
+        IngestType::Zotero => crate::zotero::ingest_zotero_library(path).await,
+        IngestType::Git => crate::git_history::ingest_git_history(path).await,
+    }
+}
+
+/// Outcome of `ingest_directory`
+#[derive(Debug, Default)]
+pub struct BulkReport {
+    pub memorized: Vec<PathBuf>,
+    /// Files of a type Tera can't read
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, anyhow::Error)>,
+}
+
+// Files under `dir` sorted by path, hidden files and directories left out. A directory linked to
+// from within itself, or twice, is read once.
+fn walk(dir: &Path, visited: &mut HashSet<PathBuf>, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    if !visited.insert(dir.canonicalize().with_context(|| format!("Unable to read {}", dir.display()))?) {
+        return Ok(());
+    }
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Unable to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| !path.file_name().and_then(|n| n.to_str()).unwrap_or(".").starts_with('.'))
+        .collect::<Vec<_>>();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            walk(&path, visited, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Memorizes every file Tera can read under a directory, `concurrency` files at a time on the
+/// workers of the runtime, only those of type `only` if set. A file which fails is reported without
+/// stopping the others.
+pub async fn ingest_directory(
+    dir: &Path,
+    concurrency: usize,
+    mapping: &JsonMapping,
+    only: Option<IngestType>,
+) -> anyhow::Result<BulkReport> {
+    let mut files = vec![];
+    walk(dir, &mut HashSet::new(), &mut files)?;
+
+    let mut report = BulkReport::default();
+    let mut supported = vec![];
+    for path in files {
+        // disabled connectors and filtered out files count as skipped
+        match IngestType::of_file(&path) {
+            Some(content_type)
+                if (only.is_none() || only == Some(content_type))
+                    && CONFIG.connector(content_type.connector()).enabled
+                    && CONFIG.connector(content_type.connector()).accepts(&path) =>
+            {
+                supported.push((content_type, path))
+            }
+            _ => report.skipped.push(path),
+        }
+    }
+
+    let total = supported.len();
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mapping = Arc::new(mapping.clone());
+    let slots = Arc::new(Semaphore::new(concurrency.max(1)));
+    // the files are memorized for the client asking, with its access
+    let key = access::granted();
+    let dry_run = is_dry_run();
+    let mut uploads = JoinSet::new();
+    for (content_type, path) in supported {
+        let slot = slots.clone().acquire_owned().await?;
+        let (mapping, finished) = (mapping.clone(), finished.clone());
+        let upload = with_dry_run(dry_run, SIDE_BY_SIDE.scope((), async move {
+            let result = ingest_path(content_type, path.clone(), &mapping).await;
+            drop(slot);
+            println!("Processed {}/{} files", finished.fetch_add(1, Ordering::Relaxed) + 1, total);
+            (path, result)
+        }));
+        match key.clone() {
+            Some(key) => uploads.spawn(access::scope(key, upload)),
+            None => uploads.spawn(upload),
+        };
+    }
+    while let Some(done) = uploads.join_next().await {
+        match done? {
+            (path, Ok(())) => report.memorized.push(path),
+            (path, Err(e)) => report.failed.push((path, e)),
+        }
+    }
+    Ok(report)
 }
 
 #[derive(Debug)]
@@ -27,25 +339,20 @@ pub struct Message {
 
 pub async fn ingest_wa_chat_log(path: PathBuf) -> anyhow::Result<()> {
     let display = path.display();
-    let file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display, why),
-        Ok(file) => file,
-    };
+    let log = read_text_file(&path).with_context(|| format!("couldn't open {}", display))?;
     println!("Processing WhatsApp chat log from {}", display);
 
-    let reader = BufReader::new(file);
     let date_pattern = Regex::new(r"\[\d{4}-\d{2}-\d{2}, \d{2}:\d{2}:\d{2}\]").unwrap();
     let mut messages: Vec<Message> = Vec::new();
     let mut last_date = String::new();
     let mut last_sender = String::new();
     let mut last_content = String::new();
 
-    for line in reader.lines() {
-        let line = line.unwrap();
+    for line in log.lines() {
         if line.starts_with("\u{200e}") {
             continue;
         }
-        if let Some(date) = date_pattern.find(&line) {
+        if let Some(date) = date_pattern.find(line) {
             if !last_content.is_empty() {
                 last_content = last_content.trim().to_string();
                 let date =
@@ -82,7 +389,7 @@ pub async fn ingest_wa_chat_log(path: PathBuf) -> anyhow::Result<()> {
             if last_date.is_empty() {
                 continue;
             }
-            last_content += &line;
+            last_content += line;
         }
     }
 
@@ -99,25 +406,36 @@ pub async fn ingest_wa_chat_log(path: PathBuf) -> anyhow::Result<()> {
         participants.len()
     );
 
-    let title = "WhatsApp Chat between ".to_string() + &participants.join(", ");
+    // the names and numbers of the participants get masked like the messages, in the title and metadata too
+    let mut masked = HashMap::new();
+    for participant in participants.iter() {
+        masked.insert(participant.clone(), redact("whatsapp", participant)?);
+    }
+    let title = "WhatsApp Chat between ".to_string()
+        + &participants.iter().map(|p| masked[p].as_str()).collect::<Vec<_>>().join(", ");
     // content format is: "DATE;;;;;SENDER;;;;;CONTENT\n"
     let content = messages
         .iter()
-        .map(|m| format!("{};;;;;{};;;;;{}", m.date, m.sender, m.content))
+        .map(|m| format!("{};;;;;{};;;;;{}", m.date, masked[&m.sender], m.content))
         .collect::<Vec<String>>()
         .join("\n");
 
-    let content = insert_content(title.as_str(), content.as_str())
+    // waits for the documents saved meanwhile by hand, and paces a folder to the workers
+    let _permit = queue::admit(Priority::Bulk).await;
+    let content = insert_content(title.as_str(), content.as_str(), "whatsapp")
         .await
         .context("Unable to insert content")?;
+    let mut provenance = Provenance::file(&path).step("chat_parsing");
+    // exports are named after the chat
+    provenance.uri = provenance.uri.map(|uri| redact("whatsapp", &uri)).transpose()?;
 
     for (i, message) in messages.iter().enumerate() {
-        print!("Memorizing messages {}/{}\r", i + 1, messages.len());
+        progress("messages", i + 1, messages.len());
         // receivers = participants - sender
-        let receivers: Vec<String> = participants
+        let receivers: Vec<&str> = participants
             .iter()
             .filter(|p| *p != &message.sender)
-            .cloned()
+            .map(|p| masked[p].as_str())
             .collect();
 
         if message.content.is_empty() {
@@ -129,10 +447,12 @@ pub async fn ingest_wa_chat_log(path: PathBuf) -> anyhow::Result<()> {
             i as u16,
             &message.content,
             json!({
-                "sender": message.sender,
+                "connector": "whatsapp",
+                "sender": masked[&message.sender],
                 "receivers": receivers,
                 "date": message.date.to_string(),
                 "source": title.clone(),
+                "provenance": provenance,
             }),
         )
         .await;
@@ -148,15 +468,18 @@ pub async fn ingest_wa_chat_log(path: PathBuf) -> anyhow::Result<()> {
         }
     }
     println!("Memorized {}", title);
+    commit_content(&content.id).await?;
 
     Ok(())
 }
 
 pub async fn ingest_via_cli(content: &str) -> anyhow::Result<()> {
-    smart_insert_content(
+    queue::memorize(
+        Priority::Interactive,
         &format!("Direct insert on {}", Utc::now().date_naive()),
-        &content,
+        content.to_string(),
         json!({
+            "connector": "remember",
             "source": "direct insert",
             "time": Utc::now(),
         }),
@@ -165,6 +488,35 @@ pub async fn ingest_via_cli(content: &str) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Memorizes stdin and returns the id of the new content
+pub async fn ingest_via_stdin(title: Option<String>) -> anyhow::Result<String> {
+    let content = read_stdin()?;
+    if content.trim().is_empty() {
+        anyhow::bail!("Nothing to memorize on stdin");
+    }
+    let title = title.unwrap_or_else(|| format!("Piped input on {}", Utc::now().date_naive()));
+
+    ingest_text(&title, &content, "stdin").await
+}
+
+/// Memorizes a piece of text under the given title and returns the id of the new content.
+/// `connector` tells where the text comes from, e.g. `stdin` or `grpc`.
+pub async fn ingest_text(title: &str, content: &str, connector: &str) -> anyhow::Result<String> {
+    let content = queue::memorize(
+        Priority::Interactive,
+        title,
+        content.to_string(),
+        json!({
+            "connector": connector,
+            "source": title,
+            "upload_time": Utc::now(),
+        }),
+    )
+    .await?;
+
+    Ok(content.id.id.to_raw())
+}
+
 pub async fn ingest_via_txt_file(path: PathBuf) -> anyhow::Result<()> {
     let display = path.display();
     let file_name = path
@@ -172,26 +524,21 @@ pub async fn ingest_via_txt_file(path: PathBuf) -> anyhow::Result<()> {
         .context("Unable to get file name")?
         .to_str()
         .context("Unable to convert file name to string")?;
-    let file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display, why),
-        Ok(file) => file,
-    };
+    let text = read_text_file(&path).with_context(|| format!("couldn't open {}", display))?;
     println!("Processing text file from {}", display);
 
-    let reader = BufReader::new(file);
     // read all lines and create a single string with "\n" as separator
-    let content = reader
-        .lines()
-        .map(|l| l.unwrap())
-        .collect::<Vec<String>>()
-        .join("\n");
+    let content = text.lines().collect::<Vec<&str>>().join("\n");
 
-    let content = smart_insert_content(
+    let content = queue::memorize(
+        Priority::Bulk,
         &format!("Contents of {:?}", file_name),
-        &content,
+        content,
         json!({
+            "connector": "text",
             "source": file_name,
             "upload_time": Utc::now(),
+            "provenance": Provenance::file(&path),
         }),
     )
     .await?;
@@ -201,10 +548,11 @@ pub async fn ingest_via_txt_file(path: PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "pdf")]
 pub async fn ingest_via_pdf_file(path: PathBuf) -> anyhow::Result<()> {
     let display = path.display();
-    let bytes = std::fs::read(path.clone()).unwrap();
-    let out = pdf_extract::extract_text_from_mem(&bytes).unwrap();
+    let bytes = std::fs::read(path.clone()).with_context(|| format!("couldn't open {}", display))?;
+    let out = pdf_extract::extract_text_from_mem(&bytes).context("Unable to extract text from pdf")?;
 
     let file_name = path
         .file_name()
@@ -214,12 +562,15 @@ pub async fn ingest_via_pdf_file(path: PathBuf) -> anyhow::Result<()> {
 
     println!("Processing pdf from {}", display);
 
-    let content = smart_insert_content(
+    let content = queue::memorize(
+        Priority::Bulk,
         &format!("Contents of {:?}", file_name),
-        &out,
+        out,
         json!({
+            "connector": "pdf",
             "source": file_name,
             "upload_time": Utc::now(),
+            "provenance": Provenance::file(&path).step("pdf_text"),
         }),
     )
     .await?;
@@ -229,6 +580,7 @@ pub async fn ingest_via_pdf_file(path: PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(feature = "whisper")]
 pub async fn ingest_via_audio_file(path: PathBuf) -> anyhow::Result<()> {
     let display = path.display();
     let file_name = path
@@ -247,16 +599,15 @@ pub async fn ingest_via_audio_file(path: PathBuf) -> anyhow::Result<()> {
         .collect::<Vec<String>>()
         .join(" ");
 
-    let content = insert_content(file_name, transcription.as_str())
+    // waits for the documents saved meanwhile by hand, and paces a folder to the workers
+    let _permit = queue::admit(Priority::Bulk).await;
+    let content = insert_content(file_name, transcription.as_str(), "audio")
         .await
         .context("Unable to insert content")?;
+    let provenance = Provenance::file(&path).step("transcription");
 
     for (i, transcription_point) in transcription_points.iter().enumerate() {
-        print!(
-            "Memorizing messages {}/{}\r",
-            i + 1,
-            transcription_points.len()
-        );
+        progress("messages", i + 1, transcription_points.len());
 
         if transcription_point.dr.text.is_empty() {
             continue;
@@ -267,10 +618,12 @@ pub async fn ingest_via_audio_file(path: PathBuf) -> anyhow::Result<()> {
             i as u16,
             &transcription_point.dr.text,
             json!({
+                "connector": "audio",
                 "start_time": transcription_point.start,
                 "end_time": transcription_point.start + transcription_point.duration,
                 "upload_time": Utc::now(),
                 "source": file_name,
+                "provenance": provenance,
             }),
         )
         .await;
@@ -286,5 +639,254 @@ pub async fn ingest_via_audio_file(path: PathBuf) -> anyhow::Result<()> {
         }
     }
     println!("Memorized {}", file_name);
+    commit_content(&content.id).await?;
+    Ok(())
+}
+
+pub async fn ingest_via_ics_file(path: PathBuf) -> anyhow::Result<()> {
+    let display = path.display();
+    let file_name = path
+        .file_name()
+        .context("Unable to get file name")?
+        .to_str()
+        .context("Unable to convert file name to string")?;
+
+    println!("Processing calendar from {}", display);
+
+    let ics = read_text_file(&path).context("Unable to read calendar")?;
+    let events = parse_ics(&ics);
+    println!("Extracted {} events", events.len());
+
+    let title = format!("Calendar {}", file_name);
+    // waits for the documents saved meanwhile by hand, and paces a folder to the workers
+    let _permit = queue::admit(Priority::Bulk).await;
+    let content = insert_content(
+        &title,
+        &events
+            .iter()
+            .map(|e| e.to_text())
+            .collect::<Vec<String>>()
+            .join("\n"),
+        "calendar",
+    )
+    .await
+    .context("Unable to insert content")?;
+    let provenance = Provenance::file(&path).step("ics_parsing");
+
+    for (i, event) in events.iter().enumerate() {
+        progress("events", i + 1, events.len());
+
+        let res = insert_vector_index(
+            content.id.clone(),
+            i as u16,
+            &event.to_text(),
+            json!({
+                "connector": "calendar",
+                "type": "event",
+                "summary": event.summary,
+                "location": event.location,
+                "start": event.start.map(|d| d.format(DATE_FORMAT).to_string()),
+                "end": event.end.map(|d| d.format(DATE_FORMAT).to_string()),
+                "upload_time": Utc::now(),
+                "source": file_name,
+                "provenance": provenance,
+            }),
+        )
+        .await;
+
+        match res {
+            Ok(_) => {}
+            Err(e) => {
+                if e.to_string().contains("Content chunk is empty") {
+                    continue;
+                }
+                println!("Unable to insert vector index: {}", e);
+            }
+        }
+    }
+    println!("Memorized {}", title);
+    commit_content(&content.id).await?;
+
+    Ok(())
+}
+
+pub async fn ingest_via_vcf_file(path: PathBuf) -> anyhow::Result<()> {
+    let display = path.display();
+    let file_name = path
+        .file_name()
+        .context("Unable to get file name")?
+        .to_str()
+        .context("Unable to convert file name to string")?;
+
+    println!("Processing contacts from {}", display);
+
+    let vcf = read_text_file(&path).context("Unable to read contacts")?;
+    let people = parse_vcf(&vcf);
+    println!("Extracted {} contacts", people.len());
+    // contacts imported before, e.g. from an older export of the address book, are memorized once
+    let people = contacts::changed(people).await?;
+    if people.is_empty() {
+        println!("No new or changed contacts in {}", display);
+        return Ok(());
+    }
+
+    let title = format!("Contacts {}", file_name);
+    // waits for the documents saved meanwhile by hand, and paces a folder to the workers
+    let _permit = queue::admit(Priority::Bulk).await;
+    let content = insert_content(
+        &title,
+        &people
+            .iter()
+            .map(|p| p.to_text())
+            .collect::<Vec<String>>()
+            .join("\n"),
+        "contacts",
+    )
+    .await
+    .context("Unable to insert content")?;
+    let provenance = Provenance::file(&path).step("vcf_parsing");
+
+    let mut linked = vec![];
+    for (i, person) in people.iter().enumerate() {
+        progress("contacts", i + 1, people.len());
+
+        let id = upsert_person(person).await?;
+        let res = insert_vector_index(
+            content.id.clone(),
+            i as u16,
+            &person.to_text(),
+            json!({
+                "connector": "contacts",
+                "type": "contact",
+                "person": id.to_string(),
+                "name": person.name,
+                "upload_time": Utc::now(),
+                "source": file_name,
+                "provenance": provenance,
+            }),
+        )
+        .await;
+
+        match res {
+            Ok(_) => {}
+            Err(e) => {
+                if e.to_string().contains("Content chunk is empty") {
+                    continue;
+                }
+                println!("Unable to insert vector index: {}", e);
+            }
+        }
+        linked.push((id, person.clone()));
+    }
+    link_existing_chunks(&linked).await?;
+    println!("Memorized {}", title);
+    commit_content(&content.id).await?;
+
+    Ok(())
+}
+
+// Looks up a dotted path such as `author.name` in a record
+fn json_field<'a>(record: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(record, |value, key| match key.parse::<usize>() {
+            Ok(i) if value.is_array() => value.get(i),
+            _ => value.get(key),
+        })
+        .filter(|v| !v.is_null())
+}
+
+fn json_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values.iter().map(json_text).collect::<Vec<_>>().join(", "),
+        value => value.to_string(),
+    }
+}
+
+pub async fn ingest_via_json_file(path: PathBuf, mapping: &JsonMapping) -> anyhow::Result<()> {
+    let display = path.display();
+    let file_name = path
+        .file_name()
+        .context("Unable to get file name")?
+        .to_str()
+        .context("Unable to convert file name to string")?;
+
+    println!("Processing JSON from {}", display);
+
+    let text = read_text_file(&path).context("Unable to read JSON file")?;
+    let records: Vec<Value> = if path.extension().is_some_and(|e| e == "jsonl") {
+        text.lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?
+    } else {
+        let value: Value = serde_json::from_str(&text)?;
+        let value = match &mapping.records {
+            Some(records) => json_field(&value, records)
+                .context("Unable to find the records in the JSON file")?
+                .clone(),
+            None => value,
+        };
+        match value {
+            Value::Array(records) => records,
+            record => vec![record],
+        }
+    };
+
+    let texts = records
+        .iter()
+        .map(|record| {
+            mapping
+                .content
+                .iter()
+                .filter_map(|field| json_field(record, field))
+                .map(json_text)
+                .collect::<Vec<String>>()
+                .join("\n")
+        })
+        .collect::<Vec<String>>();
+    println!("Extracted {} records", records.len());
+
+    let title = format!("Contents of {:?}", file_name);
+    // waits for the documents saved meanwhile by hand, and paces a folder to the workers
+    let _permit = queue::admit(Priority::Bulk).await;
+    let content = insert_content(&title, &texts.join("\n"), "json")
+        .await
+        .context("Unable to insert content")?;
+    let provenance = Provenance::file(&path).step("json_mapping");
+
+    for (i, (record, text)) in records.iter().zip(texts.iter()).enumerate() {
+        progress("records", i + 1, records.len());
+
+        let mut metadata = json!({
+            "connector": "json",
+            "upload_time": Utc::now(),
+            "source": file_name,
+            "provenance": provenance,
+        });
+        for field in mapping.metadata.iter() {
+            if let Some(value) = json_field(record, field) {
+                metadata[field] = value.clone();
+            }
+        }
+        if let Some(date) = mapping.timestamp.as_ref().and_then(|f| json_field(record, f)) {
+            metadata["date"] = Value::String(json_text(date));
+        }
+
+        let res = insert_vector_index(content.id.clone(), i as u16, text, metadata).await;
+
+        match res {
+            Ok(_) => {}
+            Err(e) => {
+                if e.to_string().contains("Content chunk is empty") {
+                    continue;
+                }
+                println!("Unable to insert vector index: {}", e);
+            }
+        }
+    }
+    println!("Memorized {}", title);
+    commit_content(&content.id).await?;
+
     Ok(())
 }
@@ -0,0 +1,70 @@
+use crate::config::{live, InjectionHandling};
+use crate::database::VectorIndex;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::Value;
+use tracing::debug;
+
+// Takes the place of the instructions neutralized in references
+const REMOVED: &str = "[instruction removed]";
+
+lazy_static! {
+    // Text addressing the model rather than the reader, up to the end of its sentence
+    static ref INSTRUCTION: Regex = Regex::new(concat!(
+        r"(?im)(",
+        r"\b(ignore|disregard|forget|override)\s+(all\s+|any\s+|the\s+)?(previous|prior|above|earlier|preceding|your|these|system)\b",
+        r"[^.!?\n]{0,30}\b(instructions?|prompts?|rules|directions|guidelines)\b",
+        r"|\byou are now\b",
+        r"|\bnew (system )?instructions?\s*:",
+        r"|\b(reveal|print|repeat) (your|the) (system prompt|instructions)\b",
+        r"|\b(do not|don't) (tell|inform|mention)[^.!?\n]{0,20}\buser\b",
+        // special tokens and markers of chat templates
+        r"|<\|[a-z_]+\|>|\[/?INST\]|<</?SYS>>|^#{1,3}\s*(system|instructions?)\s*:?\s*$",
+        r")[^.!?\n]*[.!?]?"
+    ))
+    .unwrap();
+}
+
+/// Whether a text holds instructions meant for a language model, e.g. a web page saying
+/// "ignore previous instructions"
+pub fn detect(text: &str) -> bool {
+    INSTRUCTION.is_match(text)
+}
+
+/// The text with the sentences holding instructions removed
+pub fn neutralize(text: &str) -> String {
+    INSTRUCTION.replace_all(text, REMOVED).to_string()
+}
+
+// Chunks are flagged when memorized, older chunks get checked as they are retrieved
+fn is_flagged(reference: &VectorIndex) -> bool {
+    reference.metadata["injection"] == Value::Bool(true) || detect(&reference.content_chunk)
+}
+
+/// Applies `prompt_injection` to the references of a prompt, returns whether flagged ones are left
+/// for the model to be warned about
+pub fn sanitize(references: Vec<VectorIndex>) -> (Vec<VectorIndex>, bool) {
+    let handling = live().prompt_injection;
+    let mut flagged = false;
+    let mut sanitized = Vec::with_capacity(references.len());
+    for mut reference in references {
+        if handling == InjectionHandling::Off || !is_flagged(&reference) {
+            sanitized.push(reference);
+            continue;
+        }
+        debug!(chunk = reference.id.to_string(), "Reference holds instructions");
+        match handling {
+            InjectionHandling::Off => {}
+            InjectionHandling::Flag => {
+                if let Some(fields) = reference.metadata.as_object_mut() {
+                    fields.insert("untrusted".to_string(), Value::Bool(true));
+                }
+                flagged = true;
+            }
+            InjectionHandling::Neutralize => reference.content_chunk = neutralize(&reference.content_chunk),
+            InjectionHandling::Drop => continue,
+        }
+        sanitized.push(reference);
+    }
+    (sanitized, flagged)
+}
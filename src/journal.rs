@@ -0,0 +1,94 @@
+use crate::config::live;
+use crate::database::{search_with, Clauses};
+use crate::embeddings::embed;
+use crate::inference::{answer_with_context, Answer, AnswerOptions};
+use crate::platform::read_stdin;
+use crate::queue::{self, Priority};
+use crate::redaction::loggable;
+use anyhow::Result;
+use chrono::{Datelike, NaiveDate};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::json;
+use std::io::{IsTerminal, Write};
+use tracing::debug;
+
+// Entries an answer to a question about the journal is based on
+const JOURNAL_CHUNKS: usize = 6;
+
+lazy_static! {
+    // after a preposition, so "may" isn't taken for the month
+    static ref MONTH: Regex = Regex::new(
+        r"(?i)\b(in|during|of|since)\s+(january|february|march|april|may|june|july|august|september|october|november|december)\b(\s+(\d{4}))?"
+    )
+    .unwrap();
+    static ref YEAR: Regex = Regex::new(r"\b(19|20)\d{2}\b").unwrap();
+}
+
+/// Memorizes the entry of a day, with its date, month and year in the metadata for scoped questions
+pub async fn write_entry(text: &str, date: NaiveDate) -> Result<String> {
+    if text.trim().is_empty() {
+        anyhow::bail!("The journal entry is empty");
+    }
+    let content = queue::memorize(
+        Priority::Interactive,
+        &format!("Journal {}", date.format("%Y-%m-%d")),
+        text.to_string(),
+        json!({
+            "connector": "journal",
+            "type": "journal",
+            "source": "journal",
+            "date": date.format("%Y-%m-%d").to_string(),
+            "year": date.year(),
+            "month": date.format("%B").to_string(),
+            "weekday": date.format("%A").to_string(),
+        }),
+    )
+    .await?;
+    Ok(content.id.id.to_raw())
+}
+
+/// Asks the `journal.prompts` of the config one after the other, the answers make the entry.
+/// What is piped to stdin is the entry otherwise.
+pub fn prompt_entry() -> Result<String> {
+    if !std::io::stdin().is_terminal() {
+        return read_stdin();
+    }
+    let mut entry = vec![];
+    for prompt in live().journal.prompts.iter() {
+        print!("{} ", prompt);
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        // skipped prompts are left out of the entry
+        if !answer.trim().is_empty() {
+            entry.push(format!("{}\n{}", prompt, answer.trim()));
+        }
+    }
+    Ok(entry.join("\n\n"))
+}
+
+/// The clauses keeping a question to journal entries, and to the month or year it names,
+/// e.g. "what was I worried about in March?"
+pub fn journal_clauses(question: &str) -> Clauses {
+    let mut clauses = Clauses::default();
+    clauses.metadata.insert("type".to_string(), "journal".to_string());
+    if let Some(month) = MONTH.captures(question) {
+        clauses.metadata.insert("month".to_string(), month[2].to_string());
+        if let Some(year) = month.get(4) {
+            clauses.metadata.insert("year".to_string(), year.as_str().to_string());
+        }
+    } else if let Some(year) = YEAR.find(question) {
+        clauses.metadata.insert("year".to_string(), year.as_str().to_string());
+    }
+    clauses
+}
+
+/// Answers a question from the journal entries only
+pub async fn ask(question: &str, options: &AnswerOptions) -> Result<Answer> {
+    let (query, mut clauses) = Clauses::parse(question)?;
+    clauses.extend(journal_clauses(question));
+    debug!(clauses = %loggable(&format!("{:?}", clauses.metadata)), "Journal question");
+    let entries = search_with(embed(&query).await?, &clauses, JOURNAL_CHUNKS).await?;
+    answer_with_context(question, entries, None, options).await
+}
@@ -0,0 +1,91 @@
+//! Tera remembers what you tell it and answers questions from it.
+//!
+//! ```no_run
+//! # async fn run() -> anyhow::Result<()> {
+//! let tera = tera::Tera::builder().data_dir("/tmp/tera").build().await?;
+//! tera.ingest("Groceries", "Buy oat milk and coffee").await?;
+//! let answer = tera.ask("What should I buy?").await?;
+//! println!("{}", answer.text);
+//! # Ok(())
+//! # }
+//! ```
+pub mod access;
+pub mod api;
+#[cfg(feature = "apple-notes")]
+pub mod apple_notes;
+pub mod audit;
+#[cfg(feature = "browser")]
+pub mod browser;
+pub mod calendar;
+pub mod chat;
+pub mod clipboard;
+pub mod config;
+pub mod connectors;
+pub mod contacts;
+pub mod crypto;
+#[cfg(unix)]
+pub mod daemon;
+pub mod database;
+pub mod embeddings;
+pub mod exclusion;
+pub mod feedback;
+pub mod git_history;
+pub mod health;
+#[cfg(feature = "server")]
+pub mod grpc;
+pub mod home_assistant;
+pub mod inference;
+pub mod ingest;
+pub mod injection;
+pub mod journal;
+pub mod lora;
+pub mod map;
+pub mod mcp;
+#[cfg(feature = "whisper")]
+pub mod microphone;
+pub mod migrations;
+pub mod notify;
+pub mod ollama;
+pub mod output;
+pub mod platform;
+pub mod postprocess;
+pub mod provenance;
+pub mod quantization;
+pub mod queue;
+pub mod redaction;
+pub mod reminders;
+pub mod remote;
+pub mod saved_search;
+#[cfg(feature = "scheduler")]
+pub mod scheduler;
+pub mod shutdown;
+pub mod snapshot;
+pub mod stats;
+pub mod storage;
+pub mod sync;
+#[cfg(unix)]
+pub mod systemd;
+#[cfg(feature = "tables")]
+pub mod tables;
+pub mod tasks;
+pub mod telemetry;
+pub mod topics;
+#[cfg(feature = "tray")]
+pub mod tray;
+pub mod tts;
+pub mod vector_file;
+#[cfg(feature = "crawl")]
+pub mod web;
+pub mod webhooks;
+#[cfg(feature = "whisper")]
+pub mod whisper;
+#[cfg(feature = "pdf")]
+pub mod zotero;
+
+pub use api::{Tera, TeraBuilder};
+pub use chat::Session;
+pub use config::EmbeddingBackend;
+pub use database::{Clauses, VectorIndex};
+pub use inference::{Answer, AnswerLength, Clarification, Limits, Model, Reply};
+pub use notify::{Notification, Notifier};
+pub use postprocess::PostProcessor;
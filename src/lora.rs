@@ -0,0 +1,221 @@
+use anyhow::{Context, Result};
+use candle_core::quantized::{gguf_file, QTensor};
+use candle_core::{DType, Device, Tensor};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::debug;
+
+// PEFT module names and their llama style gguf counterparts
+const MODULE_NAMES: [(&str, &str); 7] = [
+    ("self_attn.q_proj", "attn_q"),
+    ("self_attn.k_proj", "attn_k"),
+    ("self_attn.v_proj", "attn_v"),
+    ("self_attn.o_proj", "attn_output"),
+    ("mlp.gate_proj", "ffn_gate"),
+    ("mlp.up_proj", "ffn_up"),
+    ("mlp.down_proj", "ffn_down"),
+];
+
+// PEFT module names of Phi-2 in transformers and their mixformer gguf counterparts, which fuses the
+// query, key and value projections in one tensor, in that order
+const MIXFORMER_MODULE_NAMES: [(&str, &str, Option<usize>); 6] = [
+    ("self_attn.q_proj", "mixer.Wqkv", Some(0)),
+    ("self_attn.k_proj", "mixer.Wqkv", Some(1)),
+    ("self_attn.v_proj", "mixer.Wqkv", Some(2)),
+    ("self_attn.dense", "mixer.out_proj", None),
+    ("mlp.fc1", "mlp.fc1", None),
+    ("mlp.fc2", "mlp.fc2", None),
+];
+
+/// Merges a LoRA adapter into the quantized base weights and returns the path of the merged gguf.
+///
+/// `adapter` is either a PEFT adapter directory (`adapter_model.safetensors` + `adapter_config.json`)
+/// or a bare safetensors file, in which case the adapter is applied with a scale of 1.
+/// Merged weights are cached on disk so the merge only happens once per adapter.
+pub fn merge_adapter(base: &Path, adapter: &Path) -> Result<PathBuf> {
+    let (weights_filename, scale) = adapter_files(adapter)?;
+
+    // the merge is cached by what goes into it, an adapter trained again gets merged again
+    let weights = std::fs::read(&weights_filename).context("Unable to read LoRA adapter")?;
+    let mut hasher = Sha256::new();
+    // a base downloaded again at the same path is another file
+    let base_metadata = std::fs::metadata(base)?;
+    let base_modified = base_metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH)?;
+    hasher.update(base.display().to_string().as_bytes());
+    hasher.update(base_metadata.len().to_le_bytes());
+    hasher.update(base_modified.as_nanos().to_le_bytes());
+    hasher.update(&weights);
+    hasher.update(scale.to_le_bytes());
+    let cache_dir = dirs::cache_dir()
+        .context("Unable to find the cache directory")?
+        .join("tera")
+        .join("lora");
+    let merged_filename = cache_dir.join(format!("{:x}.gguf", hasher.finalize()));
+    if merged_filename.exists() {
+        debug!(path = ?merged_filename, "Using cached LoRA merge");
+        return Ok(merged_filename);
+    }
+    std::fs::create_dir_all(&cache_dir)?;
+
+    let adapter = candle_core::safetensors::load_buffer(&weights, &Device::Cpu)
+        .context("Unable to read LoRA adapter")?;
+    let mut file = std::fs::File::open(base)?;
+    let content = gguf_file::Content::read(&mut file)?;
+    let deltas = gguf_deltas(lora_deltas(&adapter, scale)?, &content)?;
+
+    let mut tensors = Vec::with_capacity(content.tensor_infos.len());
+    let mut merged = 0;
+    for name in content.tensor_infos.keys() {
+        let tensor = content.tensor(&mut file, name, &Device::Cpu)?;
+        let tensor = match deltas.get(name) {
+            Some(delta) => {
+                merged += 1;
+                let weights = (tensor.dequantize(&Device::Cpu)? + delta)?;
+                QTensor::quantize(&weights, tensor.dtype())?
+            }
+            None => tensor,
+        };
+        tensors.push((name.clone(), tensor));
+    }
+    if merged == 0 {
+        anyhow::bail!(
+            "None of the LoRA adapter weights match the base model, adapters of Phi-2 and of llama style models \
+             such as Qwen2.5 can be merged"
+        );
+    }
+    debug!(merged = merged, adapters = deltas.len(), "Merged LoRA adapter");
+
+    let metadata = content
+        .metadata
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    // write to a temporary file first so an interrupted merge is not picked up as cached
+    let partial_filename = merged_filename.with_extension("partial");
+    let mut out = std::fs::File::create(&partial_filename)?;
+    gguf_file::write(&mut out, &metadata, &tensors)?;
+    std::fs::rename(&partial_filename, &merged_filename)?;
+
+    Ok(merged_filename)
+}
+
+fn adapter_files(adapter: &Path) -> Result<(PathBuf, f64)> {
+    if !adapter.is_dir() {
+        return Ok((adapter.to_path_buf(), 1.0));
+    }
+    let config = std::fs::read_to_string(adapter.join("adapter_config.json"))
+        .context("Unable to read adapter_config.json")?;
+    let config: serde_json::Value = serde_json::from_str(&config)?;
+    let rank = config["r"].as_f64().context("adapter_config.json has no rank")?;
+    let alpha = config["lora_alpha"].as_f64().unwrap_or(rank);
+    Ok((adapter.join("adapter_model.safetensors"), alpha / rank))
+}
+
+// Computes `scale * B @ A` for every adapted module, keyed by PEFT module name
+fn lora_deltas(adapter: &HashMap<String, Tensor>, scale: f64) -> Result<HashMap<String, Tensor>> {
+    let mut deltas = HashMap::new();
+    for (name, a) in adapter.iter() {
+        let Some(module) = name.strip_suffix(".lora_A.weight") else {
+            continue;
+        };
+        let b = adapter
+            .get(&format!("{}.lora_B.weight", module))
+            .with_context(|| format!("Missing lora_B for {}", module))?;
+        let delta = (b.to_dtype(DType::F32)?.matmul(&a.to_dtype(DType::F32)?)? * scale)?;
+        deltas.insert(module.to_string(), delta);
+    }
+    Ok(deltas)
+}
+
+// Keys the deltas by the gguf tensor they apply to, the llama style name when the base has it and
+// the mixformer one otherwise. The parts of a fused tensor left out of the adapter don't change.
+fn gguf_deltas(deltas: HashMap<String, Tensor>, content: &gguf_file::Content) -> Result<HashMap<String, Tensor>> {
+    let mut by_tensor = HashMap::new();
+    let mut fused: HashMap<String, [Option<Tensor>; 3]> = HashMap::new();
+    for (module, delta) in deltas {
+        let name = gguf_name(&module);
+        match mixformer_target(&module) {
+            _ if content.tensor_infos.contains_key(&name) => {
+                by_tensor.insert(name, delta);
+            }
+            Some((name, Some(part))) => fused.entry(name).or_default()[part] = Some(delta),
+            Some((name, None)) => {
+                by_tensor.insert(name, delta);
+            }
+            // doesn't match, which the merge reports
+            None => {
+                by_tensor.insert(name, delta);
+            }
+        }
+    }
+    for (name, parts) in fused {
+        let shape = parts.iter().flatten().next().context("No part of a fused tensor")?.shape().clone();
+        let zeros = Tensor::zeros(shape, DType::F32, &Device::Cpu)?;
+        let parts = parts.map(|part| part.unwrap_or_else(|| zeros.clone()));
+        by_tensor.insert(name, Tensor::cat(&parts, 0)?);
+    }
+    Ok(by_tensor)
+}
+
+// base_model.model.model.layers.3.self_attn.q_proj -> blk.3.attn_q.weight
+fn gguf_name(module: &str) -> String {
+    let module = module.trim_start_matches("base_model.model.");
+    let module = module.trim_start_matches("model.");
+    if let Some(rest) = module.strip_prefix("layers.") {
+        if let Some((layer, rest)) = rest.split_once('.') {
+            for (peft, gguf) in MODULE_NAMES.iter() {
+                if rest == *peft {
+                    return format!("blk.{}.{}.weight", layer, gguf);
+                }
+            }
+        }
+    }
+    format!("{}.weight", module)
+}
+
+// base_model.model.model.layers.3.self_attn.q_proj -> transformer.h.3.mixer.Wqkv.weight, first part
+fn mixformer_target(module: &str) -> Option<(String, Option<usize>)> {
+    let module = module.trim_start_matches("base_model.model.");
+    let module = module.trim_start_matches("model.");
+    let (layer, rest) = module.strip_prefix("layers.")?.split_once('.')?;
+    MIXFORMER_MODULE_NAMES
+        .iter()
+        .find(|(peft, _, _)| rest == *peft)
+        .map(|(_, gguf, part)| (format!("transformer.h.{}.{}.weight", layer, gguf), *part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_llama_style_tensors() {
+        assert_eq!(gguf_name("base_model.model.model.layers.3.self_attn.q_proj"), "blk.3.attn_q.weight");
+        assert_eq!(gguf_name("base_model.model.model.layers.12.mlp.down_proj"), "blk.12.ffn_down.weight");
+        // modules named as in the gguf already, e.g. adapters of the original Phi-2 code
+        assert_eq!(
+            gguf_name("base_model.model.transformer.h.0.mixer.Wqkv"),
+            "transformer.h.0.mixer.Wqkv.weight"
+        );
+    }
+
+    #[test]
+    fn targets_mixformer_tensors() {
+        assert_eq!(
+            mixformer_target("base_model.model.model.layers.3.self_attn.k_proj"),
+            Some(("transformer.h.3.mixer.Wqkv.weight".to_string(), Some(1)))
+        );
+        assert_eq!(
+            mixformer_target("base_model.model.model.layers.3.self_attn.dense"),
+            Some(("transformer.h.3.mixer.out_proj.weight".to_string(), None))
+        );
+        assert_eq!(mixformer_target("base_model.model.model.layers.3.mlp.up_proj"), None);
+        assert_eq!(mixformer_target("base_model.model.lm_head"), None);
+    }
+}
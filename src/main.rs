@@ -1,57 +1,344 @@
+use std::future::Future;
 use std::io::Write;
+use std::pin::Pin;
+use std::time::Duration;
 
-use crate::{
-    cli::{Cli, Commands},
-    database::get_releted_chunks,
-};
-use anyhow::Result;
-use clap::Parser;
-use embeddings::get_embeddings;
-use ingest::{
-    ingest_via_audio_file, ingest_via_cli, ingest_via_pdf_file, ingest_via_txt_file,
-    ingest_wa_chat_log,
+use crate::cli::{
+    Cli, Commands, ExclusionsCommands, RemindersCommands, SearchesCommands, SnapshotsCommands, TasksCommands,
+    TopicsCommands, TrashCommands,
 };
+#[cfg(feature = "tables")]
+use crate::cli::DatabasesCommands;
+#[cfg(feature = "scheduler")]
+use crate::cli::JobsCommands;
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
 use prettytable::{Table, row};
+use tera::ingest::{self, ingest_via_cli};
+use tera::output::{self, OutputFormat};
+use tera::{
+    audit, chat, clipboard, config, connectors, database, embeddings, exclusion, feedback, health, home_assistant,
+    inference, journal, map, mcp, ollama, platform, reminders, saved_search, snapshot, stats, sync, tasks, topics,
+    vector_file,
+};
+#[cfg(feature = "scheduler")]
+use tera::scheduler;
+#[cfg(feature = "tables")]
+use tera::tables;
+#[cfg(feature = "crawl")]
+use tera::web;
 mod cli;
-mod database;
-mod embeddings;
-mod inference;
-mod ingest;
-mod whisper;
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    // before the runtime starts threads, which could read the environment while it changes
+    #[cfg(unix)]
+    tera::systemd::take_activation()?;
+    tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(start())
+}
+
+async fn start() -> Result<()> {
     let args = Cli::parse();
+    #[cfg(unix)]
+    if args.command.replaces_database() && tera::daemon::is_listening() {
+        anyhow::bail!("The daemon has the knowledge base open, stop it before forgetting everything or restoring");
+    }
+    #[cfg(unix)]
+    if args.command.forwarded() {
+        if let Some(code) = tera::daemon::forward(std::env::args().skip(1).collect())? {
+            std::process::exit(code);
+        }
+    }
+    let _telemetry = tera::telemetry::init()?;
+    run(args.command).await
+}
+
+// Jobs are tera commands, run in this process since the database can only be opened once
+fn run_job_command(args: Vec<String>) -> Pin<Box<dyn Future<Output = Result<()>>>> {
+    Box::pin(async move {
+        let cli = Cli::try_parse_from(std::iter::once("tera".to_string()).chain(args))?;
+        if cli.command.replaces_database() {
+            anyhow::bail!("Forgetting everything or restoring a snapshot only runs in a process of its own");
+        }
+        run(cli.command).await
+    })
+}
 
-    match args.command {
-        Commands::Ask { query } => {
-            let embeddings: Vec<f32> = get_embeddings(&query)?.reshape((384,))?.to_vec1()?;
-            let k = get_releted_chunks(embeddings).await?;
-            let mut context = vec![];
-            for reference in k.iter() {
-                let releted = reference.get_adjacent_chunks(1, 1).await?;
-                context.extend(releted);
+// The named JSON mapping from the config, the default one otherwise
+fn json_mapping(name: Option<String>) -> Result<config::JsonMapping> {
+    match name {
+        Some(name) => config::CONFIG
+            .json_mappings
+            .get(&name)
+            .cloned()
+            .with_context(|| format!("No JSON mapping named {}", name)),
+        None => Ok(config::JsonMapping::default()),
+    }
+}
+
+fn print_topics(topics: &[topics::Topic]) {
+    let mut table = Table::new();
+    table.add_row(row!["ID", "Topic", "Documents", "Examples"]);
+    for topic in topics {
+        let examples = topic.documents.iter().take(3).map(|d| d.title.as_str()).collect::<Vec<_>>();
+        table.add_row(row![topic.id, topic.label, topic.documents.len(), examples.join("; ")]);
+    }
+    table.printstd();
+}
+
+async fn run(command: Commands) -> Result<()> {
+    match command {
+        Commands::Ask {
+            query,
+            model,
+            lora,
+            length,
+            follow_ups,
+            clarify,
+            max_tokens,
+            max_seconds,
+            database,
+            about,
+            compare,
+            timeline,
+            fresh,
+            profile,
+            json,
+            format,
+        } => {
+            let options = inference::AnswerOptions {
+                model,
+                lora,
+                length,
+                follow_ups,
+                limits: inference::Limits {
+                    max_tokens,
+                    max_time: max_seconds.map(Duration::from_secs),
+                },
+                ..Default::default()
+            };
+            let format = if json { OutputFormat::Json } else { format };
+            config::with_profile(profile.as_deref(), async {
+                if timeline {
+                    let answer = inference::answer_timeline(&query, &options).await?;
+                    println!("{}", output::render_answer(&answer, format));
+                    return Ok(());
+                }
+                if !compare.is_empty() {
+                    let answer = inference::compare_documents(&query, &compare, &options).await?;
+                    println!("{}", output::render_answer(&answer, format));
+                    return Ok(());
+                }
+                #[cfg(not(feature = "tables"))]
+                if let Some(database) = &database {
+                    anyhow::bail!("Tera was built without the `tables` feature, unable to query {}", database);
+                }
+                if !fresh && about.is_none() && database.is_none() {
+                    if let Some(past) = inference::repeated_answer(&query, &options).await? {
+                        println!("{}", output::render_past_answer(&past, format));
+                        return Ok(());
+                    }
+                }
+                #[cfg_attr(not(feature = "tables"), allow(unused_mut))]
+                let mut context = match about {
+                    Some(path) if path.as_os_str() == "-" => {
+                        inference::text_context(&query, &std::io::read_to_string(std::io::stdin())?).await?
+                    }
+                    Some(path) => inference::text_context(&query, &std::fs::read_to_string(path)?).await?,
+                    None => inference::retrieve_context(&query, &options).await?,
+                };
+                #[cfg(feature = "tables")]
+                if let Some(database) = database {
+                    context.push(tables::registered_database(&database).await?);
+                }
+                if clarify {
+                    match inference::answer_or_clarify(&query, context, None, &options).await? {
+                        inference::Reply::Answer(answer) => println!("{}", output::render_answer(&answer, format)),
+                        inference::Reply::Clarification(clarification) => {
+                            println!("{}", output::render_clarification(&clarification, format))
+                        }
+                    }
+                    return Ok(());
+                }
+                let answer = inference::answer_with_context(&query, context, None, &options).await?;
+                println!("{}", output::render_answer(&answer, format));
+                anyhow::Ok(())
+            })
+            .await??;
+        }
+        Commands::Quick {
+            question,
+            model,
+            length,
+            copy,
+        } => {
+            let options = inference::AnswerOptions {
+                model,
+                length,
+                ..Default::default()
+            };
+            let answered = async {
+                let context = inference::retrieve_context(&question, &options).await?;
+                let answer = inference::answer_with_context(&question, context, None, &options).await?;
+                if copy {
+                    clipboard::write_clipboard(&answer.text)?;
+                }
+                anyhow::Ok(answer)
+            };
+            // launchers read stdout, failures have to be JSON too
+            match answered.await {
+                Ok(answer) => println!("{}", output::render_quick(&answer)),
+                Err(e) => {
+                    println!("{}", serde_json::json!({ "error": format!("{:#}", e) }));
+                    return Err(e);
+                }
             }
-            let answer = inference::answer_with_context(&query, context).await?;
-            println!("Answer: {}", answer);
         }
-        Commands::Upload { content_type, path } => match content_type {
-            ingest::IngestType::Whatsapp => {
-                ingest_wa_chat_log(path).await?;
+        Commands::Search {
+            query,
+            format,
+            page_size,
+            cursor,
+            must,
+            should,
+            must_not,
+        } => {
+            let (query, mut clauses) = database::Clauses::parse(&query)?;
+            clauses.extend(database::Clauses {
+                must,
+                should,
+                must_not,
+                ..Default::default()
+            });
+            let page = match (cursor, page_size) {
+                (Some(cursor), page_size) => database::next_page(&cursor, page_size.unwrap_or(10)).await?,
+                (None, Some(page_size)) => {
+                    database::search_page(embeddings::embed(&query).await?, &clauses, page_size).await?
+                }
+                (None, None) => {
+                    let chunks = database::search_with(embeddings::embed(&query).await?, &clauses, 4).await?;
+                    audit::record_query("search", &query, &chunks);
+                    stats::record_search().await;
+                    print!("{}", output::render_chunks(&chunks, format));
+                    return Ok(());
+                }
+            };
+            audit::record_query("search", &query, &page.chunks);
+            stats::record_search().await;
+            print!("{}", output::render_chunks(&page.chunks, format));
+            // on stderr so the results can be piped
+            if let Some(cursor) = page.next_cursor {
+                eprintln!("{} results, next page with --cursor {}", page.total, cursor);
             }
-            ingest::IngestType::Text => {
-                ingest_via_txt_file(path).await?;
+        }
+        Commands::Chat {
+            model,
+            lora,
+            length,
+            speak,
+            voice,
+            profile,
+        } => {
+            let options = inference::AnswerOptions {
+                model,
+                lora,
+                length,
+                ..Default::default()
+            };
+            chat::run_repl(options, profile, speak, voice).await?;
+        }
+        Commands::Upload {
+            content_type,
+            path,
+            mapping,
+            dry_run,
+        } => {
+            // absolute paths keep working past the Windows path length limit
+            let path = platform::long_path(&path)?;
+            let mapping = json_mapping(mapping)?;
+            database::with_dry_run(dry_run, ingest::ingest_path(content_type, path, &mapping)).await?;
+        }
+        Commands::UploadDir {
+            path,
+            concurrency,
+            only,
+            mapping,
+            dry_run,
+        } => {
+            let path = platform::long_path(&path)?;
+            let mapping = json_mapping(mapping)?;
+            let upload = ingest::ingest_directory(&path, concurrency, &mapping, only);
+            let report = database::with_dry_run(dry_run, upload).await?;
+            for (file, e) in &report.failed {
+                eprintln!("Unable to memorize {}: {:#}", file.display(), e);
             }
-            ingest::IngestType::PDF => {
-                ingest_via_pdf_file(path).await?;
+            println!(
+                "Memorized {} files, {} failed, skipped {} files of unknown type",
+                report.memorized.len(),
+                report.failed.len(),
+                report.skipped.len()
+            );
+        }
+        #[cfg(feature = "crawl")]
+        Commands::Crawl {
+            url,
+            sitemap,
+            depth,
+            max_pages,
+            delay,
+            dry_run,
+        } => {
+            if sitemap {
+                let upload = web::ingest_sitemap(&url, Duration::from_millis(delay));
+                let report = database::with_dry_run(dry_run, upload).await?;
+                for (page, e) in &report.failed {
+                    eprintln!("Unable to memorize {}: {:#}", page, e);
+                }
+                println!(
+                    "Memorized {} pages, {} failed, {} unchanged since the last crawl",
+                    report.memorized,
+                    report.failed.len(),
+                    report.unchanged
+                );
+                return Ok(());
             }
-            ingest::IngestType::Audio => {
-                ingest_via_audio_file(path).await?;
+            let options = web::CrawlOptions {
+                max_depth: depth,
+                max_pages,
+                delay: Duration::from_millis(delay),
+            };
+            let memorized = database::with_dry_run(dry_run, web::crawl(&url, &options)).await?;
+            println!("Memorized {} pages", memorized);
+        }
+        Commands::Ingest { title, dry_run } => {
+            let id = database::with_dry_run(dry_run, ingest::ingest_via_stdin(title)).await?;
+            if !dry_run {
+                println!("{}", id);
             }
-        },
+        }
         Commands::Remember { content } => {
             ingest_via_cli(&content).await?;
         },
+        Commands::Journal { entry, date, ask, model } => {
+            if let Some(question) = ask {
+                let options = inference::AnswerOptions {
+                    model,
+                    ..Default::default()
+                };
+                let answer = journal::ask(&question, &options).await?;
+                println!("{}", output::render_answer(&answer, OutputFormat::Plain));
+                return Ok(());
+            }
+            let entry = match entry {
+                Some(entry) => entry,
+                None => journal::prompt_entry()?,
+            };
+            let date = date.unwrap_or_else(|| chrono::Local::now().date_naive());
+            let id = journal::write_entry(&entry, date).await?;
+            println!("Journal entry of {} saved with id: {}", date, id);
+        }
+        Commands::Clip { watch } => {
+            clipboard::capture(watch).await?;
+        }
         Commands::Forget { content_id, all } => {
             if all {
                 print!("Are you sure you want me to forget everything? this cannot be undone! [y/N]: ");
@@ -70,9 +357,496 @@ async fn main() -> Result<()> {
                     return Ok(());
                 }
                 database::delete_content(content_id.clone().unwrap().as_str()).await?;
-                println!("Content {} was deleted", content_id.unwrap());
+                let content_id = content_id.unwrap();
+                println!("Content {} was moved to the trash, undo with `tera trash restore {}`", content_id, content_id);
+            }
+        },
+        Commands::Exclusions { command } => match command {
+            ExclusionsCommands::List { json } => {
+                let exclusions = exclusion::list_exclusions().await?;
+                if json {
+                    println!("{}", serde_json::to_string(&exclusions)?);
+                    return Ok(());
+                }
+                let mut table = Table::new();
+                table.add_row(row!["Excluded", "ID", "Excluded At"]);
+                for excluded in exclusions {
+                    match excluded {
+                        exclusion::Exclusion::Document {
+                            content_id,
+                            title,
+                            excluded_at,
+                        } => table.add_row(row![title, content_id, excluded_at]),
+                        exclusion::Exclusion::Source { pattern, excluded_at } => {
+                            table.add_row(row![format!("source {}", pattern), "", excluded_at])
+                        }
+                    };
+                }
+                table.printstd();
+            }
+            ExclusionsCommands::Add { content_id, source } => {
+                if let Some(pattern) = source {
+                    exclusion::exclude_source(&pattern).await?;
+                    println!("Excluded sources matching {}", pattern);
+                } else {
+                    let id = content_id.context("Name the content to exclude or a --source")?;
+                    let content = exclusion::exclude_document(&id).await?;
+                    println!("Excluded {}, include it again with `tera exclusions remove {}`", content.title, id);
+                }
+            }
+            ExclusionsCommands::Remove { content_id, source } => {
+                if let Some(pattern) = source {
+                    exclusion::include_source(&pattern).await?;
+                } else {
+                    let id = content_id.context("Name the content to include or a --source")?;
+                    exclusion::include_document(&id).await?;
+                }
+                println!("Included again");
+            }
+            ExclusionsCommands::Clear => {
+                let cleared = exclusion::include_all().await?;
+                println!("Included {} excluded documents and sources again", cleared);
+            }
+        },
+        Commands::Trash { command } => match command {
+            TrashCommands::List => {
+                let mut table = Table::new();
+                table.add_row(row!["ID", "Title", "Chunks", "Deleted At"]);
+                for trashed in database::list_trash().await? {
+                    let content = trashed.content;
+                    table.add_row(row![content.id.id, content.title, trashed.chunks.len(), trashed.deleted_at]);
+                }
+                table.printstd();
+            }
+            TrashCommands::Restore { content_id } => {
+                let content = database::restore(&content_id).await?;
+                println!("Restored {}", content.title);
+            }
+            TrashCommands::Empty => {
+                let deleted = database::empty_trash().await?;
+                println!("Deleted {} items for good", deleted);
+            }
+        },
+        Commands::Snapshots { command } => match command {
+            SnapshotsCommands::Create => {
+                let snapshot = snapshot::snapshot().await?;
+                println!("Took snapshot {}", snapshot.id);
+            }
+            SnapshotsCommands::List => {
+                let mut table = Table::new();
+                table.add_row(row!["ID", "Size"]);
+                for snapshot in snapshot::list_snapshots()? {
+                    table.add_row(row![snapshot.id, format!("{} KB", snapshot.size / 1024)]);
+                }
+                table.printstd();
+            }
+            SnapshotsCommands::Restore { snapshot_id } => {
+                print!(
+                    "Anything memorized after {}, and jobs, trash and exclusions changed since, will be lost, \
+                     continue? [y/N]: ",
+                    snapshot_id
+                );
+                std::io::stdout().flush()?;
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input)?;
+                if input.trim().to_lowercase() == "y" {
+                    let safety = snapshot::restore_snapshot(&snapshot_id).await?;
+                    println!("Restored snapshot {}, the knowledge base before is snapshot {}", snapshot_id, safety.id);
+                } else {
+                    println!("Aborting");
+                }
+            }
+            SnapshotsCommands::Delete { snapshot_id } => {
+                snapshot::delete_snapshot(&snapshot_id)?;
+                println!("Deleted snapshot {}", snapshot_id);
+            }
+        },
+        Commands::Feedback {
+            answer_id,
+            chunk_id,
+            not_useful,
+        } => {
+            match chunk_id {
+                Some(chunk_id) => {
+                    feedback::record_feedback(&answer_id, &chunk_id, !not_useful).await?
+                }
+                None => {
+                    feedback::record_answer_feedback(&answer_id, !not_useful).await?;
+                }
+            }
+            println!("Thanks for the feedback!");
+        }
+        Commands::Audit { limit, query, json } => {
+            let hash = query.as_deref().map(audit::query_hash).transpose()?;
+            let mut entries = audit::read_entries()?;
+            entries.retain(|e| hash.is_none() || e.query_hash == hash);
+            let entries = entries.split_off(entries.len().saturating_sub(limit));
+            if json {
+                for entry in entries {
+                    println!("{}", serde_json::to_string(&entry)?);
+                }
+                return Ok(());
+            }
+            let mut table = Table::new();
+            table.add_row(row!["At", "User", "Event", "Query", "Sources"]);
+            for entry in entries {
+                let query = entry.query_hash.map(|h| h[..12].to_string()).unwrap_or_default();
+                let sources = entry
+                    .sources
+                    .iter()
+                    .map(|s| s.title.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                table.add_row(row![entry.at.format("%Y-%m-%d %H:%M:%S"), entry.user, entry.event, query, sources]);
+            }
+            table.printstd();
+        }
+        Commands::Stats { days, json } => {
+            let stats = stats::stats(days).await?;
+            if json {
+                println!("{}", serde_json::to_string(&stats)?);
+                return Ok(());
+            }
+            println!("Over the last {} days:", days);
+            println!("  {} questions, {} searches", stats.queries, stats.searches);
+            if let Some(latency) = stats.average_latency_ms {
+                println!("  {:.1} seconds to answer on average", latency as f64 / 1000.0);
+            }
+            println!("  {} documents in {} chunks memorized", stats.documents, stats.chunks);
+            let queue = &stats.ingestion;
+            if queue.running > 0 || queue.interactive.waiting + queue.bulk.waiting > 0 {
+                println!(
+                    "  {} of {} ingestion workers busy, {} interactive and {} bulk documents waiting",
+                    queue.running, queue.workers, queue.interactive.waiting, queue.bulk.waiting
+                );
+            }
+            if !stats.days.is_empty() {
+                let mut table = Table::new();
+                table.add_row(row!["Day", "Questions", "Searches", "Latency (ms)", "Documents added", "Chunks added"]);
+                for day in stats.days {
+                    let latency = if day.queries > 0 { day.latency_ms / day.queries } else { 0 };
+                    table.add_row(row![day.day, day.queries, day.searches, latency, day.ingestions, day.chunks]);
+                }
+                table.printstd();
+            }
+            if !stats.top_documents.is_empty() {
+                println!("Most retrieved documents:");
+                let mut table = Table::new();
+                table.add_row(row!["ID", "Title", "Answers"]);
+                for document in stats.top_documents {
+                    table.add_row(row![document.content_id, document.title, document.retrievals]);
+                }
+                table.printstd();
+            }
+        }
+        Commands::Topics { command } => match command {
+            TopicsCommands::Build { clusters } => {
+                let topics = topics::build_topics(clusters).await?;
+                println!("Grouped the documents in {} topics", topics.len());
+                print_topics(&topics);
+            }
+            TopicsCommands::List { json } => {
+                let topics = topics::list_topics().await?;
+                if json {
+                    println!("{}", serde_json::to_string(&topics)?);
+                } else if topics.is_empty() {
+                    println!("No topics yet, group the documents with `tera topics build`");
+                } else {
+                    print_topics(&topics);
+                }
+            }
+            TopicsCommands::Show { topic_id, json } => {
+                let topic = topics::get_topic(&topic_id).await?;
+                if json {
+                    println!("{}", serde_json::to_string(&topic)?);
+                    return Ok(());
+                }
+                println!("{}", topic.label);
+                let mut table = Table::new();
+                table.add_row(row!["ID", "Title"]);
+                for document in topic.documents {
+                    table.add_row(row![document.content_id, document.title]);
+                }
+                table.printstd();
+            }
+        },
+        Commands::Health { model, json } => {
+            let health = health::check(model).await;
+            if json {
+                println!("{}", serde_json::to_string(&health)?);
+            } else {
+                let mut table = Table::new();
+                table.add_row(row!["Component", "Status", "Detail"]);
+                for component in &health.components {
+                    table.add_row(row![component.name, format!("{:?}", component.status), component.detail]);
+                }
+                table.printstd();
+            }
+            if !health.ready() {
+                anyhow::bail!("Tera can't answer questions");
+            }
+        }
+        Commands::MigrateEmbeddings => {
+            // the daemon keeps answering with the previous vectors while it re-embeds
+            #[cfg(unix)]
+            if tera::daemon::is_serving() {
+                database::migrate_in_background();
+                println!("Re-embedding in the background, searches use the previous model until it is done");
+                return Ok(());
+            }
+            let migrated = database::migrate_embeddings().await?;
+            if migrated == 0 {
+                println!("Content is already embedded with the configured model");
+            } else {
+                println!("Re-embedded {} chunks", migrated);
+            }
+        }
+        Commands::Quantize => {
+            let rewritten = database::requantize().await?;
+            println!("Rewrote the vectors of {} chunks", rewritten);
+        }
+        Commands::Reindex => {
+            let indexed = vector_file::rebuild().await?;
+            println!("Wrote the vectors of {} chunks to {}", indexed, vector_file::vector_file_path().display());
+        }
+        Commands::Cleanup => {
+            let expired = database::expire_content().await?;
+            println!("Forgot {} expired chunks", expired);
+            let emptied = database::expire_trash().await?;
+            println!("Deleted {} items from the trash", emptied);
+        }
+        Commands::Connectors => {
+            let mut table = Table::new();
+            table.add_row(row!["Name", "Description", "Schedule", "Last Run", "Status"]);
+            for (connector, settings, run) in connectors::list_connectors().await? {
+                let (last_run, status) = match run {
+                    Some(run) => (run.last_run.to_string(), run.last_status),
+                    None => ("never".to_string(), String::new()),
+                };
+                table.add_row(row![
+                    connector.name,
+                    connector.description,
+                    settings.schedule.unwrap_or_default(),
+                    last_run,
+                    status
+                ]);
+            }
+            table.printstd();
+        }
+        Commands::Sync => {
+            let report = sync::sync().await?;
+            println!("Pushed {} changes, merged {} from other devices", report.pushed, report.applied);
+            if report.compacted > 0 {
+                println!("Left {} changes to deleted content out of the remote", report.compacted);
+            }
+        }
+        Commands::Encrypt => {
+            if config::CONFIG.encryption == config::Encryption::None {
+                anyhow::bail!("Enable `encryption` in {} first", config::config_path().display());
+            }
+            let rewritten = database::encrypt_existing().await?;
+            println!("Encrypted {} records", rewritten);
+        }
+        Commands::ExportIndex { path } => {
+            let index = database::export_index().await?;
+            std::fs::write(&path, serde_json::to_string(&index)?)
+                .with_context(|| format!("Unable to write {}", path.display()))?;
+            println!("Exported {} chunks to {}", index.entries.len(), path.display());
+        }
+        Commands::ExportMap { path, format } => {
+            let points = map::map_points().await?;
+            let format = format.unwrap_or_else(|| map::MapFormat::for_path(&path));
+            std::fs::write(&path, map::render_map(&points, format)?)
+                .with_context(|| format!("Unable to write {}", path.display()))?;
+            println!("Mapped {} chunks to {}", points.len(), path.display());
+        }
+        Commands::Searches { command } => match command {
+            SearchesCommands::Save {
+                name,
+                query,
+                subscribe,
+            } => {
+                saved_search::save_search(&name, &query, subscribe).await?;
+                println!("Saved search {}", name);
+            }
+            SearchesCommands::List => {
+                let mut table = Table::new();
+                table.add_row(row!["Name", "Query", "Subscribed", "New matches"]);
+                for s in saved_search::list_searches().await? {
+                    table.add_row(row![s.name, s.query, s.subscribed, s.unseen.len()]);
+                }
+                table.printstd();
+            }
+            SearchesCommands::Run { name, format } => {
+                let chunks = saved_search::run_search(&name).await?;
+                print!("{}", output::render_chunks(&chunks, format));
+            }
+            SearchesCommands::Delete { name } => {
+                saved_search::delete_search(&name).await?;
+                println!("Deleted saved search {}", name);
+            }
+        },
+        Commands::Reminders { command } => match command {
+            RemindersCommands::List => {
+                let mut table = Table::new();
+                table.add_row(row!["Id", "Due", "Reminder"]);
+                for r in reminders::list_reminders().await? {
+                    let due = r.due.0.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+                    table.add_row(row![r.id.id.to_raw(), due, r.text]);
+                }
+                table.printstd();
+            }
+            RemindersCommands::Extract { content_id } => {
+                let content_id = surrealdb::sql::thing(format!("content:{}", content_id).as_str())?;
+                let found = reminders::extract_from(&content_id).await?;
+                println!("Found {} reminders", found.len());
+            }
+            RemindersCommands::Done { reminder_id } => {
+                reminders::complete_reminder(&reminder_id).await?;
+                println!("Marked reminder {} as done", reminder_id);
+            }
+        },
+        Commands::Tasks { command } => match command {
+            TasksCommands::List { all } => {
+                let mut table = Table::new();
+                table.add_row(row!["Id", "Done", "Due", "Owner", "Task"]);
+                for t in tasks::list_tasks(all).await? {
+                    let done = if t.done { "x" } else { "" };
+                    table.add_row(row![
+                        t.id.id.to_raw(),
+                        done,
+                        t.due.unwrap_or_default(),
+                        t.owner.unwrap_or_default(),
+                        t.text
+                    ]);
+                }
+                table.printstd();
+            }
+            TasksCommands::Extract { content_id } => {
+                let found = tasks::extract_tasks(&content_id).await?;
+                println!("Found {} tasks", found.len());
+            }
+            TasksCommands::Done { task_id } => {
+                tasks::set_done(&task_id, true).await?;
+                println!("Marked task {} as done", task_id);
+            }
+            TasksCommands::Undone { task_id } => {
+                tasks::set_done(&task_id, false).await?;
+                println!("Marked task {} as not done", task_id);
+            }
+        },
+        #[cfg(feature = "tables")]
+        Commands::Databases { command } => match command {
+            DatabasesCommands::Add { name, path } => {
+                tables::register_database(&name, &platform::long_path(&path)?).await?;
+                println!("Registered database {}", name);
+            }
+            DatabasesCommands::List => {
+                let mut table = Table::new();
+                table.add_row(row!["Name", "Path"]);
+                for d in tables::registered_databases().await? {
+                    table.add_row(row![d.metadata["name"], d.metadata["database"]]);
+                }
+                table.printstd();
+            }
+            DatabasesCommands::Remove { name } => {
+                tables::unregister_database(&name).await?;
+                println!("Removed database {}", name);
             }
         },
+        #[cfg(feature = "scheduler")]
+        Commands::Jobs { command } => match command {
+            JobsCommands::Add {
+                name,
+                schedule,
+                jitter,
+                command,
+            } => {
+                let job = scheduler::add_job(&name, &schedule, jitter, command).await?;
+                println!("Scheduled job {}, next run {}", job.name, job.next_run);
+            }
+            JobsCommands::List => {
+                let mut table = Table::new();
+                table.add_row(row!["Name", "Schedule", "Command", "Next run", "Last run", "Status"]);
+                for j in scheduler::list_jobs().await? {
+                    let last_run = j.last_run.map(|d| d.to_string()).unwrap_or_default();
+                    let status = j.last_status.unwrap_or_default();
+                    table.add_row(row![j.name, j.schedule, j.command.join(" "), j.next_run, last_run, status]);
+                }
+                table.printstd();
+            }
+            JobsCommands::Run { name } => {
+                let job = scheduler::get_job(&name).await?;
+                scheduler::run_job(&job, &run_job_command).await?;
+            }
+            JobsCommands::Cancel { name } => {
+                scheduler::cancel_job(&name).await?;
+                println!("Cancelled job {}", name);
+            }
+        },
+        #[cfg(feature = "scheduler")]
+        Commands::Scheduler => scheduler::run_scheduler(run_job_command).await?,
+        Commands::Models => {
+            let mut table = Table::new();
+            table.add_row(row!["Name", "Backend", "Size"]);
+            for model in inference::Model::value_variants() {
+                if let Some(name) = model.to_possible_value() {
+                    table.add_row(row![name.get_name(), "local", ""]);
+                }
+            }
+            if let config::InferenceBackend::Ollama { base_url, .. } = &config::CONFIG.inference {
+                for model in ollama::list_models(base_url).await? {
+                    let size = format!("{:.1} GB", model.size as f64 / 1e9);
+                    table.add_row(row![model.name, "ollama", size]);
+                }
+            }
+            table.printstd();
+        }
+        Commands::Tokens { text, model } => {
+            let text = match text {
+                Some(text) => text,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            println!("{}", inference::count_tokens(&text, model)?);
+        }
+        #[cfg(feature = "server")]
+        Commands::Grpc {
+            addr,
+            socket,
+            replica,
+            model,
+        } => {
+            if let Some(path) = replica {
+                database::open_replica(&path)?;
+                snapshot::follow_replica(path);
+            }
+            match socket {
+                #[cfg(unix)]
+                Some(path) => tera::grpc::serve_socket(&path, model).await?,
+                #[cfg(not(unix))]
+                Some(_) => anyhow::bail!("Unix sockets aren't available on this platform"),
+                None => tera::grpc::serve(addr, model).await?,
+            }
+        }
+        Commands::HomeAssistant { addr, model, length } => {
+            let options = inference::AnswerOptions {
+                model,
+                length,
+                ..Default::default()
+            };
+            home_assistant::serve(addr, options).await?;
+        }
+        #[cfg(feature = "tray")]
+        Commands::Tray { hotkey, model, length } => {
+            let tera = tera::Tera::builder().model(model).length(length).build().await?;
+            tera::tray::run(tera, &hotkey)?;
+        }
+        #[cfg(unix)]
+        Commands::Daemon => {
+            database::resume_migration().await?;
+            tera::daemon::serve(run_job_command).await?
+        }
+        Commands::Mcp => mcp::serve().await?,
         Commands::List { start, limit } => {
             let content = database::get_all_content(start, limit).await?;
             let mut table = Table::new();
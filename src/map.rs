@@ -0,0 +1,121 @@
+use crate::database::{VectorIndex, DB};
+use crate::topics::list_topics;
+use anyhow::{Error, Result};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tera_core::projection::project_2d;
+
+// Characters of each chunk kept to tell points apart
+const PREVIEW_CHARS: usize = 120;
+// Page drawing the points, they replace the placeholder
+const VIEWER: &str = include_str!("map.html");
+const POINTS_PLACEHOLDER: &str = "/*POINTS*/[]";
+
+/// How a map of the knowledge base is written
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapFormat {
+    /// An array of points
+    Json,
+    /// A header line and a line per point, for spreadsheets and plotting tools
+    Csv,
+    /// A page drawing the points, opened in a browser without a server
+    Html,
+}
+
+impl MapFormat {
+    /// The format matching the extension of the path, JSON otherwise
+    pub fn for_path(path: &Path) -> MapFormat {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+            Some("csv") => MapFormat::Csv,
+            Some("html") | Some("htm") => MapFormat::Html,
+            _ => MapFormat::Json,
+        }
+    }
+}
+
+/// A chunk placed on the plane, close to the chunks with a similar meaning
+#[derive(Serialize, Debug, Clone)]
+pub struct MapPoint {
+    pub x: f32,
+    pub y: f32,
+    pub id: String,
+    pub content_id: String,
+    /// Title of the document of the chunk
+    pub title: String,
+    pub connector: String,
+    /// Label of its topic, once `tera topics build` ran
+    pub topic: Option<String>,
+    pub preview: String,
+}
+
+/// Every chunk projected to 2D from its embedding, with the principal components of all of them
+pub async fn map_points() -> Result<Vec<MapPoint>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM vector_index ORDER BY created_at ASC").await?;
+    let chunks: Vec<VectorIndex> = result.take(0)?;
+    let vectors = chunks.iter().map(|c| c.vector.clone()).collect::<Vec<_>>();
+    let positions = project_2d(&vectors);
+
+    let mut topics = HashMap::new();
+    for topic in list_topics().await? {
+        for document in topic.documents {
+            topics.insert(document.content_id, topic.label.clone());
+        }
+    }
+    Ok(chunks
+        .into_iter()
+        .zip(positions)
+        .map(|(chunk, [x, y])| {
+            let content_id = chunk.content_id.id.to_raw();
+            MapPoint {
+                x,
+                y,
+                id: chunk.id.id.to_raw(),
+                topic: topics.get(&content_id).cloned(),
+                content_id,
+                title: chunk.metadata["source"].as_str().unwrap_or_default().to_string(),
+                connector: chunk.metadata["connector"].as_str().unwrap_or_default().to_string(),
+                preview: chunk.content_chunk.chars().take(PREVIEW_CHARS).collect(),
+            }
+        })
+        .collect())
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// The points written in the format
+pub fn render_map(points: &[MapPoint], format: MapFormat) -> Result<String> {
+    Ok(match format {
+        MapFormat::Json => serde_json::to_string(points)?,
+        MapFormat::Csv => {
+            let mut csv = "x,y,id,content_id,title,connector,topic,preview\n".to_string();
+            for point in points {
+                let fields = [
+                    point.x.to_string(),
+                    point.y.to_string(),
+                    csv_field(&point.id),
+                    csv_field(&point.content_id),
+                    csv_field(&point.title),
+                    csv_field(&point.connector),
+                    csv_field(point.topic.as_deref().unwrap_or_default()),
+                    csv_field(&point.preview),
+                ];
+                csv += &format!("{}\n", fields.join(","));
+            }
+            csv
+        }
+        MapFormat::Html => {
+            // `</` can't end the script the points are in
+            let points = serde_json::to_string(points)?.replace("</", "<\\/");
+            VIEWER.replace(POINTS_PLACEHOLDER, &points)
+        }
+    })
+}
@@ -0,0 +1,159 @@
+use crate::audit;
+use crate::database::get_releted_chunks;
+use crate::embeddings::embed;
+use crate::inference::{answer_with_context, retrieve_context, AnswerLength, AnswerOptions};
+use crate::ingest::ingest_text;
+use crate::output::{render_answer, render_chunks, OutputFormat};
+use crate::stats;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tracing::debug;
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+fn tools() -> Value {
+    json!([
+        {
+            "name": "search_knowledge",
+            "description": "Search the user's personal knowledge base and return the most relevant passages with their sources.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "query": {"type": "string", "description": "What to search for"}
+                },
+                "required": ["query"]
+            }
+        },
+        {
+            "name": "ask_knowledge",
+            "description": "Answer a question from the user's personal knowledge base, citing the sources used.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "question": {"type": "string"},
+                    "length": {"type": "string", "enum": ["short", "normal", "long"]}
+                },
+                "required": ["question"]
+            }
+        },
+        {
+            "name": "save_note",
+            "description": "Save a note to the user's personal knowledge base so it can be found later.",
+            "inputSchema": {
+                "type": "object",
+                "properties": {
+                    "title": {"type": "string"},
+                    "text": {"type": "string"}
+                },
+                "required": ["text"]
+            }
+        }
+    ])
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str> {
+    arguments[name]
+        .as_str()
+        .with_context(|| format!("Missing argument {}", name))
+}
+
+async fn call_tool(name: &str, arguments: &Value) -> Result<String> {
+    match name {
+        "search_knowledge" => {
+            let query = string_argument(arguments, "query")?;
+            let chunks = get_releted_chunks(embed(query).await?).await?;
+            audit::record_query("search", query, &chunks);
+            stats::record_search().await;
+            if chunks.is_empty() {
+                return Ok("Nothing relevant was found.".to_string());
+            }
+            Ok(render_chunks(&chunks, OutputFormat::Markdown))
+        }
+        "ask_knowledge" => {
+            let question = string_argument(arguments, "question")?;
+            let mut options = AnswerOptions::default();
+            if let Some(length) = arguments["length"].as_str() {
+                options.length = AnswerLength::from_str(length, true).map_err(anyhow::Error::msg)?;
+            }
+            let context = retrieve_context(question, &options).await?;
+            let answer = answer_with_context(question, context, None, &options).await?;
+            Ok(render_answer(&answer, OutputFormat::Markdown))
+        }
+        "save_note" => {
+            let text = string_argument(arguments, "text")?;
+            if text.trim().is_empty() {
+                anyhow::bail!("Nothing to save");
+            }
+            let title = match arguments["title"].as_str() {
+                Some(title) => title.to_string(),
+                None => format!("Note on {}", chrono::Utc::now().date_naive()),
+            };
+            let id = ingest_text(&title, text, "mcp").await?;
+            Ok(format!("Saved the note as {}", id))
+        }
+        _ => anyhow::bail!("Unknown tool {}", name),
+    }
+}
+
+// Returns the response to a request, None for notifications
+async fn handle(message: Value) -> Option<Value> {
+    let id = message.get("id").cloned()?;
+    let method = message["method"].as_str().unwrap_or_default();
+    debug!(method = method, "MCP request");
+
+    let result = match method {
+        "initialize" => json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {"tools": {}},
+            "serverInfo": {"name": "tera", "version": env!("CARGO_PKG_VERSION")},
+        }),
+        "ping" => json!({}),
+        "tools/list" => json!({"tools": tools()}),
+        "tools/call" => {
+            let Some(name) = message["params"]["name"].as_str() else {
+                return Some(error(id, INVALID_PARAMS, "Missing tool name"));
+            };
+            // tool failures are reported to the model rather than as protocol errors
+            match call_tool(name, &message["params"]["arguments"]).await {
+                Ok(text) => json!({"content": [{"type": "text", "text": text}], "isError": false}),
+                Err(e) => json!({"content": [{"type": "text", "text": e.to_string()}], "isError": true}),
+            }
+        }
+        _ => return Some(error(id, METHOD_NOT_FOUND, &format!("Unknown method {}", method))),
+    };
+
+    Some(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+/// Serves Tera as an MCP server over stdio, one JSON-RPC message per line
+pub async fn serve() -> Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(message) => handle(message).await,
+            Err(e) => Some(error(Value::Null, PARSE_ERROR, &e.to_string())),
+        };
+        if let Some(response) = response {
+            stdout.write_all(format!("{}\n", response).as_bytes()).await?;
+            stdout.flush().await?;
+        }
+    }
+
+    Ok(())
+}
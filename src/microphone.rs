@@ -0,0 +1,77 @@
+use crate::whisper::whisper_decode;
+use anyhow::{Context, Result};
+use std::process::{Child, Command, Stdio};
+use tempfile::NamedTempFile;
+use tracing::debug;
+
+// The recorders of each platform, tried in order, the file goes last
+fn recorders() -> Vec<(&'static str, Vec<&'static str>)> {
+    if cfg!(target_os = "macos") {
+        vec![
+            ("rec", vec!["-q", "-c", "1", "-r", "16000"]),
+            ("ffmpeg", vec!["-loglevel", "quiet", "-y", "-f", "avfoundation", "-i", ":0", "-ac", "1", "-ar", "16000"]),
+        ]
+    } else if cfg!(target_os = "windows") {
+        vec![("sox", vec!["-q", "-t", "waveaudio", "default", "-c", "1", "-r", "16000"])]
+    } else {
+        vec![
+            ("arecord", vec!["-q", "-f", "S16_LE", "-c", "1", "-r", "16000"]),
+            ("rec", vec!["-q", "-c", "1", "-r", "16000"]),
+        ]
+    }
+}
+
+// Stops a recorder so it finishes writing the file, killing it where there are no signals
+fn stop(mut recorder: Child) -> Result<()> {
+    if cfg!(unix) {
+        let interrupted = Command::new("kill")
+            .args(["-INT", &recorder.id().to_string()])
+            .status()
+            .is_ok_and(|s| s.success());
+        if interrupted {
+            recorder.wait()?;
+            return Ok(());
+        }
+    }
+    recorder.kill()?;
+    recorder.wait()?;
+    Ok(())
+}
+
+/// Records the microphone until Enter is pressed, push to talk
+pub fn record() -> Result<NamedTempFile> {
+    let file = tempfile::Builder::new().suffix(".wav").tempfile()?;
+    let mut recorder = None;
+    for (program, args) in recorders() {
+        let spawned = Command::new(program)
+            .args(&args)
+            .arg(file.path())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        if let Ok(child) = spawned {
+            debug!(recorder = program, "Recording");
+            recorder = Some(child);
+            break;
+        }
+    }
+    let recorder = recorder.context("Unable to record the microphone, install alsa-utils or sox")?;
+    println!("Listening, press Enter when done.");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    stop(recorder)?;
+    Ok(file)
+}
+
+/// Records a question on the microphone and transcribes it with Whisper
+pub async fn listen() -> Result<String> {
+    let recording = tokio::task::spawn_blocking(record).await??;
+    let segments = whisper_decode(recording.path().to_path_buf()).await?;
+    let text = segments
+        .iter()
+        .map(|s| s.dr.text.trim())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(text.trim().to_string())
+}
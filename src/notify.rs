@@ -0,0 +1,172 @@
+use crate::config::{live, NotificationChannel};
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+lazy_static! {
+    // Added by Rust apps embedding Tera
+    static ref NOTIFIERS: Mutex<Vec<Arc<dyn Notifier>>> = Mutex::new(vec![]);
+}
+
+/// Something to tell the user about, e.g. a reminder coming due
+#[derive(Serialize, Debug, Clone)]
+pub struct Notification {
+    /// What it is about, `reminder` or `saved_search`
+    pub kind: String,
+    pub title: String,
+    pub body: String,
+}
+
+/// A way of delivering notifications. Sending is blocking, it runs on a blocking thread.
+pub trait Notifier: Send + Sync + std::fmt::Debug {
+    fn notify(&self, notification: &Notification) -> Result<()>;
+}
+
+/// A notification of the desktop, with notify-send on Linux and Notification Center on macOS
+#[derive(Debug, Clone, Copy)]
+pub struct Desktop;
+
+impl Notifier for Desktop {
+    fn notify(&self, notification: &Notification) -> Result<()> {
+        let status = if cfg!(target_os = "macos") {
+            let script = format!(
+                "display notification {} with title {}",
+                applescript_string(&notification.body),
+                applescript_string(&notification.title)
+            );
+            Command::new("osascript").args(["-e", &script]).status()
+        } else {
+            Command::new("notify-send")
+                .args(["--app-name", "Tera", &notification.title, &notification.body])
+                .status()
+        }
+        .context("Unable to show a desktop notification, install libnotify on Linux")?;
+        if !status.success() {
+            anyhow::bail!("Unable to show a desktop notification");
+        }
+        Ok(())
+    }
+}
+
+fn applescript_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Posts a JSON body, for notifications and webhooks sent by Tera built with the `webhooks` feature
+#[cfg(feature = "webhooks")]
+pub(crate) fn post_json<'a>(
+    url: &str,
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    body: &str,
+) -> Result<()> {
+    let mut request = ureq::post(url).set("Content-Type", "application/json");
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    request.send_string(body)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "webhooks"))]
+pub(crate) fn post_json<'a>(_: &str, _: impl IntoIterator<Item = (&'a str, &'a str)>, _: &str) -> Result<()> {
+    anyhow::bail!("Tera was built without the `webhooks` feature")
+}
+
+/// Posts the notification as JSON to a URL, e.g. `{"kind": "reminder", "title": "...", "body": "..."}`
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    pub headers: HashMap<String, String>,
+}
+
+impl Notifier for Webhook {
+    fn notify(&self, notification: &Notification) -> Result<()> {
+        let headers = self.headers.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+        post_json(&self.url, headers, &serde_json::to_string(notification)?)
+            .with_context(|| format!("Unable to post notification to {}", self.url))
+    }
+}
+
+/// Sends the notification as a message of a Telegram bot
+#[derive(Debug, Clone)]
+pub struct Telegram {
+    pub token: String,
+    pub chat_id: String,
+}
+
+impl Notifier for Telegram {
+    fn notify(&self, notification: &Notification) -> Result<()> {
+        let message = json!({
+            "chat_id": self.chat_id,
+            "text": format!("{}\n{}", notification.title, notification.body),
+        });
+        let url = format!("https://api.telegram.org/bot{}/sendMessage", self.token);
+        post_json(&url, [], &message.to_string()).context("Unable to send notification to Telegram")
+    }
+}
+
+/// Posts the notification to a Slack incoming webhook
+#[derive(Debug, Clone)]
+pub struct Slack {
+    pub webhook_url: String,
+}
+
+impl Notifier for Slack {
+    fn notify(&self, notification: &Notification) -> Result<()> {
+        let message = json!({ "text": format!("*{}*\n{}", notification.title, notification.body) });
+        post_json(&self.webhook_url, [], &message.to_string()).context("Unable to send notification to Slack")
+    }
+}
+
+impl NotificationChannel {
+    fn notifier(&self) -> Arc<dyn Notifier> {
+        match self {
+            NotificationChannel::Desktop => Arc::new(Desktop),
+            NotificationChannel::Webhook { url, headers } => Arc::new(Webhook {
+                url: url.clone(),
+                headers: headers.clone(),
+            }),
+            NotificationChannel::Telegram { token, chat_id } => Arc::new(Telegram {
+                token: token.clone(),
+                chat_id: chat_id.clone(),
+            }),
+            NotificationChannel::Slack { webhook_url } => Arc::new(Slack {
+                webhook_url: webhook_url.clone(),
+            }),
+        }
+    }
+}
+
+/// Adds a notifier receiving every notification along with the configured `notifications`
+pub fn register(notifier: Arc<dyn Notifier>) {
+    NOTIFIERS.lock().unwrap_or_else(|e| e.into_inner()).push(notifier);
+}
+
+/// Prints a notification on stderr and sends it through the configured `notifications` and the registered
+/// notifiers. A channel failing doesn't keep the others from getting it.
+pub async fn notify(kind: &str, title: &str, body: &str) {
+    let notification = Notification {
+        kind: kind.to_string(),
+        title: title.to_string(),
+        body: body.to_string(),
+    };
+    eprintln!("{}: {}", notification.title, notification.body);
+    let registered = NOTIFIERS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let config = live();
+    let configured = config.notifications.iter().map(|channel| channel.notifier());
+    for notifier in configured.chain(registered) {
+        debug!(kind = notification.kind, "Sending notification");
+        let sent = notification.clone();
+        let result = tokio::task::spawn_blocking(move || notifier.notify(&sent)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("{:#}", e),
+            Err(e) => eprintln!("Unable to send notification: {}", e),
+        }
+    }
+}
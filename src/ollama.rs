@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::time::Instant;
+use tracing::debug;
+
+pub const DEFAULT_URL: &str = "http://localhost:11434";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct OllamaModel {
+    pub name: String,
+    pub size: u64,
+    pub modified_at: String,
+}
+
+#[derive(Deserialize)]
+struct ChatChunk {
+    message: Option<Value>,
+    done: bool,
+    /// `length` when `num_predict` cut the reply
+    #[serde(default)]
+    done_reason: Option<String>,
+}
+
+fn endpoint(base_url: &str, path: &str) -> String {
+    format!("{}/api/{}", base_url.trim_end_matches('/'), path)
+}
+
+/// Lists the models pulled into the Ollama server
+pub async fn list_models(base_url: &str) -> Result<Vec<OllamaModel>> {
+    let response: Value = reqwest::get(endpoint(base_url, "tags"))
+        .await
+        .context("Unable to reach Ollama")?
+        .error_for_status()?
+        .json()
+        .await?;
+    let models: Vec<OllamaModel> = serde_json::from_value(response["models"].clone())?;
+    Ok(models)
+}
+
+/// Generates a chat reply, calling `on_token` with every piece of text as it is streamed back.
+/// Also returns whether the reply was cut by `max_tokens` or the deadline.
+pub async fn chat(
+    base_url: &str,
+    model: &str,
+    system: &str,
+    user: &str,
+    max_tokens: usize,
+    deadline: Option<Instant>,
+    mut on_token: impl FnMut(&str),
+) -> Result<(String, bool)> {
+    debug!(model = model, "Requesting Ollama completion");
+    let mut response = reqwest::Client::new()
+        .post(endpoint(base_url, "chat"))
+        .json(&json!({
+            "model": model,
+            "messages": [
+                {"role": "system", "content": system},
+                {"role": "user", "content": user},
+            ],
+            "stream": true,
+            "options": {
+                "num_predict": max_tokens,
+                "temperature": 0.3,
+            },
+        }))
+        .send()
+        .await
+        .context("Unable to reach Ollama")?
+        .error_for_status()?;
+
+    // the reply is streamed as newline delimited json objects
+    let mut answer = String::new();
+    let mut buffer = Vec::new();
+    while let Some(bytes) = response.chunk().await? {
+        // dropping the response closes the connection, which stops the generation
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok((answer.trim().to_string(), true));
+        }
+        buffer.extend_from_slice(&bytes);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<u8>>();
+            if line.iter().all(|b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            let chunk: ChatChunk = serde_json::from_slice(&line)?;
+            if let Some(token) = chunk.message.as_ref().and_then(|m| m["content"].as_str()) {
+                on_token(token);
+                answer += token;
+            }
+            if chunk.done {
+                let cut = chunk.done_reason.as_deref() == Some("length");
+                return Ok((answer.trim().to_string(), cut));
+            }
+        }
+    }
+
+    Ok((answer.trim().to_string(), false))
+}
+
+/// Embeds a text with an Ollama embedding model
+pub async fn embed(base_url: &str, model: &str, text: &str) -> Result<Vec<f32>> {
+    let response: Value = reqwest::Client::new()
+        .post(endpoint(base_url, "embeddings"))
+        .json(&json!({
+            "model": model,
+            "prompt": text,
+        }))
+        .send()
+        .await
+        .context("Unable to reach Ollama")?
+        .error_for_status()?
+        .json()
+        .await?;
+    let embedding: Vec<f32> = serde_json::from_value(response["embedding"].clone())
+        .context("Unexpected embedding response from Ollama")?;
+    Ok(embedding)
+}
@@ -0,0 +1,214 @@
+use crate::database::VectorIndex;
+use crate::inference::{Answer, Clarification, PastAnswer};
+use crate::postprocess::markdown_citations;
+use crate::provenance::Provenance;
+use clap::ValueEnum;
+use prettytable::{row, Table};
+use serde_json::json;
+
+// Below this, plain answers come with a warning
+const LOW_CONFIDENCE: f32 = 0.4;
+
+/// How answers and search results get printed
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable text and tables
+    #[default]
+    Plain,
+    /// Markdown with footnote style citations
+    Markdown,
+    /// JSON, for scripts
+    Json,
+}
+
+fn source_title(source: &VectorIndex) -> &str {
+    source.metadata["citation"]
+        .as_str()
+        .or(source.metadata["source"].as_str())
+        .unwrap_or_default()
+}
+
+// Where the source was read and when, for citations to be traced to their origin
+fn source_origin(source: &VectorIndex) -> Option<String> {
+    let provenance = Provenance::of(&source.metadata)?;
+    let mut origin = provenance.uri?;
+    if let Some(fetched_at) = provenance.fetched_at {
+        origin += &format!(", read on {}", fetched_at.with_timezone(&chrono::Local).format("%Y-%m-%d"));
+    }
+    Some(origin)
+}
+
+fn chunk_json(chunk: &VectorIndex) -> serde_json::Value {
+    json!({
+        "id": chunk.id.id.to_raw(),
+        "content_id": chunk.content_id.id.to_raw(),
+        "content": chunk.content_chunk,
+        "metadata": chunk.metadata,
+        "score": chunk.score,
+    })
+}
+
+pub fn render_answer(answer: &Answer, format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => json!({
+            "id": answer.id,
+            "answer": answer.text,
+            "confidence": answer.confidence,
+            "sources": answer.sources.iter().map(chunk_json).collect::<Vec<_>>(),
+            "follow_ups": answer.follow_ups,
+        })
+        .to_string(),
+        OutputFormat::Markdown => {
+            // the citations step marks the sentences, otherwise all sources follow the answer
+            let mut out = match markdown_citations(&answer.text) {
+                Some(text) => text,
+                None if !answer.sources.is_empty() => {
+                    let markers = (1..=answer.sources.len())
+                        .map(|i| format!("[^{}]", i))
+                        .collect::<String>();
+                    format!("{} {}", answer.text, markers)
+                }
+                None => answer.text.clone(),
+            };
+            if !answer.sources.is_empty() {
+                out += "\n\n";
+                for (i, source) in answer.sources.iter().enumerate() {
+                    out += &format!("[^{}]: {} (`{}`)", i + 1, source_title(source), source.id.id.to_raw());
+                    if let Some(origin) = source_origin(source) {
+                        out += &format!(", from {}", origin);
+                    }
+                    out += "\n";
+                }
+            }
+            if !answer.follow_ups.is_empty() {
+                out += "\n**You could also ask:**\n\n";
+                for question in answer.follow_ups.iter() {
+                    out += &format!("- {}\n", question);
+                }
+            }
+            out
+        }
+        OutputFormat::Plain => {
+            let mut out = format!("Answer: {}", answer.text);
+            if answer.confidence < LOW_CONFIDENCE && !answer.sources.is_empty() {
+                out += &format!("\n(low confidence: {:.2})", answer.confidence);
+            }
+            if !answer.follow_ups.is_empty() {
+                out += "\n\nYou could also ask:";
+                for question in answer.follow_ups.iter() {
+                    out += &format!("\n  - {}", question);
+                }
+            }
+            if !answer.sources.is_empty() {
+                out += "\n\nSources:";
+                for source in answer.sources.iter() {
+                    out += &format!("\n  {} {}", source.id.id.to_raw(), source_title(source));
+                    if let Some(origin) = source_origin(source) {
+                        out += &format!("\n    from {}", origin);
+                    }
+                }
+                out += &format!("\nRate them with `tera feedback {}`", answer.id);
+            }
+            out
+        }
+    }
+}
+
+/// An answer given before, with the question it was given to and when, see `tera ask --fresh`
+pub fn render_past_answer(past: &PastAnswer, format: OutputFormat) -> String {
+    let answer = &past.answer;
+    let date = past.answered_at.0.with_timezone(&chrono::Local).format("%B %e, %Y at %H:%M");
+    match format {
+        OutputFormat::Json => json!({
+            "id": answer.id,
+            "answer": answer.text,
+            "confidence": answer.confidence,
+            "sources": answer.sources.iter().map(chunk_json).collect::<Vec<_>>(),
+            "follow_ups": answer.follow_ups,
+            "question": past.question,
+            "answered_at": past.answered_at,
+        })
+        .to_string(),
+        OutputFormat::Markdown => format!(
+            "_Answered on {} to \"{}\", ask with `--fresh` for a new answer._\n\n{}",
+            date,
+            past.question,
+            render_answer(answer, format)
+        ),
+        OutputFormat::Plain => format!(
+            "Answered on {} to \"{}\", ask with `--fresh` for a new answer.\n{}",
+            date,
+            past.question,
+            render_answer(answer, format)
+        ),
+    }
+}
+
+/// The answer as a single line of JSON for launchers such as Raycast and Alfred, with only the titles of its sources
+pub fn render_quick(answer: &Answer) -> String {
+    json!({
+        "answer": answer.text,
+        "confidence": answer.confidence,
+        "sources": answer
+            .sources
+            .iter()
+            .map(|s| {
+                let uri = Provenance::of(&s.metadata).and_then(|p| p.uri);
+                json!({"id": s.id.id.to_raw(), "title": source_title(s), "uri": uri})
+            })
+            .collect::<Vec<_>>(),
+    })
+    .to_string()
+}
+
+pub fn render_clarification(clarification: &Clarification, format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => json!({
+            "clarification": clarification.question,
+            "topics": clarification.topics,
+        })
+        .to_string(),
+        OutputFormat::Markdown => {
+            let mut out = format!("{}\n\n", clarification.question);
+            for topic in clarification.topics.iter() {
+                out += &format!("- {}\n", topic);
+            }
+            out
+        }
+        OutputFormat::Plain => {
+            let mut out = clarification.question.clone();
+            for topic in clarification.topics.iter() {
+                out += &format!("\n  - {}", topic);
+            }
+            out
+        }
+    }
+}
+
+pub fn render_chunks(chunks: &[VectorIndex], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => json!(chunks.iter().map(chunk_json).collect::<Vec<_>>()).to_string(),
+        OutputFormat::Markdown => {
+            let mut out = String::new();
+            for (i, chunk) in chunks.iter().enumerate() {
+                out += &format!(
+                    "{}. **{}** ({:.3})\n\n   > {}\n\n",
+                    i + 1,
+                    source_title(chunk),
+                    chunk.score.unwrap_or_default(),
+                    chunk.content_chunk.replace('\n', "\n   > ")
+                );
+            }
+            out
+        }
+        OutputFormat::Plain => {
+            let mut table = Table::new();
+            table.add_row(row!["Score", "Source", "Chunk"]);
+            for c in chunks {
+                let score = format!("{:.3}", c.score.unwrap_or_default());
+                table.add_row(row![score, source_title(c), c.content_chunk]);
+            }
+            table.to_string()
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// Longest path most Windows APIs accept without the `\\?\` prefix
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// Decodes text written by Windows tools as well, which favour UTF-16 and byte order marks.
+/// Invalid sequences are replaced rather than failing the whole file.
+pub fn decode_text(bytes: &[u8]) -> String {
+    match bytes {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => String::from_utf8_lossy(rest).to_string(),
+        [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, u16::from_le_bytes),
+        [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, u16::from_be_bytes),
+        _ => match utf16_byte_order(bytes) {
+            Some(from_bytes) => decode_utf16(bytes, from_bytes),
+            None => String::from_utf8_lossy(bytes).to_string(),
+        },
+    }
+}
+
+// UTF-16 written without a byte order mark, e.g. by `Out-File` or a redirection in PowerShell 5.
+// Text mostly in ASCII leaves every other byte NUL, which UTF-8 text has none of.
+fn utf16_byte_order(bytes: &[u8]) -> Option<fn([u8; 2]) -> u16> {
+    if bytes.len() < 2 || bytes.len() % 2 == 1 {
+        return None;
+    }
+    let pairs = bytes.len() / 2;
+    let nuls = |offset: usize| bytes.iter().skip(offset).step_by(2).filter(|b| **b == 0).count();
+    let (high, low) = (nuls(0), nuls(1));
+    if low > pairs / 2 && high < low / 4 {
+        Some(u16::from_le_bytes)
+    } else if high > pairs / 2 && low < high / 4 {
+        Some(u16::from_be_bytes)
+    } else {
+        None
+    }
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> String {
+    let units = bytes
+        .chunks_exact(2)
+        .map(|pair| from_bytes([pair[0], pair[1]]))
+        .collect::<Vec<u16>>();
+    String::from_utf16_lossy(&units)
+}
+
+pub fn read_text_file(path: &Path) -> Result<String> {
+    let bytes = std::fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    Ok(decode_text(&bytes))
+}
+
+/// Reads stdin, Windows PowerShell pipes text to other programs as UTF-16
+pub fn read_stdin() -> Result<String> {
+    let mut bytes = vec![];
+    std::io::stdin()
+        .read_to_end(&mut bytes)
+        .context("Unable to read stdin")?;
+    Ok(decode_text(&bytes))
+}
+
+/// Makes a path absolute so it keeps working past the 260 characters limit of Windows.
+/// Short paths are left without the `\\?\` prefix since tools like git and ffmpeg choke on it.
+pub fn long_path(path: &Path) -> Result<PathBuf> {
+    let path = std::fs::canonicalize(path)
+        .with_context(|| format!("Unable to find {}", path.display()))?;
+    #[cfg(windows)]
+    {
+        let display = path.to_string_lossy();
+        if let Some(short) = display.strip_prefix(r"\\?\") {
+            if short.len() < MAX_PATH && !short.starts_with("UNC\\") {
+                return Ok(PathBuf::from(short));
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Listens on a unix socket only the current user can connect to, replacing a socket left behind
+/// by a process which didn't stop cleanly
+#[cfg(unix)]
+pub fn bind_private_socket(path: &Path) -> Result<std::os::unix::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if UnixStream::connect(path).is_ok() {
+        anyhow::bail!("Something is already listening on {}", path.display());
+    }
+    let _ = std::fs::remove_file(path);
+    // no one else may connect while the permissions are set
+    let previous = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+    let listener = listener.with_context(|| format!("Unable to listen on {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Bytes available to the current user on the file system holding the path
+#[cfg(unix)]
+// the widths of the fields depend on the platform
+#[allow(clippy::unnecessary_cast)]
+pub fn free_space(path: &Path) -> Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let name = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(name.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Unable to stat {}", path.display()));
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        text.encode_utf16().flat_map(to_bytes).collect()
+    }
+
+    #[test]
+    fn decodes_utf16_without_a_byte_order_mark() {
+        let text = "Café opens at 8 on Sundays\r\n";
+        assert_eq!(decode_text(&utf16(text, u16::to_le_bytes)), text);
+        assert_eq!(decode_text(&utf16(text, u16::to_be_bytes)), text);
+    }
+
+    #[test]
+    fn decodes_utf8_and_byte_order_marks() {
+        assert_eq!(decode_text("Café".as_bytes()), "Café");
+        assert_eq!(decode_text(b"\xEF\xBB\xBFCaf\xC3\xA9"), "Café");
+        let mut marked = vec![0xFF, 0xFE];
+        marked.extend(utf16("Café", u16::to_le_bytes));
+        assert_eq!(decode_text(&marked), "Café");
+    }
+}
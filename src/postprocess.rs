@@ -0,0 +1,186 @@
+use crate::config::{live, PostProcessing};
+use crate::database::VectorIndex;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashSet;
+use tera_core::chunking::{split_sentences, Script};
+
+// Share of the longer words of a sentence a reference needs to have for the sentence to cite it
+const CITATION_OVERLAP: f32 = 0.5;
+
+lazy_static! {
+    // Special tokens of the chat templates models sometimes write out
+    static ref SPECIAL_TOKEN: Regex = Regex::new(r"<\|[a-z_]+\|>").unwrap();
+    // Roles and labels of the prompt the answer can start with
+    static ref LABEL: Regex = Regex::new(r"(?i)^\s*(assistant\s*\n|(assistant|answer|tera)\s*:)\s*").unwrap();
+    // `#tag` being a tag rather than a heading, only deeper headings are spaced out
+    static ref HEADING: Regex = Regex::new(r"(?m)^(#{2,6})([^#\s])").unwrap();
+    static ref SPACES: Regex = Regex::new(r"[ \t]{2,}").unwrap();
+    static ref BLANK_LINES: Regex = Regex::new(r"\n{3,}").unwrap();
+    static ref CITATION: Regex = Regex::new(r"\[(\d+)\]").unwrap();
+}
+
+/// A step rewriting generated answers before they are returned, in the order they are configured
+pub trait PostProcessor: Send + Sync + std::fmt::Debug {
+    fn process(&self, text: String, sources: &[VectorIndex]) -> String;
+}
+
+/// Removes special tokens of chat templates and labels of the prompt
+#[derive(Debug, Clone, Copy)]
+pub struct StripArtifacts;
+
+impl PostProcessor for StripArtifacts {
+    fn process(&self, text: String, _sources: &[VectorIndex]) -> String {
+        let text = SPECIAL_TOKEN.replace_all(&text, "");
+        let text = LABEL.replace(&text, "");
+        let text = text.trim();
+        // references and examples are quoted in the prompt, models answer the same way
+        let unquoted = text.strip_prefix('"').and_then(|t| t.strip_suffix('"'));
+        match unquoted {
+            Some(inner) if !inner.contains('"') => inner.to_string(),
+            _ => text.to_string(),
+        }
+    }
+}
+
+/// Closes code blocks, bold text and inline code left open, and spaces headings out
+#[derive(Debug, Clone, Copy)]
+pub struct FixMarkdown;
+
+impl PostProcessor for FixMarkdown {
+    fn process(&self, text: String, _sources: &[VectorIndex]) -> String {
+        let mut text = HEADING.replace_all(&text, "$1 $2").replace("\n• ", "\n- ");
+        if let Some(rest) = text.strip_prefix("• ") {
+            text = format!("- {}", rest);
+        }
+        if text.matches("```").count() % 2 == 1 {
+            text += "\n```";
+        }
+        let outside_blocks = text.split("```").step_by(2).collect::<String>();
+        if outside_blocks.matches("**").count() % 2 == 1 {
+            text += "**";
+        }
+        if outside_blocks.replace("**", "").matches('`').count() % 2 == 1 {
+            text += "`";
+        }
+        text
+    }
+}
+
+/// Marks sentences taken from a reference with its number, `[1]` being the first source
+#[derive(Debug, Clone, Copy)]
+pub struct InsertCitations;
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() > 3)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+impl PostProcessor for InsertCitations {
+    fn process(&self, text: String, sources: &[VectorIndex]) -> String {
+        if sources.is_empty() || CITATION.is_match(&text) {
+            return text;
+        }
+        let sources = sources.iter().map(|s| words(&s.content_chunk)).collect::<Vec<_>>();
+        let script = Script::detect(&text);
+        let mut in_block = false;
+        let mut lines = vec![];
+        for line in text.lines() {
+            let fence = line.trim_start().starts_with("```");
+            if fence {
+                in_block = !in_block;
+            }
+            if fence || in_block || line.trim().is_empty() {
+                lines.push(line.to_string());
+                continue;
+            }
+            let indent = &line[..line.len() - line.trim_start().len()];
+            let sentences = split_sentences(line, script)
+                .into_iter()
+                .map(|sentence| {
+                    let sentence_words = words(sentence);
+                    let best = sources
+                        .iter()
+                        .enumerate()
+                        .map(|(i, source)| (i, sentence_words.intersection(source).count()))
+                        .max_by_key(|(_, overlap)| *overlap);
+                    match best {
+                        Some((i, overlap))
+                            if !sentence_words.is_empty()
+                                && overlap as f32 / sentence_words.len() as f32 >= CITATION_OVERLAP =>
+                        {
+                            cite(sentence, i + 1)
+                        }
+                        _ => sentence.to_string(),
+                    }
+                })
+                .collect::<Vec<_>>();
+            lines.push(format!("{}{}", indent, sentences.join(" ")));
+        }
+        lines.join("\n")
+    }
+}
+
+// The marker goes before the punctuation ending the sentence
+fn cite(sentence: &str, number: usize) -> String {
+    let end = sentence.trim_end_matches(|c: char| ".!?;:。！？।؟".contains(c));
+    format!("{} [{}]{}", end, number, &sentence[end.len()..])
+}
+
+/// Trims lines, collapses runs of spaces outside code blocks and keeps at most one blank line
+#[derive(Debug, Clone, Copy)]
+pub struct NormalizeWhitespace;
+
+impl PostProcessor for NormalizeWhitespace {
+    fn process(&self, text: String, _sources: &[VectorIndex]) -> String {
+        let text = text.replace("\r\n", "\n");
+        let mut in_block = false;
+        let lines = text
+            .lines()
+            .map(|line| {
+                if line.trim_start().starts_with("```") {
+                    in_block = !in_block;
+                    return line.trim_end().to_string();
+                }
+                if in_block {
+                    return line.trim_end().to_string();
+                }
+                // indentation nests lists
+                let indent = &line[..line.len() - line.trim_start().len()];
+                format!("{}{}", indent, SPACES.replace_all(line.trim(), " "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        BLANK_LINES.replace_all(&text, "\n\n").trim().to_string()
+    }
+}
+
+impl PostProcessing {
+    fn processor(self) -> Box<dyn PostProcessor> {
+        match self {
+            PostProcessing::StripArtifacts => Box::new(StripArtifacts),
+            PostProcessing::FixMarkdown => Box::new(FixMarkdown),
+            PostProcessing::Citations => Box::new(InsertCitations),
+            PostProcessing::NormalizeWhitespace => Box::new(NormalizeWhitespace),
+        }
+    }
+}
+
+/// Runs the configured `post_processing` steps and then the given ones over a generated answer
+#[tracing::instrument(name = "post_processing", skip_all)]
+pub fn post_process(text: String, sources: &[VectorIndex], extra: &[std::sync::Arc<dyn PostProcessor>]) -> String {
+    let config = live();
+    let configured = config.post_processing.iter().map(|step| step.processor());
+    let text = configured.fold(text, |text, step| step.process(text, sources));
+    extra.iter().fold(text, |text, step| step.process(text, sources))
+}
+
+/// Footnote links for the `[1]` style markers of the citations step, `[^1]` in markdown
+pub fn markdown_citations(text: &str) -> Option<String> {
+    if !CITATION.is_match(text) {
+        return None;
+    }
+    Some(CITATION.replace_all(text, "[^$1]").to_string())
+}
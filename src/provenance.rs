@@ -0,0 +1,72 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+/// Where a chunk comes from and how it was made from the original, the `provenance` object of its
+/// metadata. Chunks memorized before it was recorded have none.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Provenance {
+    /// The connector which read the original, e.g. `pdf` or `web`
+    pub connector: String,
+    /// The original, e.g. `file:///home/me/report.pdf`, a URL or `git:/home/me/tera#<commit>`
+    pub uri: Option<String>,
+    /// When the original was read
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// What turned the original into the chunk, in order, e.g. `pdf_text`, `redaction`, `chunking`
+    pub steps: Vec<String>,
+}
+
+impl Provenance {
+    /// Read from the original at the uri, now
+    pub fn new(uri: impl Into<String>) -> Provenance {
+        Provenance {
+            uri: Some(uri.into()),
+            fetched_at: Some(Utc::now()),
+            ..Default::default()
+        }
+    }
+
+    /// Read from a file, now
+    pub fn file(path: &Path) -> Provenance {
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        Provenance::new(format!("file://{}", path.display()))
+    }
+
+    /// With a transformation applied after the previous ones
+    pub fn step(mut self, step: &str) -> Provenance {
+        self.steps.push(step.to_string());
+        self
+    }
+
+    /// The provenance in the metadata of a chunk
+    pub fn of(metadata: &Value) -> Option<Provenance> {
+        serde_json::from_value(metadata.get("provenance")?.clone()).ok()
+    }
+}
+
+fn update(metadata: &mut Value, change: impl FnOnce(&mut Provenance)) {
+    let mut provenance = Provenance::of(metadata).unwrap_or_default();
+    change(&mut provenance);
+    if let (Some(fields), Ok(value)) = (metadata.as_object_mut(), serde_json::to_value(provenance)) {
+        fields.insert("provenance".to_string(), value);
+    }
+}
+
+/// Adds a transformation to the provenance in the metadata, e.g. when the text got redacted
+pub(crate) fn add_step(metadata: &mut Value, step: &str) {
+    update(metadata, |p| p.steps.push(step.to_string()));
+}
+
+/// Fills in what the ingestion path left out of the provenance: the connector, and that the
+/// original was read now
+pub(crate) fn complete(metadata: &mut Value) {
+    let connector = metadata["connector"].as_str().unwrap_or_default().to_string();
+    update(metadata, |p| {
+        if p.connector.is_empty() {
+            p.connector = connector;
+        }
+        p.fetched_at.get_or_insert_with(Utc::now);
+    });
+}
@@ -0,0 +1,75 @@
+use crate::config::{Quantization, CONFIG};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{de, Deserialize, Deserializer};
+use tera_core::quantization::{quantized_cosine, Quantized};
+use tera_core::similarity::cosine_similarity;
+
+const PREFIX: &str = "tera:q8:";
+
+/// A vector as stored, quantized or not depending on `quantization` when it was written
+#[derive(Debug, Clone)]
+pub enum StoredVector {
+    Float(Vec<f32>),
+    Int8(Quantized),
+}
+
+impl StoredVector {
+    /// Cosine similarity to a query, quantized vectors are compared without dequantizing them
+    pub fn similarity(&self, query: &[f32]) -> f32 {
+        match self {
+            StoredVector::Float(vector) => cosine_similarity(query, vector),
+            StoredVector::Int8(quantized) => quantized_cosine(query, quantized),
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<f32> {
+        match self {
+            StoredVector::Float(vector) => vector,
+            StoredVector::Int8(quantized) => quantized.dequantize(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for StoredVector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Float(Vec<f32>),
+            Packed(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Float(vector) => Ok(StoredVector::Float(vector)),
+            Raw::Packed(packed) => {
+                let encoded = packed
+                    .strip_prefix(PREFIX)
+                    .ok_or_else(|| de::Error::custom("Not a quantized vector"))?;
+                let bytes = BASE64.decode(encoded).map_err(de::Error::custom)?;
+                Quantized::from_bytes(&bytes)
+                    .map(StoredVector::Int8)
+                    .ok_or_else(|| de::Error::custom("Truncated quantized vector"))
+            }
+        }
+    }
+}
+
+/// Serde adapter for vectors, written as int8 when `quantization` is `int8` and read back either way
+pub mod vector {
+    use super::*;
+    use serde::{Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(vector: &[f32], serializer: S) -> Result<S::Ok, S::Error> {
+        match CONFIG.quantization {
+            Quantization::None => vector.serialize(serializer),
+            Quantization::Int8 => {
+                let bytes = Quantized::new(vector).to_bytes();
+                serializer.serialize_str(&format!("{}{}", PREFIX, BASE64.encode(bytes)))
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<f32>, D::Error> {
+        Ok(StoredVector::deserialize(deserializer)?.into_vec())
+    }
+}
@@ -0,0 +1,192 @@
+use crate::config::{data_dir, CONFIG};
+use crate::crypto;
+use crate::database::{smart_insert_content, Content};
+use crate::redaction::loggable;
+use anyhow::{Error, Result};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+use tempfile::NamedTempFile;
+use tokio::sync::oneshot;
+use tracing::debug;
+
+/// Which documents get memorized first while more of them wait than there are `ingest_queue.workers`
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Saved by someone waiting for it, e.g. a note, a clipboard snippet or a gRPC ingest
+    Interactive,
+    /// Uploads of files and folders, scheduled connectors and crawls
+    Bulk,
+}
+
+/// Counters of the documents of a priority, since Tera started
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct QueueCounters {
+    /// Waiting for a worker now
+    pub waiting: usize,
+    /// Handed to a worker
+    pub admitted: u64,
+    /// Whose text waited on disk as the queue was full
+    pub spilled: u64,
+    /// Time the admitted documents waited, in milliseconds
+    pub waited_ms: u64,
+}
+
+/// The state of the ingestion queue of this process
+#[derive(Serialize, Debug, Clone)]
+pub struct QueueMetrics {
+    pub workers: usize,
+    /// Documents being memorized now
+    pub running: usize,
+    pub interactive: QueueCounters,
+    pub bulk: QueueCounters,
+}
+
+struct Queue {
+    running: usize,
+    // what wakes up each waiting document, by priority
+    waiting: [VecDeque<oneshot::Sender<()>>; 2],
+    counters: [QueueCounters; 2],
+}
+
+impl Queue {
+    // documents which stopped waiting, e.g. a cancelled request, left a closed sender behind
+    fn waiting(&self, priority: Priority) -> usize {
+        self.waiting[priority as usize].iter().filter(|w| !w.is_closed()).count()
+    }
+}
+
+lazy_static! {
+    static ref QUEUE: Mutex<Queue> = Mutex::new(Queue {
+        running: 0,
+        waiting: [VecDeque::new(), VecDeque::new()],
+        counters: Default::default(),
+    });
+}
+
+fn queue() -> MutexGuard<'static, Queue> {
+    QUEUE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn workers() -> usize {
+    CONFIG.ingest_queue.workers.max(1)
+}
+
+/// A worker memorizing a document, handed to the next document waiting when dropped
+pub struct Permit {
+    _private: (),
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut queue = queue();
+        for waiting in queue.waiting.iter_mut() {
+            while let Some(next) = waiting.pop_front() {
+                if next.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        queue.running -= 1;
+    }
+}
+
+// Gives the worker back when the document stops waiting right as it was handed one
+struct Ticket(oneshot::Receiver<()>);
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.0.try_recv().is_ok() {
+            drop(Permit { _private: () });
+        }
+    }
+}
+
+/// Waits for a worker to memorize a document, interactive documents going before bulk ones. Bulk
+/// uploads and crawls wait here rather than reading more documents than get memorized. Connectors
+/// storing their chunks one by one, rather than with `memorize`, hold the permit for the document.
+pub async fn admit(priority: Priority) -> Permit {
+    let started = Instant::now();
+    let receiver = {
+        let mut queue = queue();
+        let ahead = match priority {
+            Priority::Interactive => queue.waiting(Priority::Interactive),
+            Priority::Bulk => queue.waiting(Priority::Interactive) + queue.waiting(Priority::Bulk),
+        };
+        if queue.running < workers() && ahead == 0 {
+            queue.running += 1;
+            queue.counters[priority as usize].admitted += 1;
+            return Permit { _private: () };
+        }
+        let (sender, receiver) = oneshot::channel();
+        queue.waiting[priority as usize].push_back(sender);
+        receiver
+    };
+
+    let mut ticket = Ticket(receiver);
+    // senders only get dropped once they sent
+    let _ = (&mut ticket.0).await;
+    let mut queue = queue();
+    let counters = &mut queue.counters[priority as usize];
+    counters.admitted += 1;
+    counters.waited_ms += started.elapsed().as_millis() as u64;
+    Permit { _private: () }
+}
+
+enum Pending {
+    InMemory(String),
+    OnDisk(NamedTempFile),
+}
+
+// The text of a document waiting, sealed like the knowledge base, the file goes away when dropped
+fn spill(text: String) -> Result<NamedTempFile, Error> {
+    let dir = data_dir().join("queue");
+    std::fs::create_dir_all(&dir)?;
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(crypto::seal(&text)?.as_bytes())?;
+    Ok(file)
+}
+
+/// Memorizes a document with `smart_insert_content` once a worker is free. While
+/// `ingest_queue.capacity` documents wait already, its text waits on disk instead of in memory.
+pub async fn memorize(priority: Priority, title: &str, text: String, metadata: Value) -> Result<Content, Error> {
+    let waiting = {
+        let queue = queue();
+        queue.waiting(Priority::Interactive) + queue.waiting(Priority::Bulk)
+    };
+    let pending = match waiting >= CONFIG.ingest_queue.capacity {
+        true => {
+            debug!(title = %loggable(title), waiting = waiting, "Ingestion queue is full, the text waits on disk");
+            queue().counters[priority as usize].spilled += 1;
+            Pending::OnDisk(spill(text)?)
+        }
+        false => Pending::InMemory(text),
+    };
+
+    let _permit = admit(priority).await;
+    let text = match pending {
+        Pending::OnDisk(file) => crypto::open(&std::fs::read_to_string(file.path())?)?,
+        Pending::InMemory(text) => text,
+    };
+    smart_insert_content(title, &text, metadata).await
+}
+
+/// The workers busy, the documents waiting and the counters since Tera started
+pub fn metrics() -> QueueMetrics {
+    let queue = queue();
+    let counters = |priority: Priority| QueueCounters {
+        waiting: queue.waiting(priority),
+        ..queue.counters[priority as usize].clone()
+    };
+    QueueMetrics {
+        workers: workers(),
+        running: queue.running,
+        interactive: counters(Priority::Interactive),
+        bulk: counters(Priority::Bulk),
+    }
+}
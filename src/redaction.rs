@@ -0,0 +1,210 @@
+use crate::config::{live, LogContent, CONFIG};
+use anyhow::{Context, Error as E, Result};
+use candle_core::{Device, Module, Tensor};
+use candle_nn::{linear, Linear, VarBuilder};
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use hf_hub::{api::sync::Api, Repo};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokenizers::Tokenizer;
+use tracing::debug;
+
+lazy_static! {
+    static ref EMAIL: Regex = Regex::new(r"[\w.+-]+@[\w-]+(\.[\w-]+)+").unwrap();
+    static ref PHONE: Regex = Regex::new(r"\+?\(?\d[\d\s().-]{6,}\d").unwrap();
+    static ref CARD: Regex = Regex::new(r"\b\d(?:[ -]?\d){12,18}\b").unwrap();
+    static ref NER: (BertModel, Linear, Tokenizer, Vec<String>) =
+        load_ner_model().expect("Unable to load model");
+}
+
+/// Personal information which can be masked before chunks are stored
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Pii {
+    Email,
+    Phone,
+    CreditCard,
+    /// Names of people, found with a NER model
+    Person,
+}
+
+impl Pii {
+    fn mask(&self) -> &'static str {
+        match self {
+            Pii::Email => "[EMAIL]",
+            Pii::Phone => "[PHONE]",
+            Pii::CreditCard => "[CARD]",
+            Pii::Person => "[PERSON]",
+        }
+    }
+}
+
+/// Masks the personal information configured for the connector, `*` applies to every other connector
+pub fn redact(connector: &str, text: &str) -> Result<String> {
+    let Some(kinds) = CONFIG
+        .redaction
+        .get(connector)
+        .or_else(|| CONFIG.redaction.get("*"))
+    else {
+        return Ok(text.to_string());
+    };
+
+    let mut text = text.to_string();
+    // cards before phones, a card number also looks like a long phone number
+    for kind in [Pii::Email, Pii::CreditCard, Pii::Phone, Pii::Person] {
+        if !kinds.contains(&kind) {
+            continue;
+        }
+        text = match kind {
+            Pii::Email => EMAIL.replace_all(&text, kind.mask()).to_string(),
+            Pii::CreditCard => replace_matching(&CARD, &text, kind.mask(), luhn_valid),
+            Pii::Phone => replace_matching(&PHONE, &text, kind.mask(), looks_like_phone),
+            Pii::Person => redact_people(&text)?,
+        };
+    }
+    Ok(text)
+}
+
+// Characters of content kept in logs with `log_content` set to `truncated`
+const LOGGED_CHARACTERS: usize = 40;
+
+/// Content as written in logs, following `log_content`. Its size stays, to tell what took long.
+pub fn loggable(text: &str) -> String {
+    let length = text.chars().count();
+    match live().log_content {
+        LogContent::Full => text.to_string(),
+        LogContent::Truncated if length <= LOGGED_CHARACTERS => text.to_string(),
+        LogContent::Truncated => {
+            let start = text.chars().take(LOGGED_CHARACTERS).collect::<String>();
+            format!("{}… ({} chars)", start, length)
+        }
+        LogContent::Hashed => {
+            let hash = format!("{:x}", Sha256::digest(text.as_bytes()));
+            format!("sha256:{} ({} chars)", &hash[..12], length)
+        }
+    }
+}
+
+fn replace_matching(pattern: &Regex, text: &str, mask: &str, keep: fn(&str) -> bool) -> String {
+    pattern
+        .replace_all(text, |captures: &regex::Captures| {
+            let found = &captures[0];
+            if keep(found) {
+                mask.to_string()
+            } else {
+                found.to_string()
+            }
+        })
+        .to_string()
+}
+
+fn digits(text: &str) -> Vec<u32> {
+    text.chars().filter_map(|c| c.to_digit(10)).collect()
+}
+
+// Card numbers end with a Luhn check digit, which rules out most other long numbers
+fn luhn_valid(number: &str) -> bool {
+    let sum: u32 = digits(number)
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| match i % 2 {
+            0 => *d,
+            _ if *d * 2 > 9 => *d * 2 - 9,
+            _ => *d * 2,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+// Dates and times like 2024-01-03 12:30 match the pattern too but have too few digits
+fn looks_like_phone(number: &str) -> bool {
+    let count = digits(number).len();
+    (9..=15).contains(&count)
+}
+
+fn load_ner_model() -> Result<(BertModel, Linear, Tokenizer, Vec<String>)> {
+    let api = Api::new()?.repo(Repo::model("dslim/bert-base-NER".to_string()));
+    let config_filename = api.get("config.json")?;
+    let tokenizer_filename = api.get("tokenizer.json")?;
+    let weights_filename = api.get("model.safetensors")?;
+
+    let raw = std::fs::read_to_string(config_filename)?;
+    let config: Config = serde_json::from_str(&raw)?;
+    let raw: serde_json::Value = serde_json::from_str(&raw)?;
+    let hidden_size = raw["hidden_size"].as_u64().context("Missing hidden_size")? as usize;
+    let id2label = raw["id2label"].as_object().context("Missing id2label")?;
+    let mut labels = vec![String::new(); id2label.len()];
+    for (id, label) in id2label {
+        labels[id.parse::<usize>()?] = label.as_str().unwrap_or_default().to_string();
+    }
+
+    // not truncated, texts get split in windows the model reads whole
+    let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(E::msg)?;
+    let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights_filename], DTYPE, &Device::Cpu)? };
+    let model = BertModel::load(vb.pp("bert"), &config)?;
+    let classifier = linear(hidden_size, labels.len(), vb.pp("classifier"))?;
+
+    Ok((model, classifier, tokenizer, labels))
+}
+
+// Tokens of a window, well under the 512 positions of the NER model once tokenized again
+const NER_WINDOW: usize = 400;
+// How far back from a full window a cut looks for the start of a word
+const NER_WINDOW_SLACK: usize = 100;
+
+// Runs the NER model over windows of at most `NER_WINDOW` tokens, cut before a word so that no
+// part of the text, a long line included, goes unchecked
+fn redact_people(text: &str) -> Result<String> {
+    let (_, _, tokenizer, _) = &*NER;
+    let offsets = tokenizer.encode(text, false).map_err(E::msg)?.get_offsets().to_vec();
+    let mut redacted = String::with_capacity(text.len());
+    let mut start = 0;
+    let mut next = NER_WINDOW;
+    while next < offsets.len() {
+        let mut cut = next;
+        while cut > next - NER_WINDOW_SLACK && !text[..offsets[cut].0].ends_with(char::is_whitespace) {
+            cut -= 1;
+        }
+        let end = offsets[cut].0;
+        redacted += &redact_people_window(&text[start..end])?;
+        start = end;
+        next = cut + NER_WINDOW;
+    }
+    redacted += &redact_people_window(&text[start..])?;
+    Ok(redacted)
+}
+
+// Replaces the spans tagged as persons by the NER model
+fn redact_people_window(text: &str) -> Result<String> {
+    if text.trim().is_empty() {
+        return Ok(text.to_string());
+    }
+    let (model, classifier, tokenizer, labels) = &*NER;
+    let encoding = tokenizer.encode(text, true).map_err(E::msg)?;
+    let token_ids = Tensor::new(encoding.get_ids(), &Device::Cpu)?.unsqueeze(0)?;
+    let token_type_ids = token_ids.zeros_like()?;
+    let hidden = model.forward(&token_ids, &token_type_ids)?;
+    let predictions = classifier.forward(&hidden)?.argmax(2)?.squeeze(0)?.to_vec1::<u32>()?;
+
+    let mut spans: Vec<(usize, usize)> = vec![];
+    for (label, (start, end)) in predictions.iter().zip(encoding.get_offsets()) {
+        if !labels[*label as usize].ends_with("PER") || start == end {
+            continue;
+        }
+        match spans.last_mut() {
+            // word pieces and the rest of a name extend the previous span
+            Some(last) if text[last.1..*start].trim().is_empty() => last.1 = *end,
+            _ => spans.push((*start, *end)),
+        }
+    }
+    debug!(people = spans.len(), "Redacting people");
+
+    let mut redacted = text.to_string();
+    for (start, end) in spans.into_iter().rev() {
+        redacted.replace_range(start..end, Pii::Person.mask());
+    }
+    Ok(redacted)
+}
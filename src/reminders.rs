@@ -0,0 +1,142 @@
+use crate::database::{Content, DB};
+use crate::inference::{generate_with, AnswerOptions};
+use crate::notify::notify;
+use crate::redaction::loggable;
+use anyhow::{Context, Error, Result};
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{Datetime, Thing};
+use tera_core::chunking::split_chunks;
+use tracing::debug;
+
+// Reminders without a time are due in the morning
+const DEFAULT_TIME: (u32, u32) = (9, 0);
+
+lazy_static! {
+    // Chunks worth asking the model about: dates, weekdays and words of commitments
+    static ref CUE: Regex = Regex::new(concat!(
+        r"(?i)\b(\d{4}-\d{2}-\d{2}|\d{1,2}/\d{1,2}|today|tomorrow|tonight|next week|",
+        r"monday|tuesday|wednesday|thursday|friday|saturday|sunday|",
+        r"january|february|march|april|june|july|august|september|october|november|december|",
+        r"deadline|due|remind|appointment|meeting|don't forget|by the end of)\b"
+    ))
+    .unwrap();
+    // A line of the model's reply, `YYYY-MM-DD [HH:MM] | what to do`
+    static ref ITEM: Regex = Regex::new(r"^\s*[-*]?\s*(\d{4}-\d{2}-\d{2})(\s+(\d{1,2}:\d{2}))?\s*\|\s*(.+?)\s*$").unwrap();
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reminder {
+    pub id: Thing,
+    /// What to do, e.g. "Send the signed lease to the landlord"
+    pub text: String,
+    pub due: Datetime,
+    /// The content it was found in
+    pub content_id: Thing,
+    pub notified: bool,
+    pub done: bool,
+    pub created_at: Datetime,
+}
+
+#[derive(Serialize, Debug)]
+struct NewReminder {
+    text: String,
+    due: Datetime,
+    content_id: Thing,
+    notified: bool,
+    done: bool,
+    created_at: Datetime,
+}
+
+// The items of the model's reply, leaving out those due before `now` such as past deadlines the text mentions
+fn parse_items(reply: &str, now: NaiveDateTime) -> Vec<(NaiveDateTime, String)> {
+    reply
+        .lines()
+        .filter_map(|line| {
+            let item = ITEM.captures(line)?;
+            let date = NaiveDate::parse_from_str(&item[1], "%Y-%m-%d").ok()?;
+            let time = match item.get(3) {
+                Some(time) => NaiveTime::parse_from_str(time.as_str(), "%H:%M").ok()?,
+                None => NaiveTime::from_hms_opt(DEFAULT_TIME.0, DEFAULT_TIME.1, 0)?,
+            };
+            Some((date.and_time(time), item[4].to_string()))
+        })
+        .filter(|(due, _)| *due > now)
+        .collect()
+}
+
+/// Asks the model for the actionable items with a date in a text, e.g. "call the dentist on friday"
+pub async fn extract(text: &str) -> Result<Vec<(NaiveDateTime, String)>> {
+    let now = Local::now().naive_local();
+    let system = format!(
+        "You find tasks and appointments with a date in a text. Today is {today}. Write one per line as \"YYYY-MM-DD HH:MM | what to do\", leaving out the time when there is none. Write nothing else, and nothing when there is no task with a date.",
+        today = now.format("%A, %Y-%m-%d")
+    );
+    let options = AnswerOptions::default();
+    let mut items = vec![];
+    for chunk in split_chunks(text).into_iter().filter(|c| CUE.is_match(c)) {
+        let reply = generate_with(&system, &format!("text: \"{}\"\n", chunk), &options, 100, true).await?;
+        items.extend(parse_items(&reply, now));
+    }
+    Ok(items)
+}
+
+/// Stores the reminders found in memorized content
+pub async fn extract_from(content_id: &Thing) -> Result<Vec<Reminder>, Error> {
+    let db = DB.get().await.clone();
+    let content: Content = db.select(content_id.clone()).await?.context("Unknown content")?;
+    let mut reminders = vec![];
+    for (due, text) in extract(&content.text).await? {
+        let due = Local.from_local_datetime(&due).earliest().context("Invalid due date")?;
+        debug!(text = %loggable(&text), due = ?due, "Found reminder");
+        let reminder: Vec<Reminder> = db
+            .create("reminder")
+            .content(NewReminder {
+                text,
+                due: Datetime::from(due.with_timezone(&Utc)),
+                content_id: content_id.clone(),
+                notified: false,
+                done: false,
+                created_at: Datetime::default(),
+            })
+            .await?;
+        reminders.extend(reminder);
+    }
+    Ok(reminders)
+}
+
+/// Reminders not done yet, soonest first
+pub async fn list_reminders() -> Result<Vec<Reminder>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM reminder WHERE done = false ORDER BY due").await?;
+    let reminders: Vec<Reminder> = result.take(0)?;
+    Ok(reminders)
+}
+
+/// Marks a reminder as done, it is no longer listed or notified
+pub async fn complete_reminder(id: &str) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let reminder: Option<Reminder> = db.select(("reminder", id)).await?;
+    reminder.context("Unknown reminder")?;
+    db.query("UPDATE type::thing('reminder', $id) SET done = true")
+        .bind(("id", id))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+/// Reports the reminders which came due since the last check, once each
+pub async fn notify_due() -> Result<Vec<Reminder>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("UPDATE reminder SET notified = true WHERE done = false AND notified = false AND due <= time::now() RETURN BEFORE")
+        .await?;
+    let due: Vec<Reminder> = result.take(0)?;
+    for reminder in due.iter() {
+        let due = reminder.due.0.with_timezone(&Local).format("%Y-%m-%d %H:%M");
+        notify("reminder", "Reminder", &format!("{} (due {})", reminder.text, due)).await;
+    }
+    Ok(due)
+}
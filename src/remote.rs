@@ -0,0 +1,79 @@
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::time::Instant;
+use tracing::debug;
+
+/// Generates a chat completion using an OpenAI compatible server, calling `on_token` with every piece
+/// of text as it is streamed back. Also returns whether the reply was cut by `max_tokens` or the
+/// deadline, what arrived by the deadline being kept.
+#[allow(clippy::too_many_arguments)]
+pub async fn chat_completion(
+    base_url: &str,
+    api_key: Option<&str>,
+    model: &str,
+    system: &str,
+    user: &str,
+    max_tokens: usize,
+    deadline: Option<Instant>,
+    mut on_token: impl FnMut(&str),
+) -> Result<(String, bool)> {
+    let url = format!("{}/chat/completions", base_url.trim_end_matches('/'));
+    debug!(url = url, model = model, "Requesting remote completion");
+
+    let mut request = reqwest::Client::new().post(&url).json(&json!({
+        "model": model,
+        "messages": [
+            {"role": "system", "content": system},
+            {"role": "user", "content": user},
+        ],
+        "max_tokens": max_tokens,
+        "temperature": 0.3,
+        "stream": true,
+    }));
+    if let Some(api_key) = api_key {
+        request = request.bearer_auth(api_key);
+    }
+    let mut response = request
+        .send()
+        .await
+        .context("Unable to reach inference server")?
+        .error_for_status()?;
+
+    // the reply is streamed as server-sent events, one `data:` line per piece
+    let mut answer = String::new();
+    let mut buffer = Vec::new();
+    loop {
+        // dropping the response closes the connection, which stops the generation
+        let chunk = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline.into(), response.chunk()).await {
+                Ok(chunk) => chunk?,
+                Err(_) => return Ok((answer.trim().to_string(), true)),
+            },
+            None => response.chunk().await?,
+        };
+        let Some(bytes) = chunk else {
+            return Ok((answer.trim().to_string(), false));
+        };
+        buffer.extend_from_slice(&bytes);
+        while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<u8>>();
+            let Some(data) = std::str::from_utf8(&line)?.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                return Ok((answer.trim().to_string(), false));
+            }
+            let event: Value = serde_json::from_str(data).context("Unexpected response from inference server")?;
+            let choice = &event["choices"][0];
+            if let Some(token) = choice["delta"]["content"].as_str() {
+                on_token(token);
+                answer += token;
+            }
+            // `length` when `max_tokens` cut the reply
+            if let Some(reason) = choice["finish_reason"].as_str() {
+                return Ok((answer.trim().to_string(), reason == "length"));
+            }
+        }
+    }
+}
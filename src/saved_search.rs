@@ -0,0 +1,141 @@
+use crate::database::{index_embedder, search_with, Clauses, VectorIndex, DB};
+use crate::embeddings::{cosine_similarity, embed, reduce};
+use crate::notify::notify;
+use crate::quantization::StoredVector;
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use surrealdb::sql::{Datetime, Thing};
+use tracing::debug;
+
+// Similarity a new chunk needs to count as a match of a subscribed search
+const MATCH_THRESHOLD: f32 = 0.8;
+// Similarity at which chunks of different content are taken for the same item, e.g. news from two feeds
+const DUPLICATE_THRESHOLD: f32 = 0.95;
+// Results of a run, more being searched for so duplicates don't leave fewer
+const RUN_RESULTS: usize = 4;
+
+#[derive(Deserialize, Debug)]
+struct ChunkVector {
+    id: Thing,
+    vector: StoredVector,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedSearch {
+    pub name: String,
+    pub query: String,
+    /// Notify when newly memorized content matches
+    pub subscribed: bool,
+    /// Matches found since the search was last run
+    pub unseen: Vec<Thing>,
+    pub created_at: Datetime,
+}
+
+pub async fn save_search(name: &str, query: &str, subscribed: bool) -> Result<SavedSearch, Error> {
+    let db = DB.get().await.clone();
+    let search: Option<SavedSearch> = db
+        .update(("saved_search", name))
+        .content(SavedSearch {
+            name: name.to_string(),
+            query: query.to_string(),
+            subscribed,
+            unseen: vec![],
+            created_at: Datetime::default(),
+        })
+        .await?;
+    search.context("Unable to save search")
+}
+
+pub async fn list_searches() -> Result<Vec<SavedSearch>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM saved_search ORDER BY name").await?;
+    let searches: Vec<SavedSearch> = result.take(0)?;
+    Ok(searches)
+}
+
+pub async fn delete_search(name: &str) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let search: Option<SavedSearch> = db.delete(("saved_search", name)).await?;
+    search.context("Unknown saved search")?;
+    Ok(())
+}
+
+/// Runs a saved search, clearing its unseen matches. Chunks repeating a better match of other content
+/// are merged into it, their sources listed in its `duplicates` metadata.
+pub async fn run_search(name: &str) -> Result<Vec<VectorIndex>, Error> {
+    let db = DB.get().await.clone();
+    let search: SavedSearch = db
+        .select(("saved_search", name))
+        .await?
+        .context("Unknown saved search")?;
+    let chunks = search_with(embed(&search.query).await?, &Clauses::default(), RUN_RESULTS * 2).await?;
+    let mut chunks = merge_duplicates(chunks);
+    chunks.truncate(RUN_RESULTS);
+    db.query("UPDATE type::thing('saved_search', $name) SET unseen = []")
+        .bind(("name", name))
+        .await?
+        .check()?;
+    Ok(chunks)
+}
+
+// Merges every chunk, best first, into the first one of other content it nearly repeats
+fn merge_duplicates(chunks: Vec<VectorIndex>) -> Vec<VectorIndex> {
+    let mut merged: Vec<VectorIndex> = vec![];
+    for chunk in chunks {
+        let original = merged.iter_mut().find(|m| {
+            m.content_id != chunk.content_id && cosine_similarity(&m.vector, &chunk.vector) > DUPLICATE_THRESHOLD
+        });
+        let Some(original) = original else {
+            merged.push(chunk);
+            continue;
+        };
+        debug!(chunk = %chunk.id, original = %original.id, "Merging duplicate match");
+        let source = chunk.metadata["source"].clone();
+        match original.metadata["duplicates"].as_array_mut() {
+            Some(duplicates) => duplicates.push(source),
+            None => original.metadata["duplicates"] = json!([source]),
+        }
+    }
+    merged
+}
+
+/// Checks newly memorized content against subscribed searches and reports the matches
+pub async fn notify_subscribers(content_id: Thing) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT * FROM saved_search WHERE subscribed = true")
+        .await?;
+    let searches: Vec<SavedSearch> = result.take(0)?;
+    if searches.is_empty() {
+        return Ok(());
+    }
+
+    // compared here rather than in the query as the vectors may be quantized
+    let mut result = db
+        .query("SELECT id, vector FROM vector_index WHERE content_id = $content")
+        .bind(("content", content_id.clone()))
+        .await?;
+    let chunks: Vec<ChunkVector> = result.take(0)?;
+    for search in searches {
+        let query = reduce(&index_embedder().await?, &embed(&search.query).await?);
+        let matches = chunks
+            .iter()
+            .filter(|c| c.vector.similarity(&query) > MATCH_THRESHOLD)
+            .map(|c| c.id.clone())
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            continue;
+        }
+        debug!(search = search.name, matches = matches.len(), "New content matches saved search");
+        db.query("UPDATE type::thing('saved_search', $name) SET unseen += $matches")
+            .bind(("name", search.name.clone()))
+            .bind(("matches", matches.clone()))
+            .await?
+            .check()?;
+        let body = format!("New content matches \"{}\" ({} chunks)", search.name, matches.len());
+        notify("saved_search", "Saved search", &body).await;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use crate::config::{data_dir, WebhookEvent, CONFIG_MANAGER};
+use crate::database::DB;
+use crate::{shutdown, webhooks};
+use anyhow::{Context, Error, Result};
+use chrono::{DateTime, Utc};
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use surrealdb::sql::Datetime;
+use tracing::debug;
+
+// How often the scheduler looks for due jobs
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+// Heartbeats missed before the scheduler is considered stopped
+const MISSED_HEARTBEATS: u32 = 3;
+
+/// A tera command run on a cron schedule, e.g. refreshing a git history every morning
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub name: String,
+    /// Cron expression, `minute hour day month weekday` optionally preceded by seconds
+    pub schedule: String,
+    /// Arguments of the tera command, e.g. `["upload", "git", "/home/me/code/tera"]`
+    pub command: Vec<String>,
+    /// Runs are delayed by up to this many seconds so jobs on the same schedule don't pile up
+    pub jitter: u64,
+    pub next_run: Datetime,
+    pub last_run: Option<Datetime>,
+    /// `ok` or the error of the last run
+    pub last_status: Option<String>,
+    pub created_at: Datetime,
+}
+
+// A weekday of standard cron, 0 or 7 for Sunday and 1 for Monday, as the cron crate numbers them from
+// 1 for Sunday. Names like `MON` mean the same to both.
+fn weekday(day: &str) -> Result<String> {
+    match day.parse::<u32>() {
+        Ok(day @ 0..=7) => Ok((day % 7 + 1).to_string()),
+        Ok(day) => anyhow::bail!("Invalid weekday {}, expected 0 to 7", day),
+        Err(_) => Ok(day.to_string()),
+    }
+}
+
+fn weekdays(field: &str) -> Result<String> {
+    let items = field
+        .split(',')
+        .map(|item| {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => (range, Some(step)),
+                None => (item, None),
+            };
+            let range = match (range.split_once('-'), step) {
+                // Sunday ending a range comes before the other days for the cron crate
+                (Some((_, "7")), Some(_)) => anyhow::bail!("Use 0 for Sunday in a range with a step"),
+                (Some((start, "7")), None) => format!("{}-7,1", weekday(start)?),
+                (Some((start, end)), _) => format!("{}-{}", weekday(start)?, weekday(end)?),
+                (None, _) => weekday(range)?,
+            };
+            Ok(match step {
+                Some(step) => format!("{}/{}", range, step),
+                None => range,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(items.join(","))
+}
+
+pub(crate) fn parse_schedule(schedule: &str) -> Result<Schedule> {
+    let mut fields = schedule.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+    // the cron crate wants seconds first
+    if fields.len() == 5 {
+        fields.insert(0, "0".to_string());
+    }
+    if let Some(field) = fields.get_mut(5) {
+        *field = weekdays(field).with_context(|| format!("Invalid schedule {}", schedule))?;
+    }
+    Schedule::from_str(&fields.join(" ")).with_context(|| format!("Invalid schedule {}", schedule))
+}
+
+// The next run after `after`, pushed back by a jitter which is stable for a given job and run
+fn next_run(name: &str, schedule: &str, jitter: u64, after: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let next = parse_schedule(schedule)?
+        .after(&after)
+        .next()
+        .context("The schedule never runs")?;
+    if jitter == 0 {
+        return Ok(next);
+    }
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    name.hash(&mut hasher);
+    next.timestamp().hash(&mut hasher);
+    Ok(next + chrono::Duration::seconds((hasher.finish() % (jitter + 1)) as i64))
+}
+
+/// Schedules a job, replacing any job with the same name
+pub async fn add_job(name: &str, schedule: &str, jitter: u64, command: Vec<String>) -> Result<Job> {
+    let db = DB.get().await.clone();
+    let next = next_run(name, schedule, jitter, Utc::now())?;
+    let job: Option<Job> = db
+        .update(("job", name))
+        .content(Job {
+            name: name.to_string(),
+            schedule: schedule.to_string(),
+            command,
+            jitter,
+            next_run: next.into(),
+            last_run: None,
+            last_status: None,
+            created_at: Datetime::default(),
+        })
+        .await?;
+    job.context("Unable to schedule job")
+}
+
+pub async fn list_jobs() -> Result<Vec<Job>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM job ORDER BY next_run").await?;
+    let jobs: Vec<Job> = result.take(0)?;
+    Ok(jobs)
+}
+
+pub async fn get_job(name: &str) -> Result<Job, Error> {
+    let db = DB.get().await.clone();
+    let job: Option<Job> = db.select(("job", name)).await?;
+    job.context("Unknown job")
+}
+
+/// Removes a job from the schedule
+pub async fn cancel_job(name: &str) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let job: Option<Job> = db.delete(("job", name)).await?;
+    job.context("Unknown job")?;
+    Ok(())
+}
+
+/// Runs a job with `execute` and records the outcome along with its next run
+pub async fn run_job<F, Fut>(job: &Job, execute: &F) -> Result<(), Error>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    debug!(job = job.name, command = ?job.command, "Running job");
+    let status = match execute(job.command.clone()).await {
+        Ok(()) => "ok".to_string(),
+        Err(e) => {
+            let error = e.to_string();
+            let data = json!({"job": job.name, "command": job.command, "error": error});
+            webhooks::emit(WebhookEvent::JobFailed, data).await;
+            error
+        }
+    };
+    println!("Job {} finished: {}", job.name, status);
+
+    let db = DB.get().await.clone();
+    let next = next_run(&job.name, &job.schedule, job.jitter, Utc::now())?;
+    db.query("UPDATE type::thing('job', $name) SET last_run = time::now(), last_status = $status, next_run = $next")
+        .bind(("name", job.name.clone()))
+        .bind(("status", status))
+        .bind(("next", Datetime::from(next)))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+// Written by the running scheduler every poll interval, even while a job runs, for health checks of
+// other processes
+fn heartbeat_path() -> PathBuf {
+    data_dir().join("scheduler.heartbeat")
+}
+
+/// When the scheduler was last seen running, `None` once it stopped or when it is late
+pub fn last_heartbeat() -> Option<DateTime<Utc>> {
+    let beat = std::fs::read_to_string(heartbeat_path()).ok()?;
+    let at = DateTime::parse_from_rfc3339(beat.trim()).ok()?.with_timezone(&Utc);
+    let late = (Utc::now() - at).to_std().unwrap_or_default() > POLL_INTERVAL * MISSED_HEARTBEATS;
+    (!late).then_some(at)
+}
+
+// Beats until dropped, however the scheduler stops
+struct Heartbeat(tokio::task::JoinHandle<()>);
+
+impl Heartbeat {
+    fn start() -> Heartbeat {
+        Heartbeat(tokio::spawn(async {
+            loop {
+                if let Err(e) = std::fs::write(heartbeat_path(), Utc::now().to_rfc3339()) {
+                    debug!(error = ?e, "Unable to write the scheduler heartbeat");
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }))
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        self.0.abort();
+        let _ = std::fs::remove_file(heartbeat_path());
+    }
+}
+
+// Runs the jobs which are due, one at a time
+async fn run_due<F, Fut>(execute: &F) -> Result<()>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT * FROM job WHERE next_run <= time::now() ORDER BY next_run")
+        .await?;
+    let due: Vec<Job> = result.take(0)?;
+    for job in due {
+        // the job under way finishes and records its outcome, the others wait for the next start
+        if shutdown::requested() {
+            break;
+        }
+        if let Err(e) = run_job(&job, execute).await {
+            eprintln!("Unable to record the run of job {}: {:?}", job.name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Runs due jobs one at a time and reports due reminders until Ctrl+C or SIGTERM
+pub async fn run_scheduler<F, Fut>(execute: F) -> Result<()>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    crate::connectors::schedule_connectors().await?;
+    shutdown::listen();
+    CONFIG_MANAGER.watch();
+    println!("Running scheduled jobs, press Ctrl+C to stop.");
+    let _heartbeat = Heartbeat::start();
+    while !shutdown::requested() {
+        // a failure is reported and tried again on the next poll, the scheduler keeps running
+        if let Err(e) = run_due(&execute).await {
+            eprintln!("Unable to run the due jobs: {:?}", e);
+        }
+        if let Err(e) = crate::reminders::notify_due().await {
+            eprintln!("Unable to report the due reminders: {:?}", e);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = shutdown::wait() => {}
+        }
+    }
+    shutdown::finish();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone, Weekday};
+
+    #[test]
+    fn monday_is_day_one() {
+        // a Sunday
+        let after = Utc.with_ymd_and_hms(2024, 1, 7, 12, 0, 0).unwrap();
+        let next = next_run("test", "0 9 * * 1", 0, after).unwrap();
+        assert_eq!(next, Utc.with_ymd_and_hms(2024, 1, 8, 9, 0, 0).unwrap());
+        assert_eq!(next.weekday(), Weekday::Mon);
+    }
+
+    #[test]
+    fn sunday_is_day_zero_or_seven() {
+        let after = Utc.with_ymd_and_hms(2024, 1, 8, 12, 0, 0).unwrap();
+        let schedules = [("0 9 * * 0", Weekday::Sun), ("0 9 * * 7", Weekday::Sun), ("0 9 * * 6-7", Weekday::Sat)];
+        for (schedule, day) in schedules {
+            assert_eq!(next_run("test", schedule, 0, after).unwrap().weekday(), day);
+        }
+        assert_eq!(weekdays("1-5").unwrap(), "2-6");
+        assert_eq!(weekdays("5-7").unwrap(), "6-7,1");
+    }
+}
@@ -0,0 +1,99 @@
+use crate::vector_file;
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::debug;
+
+/// How long generations under way get to finish once a shutdown is requested, they are cut after
+pub const DEADLINE: Duration = Duration::from_secs(30);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+static LISTEN: Once = Once::new();
+
+lazy_static! {
+    static ref STOPPING: Notify = Notify::new();
+}
+
+/// Whether the process is stopping, no new work should be started
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Whether work under way should stop at once, generations end at their last complete sentence
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Stops the process: servers stop accepting, and generations under way are cut after the [`DEADLINE`]
+pub fn request() {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    debug!("Shutdown requested");
+    STOPPING.notify_waiters();
+    tokio::spawn(async {
+        tokio::time::sleep(DEADLINE).await;
+        cancel();
+    });
+}
+
+fn cancel() {
+    if !CANCELLED.swap(true, Ordering::SeqCst) {
+        eprintln!("Cancelling the work under way");
+    }
+}
+
+/// Resolves once a shutdown is requested
+pub async fn wait() {
+    loop {
+        // created before checking, so a request in between isn't missed
+        let stopping = STOPPING.notified();
+        if requested() {
+            return;
+        }
+        stopping.await;
+    }
+}
+
+async fn signalled() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Requests a shutdown on Ctrl+C or SIGTERM, for servers and the daemon. A second signal cancels the work
+/// under way without waiting for the deadline.
+pub fn listen() {
+    LISTEN.call_once(|| {
+        tokio::spawn(async {
+            signalled().await;
+            eprintln!(
+                "Stopping, work under way gets {} seconds to finish. Press Ctrl+C again to cancel it.",
+                DEADLINE.as_secs()
+            );
+            request();
+            signalled().await;
+            cancel();
+        });
+    });
+}
+
+/// Writes what is kept in memory to disk, once the work under way is done
+pub fn finish() {
+    if let Err(e) = vector_file::flush() {
+        eprintln!("Unable to flush the vector file: {:#}", e);
+    }
+    debug!("Shutdown complete");
+}
@@ -0,0 +1,233 @@
+use crate::config::{data_dir, tables_path};
+use crate::database::{ensure_writable, DB};
+use crate::vector_file;
+use anyhow::{Context, Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use surrealdb::engine::local::{Db, Mem};
+use surrealdb::Surreal;
+use tracing::debug;
+
+// How often a replica looks for a newer snapshot
+const REPLICA_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A copy of the whole knowledge base, vectors, metadata and imported tables included
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    /// When the snapshot was taken, e.g. `20240103-120000-250`, followed by a counter when
+    /// several snapshots were taken within the same millisecond
+    pub id: String,
+    /// Size of the database export in bytes
+    pub size: u64,
+}
+
+fn snapshots_dir() -> PathBuf {
+    data_dir().join("snapshots")
+}
+
+fn export_path(id: &str) -> PathBuf {
+    snapshots_dir().join(format!("{}.surql", id))
+}
+
+fn tables_copy_path(id: &str) -> PathBuf {
+    snapshots_dir().join(format!("{}.tables.sqlite", id))
+}
+
+// The export of a snapshot given by the user, which can only name a file of the snapshots directory
+fn existing_export(id: &str) -> Result<PathBuf, Error> {
+    if id.is_empty() || id.contains(['/', '\\']) || id.contains("..") {
+        anyhow::bail!("Invalid snapshot id {}", id);
+    }
+    let path = export_path(id);
+    if !path.exists() {
+        anyhow::bail!("Unknown snapshot {}", id);
+    }
+    Ok(path)
+}
+
+// Taken within the same millisecond, e.g. right before and after an upgrade, snapshots get a counter
+fn new_id() -> String {
+    let id = chrono::Local::now().format("%Y%m%d-%H%M%S-%3f").to_string();
+    let mut unique = id.clone();
+    let mut count = 0;
+    while export_path(&unique).exists() {
+        count += 1;
+        unique = format!("{}-{}", id, count);
+    }
+    unique
+}
+
+/// Exports the database, and the imported tables next to it, under a new snapshot id
+pub async fn snapshot() -> Result<Snapshot, Error> {
+    let db = DB.get().await.clone();
+    snapshot_of(&db).await
+}
+
+pub(crate) async fn snapshot_of(db: &Surreal<Db>) -> Result<Snapshot, Error> {
+    std::fs::create_dir_all(snapshots_dir())?;
+    let id = new_id();
+    let path = export_path(&id);
+    debug!(path = ?path, "Taking snapshot");
+
+    // a single export is consistent, unlike copying the RocksDB files of an open database
+    db.export(&path).await.context("Unable to export the database")?;
+    if tables_path().exists() {
+        std::fs::copy(tables_path(), tables_copy_path(&id))?;
+    }
+
+    Ok(Snapshot {
+        size: std::fs::metadata(&path)?.len(),
+        id,
+    })
+}
+
+/// Snapshots oldest first
+pub fn list_snapshots() -> Result<Vec<Snapshot>, Error> {
+    if !snapshots_dir().exists() {
+        return Ok(vec![]);
+    }
+    let mut snapshots = vec![];
+    for entry in std::fs::read_dir(snapshots_dir())? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("surql") {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+        snapshots.push(Snapshot {
+            id: id.to_string(),
+            size: std::fs::metadata(&path)?.len(),
+        });
+    }
+    snapshots.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(snapshots)
+}
+
+/// The database exports of the snapshots, which still hold the values offloaded when they were taken
+pub(crate) fn export_paths() -> Result<Vec<PathBuf>, Error> {
+    Ok(list_snapshots()?.iter().map(|snapshot| export_path(&snapshot.id)).collect())
+}
+
+/// Replaces the whole knowledge base with a snapshot, anything memorized since is lost. Everything in
+/// the database is rolled back, jobs, trash, exclusions, feedback, saved searches and sync state
+/// included. A snapshot of the knowledge base is taken first, and restored if the import fails.
+/// Returns that snapshot.
+pub async fn restore_snapshot(id: &str) -> Result<Snapshot, Error> {
+    ensure_writable()?;
+    let path = existing_export(id)?;
+    let db = DB.get().await.clone();
+    let safety = snapshot_of(&db)
+        .await
+        .context("Unable to take a snapshot before restoring")?;
+    debug!(path = ?path, safety = safety.id, "Restoring snapshot");
+    if let Err(e) = replace_database(&db, &path).await {
+        replace_database(&db, &export_path(&safety.id))
+            .await
+            .with_context(|| format!("Unable to restore {}, nor to roll back to {}: {}", id, safety.id, e))?;
+        return Err(e.context(format!("Unable to restore {}, rolled back to {}", id, safety.id)));
+    }
+    vector_file::refresh().await?;
+
+    let tables_copy = tables_copy_path(id);
+    if tables_copy.exists() {
+        std::fs::copy(tables_copy, tables_path())?;
+    } else if tables_path().exists() {
+        std::fs::remove_file(tables_path())?;
+    }
+    Ok(safety)
+}
+
+/// Replaces everything in the database with a snapshot export, upgraded to the current schema
+pub(crate) async fn replace_database(db: &Surreal<Db>, path: &Path) -> Result<(), Error> {
+    // the export defines its tables again, so they are dropped rather than emptied
+    let mut result = db.query("INFO FOR DB").await?;
+    let info: Option<Value> = result.take(0)?;
+    let tables = info
+        .as_ref()
+        .and_then(|info| info["tables"].as_object())
+        .map(|tables| tables.keys().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    for table in tables {
+        db.query(format!("REMOVE TABLE {}", table)).await?.check()?;
+    }
+    db.import(path).await.context("Unable to import the snapshot")?;
+    crate::contacts::people_changed();
+    // snapshots taken by an earlier version of Tera get upgraded like the knowledge base
+    if let Some(upgrade) = crate::migrations::migrate(db).await? {
+        debug!(from = upgrade.from, steps = ?upgrade.steps, "Upgraded the snapshot");
+    }
+    Ok(())
+}
+
+/// The snapshot a replica serves, the latest one when `path` is a snapshots directory
+pub fn replica_snapshot(path: &Path) -> Result<PathBuf, Error> {
+    if !path.is_dir() {
+        return Ok(path.to_path_buf());
+    }
+    let mut exports = std::fs::read_dir(path)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("surql"))
+        .collect::<Vec<_>>();
+    exports.sort();
+    exports
+        .pop()
+        .with_context(|| format!("No snapshot in {}", path.display()))
+}
+
+/// Reloads the replica whenever a newer snapshot shows up, e.g. synced from the main machine
+pub fn follow_replica(path: PathBuf) {
+    tokio::spawn(async move {
+        let mut loaded = replica_snapshot(&path).ok().and_then(|p| modified(&p));
+        loop {
+            tokio::time::sleep(REPLICA_POLL_INTERVAL).await;
+            let Ok(snapshot) = replica_snapshot(&path) else {
+                continue;
+            };
+            let current = modified(&snapshot);
+            if current.is_none() || current == loaded {
+                continue;
+            }
+            debug!(path = ?snapshot, "Reloading replica");
+            // searches keep the copy loaded until the new one is complete
+            match load_replica(&snapshot).await {
+                Ok(db) => {
+                    DB.swap(db);
+                    loaded = current;
+                    if let Err(e) = vector_file::refresh().await {
+                        eprintln!("Unable to rebuild the vector file: {}", e);
+                    }
+                }
+                Err(e) => eprintln!("Unable to reload the replica: {}", e),
+            }
+        }
+    });
+}
+
+// A new copy of the database in memory holding a snapshot, upgraded to the current schema
+async fn load_replica(path: &Path) -> Result<Surreal<Db>, Error> {
+    let db = Surreal::new::<Mem>(()).await?;
+    db.use_ns("rag").use_db("content").await?;
+    db.import(path).await.context("Unable to import the snapshot")?;
+    if let Some(upgrade) = crate::migrations::migrate(&db).await? {
+        debug!(from = upgrade.from, steps = ?upgrade.steps, "Upgraded the snapshot");
+    }
+    Ok(db)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Deletes a snapshot
+pub fn delete_snapshot(id: &str) -> Result<(), Error> {
+    let path = existing_export(id)?;
+    std::fs::remove_file(path)?;
+    let tables_copy = tables_copy_path(id);
+    if tables_copy.exists() {
+        std::fs::remove_file(tables_copy)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use crate::database::{Content, VectorIndex, DB};
+use crate::queue::{metrics, QueueMetrics};
+use anyhow::{Error, Result};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use surrealdb::sql::Thing;
+use tracing::debug;
+
+// Documents listed as most retrieved
+const TOP_DOCUMENTS: usize = 10;
+
+/// Counters of a day, kept in the `usage` table
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DailyUsage {
+    /// `YYYY-MM-DD`, in UTC
+    pub day: String,
+    /// Questions answered
+    pub queries: u64,
+    pub searches: u64,
+    /// Time spent answering the questions, in milliseconds
+    pub latency_ms: u64,
+    /// Documents memorized, and the chunks they were split into
+    pub ingestions: u64,
+    pub chunks: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetrievedDocument {
+    pub content_id: String,
+    pub title: String,
+    /// How many answers were based on it
+    pub retrievals: u64,
+}
+
+/// A summary of the use of Tera
+#[derive(Serialize, Debug, Clone)]
+pub struct Stats {
+    pub queries: u64,
+    pub searches: u64,
+    pub average_latency_ms: Option<u64>,
+    pub top_documents: Vec<RetrievedDocument>,
+    /// Documents and chunks memorized now
+    pub documents: usize,
+    pub chunks: usize,
+    /// The counters of each day with any, oldest first
+    pub days: Vec<DailyUsage>,
+    /// The documents this process is memorizing and waiting to
+    pub ingestion: QueueMetrics,
+}
+
+#[derive(Deserialize, Debug)]
+struct Retrieval {
+    content_id: Thing,
+    count: u64,
+}
+
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+async fn count_usage(counters: &str) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    db.query(format!("UPDATE type::thing('usage', $day) SET day = $day, {}", counters))
+        .bind(("day", today()))
+        .await?
+        .check()?;
+    Ok(())
+}
+
+// Counting is best effort, failing doesn't fail the question or the ingestion
+fn report(result: Result<(), Error>) {
+    if let Err(e) = result {
+        debug!(error = ?e, "Unable to update usage counters");
+    }
+}
+
+/// Counts an answered question, the time it took and the documents it was based on
+pub async fn record_answer(latency: std::time::Duration, sources: &[VectorIndex]) {
+    let counted = async {
+        count_usage(&format!("queries += 1, latency_ms += {}", latency.as_millis())).await?;
+        let db = DB.get().await.clone();
+        let documents = sources.iter().map(|s| s.content_id.clone()).collect::<HashSet<_>>();
+        for content_id in documents {
+            db.query("UPDATE type::thing('retrieval', $key) SET content_id = $content_id, count += 1")
+                .bind(("key", content_id.id.to_raw()))
+                .bind(("content_id", content_id))
+                .await?
+                .check()?;
+        }
+        Ok::<(), Error>(())
+    };
+    report(counted.await);
+}
+
+pub async fn record_search() {
+    report(count_usage("searches += 1").await);
+}
+
+/// Counts a memorized document along with its chunks
+pub async fn record_ingestion(content_id: &Thing) {
+    let counted = async {
+        let db = DB.get().await.clone();
+        let mut result = db
+            .query("SELECT count() AS total FROM vector_index WHERE content_id = $content_id GROUP ALL")
+            .bind(("content_id", content_id.clone()))
+            .await?;
+        let chunks: Option<u64> = result.take((0, "total"))?;
+        count_usage(&format!("ingestions += 1, chunks += {}", chunks.unwrap_or(0))).await
+    };
+    report(counted.await);
+}
+
+async fn total(table: &str) -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query(format!("SELECT count() AS total FROM {} GROUP ALL", table)).await?;
+    let total: Option<usize> = result.take((0, "total"))?;
+    Ok(total.unwrap_or(0))
+}
+
+/// The counters of the last `days` days, and what is memorized now
+pub async fn stats(days: i64) -> Result<Stats, Error> {
+    let db = DB.get().await.clone();
+    let since = (Utc::now() - Duration::days(days - 1)).format("%Y-%m-%d").to_string();
+    let mut result = db
+        .query("SELECT * FROM usage WHERE day >= $since ORDER BY day")
+        .bind(("since", since))
+        .await?;
+    let usage: Vec<DailyUsage> = result.take(0)?;
+
+    let mut result = db
+        .query("SELECT content_id, count FROM retrieval ORDER BY count DESC LIMIT $limit")
+        .bind(("limit", TOP_DOCUMENTS))
+        .await?;
+    let retrievals: Vec<Retrieval> = result.take(0)?;
+    let mut top_documents = vec![];
+    for retrieval in retrievals {
+        // forgotten documents keep their count, under their id
+        let content: Option<Content> = db.select(retrieval.content_id.clone()).await?;
+        top_documents.push(RetrievedDocument {
+            content_id: retrieval.content_id.id.to_raw(),
+            title: content.map(|c| c.title).unwrap_or_default(),
+            retrievals: retrieval.count,
+        });
+    }
+
+    let queries = usage.iter().map(|d| d.queries).sum::<u64>();
+    let latency = usage.iter().map(|d| d.latency_ms).sum::<u64>();
+    Ok(Stats {
+        queries,
+        searches: usage.iter().map(|d| d.searches).sum(),
+        average_latency_ms: (queries > 0).then(|| latency / queries),
+        top_documents,
+        documents: total("content").await?,
+        chunks: total("vector_index").await?,
+        days: usage,
+        ingestion: metrics(),
+    })
+}
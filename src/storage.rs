@@ -0,0 +1,286 @@
+use crate::config::{Storage, CONFIG};
+use anyhow::Result;
+use serde_json::Value;
+use surrealdb::engine::local::Db;
+use surrealdb::Surreal;
+#[cfg(feature = "s3")]
+use {
+    crate::config::data_dir,
+    anyhow::Context,
+    lazy_static::lazy_static,
+    rusty_s3::{Bucket, Credentials, S3Action, UrlStyle},
+    sha2::{Digest, Sha256},
+    std::collections::HashSet,
+    std::path::PathBuf,
+    std::sync::atomic::{AtomicU64, Ordering},
+    std::time::{Duration, SystemTime},
+    tokio::runtime::{Handle, RuntimeFlavor},
+    tracing::debug,
+};
+
+// Marks values kept in the object storage, the rest of the value is the object key
+const PREFIX: &str = "tera:s3:";
+// Shorter values stay in the database, a request would cost more than storing them
+#[cfg(feature = "s3")]
+const INLINE_LIMIT: usize = 256;
+#[cfg(feature = "s3")]
+const SIGNATURE_TTL: Duration = Duration::from_secs(600);
+
+#[cfg(feature = "s3")]
+lazy_static! {
+    static ref BUCKET: Option<(Bucket, Credentials)> = bucket().expect("Invalid object storage config");
+    // bytes in the local cache, scanned on first use
+    static ref CACHE_SIZE: AtomicU64 = AtomicU64::new(cache_size());
+}
+
+#[cfg(feature = "s3")]
+fn bucket() -> Result<Option<(Bucket, Credentials)>> {
+    let Storage::S3 {
+        endpoint,
+        bucket,
+        region,
+        access_key,
+        secret_key,
+        ..
+    } = &CONFIG.storage
+    else {
+        return Ok(None);
+    };
+    let bucket = Bucket::new(endpoint.parse()?, UrlStyle::Path, bucket.clone(), region.clone())?;
+    Ok(Some((bucket, Credentials::new(access_key, secret_key))))
+}
+
+#[cfg(feature = "s3")]
+fn cache_dir() -> PathBuf {
+    data_dir().join("blob_cache")
+}
+
+#[cfg(feature = "s3")]
+fn cache_limit() -> u64 {
+    match &CONFIG.storage {
+        Storage::S3 { cache_mb, .. } => cache_mb * 1024 * 1024,
+        Storage::Local => 0,
+    }
+}
+
+#[cfg(feature = "s3")]
+fn cached_files() -> Vec<(PathBuf, u64, SystemTime)> {
+    let Ok(entries) = std::fs::read_dir(cache_dir()) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(feature = "s3")]
+fn cache_size() -> u64 {
+    cached_files().iter().map(|(_, size, _)| size).sum()
+}
+
+#[cfg(feature = "s3")]
+fn cache(key: &str, value: &str) -> Result<()> {
+    std::fs::create_dir_all(cache_dir())?;
+    std::fs::write(cache_dir().join(key), value)?;
+    let size = CACHE_SIZE.fetch_add(value.len() as u64, Ordering::Relaxed) + value.len() as u64;
+    if size <= cache_limit() {
+        return Ok(());
+    }
+
+    // least recently used first, reads refresh the modification time
+    let mut files = cached_files();
+    files.sort_by_key(|(_, _, modified)| *modified);
+    let mut size = files.iter().map(|(_, size, _)| size).sum::<u64>();
+    for (path, file_size, _) in files {
+        if size <= cache_limit() * 9 / 10 {
+            break;
+        }
+        std::fs::remove_file(path)?;
+        size -= file_size;
+    }
+    debug!(size = size, "Evicted blobs from the cache");
+    CACHE_SIZE.store(size, Ordering::Relaxed);
+    Ok(())
+}
+
+#[cfg(feature = "s3")]
+fn cached(key: &str) -> Option<String> {
+    let path = cache_dir().join(key);
+    let value = std::fs::read_to_string(&path).ok()?;
+    if let Ok(file) = std::fs::File::options().write(true).open(&path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+    Some(value)
+}
+
+#[cfg(feature = "s3")]
+// Serde is synchronous, so requests to the object storage block the thread. The runtime moves its other
+// tasks to another worker meanwhile rather than waiting for the request.
+fn blocking<T>(request: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(request),
+        _ => request(),
+    }
+}
+
+/// Uploads a value to the object storage and returns its key
+#[cfg(feature = "s3")]
+fn put(value: &str) -> Result<String> {
+    let (bucket, credentials) = BUCKET.as_ref().context("No object storage configured")?;
+    let key = format!("{:x}", Sha256::digest(value.as_bytes()));
+    if cached(&key).is_none() {
+        let url = bucket.put_object(Some(credentials), &key).sign(SIGNATURE_TTL);
+        blocking(|| ureq::put(url.as_str()).send_string(value))
+            .with_context(|| format!("Unable to upload blob {}", key))?;
+        cache(&key, value)?;
+    }
+    Ok(key)
+}
+
+/// Reads a value from the local cache, or from the object storage
+#[cfg(feature = "s3")]
+fn get(key: &str) -> Result<String> {
+    if let Some(value) = cached(key) {
+        return Ok(value);
+    }
+    let (bucket, credentials) = BUCKET.as_ref().context("The text is in an object storage, set `storage` in the config")?;
+    debug!(key = key, "Downloading blob");
+    let url = bucket.get_object(Some(credentials), key).sign(SIGNATURE_TTL);
+    let value = blocking(|| ureq::get(url.as_str()).call()?.into_string().map_err(anyhow::Error::from))
+        .with_context(|| format!("Unable to download blob {}", key))?;
+    cache(key, &value)?;
+    Ok(value)
+}
+
+/// Moves a long text to the object storage when one is configured, leaving a reference behind
+pub fn offload(value: String) -> Result<String> {
+    #[cfg(feature = "s3")]
+    if BUCKET.is_some() && value.len() > INLINE_LIMIT {
+        return Ok(format!("{}{}", PREFIX, put(&value)?));
+    }
+    if !cfg!(feature = "s3") && matches!(CONFIG.storage, Storage::S3 { .. }) {
+        anyhow::bail!("Tera was built without the `s3` feature, set `storage` to `local` in the config");
+    }
+    Ok(value)
+}
+
+/// The text a value refers to, values stored in the database are returned as is
+pub fn load(value: String) -> Result<String> {
+    match value.strip_prefix(PREFIX) {
+        #[cfg(feature = "s3")]
+        Some(key) => get(key),
+        #[cfg(not(feature = "s3"))]
+        Some(_) => anyhow::bail!("The text is in an object storage, which needs Tera built with the `s3` feature"),
+        None => Ok(value),
+    }
+}
+
+/// The texts of many stored values at once, e.g. a page of records being upgraded, downloaded side by
+/// side off the async workers then decrypted
+pub async fn load_all(values: Vec<String>) -> Result<Vec<String>> {
+    let mut downloads = tokio::task::JoinSet::new();
+    for (i, value) in values.into_iter().enumerate() {
+        downloads.spawn_blocking(move || Ok::<_, anyhow::Error>((i, crate::crypto::open(&load(value)?)?)));
+    }
+    let mut texts = vec![String::new(); downloads.len()];
+    while let Some(downloaded) = downloads.join_next().await {
+        let (i, text) = downloaded??;
+        texts[i] = text;
+    }
+    Ok(texts)
+}
+
+/// The values kept in the object storage which a record holds, e.g. one being deleted
+pub fn references(value: &Value) -> Vec<String> {
+    match value {
+        Value::String(value) if value.starts_with(PREFIX) => vec![value.clone()],
+        Value::Array(values) => values.iter().flat_map(references).collect(),
+        Value::Object(fields) => fields.values().flat_map(references).collect(),
+        _ => vec![],
+    }
+}
+
+// The values which a record or a snapshot still holds, the same text memorized twice is stored once
+#[cfg(feature = "s3")]
+async fn referenced(db: &Surreal<Db>, values: &[String]) -> Result<HashSet<String>> {
+    let mut result = db
+        .query(
+            "SELECT VALUE text FROM content WHERE text INSIDE $values;
+            SELECT VALUE content_chunk FROM vector_index WHERE content_chunk INSIDE $values;
+            SELECT content.text, chunks.content_chunk FROM trash
+                WHERE content.text INSIDE $values OR chunks.content_chunk ANYINSIDE $values;
+            SELECT op.content.text, op.chunk.content_chunk FROM oplog
+                WHERE op.content.text INSIDE $values OR op.chunk.content_chunk INSIDE $values;",
+        )
+        .bind(("values", values.to_vec()))
+        .await?;
+    let mut held = HashSet::new();
+    for statement in 0..4 {
+        let records: Vec<Value> = result.take(statement)?;
+        held.extend(records.iter().flat_map(references));
+    }
+    // exported as SurrealQL, where the values are written as they are stored
+    for path in crate::snapshot::export_paths()? {
+        let export = std::fs::read_to_string(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+        held.extend(values.iter().filter(|value| export.contains(value.as_str())).cloned());
+    }
+    Ok(held)
+}
+
+/// Deletes the objects of values which were held by deleted records, once no record holds them
+#[cfg(not(feature = "s3"))]
+pub async fn delete_unreferenced(_db: &Surreal<Db>, _values: Vec<String>) -> Result<usize> {
+    Ok(0)
+}
+
+/// Deletes the objects of values which were held by deleted records, once no record or snapshot holds them
+#[cfg(feature = "s3")]
+pub async fn delete_unreferenced(db: &Surreal<Db>, mut values: Vec<String>) -> Result<usize> {
+    let Some((bucket, credentials)) = BUCKET.as_ref() else {
+        return Ok(0);
+    };
+    values.retain(|value| value.starts_with(PREFIX));
+    values.sort();
+    values.dedup();
+
+    let referenced = referenced(db, &values).await?;
+    let mut deleted = 0;
+    for value in values {
+        if referenced.contains(&value) {
+            continue;
+        }
+        let key = value[PREFIX.len()..].to_string();
+        let url = bucket.delete_object(Some(credentials), &key).sign(SIGNATURE_TTL);
+        tokio::task::spawn_blocking(move || ureq::delete(url.as_str()).call())
+            .await?
+            .with_context(|| format!("Unable to delete blob {}", key))?;
+        if let Ok(metadata) = std::fs::metadata(cache_dir().join(&key)) {
+            std::fs::remove_file(cache_dir().join(&key))?;
+            CACHE_SIZE.fetch_sub(metadata.len().min(CACHE_SIZE.load(Ordering::Relaxed)), Ordering::Relaxed);
+        }
+        deleted += 1;
+    }
+    debug!(deleted = deleted, "Deleted blobs");
+    Ok(deleted)
+}
+
+/// Serde helpers for long text fields, encrypted like `crypto::sealed` then offloaded,
+/// `#[serde(with = "crate::storage::blob")]`
+pub mod blob {
+    use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        let sealed = crate::crypto::seal(value).map_err(ser::Error::custom)?;
+        serializer.serialize_str(&super::offload(sealed).map_err(ser::Error::custom)?)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        let sealed = super::load(value).map_err(de::Error::custom)?;
+        crate::crypto::open(&sealed).map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,515 @@
+use crate::config::{data_dir, Encryption, SyncRemote, CONFIG};
+use crate::contacts::link_chunk;
+use crate::database::{remove_content, Content, VectorIndex, DB};
+use crate::vector_file::index_chunk;
+use anyhow::{Context, Error, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+#[cfg(feature = "s3")]
+use rusty_s3::actions::ListObjectsV2;
+#[cfg(feature = "s3")]
+use rusty_s3::{Bucket, Credentials, S3Action, UrlStyle};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+#[cfg(feature = "s3")]
+use std::time::Duration;
+use surrealdb::engine::local::Db;
+use surrealdb::sql::{Datetime, Thing, Uuid};
+use surrealdb::Surreal;
+use tracing::debug;
+
+lazy_static! {
+    static ref HREF: Regex = Regex::new(r"<(?:\w+:)?href>([^<]*)</(?:\w+:)?href>").unwrap();
+}
+
+#[cfg(feature = "s3")]
+const SIGNATURE_TTL: Duration = Duration::from_secs(600);
+#[cfg(not(feature = "s3"))]
+const WITHOUT_S3: &str = "Tera was built without the `s3` feature, sync through a folder or WebDAV";
+// Files of its own a device keeps in the remote before it merges them in one
+const COMPACT_AFTER: usize = 32;
+
+/// A change to the knowledge base, replayed on the other devices
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    InsertContent { content: Content },
+    InsertChunk { chunk: VectorIndex },
+    /// Deletes a content and all of its chunks
+    Delete { content_id: Thing },
+}
+
+impl Op {
+    fn content_id(&self) -> &Thing {
+        match self {
+            Op::InsertContent { content } => &content.id,
+            Op::InsertChunk { chunk } => &chunk.content_id,
+            Op::Delete { content_id } => content_id,
+        }
+    }
+}
+
+/// An entry of the oplog, local changes are kept until they are pushed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OpEntry {
+    /// The device which made the change
+    pub device: String,
+    pub at: Datetime,
+    pub op: Op,
+    #[serde(default)]
+    pub pushed: bool,
+}
+
+/// When a content was last inserted and deleted on any device, the latest change wins
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Clock {
+    inserted: Option<Datetime>,
+    deleted: Option<Datetime>,
+}
+
+/// How far the oplog of another device has been applied
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct SyncState {
+    /// The file read last
+    #[serde(default)]
+    segment: u64,
+    /// The entries of that file applied
+    applied: usize,
+}
+
+/// Outcome of `sync`
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub pushed: usize,
+    pub applied: usize,
+    /// Entries of this device left out of the remote as their content was deleted since
+    pub compacted: usize,
+}
+
+// A file of the oplog of a device in the remote, `<device>.<segment>.jsonl`. Each push writes a new
+// one, the `<device>.jsonl` of earlier versions comes first.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Segment {
+    device: String,
+    number: u64,
+    name: String,
+}
+
+impl Segment {
+    fn parse(name: &str) -> Option<Segment> {
+        // files being written start with a dot
+        let stem = name.strip_suffix(".jsonl").filter(|_| !name.starts_with('.'))?;
+        let numbered = stem
+            .rsplit_once('.')
+            .and_then(|(device, number)| Some((device, number.parse::<u64>().ok()?)));
+        let (device, number) = numbered.unwrap_or((stem, 0));
+        Some(Segment {
+            device: device.to_string(),
+            number,
+            name: name.to_string(),
+        })
+    }
+
+    fn name(device: &str, number: u64) -> String {
+        format!("{}.{:010}.jsonl", device, number)
+    }
+}
+
+/// Id of this device in the sync remote, created on first use
+pub fn device_id() -> Result<String> {
+    let path = data_dir().join("device_id");
+    if let Ok(id) = std::fs::read_to_string(&path) {
+        return Ok(id.trim().to_string());
+    }
+    let id = Uuid::new_v4().0.to_string();
+    std::fs::create_dir_all(data_dir())?;
+    std::fs::write(&path, &id)?;
+    Ok(id)
+}
+
+fn latest(current: Option<Datetime>, at: &Datetime) -> Option<Datetime> {
+    match current {
+        Some(current) if current >= *at => Some(current),
+        _ => Some(at.clone()),
+    }
+}
+
+async fn update_clock(db: &Surreal<Db>, op: &Op, at: &Datetime) -> Result<(), Error> {
+    let key = op.content_id().id.to_raw();
+    let mut clock: Clock = db.select(("sync_clock", key.as_str())).await?.unwrap_or_default();
+    match op {
+        Op::Delete { .. } => clock.deleted = latest(clock.deleted, at),
+        _ => clock.inserted = latest(clock.inserted, at),
+    }
+    let _: Option<Clock> = db.update(("sync_clock", key.as_str())).content(clock).await?;
+    Ok(())
+}
+
+/// Adds a local change to the oplog, nothing is recorded until sync is configured
+pub async fn record(op: Op) -> Result<(), Error> {
+    record_in(&DB.get().await.clone(), op).await
+}
+
+/// `record` on a given connection, for use while the database is being opened
+pub(crate) async fn record_in(db: &Surreal<Db>, op: Op) -> Result<(), Error> {
+    if CONFIG.sync.is_none() {
+        return Ok(());
+    }
+    let at = Datetime::default();
+    update_clock(db, &op, &at).await?;
+    let _: Vec<OpEntry> = db
+        .create("oplog")
+        .content(OpEntry {
+            device: device_id()?,
+            at,
+            op,
+            pushed: false,
+        })
+        .await?;
+    Ok(())
+}
+
+// Content memorized before sync was enabled goes out as regular inserts
+async fn seed() -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let state: Option<SyncState> = db.select(("sync_state", "seeded")).await?;
+    if state.is_some() {
+        return Ok(());
+    }
+    debug!("Seeding oplog with existing content");
+    let mut result = db.query("SELECT * FROM content; SELECT * FROM vector_index").await?;
+    let contents: Vec<Content> = result.take(0)?;
+    let chunks: Vec<VectorIndex> = result.take(1)?;
+    for content in contents {
+        record(Op::InsertContent { content }).await?;
+    }
+    for chunk in chunks {
+        record(Op::InsertChunk { chunk }).await?;
+    }
+    let _: Option<SyncState> = db
+        .update(("sync_state", "seeded"))
+        .content(SyncState::default())
+        .await?;
+    Ok(())
+}
+
+// Applies a change made on another device unless the content changed since
+async fn apply(entry: &OpEntry) -> Result<bool, Error> {
+    let db = DB.get().await.clone();
+    let key = entry.op.content_id().id.to_raw();
+    let clock: Clock = db.select(("sync_clock", key.as_str())).await?.unwrap_or_default();
+    let stale = match entry.op {
+        Op::Delete { .. } => clock.inserted.is_some_and(|inserted| inserted > entry.at),
+        // deletes win ties so content deleted anywhere stays deleted
+        _ => clock.deleted.is_some_and(|deleted| deleted >= entry.at),
+    };
+    if stale {
+        return Ok(false);
+    }
+
+    match &entry.op {
+        Op::InsertContent { content } => {
+            let _: Option<Content> = db
+                .update(("content", content.id.id.clone()))
+                .content(content.clone())
+                .await?;
+        }
+        Op::InsertChunk { chunk } => {
+            // a merged file of another device holds changes applied already
+            let mut result = db.query("SELECT VALUE id FROM $id").bind(("id", chunk.id.clone())).await?;
+            let applied: Vec<Thing> = result.take(0)?;
+            if !applied.is_empty() {
+                return Ok(false);
+            }
+            let chunk: Option<VectorIndex> = db
+                .update(("vector_index", chunk.id.id.clone()))
+                .content(chunk.clone())
+                .await?;
+            let chunk = chunk.context("Unable to insert vector index")?;
+            index_chunk(&chunk)?;
+            if chunk.metadata["type"] != "contact" {
+                link_chunk(&chunk).await?;
+            }
+        }
+        Op::Delete { content_id } => remove_content(content_id).await?,
+    }
+    update_clock(&db, &entry.op, &entry.at).await?;
+    Ok(true)
+}
+
+impl SyncRemote {
+    fn client(&self, method: &str, name: &str) -> Result<reqwest::RequestBuilder> {
+        let SyncRemote::WebDav { url, username, password } = self else {
+            anyhow::bail!("Not a WebDAV remote");
+        };
+        let url = format!("{}/{}", url.trim_end_matches('/'), name);
+        let mut request = reqwest::Client::new().request(reqwest::Method::from_bytes(method.as_bytes())?, url);
+        if let Some(username) = username {
+            request = request.basic_auth(username, password.as_ref());
+        }
+        Ok(request)
+    }
+
+    #[cfg(feature = "s3")]
+    fn bucket(&self) -> Result<(Bucket, Credentials, &str)> {
+        let SyncRemote::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            prefix,
+        } = self
+        else {
+            anyhow::bail!("Not an S3 remote");
+        };
+        let bucket = Bucket::new(endpoint.parse()?, UrlStyle::Path, bucket.clone(), region.clone())?;
+        Ok((bucket, Credentials::new(access_key, secret_key), prefix))
+    }
+
+    /// The oplog files in the remote, oldest first for each device
+    async fn list(&self) -> Result<Vec<Segment>> {
+        let names = match self {
+            SyncRemote::Folder { path } => {
+                std::fs::create_dir_all(path)?;
+                std::fs::read_dir(path)?
+                    .filter_map(|entry| entry.ok())
+                    .filter_map(|entry| entry.file_name().to_str().map(|n| n.to_string()))
+                    .collect::<Vec<_>>()
+            }
+            SyncRemote::WebDav { .. } => {
+                let body = self
+                    .client("PROPFIND", "")?
+                    .header("Depth", "1")
+                    .send()
+                    .await
+                    .context("Unable to reach the WebDAV server")?
+                    .error_for_status()?
+                    .text()
+                    .await?;
+                HREF.captures_iter(&body)
+                    .filter_map(|c| c[1].trim_end_matches('/').rsplit('/').next().map(|n| n.to_string()))
+                    .collect()
+            }
+            #[cfg(not(feature = "s3"))]
+            SyncRemote::S3 { .. } => anyhow::bail!(WITHOUT_S3),
+            #[cfg(feature = "s3")]
+            SyncRemote::S3 { .. } => {
+                let (bucket, credentials, prefix) = self.bucket()?;
+                let mut names = vec![];
+                let mut token = None;
+                loop {
+                    let mut action = bucket.list_objects_v2(Some(&credentials));
+                    action.with_prefix(prefix);
+                    if let Some(token) = &token {
+                        action.with_continuation_token(token);
+                    }
+                    let body = reqwest::get(action.sign(SIGNATURE_TTL))
+                        .await
+                        .context("Unable to reach the object storage")?
+                        .error_for_status()?
+                        .text()
+                        .await?;
+                    let listed = ListObjectsV2::parse_response(&body)?;
+                    let keys = listed.contents.into_iter().map(|object| object.key);
+                    names.extend(keys.filter_map(|key| key.strip_prefix(prefix).map(str::to_string)));
+                    token = listed.next_continuation_token;
+                    if token.is_none() {
+                        break names;
+                    }
+                }
+            }
+        };
+        let mut segments = names.iter().filter_map(|name| Segment::parse(name)).collect::<Vec<_>>();
+        segments.sort();
+        Ok(segments)
+    }
+
+    async fn read(&self, name: &str) -> Result<String> {
+        let response = match self {
+            SyncRemote::Folder { path } => {
+                return match std::fs::read_to_string(path.join(name)) {
+                    Ok(text) => Ok(text),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            SyncRemote::WebDav { .. } => self.client("GET", name)?.send().await?,
+            #[cfg(not(feature = "s3"))]
+            SyncRemote::S3 { .. } => anyhow::bail!(WITHOUT_S3),
+            #[cfg(feature = "s3")]
+            SyncRemote::S3 { .. } => {
+                let (bucket, credentials, prefix) = self.bucket()?;
+                let key = format!("{}{}", prefix, name);
+                reqwest::get(bucket.get_object(Some(&credentials), &key).sign(SIGNATURE_TTL)).await?
+            }
+        };
+        // merged into another file meanwhile
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(String::new());
+        }
+        Ok(response.error_for_status()?.text().await?)
+    }
+
+    // Only this device writes its files, each of them once
+    async fn write(&self, name: &str, lines: String) -> Result<()> {
+        let request = match self {
+            SyncRemote::Folder { path } => {
+                // written aside first, so other devices never read part of it
+                let partial = path.join(format!(".{}", name));
+                std::fs::write(&partial, lines)?;
+                std::fs::rename(partial, path.join(name))?;
+                return Ok(());
+            }
+            SyncRemote::WebDav { .. } => self.client("PUT", name)?,
+            #[cfg(not(feature = "s3"))]
+            SyncRemote::S3 { .. } => anyhow::bail!(WITHOUT_S3),
+            #[cfg(feature = "s3")]
+            SyncRemote::S3 { .. } => {
+                let (bucket, credentials, prefix) = self.bucket()?;
+                let key = format!("{}{}", prefix, name);
+                reqwest::Client::new().put(bucket.put_object(Some(&credentials), &key).sign(SIGNATURE_TTL))
+            }
+        };
+        request.body(lines).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let request = match self {
+            SyncRemote::Folder { path } => return Ok(std::fs::remove_file(path.join(name))?),
+            SyncRemote::WebDav { .. } => self.client("DELETE", name)?,
+            #[cfg(not(feature = "s3"))]
+            SyncRemote::S3 { .. } => anyhow::bail!(WITHOUT_S3),
+            #[cfg(feature = "s3")]
+            SyncRemote::S3 { .. } => {
+                let (bucket, credentials, prefix) = self.bucket()?;
+                let key = format!("{}{}", prefix, name);
+                reqwest::Client::new().delete(bucket.delete_object(Some(&credentials), &key).sign(SIGNATURE_TTL))
+            }
+        };
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+fn lines(entries: &[OpEntry]) -> Result<String> {
+    let mut lines = String::new();
+    for entry in entries {
+        lines += &serde_json::to_string(entry)?;
+        lines.push('\n');
+    }
+    Ok(lines)
+}
+
+// Merges the files of this device in one once they pile up, leaving out what was memorized then
+// deleted. Devices which read the older files skip the changes they applied already.
+async fn compact(remote: &SyncRemote, device: &str, own: &[Segment]) -> Result<usize, Error> {
+    #[derive(Deserialize)]
+    struct Row {
+        id: Thing,
+        #[serde(flatten)]
+        entry: OpEntry,
+    }
+    let Some(last) = own.last().filter(|_| own.len() > COMPACT_AFTER) else {
+        return Ok(0);
+    };
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT * FROM oplog WHERE device = $device ORDER BY at")
+        .bind(("device", device.to_string()))
+        .await?;
+    let rows: Vec<Row> = result.take(0)?;
+    let deleted = rows
+        .iter()
+        .filter(|row| matches!(row.entry.op, Op::Delete { .. }))
+        .map(|row| row.entry.op.content_id().clone())
+        .collect::<HashSet<_>>();
+    let (dropped, kept): (Vec<Row>, Vec<Row>) = rows
+        .into_iter()
+        .partition(|row| !matches!(row.entry.op, Op::Delete { .. }) && deleted.contains(row.entry.op.content_id()));
+
+    let kept = kept.into_iter().map(|row| row.entry).collect::<Vec<_>>();
+    remote.write(&Segment::name(device, last.number + 1), lines(&kept)?).await?;
+    for segment in own {
+        remote.delete(&segment.name).await?;
+    }
+    db.query("DELETE oplog WHERE id INSIDE $ids")
+        .bind(("ids", dropped.iter().map(|row| row.id.clone()).collect::<Vec<_>>()))
+        .await?
+        .check()?;
+    debug!(kept = kept.len(), dropped = dropped.len(), "Compacted oplog");
+    Ok(dropped.len())
+}
+
+/// Pushes the local oplog to the remote and applies the changes of the other devices
+pub async fn sync() -> Result<SyncReport, Error> {
+    let remote = CONFIG
+        .sync
+        .as_ref()
+        .context("Set `sync` in the config first")?;
+    if CONFIG.encryption == Encryption::Keychain {
+        anyhow::bail!("The keychain key is only known to this device, set `encryption` to `passphrase` to sync");
+    }
+    let db = DB.get().await.clone();
+    let device = device_id()?;
+    seed().await?;
+    let mut report = SyncReport::default();
+
+    let segments = remote.list().await?;
+    let mut own = segments.iter().filter(|s| s.device == device).cloned().collect::<Vec<_>>();
+    let mut result = db
+        .query("SELECT * FROM oplog WHERE device = $device AND pushed = false ORDER BY at")
+        .bind(("device", device.clone()))
+        .await?;
+    let pending: Vec<OpEntry> = result.take(0)?;
+    if let Some(last) = pending.last() {
+        let name = Segment::name(&device, own.last().map_or(1, |s| s.number + 1));
+        remote.write(&name, lines(&pending)?).await?;
+        db.query("UPDATE oplog SET pushed = true WHERE device = $device AND at <= $last")
+            .bind(("device", device.clone()))
+            .bind(("last", last.at.clone()))
+            .await?
+            .check()?;
+        own.extend(Segment::parse(&name));
+        report.pushed = pending.len();
+    }
+    report.compacted = compact(remote, &device, &own).await?;
+
+    let mut others = segments.into_iter().filter(|s| s.device != device).peekable();
+    while let Some(first) = others.peek().cloned() {
+        let mut state: SyncState = db.select(("sync_state", first.device.as_str())).await?.unwrap_or_default();
+        while let Some(segment) = others.next_if(|s| s.device == first.device) {
+            if segment.number < state.segment {
+                continue;
+            }
+            if segment.number > state.segment {
+                state = SyncState {
+                    segment: segment.number,
+                    applied: 0,
+                };
+            }
+            let log = remote.read(&segment.name).await?;
+            // a partly synced last line is picked up next time
+            let lines = log.lines().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>();
+            for line in lines.iter().skip(state.applied) {
+                let Ok(entry) = serde_json::from_str::<OpEntry>(line) else {
+                    break;
+                };
+                if apply(&entry).await? {
+                    report.applied += 1;
+                }
+                state.applied += 1;
+            }
+            if state.applied < lines.len() {
+                break;
+            }
+        }
+        // files after one read partly wait for the next sync
+        while others.next_if(|s| s.device == first.device).is_some() {}
+        debug!(device = %first.device, segment = state.segment, applied = state.applied, "Merged oplog");
+        let _: Option<SyncState> = db.update(("sync_state", first.device.as_str())).content(state).await?;
+    }
+
+    Ok(report)
+}
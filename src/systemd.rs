@@ -0,0 +1,86 @@
+use anyhow::{Context, Result};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::sync::Mutex;
+use tracing::debug;
+
+// The first socket passed by systemd, after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+// The socket passed by systemd until the daemon listens on it
+static ACTIVATED: Mutex<Option<RawFd>> = Mutex::new(None);
+
+/// Tells systemd about the state of the service, e.g. `READY=1` once it accepts requests, for units with
+/// `Type=notify`. Does nothing when not started by systemd.
+pub fn notify(state: &str) -> Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    debug!(state = state, "Notifying systemd");
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy().to_string();
+    match path.strip_prefix('@') {
+        // abstract sockets have no file, their name starts with a NUL byte written as @
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    }
+    .context("Unable to notify systemd")?;
+    Ok(())
+}
+
+/// Takes the socket systemd passed to the process, for socket activation with a `.socket` unit, out of
+/// the environment. Called first thing in `main`, while no other thread reads the environment.
+pub fn take_activation() -> Result<()> {
+    let for_us = std::env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .map(|count| count.parse::<RawFd>())
+        .transpose()
+        .context("Invalid LISTEN_FDS")?
+        .unwrap_or(0);
+    // processes started by the service mustn't take the sockets for theirs
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if !for_us || count == 0 {
+        return Ok(());
+    }
+    if count > 1 {
+        anyhow::bail!("systemd passed {} sockets, Tera listens on one", count);
+    }
+    if !is_unix_stream(LISTEN_FDS_START) {
+        anyhow::bail!("systemd passed a socket Tera can't listen on, set ListenStream= to a path in the .socket unit");
+    }
+    unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) };
+    *ACTIVATED.lock().unwrap_or_else(|e| e.into_inner()) = Some(LISTEN_FDS_START);
+    Ok(())
+}
+
+// Clients connect to the daemon with a unix stream socket
+fn is_unix_stream(fd: RawFd) -> bool {
+    let mut kind: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut address_length = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // fails for a descriptor which isn't a socket
+    let described = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut kind as *mut _ as *mut libc::c_void, &mut length)
+            == 0
+            && libc::getsockname(fd, &mut address as *mut _ as *mut libc::sockaddr, &mut address_length) == 0
+    };
+    described && kind == libc::SOCK_STREAM && address.ss_family as libc::c_int == libc::AF_UNIX
+}
+
+/// The unix socket systemd listens on for the service, taken with `take_activation`. `None` when not
+/// socket activated.
+pub fn activated_socket() -> Option<UnixListener> {
+    let fd = ACTIVATED.lock().unwrap_or_else(|e| e.into_inner()).take()?;
+    debug!("Socket activated by systemd");
+    // systemd hands the socket over and doesn't use it anymore
+    Some(unsafe { UnixListener::from_raw_fd(fd) })
+}
@@ -0,0 +1,409 @@
+use crate::config::tables_path;
+use crate::database::{commit_content, delete_content, is_dry_run, insert_content, insert_vector_index, VectorIndex, DB};
+use crate::inference::{generate_with, AnswerOptions};
+use crate::provenance::Provenance;
+use crate::queue::{self, Priority};
+use crate::redaction::loggable;
+use anyhow::{Context, Result};
+use chrono::Utc;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use rusqlite::types::{Value as SqlValue, ValueRef};
+use rusqlite::{Connection, OpenFlags};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+// Rows shown to the model next to the schema
+const SAMPLE_ROWS: usize = 5;
+// Rows of a query result passed back to the model
+const MAX_RESULT_ROWS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnType {
+    Integer,
+    Real,
+    Text,
+}
+
+impl ColumnType {
+    fn infer(values: impl Iterator<Item = String>) -> Self {
+        let mut column_type = ColumnType::Integer;
+        for value in values.filter(|v| !v.is_empty()) {
+            if column_type == ColumnType::Integer && value.parse::<i64>().is_err() {
+                column_type = ColumnType::Real;
+            }
+            if column_type == ColumnType::Real && value.parse::<f64>().is_err() {
+                return ColumnType::Text;
+            }
+        }
+        column_type
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Real => "REAL",
+            ColumnType::Text => "TEXT",
+        }
+    }
+
+    fn value(&self, value: &str) -> SqlValue {
+        if value.is_empty() {
+            return SqlValue::Null;
+        }
+        match self {
+            ColumnType::Integer => value.parse().map(SqlValue::Integer).unwrap_or(SqlValue::Null),
+            ColumnType::Real => value.parse().map(SqlValue::Real).unwrap_or(SqlValue::Null),
+            ColumnType::Text => SqlValue::Text(value.to_string()),
+        }
+    }
+}
+
+fn sql_identifier(name: &str) -> String {
+    let name = name
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name,
+        _ => format!("t_{}", name),
+    }
+}
+
+fn read_csv(path: &Path) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let mut reader = csv::Reader::from_path(path).context("Unable to open csv")?;
+    let headers = reader.headers()?.iter().map(|h| h.to_string()).collect();
+    let mut rows = vec![];
+    for record in reader.records() {
+        rows.push(record?.iter().map(|v| v.trim().to_string()).collect());
+    }
+    Ok((headers, rows))
+}
+
+fn read_parquet(path: &Path) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let reader = SerializedFileReader::new(std::fs::File::open(path)?)?;
+    let headers = reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|c| c.name().to_string())
+        .collect();
+    let mut rows = vec![];
+    for row in reader.get_row_iter(None)? {
+        let row = row?;
+        rows.push(
+            row.get_column_iter()
+                .map(|(_, field)| match field {
+                    Field::Null => String::new(),
+                    Field::Str(s) => s.clone(),
+                    field => field.to_string(),
+                })
+                .collect(),
+        );
+    }
+    Ok((headers, rows))
+}
+
+/// Imports a CSV or Parquet file as a SQLite table and memorizes its schema
+pub async fn ingest_table(path: PathBuf) -> Result<()> {
+    let display = path.display();
+    let file_name = path
+        .file_name()
+        .context("Unable to get file name")?
+        .to_str()
+        .context("Unable to convert file name to string")?;
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .context("Unable to get file name")?;
+
+    println!("Processing table from {}", display);
+
+    let (headers, rows) = match path.extension().and_then(|e| e.to_str()) {
+        Some("parquet") => read_parquet(&path)?,
+        _ => read_csv(&path)?,
+    };
+    let table = sql_identifier(stem);
+    let columns = headers.iter().map(|h| sql_identifier(h)).collect::<Vec<String>>();
+    let types = (0..columns.len())
+        .map(|i| ColumnType::infer(rows.iter().filter_map(|r| r.get(i).cloned())))
+        .collect::<Vec<ColumnType>>();
+
+    let database = tables_path();
+    if let Some(dir) = database.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // a dry run still imports the rows, so the schema and samples shown are the real ones
+    let mut conn = match is_dry_run() {
+        true => Connection::open_in_memory()?,
+        false => Connection::open(&database)?,
+    };
+    let tx = conn.transaction()?;
+    tx.execute(&format!("DROP TABLE IF EXISTS \"{}\"", table), [])?;
+    let definition = columns
+        .iter()
+        .zip(types.iter())
+        .map(|(c, t)| format!("\"{}\" {}", c, t.sql()))
+        .collect::<Vec<String>>()
+        .join(", ");
+    tx.execute(&format!("CREATE TABLE \"{}\" ({})", table, definition), [])?;
+    {
+        let placeholders = vec!["?"; columns.len()].join(", ");
+        let mut insert =
+            tx.prepare(&format!("INSERT INTO \"{}\" VALUES ({})", table, placeholders))?;
+        for row in rows.iter() {
+            let values = types
+                .iter()
+                .enumerate()
+                .map(|(i, t)| t.value(row.get(i).map(|v| v.as_str()).unwrap_or_default()));
+            insert.execute(rusqlite::params_from_iter(values))?;
+        }
+    }
+    tx.commit()?;
+    println!("Imported {} rows into table {}", rows.len(), table);
+
+    let schema = describe_tables(&conn, Some(&table))?;
+    // waits for the documents saved meanwhile by hand, and paces a folder to the workers
+    let _permit = queue::admit(Priority::Bulk).await;
+    let content = insert_content(&format!("Table {}", table), &schema, "table")
+        .await
+        .context("Unable to insert content")?;
+    insert_vector_index(
+        content.id.clone(),
+        0,
+        &schema,
+        json!({
+            "connector": "table",
+            "type": "table",
+            "database": database,
+            "table": table,
+            "columns": columns,
+            "rows": rows.len(),
+            "upload_time": Utc::now(),
+            "source": file_name,
+            "provenance": Provenance::file(&path).step("table_import"),
+        }),
+    )
+    .await?;
+    println!("Memorized table {}", table);
+    commit_content(&content.id).await?;
+
+    Ok(())
+}
+
+/// Schema and sample rows of the tables of a database, as shown to the model
+pub fn describe_tables(conn: &Connection, only: Option<&str>) -> Result<String> {
+    let mut statement =
+        conn.prepare("SELECT name, sql FROM sqlite_master WHERE type = 'table' ORDER BY name")?;
+    let tables = statement
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+
+    let mut description = String::new();
+    for (name, sql) in tables {
+        if only.is_some_and(|only| only != name) {
+            continue;
+        }
+        let (columns, sample) =
+            run_query(conn, &format!("SELECT * FROM \"{}\" LIMIT {}", name, SAMPLE_ROWS))?;
+        description += &format!("{};\n-- sample rows:\n-- {}\n", sql, columns.join(", "));
+        for row in sample {
+            description += &format!("-- {}\n", row.join(", "));
+        }
+    }
+    Ok(description)
+}
+
+fn run_query(conn: &Connection, sql: &str) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let mut statement = conn.prepare(sql)?;
+    if !statement.readonly() {
+        anyhow::bail!("Only read-only queries are allowed");
+    }
+    let columns = statement
+        .column_names()
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<String>>();
+    let mut rows = vec![];
+    let mut result = statement.query([])?;
+    while let Some(row) = result.next()? {
+        if rows.len() == MAX_RESULT_ROWS {
+            break;
+        }
+        let mut values = vec![];
+        for i in 0..columns.len() {
+            values.push(match row.get_ref(i)? {
+                ValueRef::Null => "NULL".to_string(),
+                ValueRef::Integer(v) => v.to_string(),
+                ValueRef::Real(v) => v.to_string(),
+                ValueRef::Text(v) => String::from_utf8_lossy(v).to_string(),
+                ValueRef::Blob(v) => format!("<{} bytes>", v.len()),
+            });
+        }
+        rows.push(values);
+    }
+    Ok((columns, rows))
+}
+
+// Only a single SELECT statement is accepted, on top of the connection being read-only
+fn validate_query(sql: &str) -> Result<String> {
+    let sql = sql
+        .trim()
+        .trim_start_matches("```sql")
+        .trim_start_matches("```")
+        .trim_end_matches("```")
+        .trim()
+        .trim_end_matches(';')
+        .trim();
+    if sql.contains(';') {
+        anyhow::bail!("Only a single statement is allowed");
+    }
+    let keyword = sql.split_whitespace().next().unwrap_or_default().to_uppercase();
+    if keyword != "SELECT" && keyword != "WITH" {
+        anyhow::bail!("Only SELECT queries are allowed");
+    }
+    // a CTE can wrap a write, and functions like load_extension have side effects
+    let upper = sql.to_uppercase();
+    for forbidden in [
+        "INSERT", "UPDATE", "DELETE", "DROP", "ALTER", "CREATE", "ATTACH", "DETACH", "PRAGMA",
+        "VACUUM", "LOAD_EXTENSION",
+    ] {
+        let pattern = regex::Regex::new(&format!(r"\b{}\b", forbidden)).unwrap();
+        if pattern.is_match(&upper) {
+            anyhow::bail!("{} is not allowed in queries", forbidden);
+        }
+    }
+    Ok(sql.to_string())
+}
+
+/// Has the model write a SQL query answering the question and runs it on a read-only connection.
+/// Returns the query along with its result formatted as CSV.
+pub async fn query_database(
+    question: &str,
+    database: &Path,
+    only: Option<&str>,
+    options: &AnswerOptions,
+) -> Result<(String, String)> {
+    let conn = Connection::open_with_flags(database, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let schema = describe_tables(&conn, only)?;
+
+    let system = "You write SQLite queries. Answer with a single SELECT statement answering the question and nothing else.";
+    let user = format!("schema:\n{schema}\nquestion: \"{question}\"\n");
+    let sql = generate_with(system, &user, options, 200, true).await?;
+    let sql = validate_query(&sql)?;
+    debug!(sql = %loggable(&sql), "Running generated query");
+
+    let (columns, rows) = run_query(&conn, &sql)?;
+    let mut result = columns.join(", ");
+    for row in rows {
+        result += &format!("\n{}", row.join(", "));
+    }
+    Ok((sql, result))
+}
+
+/// Registers a SQLite database so questions about it are answered by querying it
+pub async fn register_database(name: &str, path: &Path) -> Result<()> {
+    let path = path.canonicalize().context("Unable to find database")?;
+    let conn = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let schema = describe_tables(&conn, None)?;
+    if registered_database(name).await.is_ok() {
+        unregister_database(name).await?;
+    }
+
+    // waits for the documents saved meanwhile by hand, and paces a folder to the workers
+    let _permit = queue::admit(Priority::Bulk).await;
+    let content = insert_content(&format!("SQLite database {}", name), &schema, "sqlite")
+        .await
+        .context("Unable to insert content")?;
+    insert_vector_index(
+        content.id.clone(),
+        0,
+        &format!("SQLite database {}\n{}", name, schema),
+        json!({
+            "connector": "sqlite",
+            "type": "sqlite",
+            "name": name,
+            "database": path,
+            "upload_time": Utc::now(),
+            "source": format!("SQLite database {}", name),
+            "provenance": Provenance::file(&path).step("schema_description"),
+        }),
+    )
+    .await?;
+    commit_content(&content.id).await?;
+    Ok(())
+}
+
+pub async fn registered_databases() -> Result<Vec<VectorIndex>> {
+    let db = DB.get().await.clone();
+    let mut result = db
+        .query("SELECT * FROM vector_index WHERE metadata.type = 'sqlite' ORDER BY metadata.name")
+        .await?;
+    let databases: Vec<VectorIndex> = result.take(0)?;
+    Ok(databases)
+}
+
+pub async fn registered_database(name: &str) -> Result<VectorIndex> {
+    registered_databases()
+        .await?
+        .into_iter()
+        .find(|d| d.metadata["name"] == name)
+        .context("Unknown database")
+}
+
+pub async fn unregister_database(name: &str) -> Result<()> {
+    let database = registered_database(name).await?;
+    delete_content(&database.content_id.id.to_raw()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_a_single_select() {
+        let sql = validate_query("```sql\nSELECT name, created_at FROM notes WHERE state = 'updated';\n```").unwrap();
+        assert_eq!(sql, "SELECT name, created_at FROM notes WHERE state = 'updated'");
+        // table valued pragma functions only read
+        assert!(validate_query("SELECT name FROM pragma_table_info('notes')").is_ok());
+    }
+
+    #[test]
+    fn rejects_attach() {
+        assert!(validate_query("ATTACH DATABASE '/tmp/other.db' AS other").is_err());
+        let error = validate_query("WITH t AS (SELECT 1) ATTACH DATABASE '/tmp/other.db' AS other").unwrap_err();
+        assert_eq!(error.to_string(), "ATTACH is not allowed in queries");
+    }
+
+    #[test]
+    fn rejects_pragma_writes() {
+        assert!(validate_query("PRAGMA journal_mode = DELETE").is_err());
+        let error = validate_query("WITH t AS (SELECT 1) PRAGMA writable_schema = ON").unwrap_err();
+        assert_eq!(error.to_string(), "PRAGMA is not allowed in queries");
+    }
+
+    #[test]
+    fn rejects_multiple_statements() {
+        let error = validate_query("SELECT * FROM notes; DROP TABLE notes").unwrap_err();
+        assert_eq!(error.to_string(), "Only a single statement is allowed");
+    }
+
+    #[test]
+    fn rejects_keywords_next_to_comments() {
+        assert!(validate_query("SELECT 1 /* only reads */; DELETE FROM notes").is_err());
+        let error = validate_query("SELECT * FROM notes --\nUNION SELECT 1 /**/DELETE FROM notes").unwrap_err();
+        assert_eq!(error.to_string(), "DELETE is not allowed in queries");
+        assert!(validate_query("/* a note */ DELETE FROM notes").is_err());
+    }
+
+    #[test]
+    fn rejects_writes_in_a_cte() {
+        let error =
+            validate_query("WITH old AS (SELECT * FROM notes) INSERT INTO archive SELECT * FROM old").unwrap_err();
+        assert_eq!(error.to_string(), "INSERT is not allowed in queries");
+    }
+}
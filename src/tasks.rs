@@ -0,0 +1,129 @@
+use crate::database::{Content, DB};
+use crate::inference::{generate_with, AnswerOptions};
+use anyhow::{Context, Error, Result};
+use chrono::{Local, NaiveDate};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use surrealdb::sql::{thing, Datetime, Thing};
+use tracing::debug;
+
+lazy_static! {
+    // A line of the model's reply, `owner | YYYY-MM-DD | what to do` with `-` for an unknown owner or date
+    static ref ITEM: Regex = Regex::new(r"^\s*[-*]?\s*([^|]*?)\s*\|\s*(\d{4}-\d{2}-\d{2}|-)\s*\|\s*(.+?)\s*$").unwrap();
+}
+
+/// An action item of a memorized document, e.g. "Alice sends the budget by Friday" in meeting notes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Task {
+    pub id: Thing,
+    pub text: String,
+    /// Who the task is for, when the document says
+    pub owner: Option<String>,
+    pub due: Option<String>,
+    pub done: bool,
+    /// The content it was found in
+    pub content_id: Thing,
+    pub created_at: Datetime,
+}
+
+#[derive(Serialize, Debug)]
+struct NewTask {
+    text: String,
+    owner: Option<String>,
+    due: Option<String>,
+    done: bool,
+    content_id: Thing,
+    created_at: Datetime,
+}
+
+fn parse_items(reply: &str) -> Vec<(Option<String>, Option<String>, String)> {
+    reply
+        .lines()
+        .filter_map(|line| {
+            let item = ITEM.captures(line)?;
+            let owner = match item[1].trim() {
+                "-" | "" => None,
+                owner => Some(owner.to_string()),
+            };
+            let due = NaiveDate::parse_from_str(&item[2], "%Y-%m-%d")
+                .ok()
+                .map(|d| d.format("%Y-%m-%d").to_string());
+            Some((owner, due, item[3].to_string()))
+        })
+        .collect()
+}
+
+/// Asks the model for the action items of memorized content, chunk by chunk, and stores them as tasks.
+/// Extracting again replaces the tasks not done yet, those done stay done.
+pub async fn extract_tasks(document_id: &str) -> Result<Vec<Task>, Error> {
+    let db = DB.get().await.clone();
+    let content_id = thing(format!("content:{}", document_id).as_str())?;
+    let content: Option<Content> = db.select(content_id.clone()).await?;
+    let content = content.context("Unknown content")?;
+
+    let system = format!(
+        "You find action items in a text. Today is {today}. Write one per line as \"owner | YYYY-MM-DD | what to do\", with - for an owner or a date the text doesn't give. Write nothing else, and nothing when there is no action item.",
+        today = Local::now().format("%A, %Y-%m-%d")
+    );
+    let options = AnswerOptions::default();
+    let mut items = vec![];
+    for chunk in content.get_vector_indexes().await? {
+        let user = format!("text: \"{}\"\n", chunk.content_chunk);
+        items.extend(parse_items(&generate_with(&system, &user, &options, 150, true).await?));
+    }
+    debug!(content = document_id, tasks = items.len(), "Extracted tasks");
+
+    let mut result = db
+        .query("DELETE task WHERE content_id = $content AND done = false")
+        .query("SELECT text FROM task WHERE content_id = $content AND done = true")
+        .bind(("content", content_id.clone()))
+        .await?;
+    let done: Vec<String> = result.take((1, "text"))?;
+    let mut tasks = vec![];
+    for (owner, due, text) in items {
+        if done.contains(&text) {
+            continue;
+        }
+        let task: Vec<Task> = db
+            .create("task")
+            .content(NewTask {
+                text,
+                owner,
+                due,
+                done: false,
+                content_id: content_id.clone(),
+                created_at: Datetime::default(),
+            })
+            .await?;
+        tasks.extend(task);
+    }
+    Ok(tasks)
+}
+
+/// Tasks by due date, those without one last, only those left to do unless `all`
+pub async fn list_tasks(all: bool) -> Result<Vec<Task>, Error> {
+    let db = DB.get().await.clone();
+    let query = if all {
+        "SELECT * FROM task ORDER BY created_at"
+    } else {
+        "SELECT * FROM task WHERE done = false ORDER BY created_at"
+    };
+    let mut result = db.query(query).await?;
+    let mut tasks: Vec<Task> = result.take(0)?;
+    tasks.sort_by_key(|t| (t.done, t.due.is_none(), t.due.clone()));
+    Ok(tasks)
+}
+
+/// Marks a task done or not done
+pub async fn set_done(id: &str, done: bool) -> Result<(), Error> {
+    let db = DB.get().await.clone();
+    let task: Option<Task> = db.select(("task", id)).await?;
+    task.context("Unknown task")?;
+    db.query("UPDATE type::thing('task', $id) SET done = $done")
+        .bind(("id", id))
+        .bind(("done", done))
+        .await?
+        .check()?;
+    Ok(())
+}
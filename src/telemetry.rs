@@ -0,0 +1,54 @@
+use crate::config::CONFIG;
+use anyhow::Result;
+
+/// Exports the spans not sent yet when dropped, keep it until the process ends
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+pub struct TelemetryGuard {
+    exporting: bool,
+}
+
+/// Exports the spans of retrieval, reranking, prompt assembly and generation over OTLP when `telemetry`
+/// is set, e.g. to Jaeger or Grafana Tempo
+#[cfg(feature = "otel")]
+pub fn init() -> Result<TelemetryGuard> {
+    use opentelemetry::KeyValue;
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::{runtime, trace, Resource};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    let Some(telemetry) = &CONFIG.telemetry else {
+        return Ok(TelemetryGuard { exporting: false });
+    };
+    let mut exporter = opentelemetry_otlp::new_exporter().http();
+    if let Some(endpoint) = &telemetry.endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+    let resource = Resource::new(vec![KeyValue::new("service.name", telemetry.service_name.clone())]);
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace::config().with_resource(resource))
+        .install_batch(runtime::Tokio)?;
+    tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .try_init()?;
+    Ok(TelemetryGuard { exporting: true })
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init() -> Result<TelemetryGuard> {
+    if CONFIG.telemetry.is_some() {
+        eprintln!("Tera was built without the `otel` feature, spans aren't exported");
+    }
+    Ok(TelemetryGuard { exporting: false })
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if self.exporting {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
+    }
+}
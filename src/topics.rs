@@ -0,0 +1,178 @@
+use crate::database::{Content, VectorIndex, DB};
+use anyhow::{Context, Error, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use surrealdb::sql::{Datetime, Thing};
+use tera_core::clustering::kmeans;
+use tracing::debug;
+
+// Topics made when not given, fewer for small knowledge bases
+const MAX_TOPICS: usize = 20;
+const KMEANS_ITERATIONS: usize = 50;
+// Words a topic is labeled with
+const LABEL_WORDS: usize = 3;
+// Common words which don't tell topics apart
+const STOP_WORDS: &[&str] = &[
+    "about", "after", "also", "been", "before", "being", "chat", "could", "does", "from", "have", "here", "into",
+    "just", "like", "more", "most", "much", "only", "other", "over", "some", "such", "than", "that", "their",
+    "them", "then", "there", "these", "they", "this", "very", "were", "what", "when", "where", "which", "while",
+    "will", "with", "would", "your",
+];
+
+lazy_static! {
+    static ref WORD: Regex = Regex::new(r"\p{Alphabetic}{4,}").unwrap();
+}
+
+/// Documents memorized about the same thing, found by `tera topics build`
+#[derive(Serialize, Debug, Clone)]
+pub struct Topic {
+    pub id: String,
+    /// The words telling its documents apart from the others, e.g. "garden, tomatoes, compost"
+    pub label: String,
+    pub documents: Vec<TopicDocument>,
+    pub built_at: Datetime,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TopicDocument {
+    pub content_id: String,
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredTopic {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<Thing>,
+    #[serde(with = "crate::crypto::sealed")]
+    label: String,
+    documents: Vec<Thing>,
+    built_at: Datetime,
+}
+
+fn words(text: &str) -> HashSet<String> {
+    WORD.find_iter(text)
+        .map(|w| w.as_str().to_lowercase())
+        .filter(|w| !STOP_WORDS.contains(&w.as_str()))
+        .collect()
+}
+
+// Words found in many documents of the topic and few of the others
+fn label(members: &[&HashSet<String>], frequencies: &HashMap<&str, usize>, documents: usize) -> String {
+    let mut counts = HashMap::<&str, usize>::new();
+    for words in members {
+        for word in words.iter() {
+            *counts.entry(word.as_str()).or_default() += 1;
+        }
+    }
+    let mut scored = counts
+        .into_iter()
+        .map(|(word, count)| {
+            let inside = count as f32 / members.len() as f32;
+            let overall = frequencies.get(word).copied().unwrap_or(0) as f32 / documents as f32;
+            (word, inside - overall)
+        })
+        .collect::<Vec<_>>();
+    // ties broken alphabetically, so labels don't change between builds
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+    scored.iter().take(LABEL_WORDS).map(|(word, _)| *word).collect::<Vec<_>>().join(", ")
+}
+
+/// Groups memorized documents by topic with k-means over their embeddings, replacing the previous
+/// topics. `clusters` defaults to about the square root of half the number of documents.
+pub async fn build_topics(clusters: Option<usize>) -> Result<Vec<Topic>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM vector_index ORDER BY created_at ASC").await?;
+    let chunks: Vec<VectorIndex> = result.take(0)?;
+
+    // a document is the mean of its chunks, with the words of its title and text
+    let mut order = vec![];
+    let mut documents = HashMap::<Thing, (Vec<f32>, HashSet<String>)>::new();
+    for chunk in chunks {
+        let (vector, text_words) = documents.entry(chunk.content_id.clone()).or_insert_with(|| {
+            order.push(chunk.content_id.clone());
+            (vec![0.0; chunk.vector.len()], HashSet::new())
+        });
+        for (total, x) in vector.iter_mut().zip(&chunk.vector) {
+            *total += x;
+        }
+        text_words.extend(words(chunk.metadata["source"].as_str().unwrap_or_default()));
+        text_words.extend(words(&chunk.content_chunk));
+    }
+    if documents.is_empty() {
+        anyhow::bail!("Nothing is memorized yet");
+    }
+
+    let k = clusters
+        .unwrap_or_else(|| ((order.len() as f32 / 2.0).sqrt().round() as usize).clamp(2, MAX_TOPICS))
+        .clamp(1, order.len());
+    let vectors = order.iter().map(|id| documents[id].0.clone()).collect::<Vec<_>>();
+    let found = kmeans(&vectors, k, KMEANS_ITERATIONS);
+    debug!(documents = order.len(), topics = found.centroids.len(), "Clustered documents");
+
+    let mut frequencies = HashMap::<&str, usize>::new();
+    for (_, document_words) in documents.values() {
+        for word in document_words {
+            *frequencies.entry(word.as_str()).or_default() += 1;
+        }
+    }
+    let mut members = vec![vec![]; found.centroids.len()];
+    for (id, cluster) in order.iter().zip(&found.assignments) {
+        members[*cluster].push(id);
+    }
+    // largest first
+    members.retain(|m| !m.is_empty());
+    members.sort_by_key(|m| std::cmp::Reverse(m.len()));
+
+    db.query("DELETE topic").await?.check()?;
+    let built_at = Datetime::default();
+    for (index, ids) in members.iter().enumerate() {
+        let member_words = ids.iter().map(|id| &documents[*id].1).collect::<Vec<_>>();
+        let topic = StoredTopic {
+            id: None,
+            label: label(&member_words, &frequencies, order.len()),
+            documents: ids.iter().map(|id| (*id).clone()).collect(),
+            built_at: built_at.clone(),
+        };
+        let _: Option<StoredTopic> = db.update(("topic", (index + 1).to_string())).content(topic).await?;
+    }
+    list_topics().await
+}
+
+/// The topics of the last build, largest first, with the titles of the documents still memorized
+pub async fn list_topics() -> Result<Vec<Topic>, Error> {
+    let db = DB.get().await.clone();
+    let mut result = db.query("SELECT * FROM topic").await?;
+    let stored: Vec<StoredTopic> = result.take(0)?;
+    let mut topics = vec![];
+    for topic in stored {
+        let mut documents = vec![];
+        for content_id in topic.documents {
+            let content: Option<Content> = db.select(content_id.clone()).await?;
+            if let Some(content) = content {
+                documents.push(TopicDocument {
+                    content_id: content_id.id.to_raw(),
+                    title: content.title,
+                });
+            }
+        }
+        topics.push(Topic {
+            id: topic.id.map(|id| id.id.to_raw()).unwrap_or_default(),
+            label: topic.label,
+            documents,
+            built_at: topic.built_at,
+        });
+    }
+    topics.sort_by_key(|t| std::cmp::Reverse(t.documents.len()));
+    Ok(topics)
+}
+
+/// A topic with its documents
+pub async fn get_topic(id: &str) -> Result<Topic, Error> {
+    list_topics()
+        .await?
+        .into_iter()
+        .find(|t| t.id == id)
+        .with_context(|| format!("Unknown topic {}, see `tera topics`", id))
+}
@@ -0,0 +1,159 @@
+use crate::api::Tera;
+use anyhow::{Context, Result};
+use global_hotkey::hotkey::HotKey;
+use global_hotkey::{GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tao::event_loop::{ControlFlow, EventLoopBuilder};
+use tracing::debug;
+use tray_icon::menu::{Menu, MenuEvent, MenuItem};
+use tray_icon::{Icon, TrayIconBuilder};
+
+// Side of the icon in pixels
+const ICON_SIZE: u32 = 32;
+// How often the menu and the hotkey are checked
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// A filled circle, so there is no image to ship
+fn icon() -> Result<Icon> {
+    let center = ICON_SIZE as f32 / 2.0;
+    let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+    for y in 0..ICON_SIZE {
+        for x in 0..ICON_SIZE {
+            let distance = ((x as f32 + 0.5 - center).powi(2) + (y as f32 + 0.5 - center).powi(2)).sqrt();
+            let alpha = if distance < center - 1.0 { 255 } else { 0 };
+            rgba.extend_from_slice(&[0x3b, 0x82, 0xf6, alpha]);
+        }
+    }
+    Ok(Icon::from_rgba(rgba, ICON_SIZE, ICON_SIZE)?)
+}
+
+fn run_dialog(command: &mut Command) -> Result<Option<String>> {
+    let output = command.output().context(if cfg!(target_os = "linux") {
+        "Unable to show a dialog, install zenity"
+    } else {
+        "Unable to show a dialog"
+    })?;
+    // Cancelling the dialog exits with an error
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    Ok(Some(text).filter(|t| !t.is_empty()))
+}
+
+/// Shows a dialog asking for a question, `None` when it was cancelled
+pub fn ask_dialog() -> Result<Option<String>> {
+    if cfg!(target_os = "macos") {
+        run_dialog(Command::new("osascript").args([
+            "-e",
+            "text returned of (display dialog \"Ask Tera\" default answer \"\" with title \"Tera\")",
+        ]))
+    } else if cfg!(target_os = "windows") {
+        run_dialog(Command::new("powershell").args([
+            "-NoProfile",
+            "-Command",
+            "Add-Type -AssemblyName Microsoft.VisualBasic; [Microsoft.VisualBasic.Interaction]::InputBox('Ask Tera', 'Tera')",
+        ]))
+    } else {
+        run_dialog(Command::new("zenity").args(["--entry", "--title", "Tera", "--text", "Ask Tera"]))
+    }
+}
+
+/// Shows a text in a dialog until it is closed
+pub fn show_dialog(text: &str) -> Result<()> {
+    // The text goes to the scripts through the environment, so it needs no quoting
+    if cfg!(target_os = "macos") {
+        run_dialog(
+            Command::new("osascript")
+                .args([
+                    "-e",
+                    "display dialog (system attribute \"TERA_TEXT\") buttons {\"OK\"} default button 1 with title \"Tera\"",
+                ])
+                .env("TERA_TEXT", text),
+        )?;
+    } else if cfg!(target_os = "windows") {
+        run_dialog(
+            Command::new("powershell")
+                .args([
+                    "-NoProfile",
+                    "-Command",
+                    "Add-Type -AssemblyName System.Windows.Forms; [System.Windows.Forms.MessageBox]::Show($env:TERA_TEXT, 'Tera') | Out-Null",
+                ])
+                .env("TERA_TEXT", text),
+        )?;
+    } else {
+        run_dialog(Command::new("zenity").args(["--info", "--no-markup", "--title", "Tera", "--text", text]))?;
+    }
+    Ok(())
+}
+
+// Asks for a question and shows the answer, errors are shown instead
+fn quick_ask(tera: &Tera, runtime: &tokio::runtime::Handle) -> Result<()> {
+    let Some(question) = ask_dialog()? else {
+        return Ok(());
+    };
+    let text = match runtime.block_on(tera.ask(&question)) {
+        Ok(answer) => answer.text,
+        Err(e) => format!("Unable to answer: {:#}", e),
+    };
+    show_dialog(&text)
+}
+
+/// Puts Tera in the tray or the menu bar, the hotkey, e.g. `ctrl+shift+space`, or the "Ask" item of
+/// its menu popping up a quick-ask window. Runs until "Quit" is chosen, it must be called on the main thread.
+pub fn run(tera: Tera, shortcut: &str) -> Result<()> {
+    let runtime = tokio::runtime::Handle::current();
+    let event_loop = EventLoopBuilder::new().build();
+
+    let menu = Menu::new();
+    let ask = MenuItem::new("Ask Tera…", true, None);
+    let quit = MenuItem::new("Quit", true, None);
+    menu.append_items(&[&ask, &quit])?;
+    let tray = TrayIconBuilder::new()
+        .with_menu(Box::new(menu))
+        .with_tooltip(format!("Tera ({})", shortcut))
+        .with_icon(icon()?)
+        .build()?;
+
+    let hotkeys = GlobalHotKeyManager::new()?;
+    let hotkey: HotKey = shortcut
+        .parse()
+        .with_context(|| format!("Invalid hotkey {}", shortcut))?;
+    hotkeys.register(hotkey)?;
+    println!("Tera is in the tray, press {} to ask.", shortcut);
+
+    // A single quick-ask window at a time
+    let asking = Arc::new(AtomicBool::new(false));
+    let (ask_id, quit_id) = (ask.id().clone(), quit.id().clone());
+    event_loop.run(move |_event, _, control_flow| {
+        *control_flow = ControlFlow::WaitUntil(Instant::now() + POLL_INTERVAL);
+        // Kept alive by the loop, they go away with it
+        let _ = (&tray, &hotkeys);
+
+        let mut wanted = false;
+        if let Ok(event) = MenuEvent::receiver().try_recv() {
+            if event.id == quit_id {
+                *control_flow = ControlFlow::Exit;
+                return;
+            }
+            wanted = event.id == ask_id;
+        }
+        if let Ok(event) = GlobalHotKeyEvent::receiver().try_recv() {
+            wanted |= event.id == hotkey.id() && event.state == HotKeyState::Pressed;
+        }
+        if !wanted || asking.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        debug!("Quick ask");
+        let (tera, runtime, asking) = (tera.clone(), runtime.clone(), asking.clone());
+        std::thread::spawn(move || {
+            if let Err(e) = quick_ask(&tera, &runtime) {
+                eprintln!("{:#}", e);
+            }
+            asking.store(false, Ordering::SeqCst);
+        });
+    })
+}
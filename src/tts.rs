@@ -0,0 +1,94 @@
+use crate::config::CONFIG;
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tracing::debug;
+
+lazy_static! {
+    // Markdown and citation markers, which would be read out
+    static ref MARKUP: Regex = Regex::new(r"\[\^?\d+\]|[*_`#>]+|^\s*[-•]\s+").unwrap();
+}
+
+/// The answer as it should be read out, without markdown and citations
+pub fn spoken_text(text: &str) -> String {
+    text.lines()
+        .map(|line| MARKUP.replace_all(&line.replace("[…]", ""), "").trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Reads a text out with the `tts` voice of the config and returns the audio as WAV bytes
+pub fn speak(text: &str) -> Result<Vec<u8>> {
+    let tts = CONFIG
+        .tts
+        .as_ref()
+        .context("No voice configured, set `tts` in the config")?;
+    let output = tempfile::Builder::new().suffix(".wav").tempfile()?;
+    let mut command = Command::new(&tts.program);
+    command
+        .arg("--model")
+        .arg(&tts.voice)
+        .arg("--output_file")
+        .arg(output.path());
+    if let Some(speaker) = tts.speaker {
+        command.args(["--speaker", &speaker.to_string()]);
+    }
+    debug!(program = tts.program, voice = ?tts.voice, "Synthesizing speech");
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Unable to run {}, install piper or set `tts.program`", tts.program))?;
+    // piper reads a line of text per utterance
+    child
+        .stdin
+        .take()
+        .context("Unable to write to the voice")?
+        .write_all(spoken_text(text).replace('\n', " ").as_bytes())?;
+    let result = child.wait_with_output()?;
+    if !result.status.success() {
+        anyhow::bail!("{} failed: {}", tts.program, String::from_utf8_lossy(&result.stderr).trim());
+    }
+    Ok(std::fs::read(output.path())?)
+}
+
+// The audio players of each platform, tried in order, the file goes last
+fn players() -> Vec<(&'static str, Vec<&'static str>)> {
+    if cfg!(target_os = "macos") {
+        vec![("afplay", vec![])]
+    } else {
+        vec![
+            ("paplay", vec![]),
+            ("aplay", vec!["-q"]),
+            ("ffplay", vec!["-nodisp", "-autoexit", "-loglevel", "quiet"]),
+        ]
+    }
+}
+
+/// Plays WAV audio until it ends
+pub fn play(wav: &[u8]) -> Result<()> {
+    let mut file = tempfile::Builder::new().suffix(".wav").tempfile()?;
+    file.write_all(wav)?;
+    file.flush()?;
+    if cfg!(target_os = "windows") {
+        let script = format!("(New-Object Media.SoundPlayer '{}').PlaySync()", file.path().display());
+        let status = Command::new("powershell").args(["-NoProfile", "-Command", &script]).status()?;
+        if !status.success() {
+            anyhow::bail!("Unable to play audio");
+        }
+        return Ok(());
+    }
+    for (program, args) in players() {
+        let Ok(status) = Command::new(program).args(&args).arg(file.path()).status() else {
+            continue;
+        };
+        if status.success() {
+            return Ok(());
+        }
+    }
+    anyhow::bail!("Unable to play audio, install pulseaudio-utils, alsa-utils or ffmpeg")
+}
@@ -0,0 +1,259 @@
+use crate::config::{data_dir, CONFIG};
+use crate::database::{VectorIndex, DB};
+use anyhow::{Context, Error, Result};
+use lazy_static::lazy_static;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use surrealdb::sql::{thing, Thing};
+use tera_core::similarity::cosine_similarity;
+use tracing::debug;
+
+const MAGIC: &[u8; 8] = b"TERAVEC1";
+// Magic, dimensions and padding so rows start 4 byte aligned
+const HEADER_LEN: usize = 16;
+// Chunk ids are stored zero padded
+const ID_LEN: usize = 64;
+// Rows scored between two trims of the best candidates
+const SCAN_BLOCK: usize = 4096;
+// Deleted rows, out of all rows, past which a search rebuilds the file first
+const MAX_DELETED_SHARE: usize = 4;
+
+lazy_static! {
+    // Rows are appended by concurrent uploads
+    static ref APPEND: Mutex<()> = Mutex::new(());
+}
+
+/// Where the vectors get scanned from, with `mmap_vectors` on
+pub fn vector_file_path() -> PathBuf {
+    data_dir().join("vectors.bin")
+}
+
+// Ids of deleted chunks, each followed by the number of rows the file had then, as a little endian u64
+fn tombstones_path() -> PathBuf {
+    data_dir().join("vectors.deleted")
+}
+
+fn row_len(dimensions: usize) -> usize {
+    ID_LEN + dimensions * 4
+}
+
+fn padded_id(id: &Thing) -> Result<Vec<u8>> {
+    let key = id.id.to_raw();
+    if key.len() > ID_LEN {
+        anyhow::bail!("Chunk id {} is too long for the vector file", key);
+    }
+    let mut padded = key.into_bytes();
+    padded.resize(ID_LEN, 0);
+    Ok(padded)
+}
+
+fn header(dimensions: usize) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend((dimensions as u32).to_le_bytes());
+    header.resize(HEADER_LEN, 0);
+    header
+}
+
+fn row(id: &Thing, vector: &[f32]) -> Result<Vec<u8>> {
+    let mut row = padded_id(id)?;
+    row.extend(vector.iter().flat_map(|x| x.to_le_bytes()));
+    Ok(row)
+}
+
+/// Adds a newly stored chunk to the vector file, with `mmap_vectors` on
+pub fn index_chunk(chunk: &VectorIndex) -> Result<()> {
+    if !CONFIG.mmap_vectors {
+        return Ok(());
+    }
+    append(&chunk.id, &chunk.vector)
+}
+
+/// Rebuilds the vector file after the database was replaced, with `mmap_vectors` on
+pub async fn refresh() -> Result<(), Error> {
+    if CONFIG.mmap_vectors {
+        rebuild().await?;
+    }
+    Ok(())
+}
+
+fn append(id: &Thing, vector: &[f32]) -> Result<()> {
+    let _lock = APPEND.lock().unwrap_or_else(|e| e.into_inner());
+    let path = vector_file_path();
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(&header(vector.len()))?;
+    } else if dimensions(&path)? != vector.len() {
+        anyhow::bail!("The vector file has other dimensions, run `tera reindex`");
+    }
+    file.write_all(&row(id, vector)?)?;
+    Ok(())
+}
+
+/// Marks the rows of deleted chunks so searches skip them, with `mmap_vectors` on. A chunk stored again
+/// afterwards, e.g. restored from the trash, gets a new row which isn't skipped.
+pub fn forget_chunks(ids: &[Thing]) -> Result<()> {
+    let path = vector_file_path();
+    if !CONFIG.mmap_vectors || ids.is_empty() || !path.exists() {
+        return Ok(());
+    }
+    let _lock = APPEND.lock().unwrap_or_else(|e| e.into_inner());
+    let rows = match std::fs::metadata(&path)?.len() as usize {
+        len if len < HEADER_LEN => 0,
+        len => (len - HEADER_LEN) / row_len(dimensions(&path)?),
+    };
+    let mut tombstones = vec![];
+    for id in ids {
+        tombstones.extend(padded_id(id)?);
+        tombstones.extend((rows as u64).to_le_bytes());
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(tombstones_path())?
+        .write_all(&tombstones)?;
+    Ok(())
+}
+
+// The deleted ids with the number of rows before the deletion, rows after it are of chunks stored again
+fn tombstones() -> Result<HashMap<Vec<u8>, usize>> {
+    let Ok(bytes) = std::fs::read(tombstones_path()) else {
+        return Ok(HashMap::new());
+    };
+    let mut tombstones = HashMap::new();
+    for entry in bytes.chunks_exact(ID_LEN + 8) {
+        let rows = u64::from_le_bytes(entry[ID_LEN..].try_into()?) as usize;
+        let before = tombstones.entry(entry[..ID_LEN].to_vec()).or_insert(0);
+        *before = rows.max(*before);
+    }
+    Ok(tombstones)
+}
+
+/// Makes sure the appended rows are on disk, before the process stops
+pub fn flush() -> Result<()> {
+    let _lock = APPEND.lock().unwrap_or_else(|e| e.into_inner());
+    let path = vector_file_path();
+    if path.exists() {
+        File::open(&path)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn dimensions(path: &Path) -> Result<usize> {
+    let mut header = [0u8; HEADER_LEN];
+    std::io::Read::read_exact(&mut File::open(path)?, &mut header)?;
+    if &header[..8] != MAGIC {
+        anyhow::bail!("{} is not a vector file", path.display());
+    }
+    Ok(u32::from_le_bytes(header[8..12].try_into()?) as usize)
+}
+
+#[derive(Deserialize, Debug)]
+struct ChunkVector {
+    id: Thing,
+    #[serde(with = "crate::quantization::vector")]
+    vector: Vec<f32>,
+}
+
+/// Writes the vectors of every chunk to a new vector file, reading the database a page at a time.
+/// Deleted chunks have no row in it, so the marks of their rows are dropped.
+pub async fn rebuild() -> Result<usize, Error> {
+    let db = DB.get().await.clone();
+    let path = vector_file_path();
+    let partial = path.with_extension("bin.partial");
+    let mut file = File::create(&partial)?;
+    let mut written = 0;
+    loop {
+        let mut result = db
+            .query("SELECT id, vector FROM vector_index ORDER BY id LIMIT 1000 START $start")
+            .bind(("start", written))
+            .await?;
+        let chunks: Vec<ChunkVector> = result.take(0)?;
+        if chunks.is_empty() {
+            break;
+        }
+        for chunk in &chunks {
+            if written == 0 {
+                file.write_all(&header(chunk.vector.len()))?;
+            }
+            file.write_all(&row(&chunk.id, &chunk.vector)?)?;
+            written += 1;
+        }
+        print!("Indexed {} chunks\r", written);
+    }
+    file.sync_all()?;
+    let _lock = APPEND.lock().unwrap_or_else(|e| e.into_inner());
+    std::fs::rename(partial, path)?;
+    if tombstones_path().exists() {
+        std::fs::remove_file(tombstones_path())?;
+    }
+    Ok(written)
+}
+
+/// Ids of the `limit` chunks most similar to the query with their score, best first, deleted chunks
+/// left out. The file is scanned through a memory map so the OS pages vectors in and out as needed,
+/// and rebuilt first once a quarter of its rows are of deleted chunks.
+pub async fn search(query: &[f32], limit: usize) -> Result<Vec<(Thing, f32)>, Error> {
+    let path = vector_file_path();
+    if !path.exists() {
+        debug!("Building the vector file");
+        rebuild().await?;
+    }
+    let mut tombstones = tombstones()?;
+    let row_count = std::fs::metadata(&path)?.len().saturating_sub(HEADER_LEN as u64) as usize / row_len(query.len());
+    if !tombstones.is_empty() && tombstones.len() * MAX_DELETED_SHARE > row_count {
+        debug!(deleted = tombstones.len(), rows = row_count, "Compacting the vector file");
+        rebuild().await?;
+        tombstones.clear();
+    }
+    let file = File::open(&path)?;
+    if file.metadata()?.len() < HEADER_LEN as u64 {
+        return Ok(vec![]);
+    }
+    // SAFETY: rows are only ever appended, and a rebuild replaces the file rather than rewriting it
+    #[cfg(feature = "mmap")]
+    let bytes = unsafe { Mmap::map(&file)? };
+    #[cfg(all(unix, feature = "mmap"))]
+    bytes.advise(memmap2::Advice::Sequential)?;
+    // read whole without the `mmap` feature
+    #[cfg(not(feature = "mmap"))]
+    let bytes = std::fs::read(&path)?;
+
+    let dimensions = dimensions(&path)?;
+    if dimensions != query.len() {
+        anyhow::bail!("The vector file has {} dimensions, run `tera reindex`", dimensions);
+    }
+    let mut vector = vec![0f32; dimensions];
+    let mut best: Vec<(f32, usize)> = vec![];
+    let rows = bytes[HEADER_LEN..].chunks_exact(row_len(dimensions));
+    for (i, row) in rows.enumerate() {
+        if tombstones.get(&row[..ID_LEN]).is_some_and(|before| i < *before) {
+            continue;
+        }
+        for (x, bytes) in vector.iter_mut().zip(row[ID_LEN..].chunks_exact(4)) {
+            *x = f32::from_le_bytes(bytes.try_into()?);
+        }
+        best.push((cosine_similarity(query, &vector), i));
+        if best.len() >= SCAN_BLOCK + limit {
+            best.sort_by(|a, b| b.0.total_cmp(&a.0));
+            best.truncate(limit);
+        }
+    }
+    best.sort_by(|a, b| b.0.total_cmp(&a.0));
+    best.truncate(limit);
+
+    best.into_iter()
+        .map(|(score, i)| {
+            let start = HEADER_LEN + i * row_len(dimensions);
+            let id = &bytes[start..start + ID_LEN];
+            let id = std::str::from_utf8(id)?.trim_end_matches('\0');
+            Ok((thing(&format!("vector_index:{}", id))?, score))
+        })
+        .collect::<Result<Vec<_>>>()
+        .context("Corrupted vector file, run `tera reindex`")
+}
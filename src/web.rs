@@ -0,0 +1,570 @@
+use crate::config::CONFIG;
+use crate::connectors::record_run;
+use crate::database::{is_dry_run, remove_content, DB};
+use crate::provenance::Provenance;
+use crate::queue::{self, Priority};
+use crate::sync::{record, Op};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::Url;
+use scraper::{ElementRef, Html, Node, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use surrealdb::sql::Thing;
+use tokio::time::Instant;
+use tracing::debug;
+
+const USER_AGENT: &str = concat!("tera/", env!("CARGO_PKG_VERSION"));
+// Never shown to the reader, only their text gets memorized
+const SKIPPED_TAGS: [&str; 8] = ["script", "style", "noscript", "nav", "header", "footer", "svg", "form"];
+const BLOCK_TAGS: [&str; 16] = [
+    "p", "div", "li", "h1", "h2", "h3", "h4", "h5", "h6", "pre", "blockquote", "td", "th", "dt", "dd", "br",
+];
+
+lazy_static! {
+    static ref SITEMAP_ENTRY: Regex = Regex::new(r"(?s)<(?:\w+:)?(url|sitemap)>(.*?)</(?:\w+:)?(?:url|sitemap)>").unwrap();
+    static ref LOC: Regex = Regex::new(r"(?s)<(?:\w+:)?loc>\s*(.*?)\s*</(?:\w+:)?loc>").unwrap();
+    static ref LASTMOD: Regex = Regex::new(r"(?s)<(?:\w+:)?lastmod>\s*(.*?)\s*</(?:\w+:)?lastmod>").unwrap();
+}
+
+// Redirects followed for a request, like reqwest does by default
+const MAX_REDIRECTS: usize = 10;
+// Sitemap indexes listed deeper than this by other indexes are left out
+const MAX_SITEMAP_DEPTH: usize = 4;
+
+// Pages with less text than this are likely rendered by scripts
+#[cfg(feature = "browser")]
+const MIN_TEXT_LEN: usize = 200;
+
+/// Limits of a crawl
+#[derive(Debug, Clone)]
+pub struct CrawlOptions {
+    /// How many links away from the first page to go, 0 only memorizes the first page
+    pub max_depth: usize,
+    pub max_pages: usize,
+    /// Pause between two requests, raised to the site's `Crawl-delay`
+    pub delay: Duration,
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 2,
+            max_pages: 100,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// A fetched HTML page
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub url: Url,
+    pub title: String,
+    pub text: String,
+    pub links: Vec<Url>,
+}
+
+/// The rules of a site's robots.txt which apply to Tera
+#[derive(Debug, Clone, Default)]
+pub struct Robots {
+    /// Path prefixes, `true` when allowed
+    rules: Vec<(String, bool)>,
+    pub crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    /// Parses the groups for `tera`, or `*` if there are none for it
+    pub fn parse(robots: &str) -> Robots {
+        let mut groups: Vec<(Vec<String>, Robots)> = vec![];
+        let mut in_agents = false;
+        for line in robots.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            let (field, value) = (field.trim().to_lowercase(), value.trim());
+            match field.as_str() {
+                "user-agent" => {
+                    if !in_agents {
+                        groups.push((vec![], Robots::default()));
+                    }
+                    in_agents = true;
+                    if let Some((agents, _)) = groups.last_mut() {
+                        agents.push(value.to_lowercase());
+                    }
+                }
+                "allow" | "disallow" => {
+                    in_agents = false;
+                    // an empty disallow allows everything
+                    if let (Some((_, rules)), false) = (groups.last_mut(), value.is_empty()) {
+                        rules.rules.push((value.to_string(), field == "allow"));
+                    }
+                }
+                "crawl-delay" => {
+                    in_agents = false;
+                    if let (Some((_, rules)), Ok(delay)) = (groups.last_mut(), value.parse::<f64>()) {
+                        rules.crawl_delay = Some(Duration::from_secs_f64(delay));
+                    }
+                }
+                _ => {}
+            }
+        }
+        let find = |agent: &str| groups.iter().find(|(agents, _)| agents.iter().any(|a| a == agent));
+        find("tera")
+            .or_else(|| find("*"))
+            .map(|(_, rules)| rules.clone())
+            .unwrap_or_default()
+    }
+
+    // What a site whose robots.txt can't be read gets, its rules may well be there
+    fn disallow_all() -> Robots {
+        Robots {
+            rules: vec![("/".to_string(), false)],
+            crawl_delay: None,
+        }
+    }
+
+    /// Whether a path may be fetched, the longest matching rule wins
+    pub fn allows(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.trim_end_matches('*')))
+            .max_by_key(|(prefix, allowed)| (prefix.len(), *allowed))
+            .map(|(_, allowed)| *allowed)
+            .unwrap_or(true)
+    }
+}
+
+fn client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder().user_agent(USER_AGENT).build()?)
+}
+
+// A client for the pages of a site, only following redirects to paths of the same host which its
+// robots.txt allows
+fn site_client(robots: Arc<Robots>) -> Result<reqwest::Client> {
+    let policy = reqwest::redirect::Policy::custom(move |attempt| {
+        let same_host = attempt.previous().first().map(|first| first.host_str()) == Some(attempt.url().host_str());
+        let allowed = same_host && robots.allows(attempt.url().path());
+        match attempt.previous().len() {
+            MAX_REDIRECTS.. => attempt.error("Too many redirects"),
+            _ if allowed => attempt.follow(),
+            _ => attempt.stop(),
+        }
+    });
+    Ok(reqwest::Client::builder().user_agent(USER_AGENT).redirect(policy).build()?)
+}
+
+// Spaces the requests to a site by the delay, whether the previous one succeeded or not
+struct Pacer {
+    delay: Duration,
+    last: Instant,
+}
+
+impl Pacer {
+    // Right after a first request, e.g. for the robots.txt
+    fn new(delay: Duration) -> Pacer {
+        Pacer {
+            delay,
+            last: Instant::now(),
+        }
+    }
+
+    async fn wait(&mut self) {
+        tokio::time::sleep_until(self.last + self.delay).await;
+        self.last = Instant::now();
+    }
+}
+
+/// A GET request with the headers and cookies configured for the host in `sites`
+fn get(client: &reqwest::Client, url: &Url) -> reqwest::RequestBuilder {
+    let site = CONFIG.site(url.host_str().unwrap_or_default());
+    let mut request = client.get(url.clone());
+    for (name, value) in &site.headers {
+        request = request.header(name, value);
+    }
+    if !site.cookies.is_empty() {
+        let cookies = site
+            .cookies
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>();
+        request = request.header(reqwest::header::COOKIE, cookies.join("; "));
+    }
+    request
+}
+
+async fn fetch_robots(client: &reqwest::Client, url: &Url) -> Result<Robots> {
+    let robots_url = url.join("/robots.txt")?;
+    let response = get(client, &robots_url).send().await?;
+    match response.status() {
+        // the site may be down, it gets crawled once it serves its rules again
+        status if status.is_server_error() => Ok(Robots::disallow_all()),
+        // no robots.txt means no rules
+        status if !status.is_success() => Ok(Robots::default()),
+        _ => Ok(Robots::parse(&response.text().await?)),
+    }
+}
+
+// The rules for the site of the url, nothing may be fetched when they can't be read
+async fn site_robots(url: &Url) -> Result<Arc<Robots>> {
+    let robots = fetch_robots(&client()?, url).await.unwrap_or_else(|e| {
+        eprintln!("Unable to read the robots.txt of {}, nothing gets fetched: {:#}", url, e);
+        Robots::disallow_all()
+    });
+    Ok(Arc::new(robots))
+}
+
+// Visible text of an element, one line per block
+fn visible_text(element: ElementRef, text: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(t) => {
+                let t = t.split_whitespace().collect::<Vec<_>>().join(" ");
+                if !t.is_empty() {
+                    if !text.is_empty() && !text.ends_with('\n') {
+                        text.push(' ');
+                    }
+                    text.push_str(&t);
+                }
+            }
+            Node::Element(e) if SKIPPED_TAGS.contains(&e.name()) => {}
+            Node::Element(e) => {
+                if let Some(child) = ElementRef::wrap(child) {
+                    visible_text(child, text);
+                }
+                if BLOCK_TAGS.contains(&e.name()) && !text.ends_with('\n') {
+                    text.push('\n');
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Extracts the title, text and links of an HTML page
+pub fn parse_page(url: &Url, html: &str) -> Page {
+    let document = Html::parse_document(html);
+    let title = Selector::parse("title").unwrap();
+    let content = Selector::parse("main, article, body").unwrap();
+    let anchor = Selector::parse("a[href]").unwrap();
+
+    let title = document
+        .select(&title)
+        .next()
+        .map(|t| t.text().collect::<String>().trim().to_string())
+        .filter(|t| !t.is_empty())
+        .unwrap_or_else(|| url.to_string());
+    let mut text = String::new();
+    if let Some(root) = document.select(&content).next() {
+        visible_text(root, &mut text);
+    }
+    let links = document
+        .select(&anchor)
+        .filter_map(|a| a.value().attr("href"))
+        .filter_map(|href| url.join(href).ok())
+        .filter(|link| ["http", "https"].contains(&link.scheme()))
+        .map(|mut link| {
+            link.set_fragment(None);
+            link
+        })
+        .collect();
+
+    Page {
+        url: url.clone(),
+        title,
+        text: text.trim().to_string(),
+        links,
+    }
+}
+
+/// Fetches a page once the pacer lets it, None when it isn't HTML or redirects off the site. With the
+/// `browser` feature, pages which have too little text without JavaScript get rendered in a headless
+/// browser.
+async fn fetch_page(client: &reqwest::Client, url: &Url, pacer: &mut Pacer) -> Result<Option<Page>> {
+    pacer.wait().await;
+    let page = fetch_html(client, url).await?;
+    #[cfg(feature = "browser")]
+    if let Some(page) = page.as_ref().filter(|p| p.text.len() < MIN_TEXT_LEN) {
+        pacer.wait().await;
+        match crate::browser::render(&page.url).await {
+            Ok(html) => {
+                let rendered = parse_page(&page.url, &html);
+                if rendered.text.len() > page.text.len() {
+                    return Ok(Some(rendered));
+                }
+            }
+            Err(e) => eprintln!("Unable to render {}: {:#}", page.url, e),
+        }
+    }
+    Ok(page)
+}
+
+async fn fetch_html(client: &reqwest::Client, url: &Url) -> Result<Option<Page>> {
+    let response = get(client, url)
+        .send()
+        .await
+        .with_context(|| format!("Unable to fetch {}", url))?
+        .error_for_status()?;
+    // the redirect wasn't followed
+    if response.status().is_redirection() {
+        debug!(url = %url, "Redirected off the site or to a disallowed page");
+        return Ok(None);
+    }
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok());
+    if content_type.is_some_and(|t| !t.contains("html")) {
+        return Ok(None);
+    }
+    // redirects can land on another page
+    let url = response.url().clone();
+    Ok(Some(parse_page(&url, &response.text().await?)))
+}
+
+/// A memorized page, so it gets replaced rather than duplicated when memorized again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WebPage {
+    pub url: String,
+    /// `lastmod` of the page in its sitemap
+    pub lastmod: Option<String>,
+    pub content_id: Thing,
+}
+
+async fn memorized_page(url: &str) -> Result<Option<WebPage>> {
+    let db = DB.get().await.clone();
+    Ok(db.select(("web_page", url)).await?)
+}
+
+async fn memorize(page: &Page, depth: usize, lastmod: Option<String>) -> Result<()> {
+    if page.text.is_empty() {
+        return Ok(());
+    }
+    let url = page.url.to_string();
+    let previous = match is_dry_run() {
+        true => None,
+        false => memorized_page(&url).await?,
+    };
+    let content = queue::memorize(
+        Priority::Bulk,
+        &page.title,
+        page.text.clone(),
+        json!({
+            "connector": "web",
+            "source": page.url.to_string(),
+            "title": page.title,
+            "depth": depth,
+            "upload_time": Utc::now(),
+            "provenance": Provenance::new(url.as_str()).step("html_to_text"),
+        }),
+    )
+    .await?;
+    if is_dry_run() {
+        return Ok(());
+    }
+
+    if let Some(previous) = previous {
+        remove_content(&previous.content_id).await?;
+        record(Op::Delete {
+            content_id: previous.content_id,
+        })
+        .await?;
+    }
+    let db = DB.get().await.clone();
+    let _: Option<WebPage> = db
+        .update(("web_page", url.as_str()))
+        .content(WebPage {
+            url: url.clone(),
+            lastmod,
+            content_id: content.id,
+        })
+        .await?;
+    Ok(())
+}
+
+/// Memorizes a page and the pages of the same site it links to, breadth first.
+/// robots.txt is respected and requests are spaced by `delay`. Returns how many pages were memorized.
+pub async fn crawl(start: &str, options: &CrawlOptions) -> Result<usize> {
+    if !CONFIG.connector("web").enabled {
+        anyhow::bail!("The web connector is disabled in the config");
+    }
+    let result = crawl_site(start, options).await;
+    #[cfg(feature = "browser")]
+    crate::browser::close().await?;
+    if !is_dry_run() {
+        let status = match &result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(anyhow::anyhow!("{:#}", e)),
+        };
+        record_run("web", &status).await?;
+    }
+    result
+}
+
+async fn crawl_site(start: &str, options: &CrawlOptions) -> Result<usize> {
+    let start = Url::parse(start).with_context(|| format!("Invalid URL {}", start))?;
+    let robots = site_robots(&start).await?;
+    let mut pacer = Pacer::new(options.delay.max(robots.crawl_delay.unwrap_or_default()));
+    let client = site_client(robots.clone())?;
+
+    let mut queue = VecDeque::from([(start.clone(), 0)]);
+    let mut seen = HashSet::from([start.to_string()]);
+    let mut memorized = 0;
+    while let Some((url, depth)) = queue.pop_front() {
+        if memorized >= options.max_pages {
+            break;
+        }
+        if !robots.allows(url.path()) {
+            debug!(url = %url, "Disallowed by robots.txt");
+            continue;
+        }
+
+        println!("Processing {}", url);
+        let page = match fetch_page(&client, &url, &mut pacer).await {
+            Ok(Some(page)) => page,
+            Ok(None) => continue,
+            // a broken link shouldn't stop the crawl
+            Err(e) => {
+                eprintln!("Unable to fetch {}: {}", url, e);
+                continue;
+            }
+        };
+        memorize(&page, depth, None).await?;
+        memorized += 1;
+
+        if depth >= options.max_depth {
+            continue;
+        }
+        for link in page.links {
+            if link.host_str() != start.host_str() || !seen.insert(link.to_string()) {
+                continue;
+            }
+            queue.push_back((link, depth + 1));
+        }
+    }
+
+    Ok(memorized)
+}
+
+/// Outcome of a sitemap ingestion
+#[derive(Debug, Default)]
+pub struct SitemapReport {
+    pub memorized: usize,
+    /// Pages whose `lastmod` didn't change since they were memorized
+    pub unchanged: usize,
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+// Where a sitemap and the indexes it lists got read to
+struct SitemapWalk {
+    pacer: Pacer,
+    /// Sitemaps and pages seen, an index listing itself or a page listed twice is read once
+    visited: HashSet<String>,
+    pages: Vec<(Url, Option<String>)>,
+}
+
+/// Pages listed in a sitemap with their `lastmod`, following sitemap indexes up to
+/// `MAX_SITEMAP_DEPTH` and spacing their requests with the pacer
+async fn sitemap_pages(client: &reqwest::Client, url: Url, depth: usize, walk: &mut SitemapWalk) -> Result<()> {
+    if depth > MAX_SITEMAP_DEPTH {
+        debug!(url = %url, "Sitemap index nested too deep");
+        return Ok(());
+    }
+    walk.pacer.wait().await;
+    walk.visited.insert(url.to_string());
+    let xml = get(client, &url)
+        .send()
+        .await
+        .with_context(|| format!("Unable to fetch {}", url))?
+        .error_for_status()?
+        .text()
+        .await?;
+    for entry in SITEMAP_ENTRY.captures_iter(&xml) {
+        let Some(loc) = LOC.captures(&entry[2]) else {
+            continue;
+        };
+        let Ok(loc) = Url::parse(&loc[1].replace("&amp;", "&")) else {
+            continue;
+        };
+        if walk.visited.contains(loc.as_str()) {
+            continue;
+        }
+        let lastmod = LASTMOD.captures(&entry[2]).map(|m| m[1].to_string());
+        match &entry[1] {
+            "sitemap" => Box::pin(sitemap_pages(client, loc, depth + 1, walk)).await?,
+            _ => {
+                walk.visited.insert(loc.to_string());
+                walk.pages.push((loc, lastmod));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Memorizes the pages listed in a sitemap. Pages memorized before are only fetched again
+/// when their `lastmod` changed, and then replace what was memorized of them.
+pub async fn ingest_sitemap(sitemap: &str, delay: Duration) -> Result<SitemapReport> {
+    if !CONFIG.connector("web").enabled {
+        anyhow::bail!("The web connector is disabled in the config");
+    }
+    let sitemap = Url::parse(sitemap).with_context(|| format!("Invalid URL {}", sitemap))?;
+    let robots = site_robots(&sitemap).await?;
+    let client = site_client(robots.clone())?;
+    let host = sitemap.host_str().map(str::to_string);
+
+    let mut walk = SitemapWalk {
+        pacer: Pacer::new(delay.max(robots.crawl_delay.unwrap_or_default())),
+        visited: HashSet::new(),
+        pages: vec![],
+    };
+    sitemap_pages(&client, sitemap, 0, &mut walk).await?;
+    let (pages, mut pacer) = (walk.pages, walk.pacer);
+    let mut report = SitemapReport::default();
+    for (i, (url, lastmod)) in pages.iter().enumerate() {
+        // the robots.txt read is the one of the sitemap's host
+        if url.host_str() != host.as_deref() {
+            debug!(url = %url, "Listed for another site");
+            continue;
+        }
+        if !robots.allows(url.path()) {
+            debug!(url = %url, "Disallowed by robots.txt");
+            continue;
+        }
+        // pages without a lastmod are always fetched again
+        if let (Some(lastmod), false) = (lastmod, is_dry_run()) {
+            let previous = memorized_page(url.as_str()).await?;
+            if previous.and_then(|p| p.lastmod).as_ref() == Some(lastmod) {
+                report.unchanged += 1;
+                continue;
+            }
+        }
+        println!("Processing {}/{} {}", i + 1, pages.len(), url);
+        let result = match fetch_page(&client, url, &mut pacer).await {
+            Ok(Some(page)) => memorize(&page, 0, lastmod.clone()).await,
+            Ok(None) => continue,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => report.memorized += 1,
+            Err(e) => report.failed.push((url.to_string(), e)),
+        }
+    }
+
+    #[cfg(feature = "browser")]
+    crate::browser::close().await?;
+
+    if !is_dry_run() {
+        let status = match report.failed.first() {
+            None => Ok(()),
+            Some((url, e)) => Err(anyhow::anyhow!("{}: {:#}", url, e)),
+        };
+        record_run("web", &status).await?;
+    }
+    Ok(report)
+}
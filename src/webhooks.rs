@@ -0,0 +1,65 @@
+use crate::config::{live, WebhookEvent};
+use crate::notify::post_json;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tracing::debug;
+
+// Block size of SHA-256, keys of HMAC are padded to it
+const BLOCK_SIZE: usize = 64;
+
+/// HMAC-SHA256 of a payload as hex, sent in the `X-Tera-Signature` header as `sha256=<hex>`
+pub fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut key = secret.as_bytes().to_vec();
+    if key.len() > BLOCK_SIZE {
+        key = Sha256::digest(&key).to_vec();
+    }
+    key.resize(BLOCK_SIZE, 0);
+    let pad = |byte: u8| key.iter().map(|k| k ^ byte).collect::<Vec<_>>();
+    let inner = Sha256::new().chain_update(pad(0x36)).chain_update(payload).finalize();
+    let outer = Sha256::new().chain_update(pad(0x5c)).chain_update(inner).finalize();
+    format!("{:x}", outer)
+}
+
+/// Whether a webhook is configured for the event, so its data only gets gathered when needed
+pub fn subscribed(event: WebhookEvent) -> bool {
+    live()
+        .webhooks
+        .iter()
+        .any(|w| w.events.is_empty() || w.events.contains(&event))
+}
+
+fn deliver(url: &str, secret: Option<&str>, payload: &str) -> Result<()> {
+    let signature = secret.map(|secret| format!("sha256={}", sign(secret, payload.as_bytes())));
+    let headers = signature.iter().map(|signature| ("X-Tera-Signature", signature.as_str()));
+    post_json(url, headers, payload).with_context(|| format!("Unable to deliver webhook to {}", url))
+}
+
+/// Posts an event to the `webhooks` of the config which want it, as
+/// `{"event": "answer_completed", "at": "...", "data": {...}}`.
+/// Failed deliveries are reported and don't fail what triggered them.
+pub async fn emit(event: WebhookEvent, data: Value) {
+    if !subscribed(event) {
+        return;
+    }
+    let payload = json!({
+        "event": event,
+        "at": chrono::Utc::now(),
+        "data": data,
+    })
+    .to_string();
+    let config = live();
+    for webhook in config.webhooks.iter() {
+        if !webhook.events.is_empty() && !webhook.events.contains(&event) {
+            continue;
+        }
+        debug!(url = webhook.url, event = ?event, "Delivering webhook");
+        let payload = payload.clone();
+        let result = tokio::task::spawn_blocking(move || deliver(&webhook.url, webhook.secret.as_deref(), &payload)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("{:#}", e),
+            Err(e) => eprintln!("Unable to deliver webhook: {}", e),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::provenance::Provenance;
+use crate::queue::{self, Priority};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use rusqlite::{Connection, OpenFlags};
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tempfile::tempdir;
+use tracing::debug;
+
+#[derive(Debug, Clone, Default)]
+pub struct Paper {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub year: String,
+    pub doi: String,
+    pub pdf: PathBuf,
+}
+
+impl Paper {
+    /// Author-year citation, e.g. "Vaswani et al. (2017)"
+    pub fn citation(&self) -> String {
+        let authors = match self.authors.len() {
+            0 => "Unknown".to_string(),
+            1 => self.authors[0].clone(),
+            2 => format!("{} and {}", self.authors[0], self.authors[1]),
+            _ => format!("{} et al.", self.authors[0]),
+        };
+        let mut citation = format!("{} ({}) {}", authors, self.year, self.title);
+        if !self.doi.is_empty() {
+            citation += &format!(", doi:{}", self.doi);
+        }
+        citation
+    }
+}
+
+/// Lists the papers of a Zotero data directory (`~/Zotero`) which have a PDF attachment
+pub fn read_library(data_dir: &Path) -> Result<Vec<Paper>> {
+    // Zotero locks its database while running, query a copy
+    let temp_dir = tempdir()?;
+    let copy = temp_dir.path().join("zotero.sqlite");
+    std::fs::copy(data_dir.join("zotero.sqlite"), &copy)
+        .context("Unable to copy zotero.sqlite from the Zotero data directory, on Windows close Zotero first")?;
+    let conn = Connection::open_with_flags(&copy, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let mut fields: HashMap<i64, Paper> = HashMap::new();
+    let mut statement = conn.prepare(
+        "SELECT itemData.itemID, fields.fieldName, itemDataValues.value
+        FROM itemData
+        JOIN fields ON fields.fieldID = itemData.fieldID
+        JOIN itemDataValues ON itemDataValues.valueID = itemData.valueID
+        WHERE fields.fieldName IN ('title', 'date', 'DOI')
+        AND itemData.itemID NOT IN (SELECT itemID FROM deletedItems)",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+    })?;
+    for row in rows {
+        let (item, field, value) = row?;
+        let paper = fields.entry(item).or_default();
+        match field.as_str() {
+            "title" => paper.title = value,
+            // dates are stored as "2017-06-12 2017-06-12", the year is enough for citations
+            "date" => paper.year = value.chars().take(4).collect(),
+            "DOI" => paper.doi = value,
+            _ => {}
+        }
+    }
+
+    let mut statement = conn.prepare(
+        "SELECT itemCreators.itemID, creators.lastName
+        FROM itemCreators
+        JOIN creators ON creators.creatorID = itemCreators.creatorID
+        ORDER BY itemCreators.itemID, itemCreators.orderIndex",
+    )?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))?;
+    for row in rows {
+        let (item, author) = row?;
+        if let Some(paper) = fields.get_mut(&item) {
+            paper.authors.push(author);
+        }
+    }
+
+    // attachments live in storage/<attachment key>/<file name>, with a path of "storage:<file name>"
+    let mut statement = conn.prepare(
+        "SELECT itemAttachments.parentItemID, items.key, itemAttachments.path
+        FROM itemAttachments
+        JOIN items ON items.itemID = itemAttachments.itemID
+        WHERE itemAttachments.contentType = 'application/pdf' AND itemAttachments.parentItemID IS NOT NULL",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, Option<String>>(2)?,
+        ))
+    })?;
+    let mut papers = vec![];
+    for row in rows {
+        let (parent, key, path) = row?;
+        let (Some(paper), Some(path)) = (fields.get(&parent), path) else {
+            continue;
+        };
+        let pdf = match path.strip_prefix("storage:") {
+            Some(file_name) => data_dir.join("storage").join(key).join(file_name),
+            // linked files keep their absolute path
+            None => PathBuf::from(path),
+        };
+        if !pdf.exists() {
+            debug!(pdf = ?pdf, "Missing Zotero attachment, skipping");
+            continue;
+        }
+        papers.push(Paper {
+            pdf,
+            ..paper.clone()
+        });
+    }
+    Ok(papers)
+}
+
+pub async fn ingest_zotero_library(data_dir: PathBuf) -> Result<()> {
+    println!("Processing Zotero library from {}", data_dir.display());
+    let papers = read_library(&data_dir)?;
+    println!("Found {} papers with a PDF", papers.len());
+
+    for (i, paper) in papers.iter().enumerate() {
+        println!("Memorizing paper {}/{}: {}", i + 1, papers.len(), paper.title);
+        let bytes = std::fs::read(&paper.pdf)?;
+        let text = match pdf_extract::extract_text_from_mem(&bytes) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("Unable to read {}: {}", paper.pdf.display(), e);
+                continue;
+            }
+        };
+        queue::memorize(
+            Priority::Bulk,
+            &paper.title,
+            text,
+            json!({
+                "connector": "zotero",
+                "title": paper.title,
+                "authors": paper.authors,
+                "year": paper.year,
+                "doi": paper.doi,
+                "citation": paper.citation(),
+                "upload_time": Utc::now(),
+                "source": "Zotero",
+                "provenance": Provenance::file(&paper.pdf).step("pdf_text"),
+            }),
+        )
+        .await?;
+    }
+
+    Ok(())
+}